@@ -0,0 +1,462 @@
+use actix_web::{
+    get, post,
+    web::{self, Data, Json, Path, Query},
+};
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::AccessToken,
+    error::{Error, ErrorKind},
+    storage::{EventQuery, QueryType},
+    util::{MatrixId, RoomId},
+    ServerState,
+};
+
+/// How long a registration nonce stays valid after it is issued.
+const NONCE_LIFETIME: Duration = Duration::from_secs(60);
+
+pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
+    let v1 = web::scope("/v1")
+        .service(get_register_nonce)
+        .service(register)
+        .service(room_messages);
+
+    cfg.service(v1);
+}
+
+/// The nonces handed out for shared-secret registration. Each one can be used once, and only
+/// until it expires.
+#[derive(Default)]
+pub struct RegistrationNonces {
+    issued: Mutex<HashMap<String, Instant>>,
+}
+
+impl RegistrationNonces {
+    fn issue(&self) -> String {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let now = Instant::now();
+        let mut issued = self.issued.lock().unwrap();
+        issued.retain(|_, issued_at| now.duration_since(*issued_at) < NONCE_LIFETIME);
+        issued.insert(nonce.clone(), now);
+        nonce
+    }
+
+    /// Returns whether the nonce was issued and hasn't expired, forgetting it either way.
+    fn consume(&self, nonce: &str) -> bool {
+        match self.issued.lock().unwrap().remove(nonce) {
+            Some(issued_at) => issued_at.elapsed() < NONCE_LIFETIME,
+            None => false,
+        }
+    }
+}
+
+fn shared_secret(state: &ServerState) -> Result<&str, Error> {
+    state
+        .config
+        .registration_shared_secret
+        .as_deref()
+        .ok_or_else(|| {
+            ErrorKind::Unknown("Shared secret registration is not enabled".to_string()).into()
+        })
+}
+
+#[get("/register")]
+#[instrument(skip(state), err = Level::DEBUG)]
+async fn get_register_nonce(state: Data<Arc<ServerState>>) -> Result<Json<JsonValue>, Error> {
+    shared_secret(&state)?;
+    Ok(Json(json!({ "nonce": state.registration_nonces.issue() })))
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    nonce: String,
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    mac: String,
+}
+
+impl RegisterRequest {
+    /// The message that the client signs with the shared secret.
+    fn mac_input(&self) -> Vec<u8> {
+        let admin = if self.admin { "admin" } else { "notadmin" };
+        [&*self.nonce, &*self.username, &*self.password, admin]
+            .join("\0")
+            .into_bytes()
+    }
+}
+
+fn verify_mac(secret: &str, req: &RegisterRequest) -> bool {
+    let mac = match (0..req.mac.len())
+        .step_by(2)
+        .map(|i| {
+            req.mac
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+    {
+        Some(mac) => mac,
+        None => return false,
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    hmac::verify(&key, &req.mac_input(), &mac).is_ok()
+}
+
+#[post("/register")]
+#[instrument(skip(state, req), fields(username = Empty), err = Level::DEBUG)]
+async fn register(
+    state: Data<Arc<ServerState>>,
+    req: Json<RegisterRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let secret = shared_secret(&state)?;
+    let req = req.into_inner();
+    Span::current().record("username", &req.username.as_str());
+
+    if !state.registration_nonces.consume(&req.nonce) {
+        return Err(ErrorKind::InvalidParam(String::from("unrecognised nonce")).into());
+    }
+    if !verify_mac(secret, &req) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let user_id = MatrixId::new(&req.username, &state.config.domain)
+        .map_err(|e| ErrorKind::BadJson(format!("{}", e)))?;
+    let db = state.db_pool.get_handle().await?;
+    db.create_user(user_id.localpart(), &req.password).await?;
+    if req.admin {
+        db.set_admin(user_id.localpart(), true).await?;
+    }
+
+    let device_id = format!("{:08X}", rand::random::<u32>());
+    let access_token = db
+        .create_access_token(user_id.localpart(), &device_id)
+        .await?;
+    let access_token = format!("{}", access_token.to_hyphenated());
+
+    tracing::info!(admin = req.admin, "User registered with shared secret");
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "access_token": access_token,
+        "home_server": state.config.domain,
+        "device_id": device_id
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomMessagesRequest {
+    #[serde(default)]
+    from: usize,
+    #[serde(default = "default_messages_limit")]
+    limit: usize,
+}
+
+fn default_messages_limit() -> usize {
+    10
+}
+
+/// Lists the events in a room's timeline, including the soft failed ones that clients never see.
+#[get("/rooms/{room_id}/messages")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+async fn room_messages(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<RoomId>,
+    req: Query<RoomMessagesRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if !db.is_admin(&username).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let (events, end) = db
+        .query_events(
+            EventQuery {
+                query_type: QueryType::Timeline {
+                    from: req.from,
+                    to: Some(req.from.saturating_add(req.limit.max(1) - 1)),
+                },
+                room_id: room_id.as_str(),
+                senders: &[],
+                not_senders: &[],
+                types: &[],
+                not_types: &[],
+                contains_json: None,
+                include_soft_failed: true,
+            },
+            false,
+        )
+        .await?;
+    Ok(Json(json!({
+        "chunk": events,
+        "end": (end + 1).to_string(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use ring::hmac;
+    use serde_json::{json, Value as JsonValue};
+    use std::{collections::HashMap, time::Duration};
+
+    use super::{verify_mac, RegisterRequest};
+    use crate::{
+        client_api::tests::{server_state, server_state_with_config, test_config},
+        events::{
+            room::Message,
+            room_version::{v4::UnhashedPdu, VersionedPdu},
+            EventContent,
+        },
+        storage::{mem::MemStorageManager, StorageManager},
+        util::{
+            storage::{calc_auth_events, NewEvent},
+            MatrixId, StorageExt,
+        },
+        Config,
+    };
+
+    fn sign(secret: &str, nonce: &str, username: &str, password: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+        let message = format!("{}\0{}\0{}\0admin", nonce, username, password);
+        let tag = hmac::sign(&key, message.as_bytes());
+        tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn mac_matches_synapse() {
+        let req = RegisterRequest {
+            nonce: String::from("abc"),
+            username: String::from("admin"),
+            password: String::from("password"),
+            admin: true,
+            mac: String::from("7a2affbc3ef8c972a5257ee8e25b2be107619bf8"),
+        };
+        assert!(verify_mac("hunter2", &req));
+        assert!(!verify_mac("hunter3", &req));
+        assert!(!verify_mac(
+            "hunter2",
+            &RegisterRequest {
+                admin: false,
+                ..req
+            }
+        ));
+    }
+
+    #[test]
+    fn register_admin_with_shared_secret() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let config = Config {
+                registration_shared_secret: Some(String::from("hunter2")),
+                ..test_config()
+            };
+            let state = server_state_with_config(db_pool, config).await;
+            let mut app = test::init_service(
+                App::new()
+                    .data(state)
+                    .service(web::scope("/_synapse/admin").configure(super::configure_endpoints)),
+            )
+            .await;
+
+            let get_nonce = || {
+                test::TestRequest::get()
+                    .uri("/_synapse/admin/v1/register")
+                    .to_request()
+            };
+            let register = |nonce: &str, mac: &str| {
+                test::TestRequest::post()
+                    .uri("/_synapse/admin/v1/register")
+                    .set_json(&json!({
+                        "nonce": nonce,
+                        "username": "admin",
+                        "password": "password",
+                        "admin": true,
+                        "mac": mac,
+                    }))
+                    .to_request()
+            };
+
+            let res: JsonValue = test::read_response_json(&mut app, get_nonce()).await;
+            let nonce = res["nonce"].as_str().unwrap().to_string();
+            let wrong_mac = sign("hunter3", &nonce, "admin", "password");
+            let res = test::call_service(&mut app, register(&nonce, &wrong_mac)).await;
+            assert_eq!(res.status(), 403);
+            assert!(!db.verify_password("admin", "password").await.unwrap());
+
+            // the failed attempt used up the nonce
+            let mac = sign("hunter2", &nonce, "admin", "password");
+            let res = test::call_service(&mut app, register(&nonce, &mac)).await;
+            assert_eq!(res.status(), 400);
+
+            let res: JsonValue = test::read_response_json(&mut app, get_nonce()).await;
+            let nonce = res["nonce"].as_str().unwrap().to_string();
+            let mac = sign("hunter2", &nonce, "admin", "password");
+            let res: JsonValue = test::read_response_json(&mut app, register(&nonce, &mac)).await;
+            assert_eq!(res["user_id"], "@admin:example.org");
+            assert!(res["access_token"].is_string());
+            assert!(db.verify_password("admin", "password").await.unwrap());
+            assert!(db.is_admin("admin").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn disabled_without_shared_secret() {
+        actix_web::rt::System::new("test").block_on(async {
+            let state = server_state_with_config(MemStorageManager::new(), test_config()).await;
+            let mut app = test::init_service(
+                App::new()
+                    .data(state)
+                    .service(web::scope("/_synapse/admin").configure(super::configure_endpoints)),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/_synapse/admin/v1/register")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(!res.status().is_success());
+        });
+    }
+
+    #[test]
+    fn soft_failed_events_are_only_shown_to_admins() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            db.set_admin("alice", true).await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(
+                App::new()
+                    .data(state.clone())
+                    .service(
+                        web::scope("/_matrix/client")
+                            .configure(crate::client_api::configure_endpoints),
+                    )
+                    .service(web::scope("/_synapse/admin").configure(super::configure_endpoints)),
+            )
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({
+                    "visibility": "public",
+                    "power_level_content_override": {
+                        "events": {},
+                        "users": { "@alice:example.org": 100 },
+                    },
+                }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            // bob's server sends a message that it says came before bob got banned
+            let (prev_events, depth) = db.get_prev_events(&room_id).await.unwrap();
+            let state_before = state
+                .state_resolver
+                .resolve(&room_id, &prev_events)
+                .await
+                .unwrap();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/ban", room_id))
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({ "user_id": "@bob:example.org" }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let bob_id = MatrixId::new("bob", "example.org").unwrap();
+            let message = NewEvent::builder(
+                bob_id.clone(),
+                EventContent::Message(Message {
+                    msgtype: Some(String::from("m.text")),
+                    body: Some(String::from("you can't ban me")),
+                    extra: HashMap::new(),
+                }),
+            )
+            .build();
+            let pdu = VersionedPdu::V4(
+                UnhashedPdu {
+                    auth_events: calc_auth_events(&message, &state_before),
+                    event_content: message.event_content,
+                    room_id: room_id.clone(),
+                    sender: bob_id,
+                    state_key: None,
+                    unsigned: None,
+                    redacts: None,
+                    origin: String::from("example.org"),
+                    origin_server_ts: chrono::Utc::now().timestamp_millis(),
+                    prev_events,
+                    depth: depth + 1,
+                }
+                .finalize(),
+            );
+            let event_id = db
+                .receive_pdu(pdu, &state.state_resolver, Duration::from_secs(300))
+                .await
+                .unwrap();
+            let pdu = db.get_pdu(&room_id, &event_id).await.unwrap().unwrap();
+            assert!(pdu.did_pass_auth());
+            assert!(pdu.soft_failed);
+
+            let has_event = |events: &JsonValue| {
+                events
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|e| e["event_id"] == event_id.as_str())
+            };
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let timeline = &res["rooms"]["join"][&room_id]["timeline"]["events"];
+            assert!(!timeline.as_array().unwrap().is_empty());
+            assert!(!has_event(timeline));
+
+            let messages = |auth: &(header::HeaderName, String)| {
+                test::TestRequest::get()
+                    .uri(&format!(
+                        "/_synapse/admin/v1/rooms/{}/messages?limit=100",
+                        room_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, messages(&alice)).await;
+            assert!(has_event(&res["chunk"]));
+            let res = test::call_service(&mut app, messages(&bob)).await;
+            assert_eq!(res.status(), 403);
+        });
+    }
+}
@@ -1,12 +1,18 @@
 use actix_web::{
-    dev::Payload,
+    dev::{Payload, RequestHead, Service, ServiceRequest, ServiceResponse},
     get, post,
-    web::{Data, Json},
+    web::{Data, Json, Query},
     FromRequest, HttpRequest,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{convert::TryFrom, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 use tracing::{field::Empty, instrument, span::Span, Level};
 use uuid::Uuid;
 
@@ -25,45 +31,124 @@ enum LoginType {
 #[derive(Debug)]
 pub struct AccessToken(pub Uuid);
 
+impl AccessToken {
+    fn from_request_head(head: &RequestHead) -> Result<Self, ErrorKind> {
+        if let Some(s) = head.headers().get("Authorization") {
+            let s: &str = s.to_str().map_err(|_| ErrorKind::MissingToken)?;
+            if !s.starts_with("Bearer ") {
+                return Err(ErrorKind::MissingToken);
+            }
+            let token = s
+                .trim_start_matches("Bearer ")
+                .parse()
+                .map_err(|_| ErrorKind::UnknownToken)?;
+            Ok(AccessToken(token))
+        } else if let Some(pair) = head
+            .uri
+            .query()
+            .ok_or(ErrorKind::MissingToken)?
+            .split('&')
+            .find(|pair| pair.starts_with("access_token"))
+        {
+            let token = pair
+                .trim_start_matches("access_token=")
+                .parse()
+                .map_err(|_| ErrorKind::UnknownToken)?;
+            Ok(AccessToken(token))
+        } else {
+            Err(ErrorKind::MissingToken)
+        }
+    }
+}
+
 impl FromRequest for AccessToken {
     type Error = Error;
     type Future = futures::future::Ready<Result<Self, Self::Error>>;
     type Config = ();
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let res = (|| {
-            if let Some(s) = req.headers().get("Authorization") {
-                let s: &str = s.to_str().map_err(|_| ErrorKind::MissingToken)?;
-                if !s.starts_with("Bearer ") {
-                    return Err(ErrorKind::MissingToken);
-                }
-                let token = s
-                    .trim_start_matches("Bearer ")
-                    .parse()
-                    .map_err(|_| ErrorKind::UnknownToken)?;
-                Ok(token)
-            } else if let Some(pair) = req
-                .uri()
-                .query()
-                .ok_or(ErrorKind::MissingToken)?
-                .split('&')
-                .find(|pair| pair.starts_with("access_token"))
-            {
-                let token = pair
-                    .trim_start_matches("access_token=")
-                    .parse()
-                    .map_err(|_| ErrorKind::UnknownToken)?;
-                Ok(token)
-            } else {
-                Err(ErrorKind::MissingToken)
-            }
-        })();
-        match res {
-            Ok(token) => futures::future::ok(AccessToken(token)),
+        match AccessToken::from_request_head(req.head()) {
+            Ok(token) => futures::future::ok(token),
             Err(e) => futures::future::err(e.into()),
         }
     }
 }
 
+/// How long to wait before recording another use of the same access token, in milliseconds.
+const LAST_SEEN_INTERVAL: i64 = 60 * 1000;
+
+/// Remembers when each access token's use was last recorded, so that busy clients don't cause a
+/// database write on every request.
+#[derive(Debug, Default)]
+pub struct LastSeen {
+    recorded: Mutex<HashMap<Uuid, i64>>,
+}
+
+impl LastSeen {
+    /// Returns whether a use of `token` at time `now` should be written to the database.
+    pub fn should_record(&self, token: Uuid, now: i64) -> bool {
+        let mut recorded = self.recorded.lock().unwrap();
+        // forget about tokens which haven't been used in a while, so this doesn't grow forever
+        recorded.retain(|_, ts| now - *ts < LAST_SEEN_INTERVAL);
+        !recorded.contains_key(&token)
+    }
+
+    /// Notes that a use of `token` at time `now` has been written to the database.
+    pub fn recorded(&self, token: Uuid, now: i64) {
+        self.recorded.lock().unwrap().insert(token, now);
+    }
+}
+
+/// Records a use of `token` at time `now`, unless one was recorded less than `LAST_SEEN_INTERVAL`
+/// before. Only tokens that authenticate count, so that made up ones can't crowd out real ones.
+pub async fn record_token_use(
+    state: &ServerState,
+    token: Uuid,
+    ip: Option<&str>,
+    now: i64,
+) -> Result<(), Error> {
+    if !state.last_seen.should_record(token, now) {
+        return Ok(());
+    }
+    let db = state.db_pool.get_handle().await?;
+    if db.try_auth(token).await?.is_none() {
+        return Ok(());
+    }
+    db.update_token_last_seen(token, ip, now).await?;
+    state.last_seen.recorded(token, now);
+    Ok(())
+}
+
+/// Middleware which records when and from where each access token was last used, for the
+/// user's device list. Requests without a token are passed through untouched, and the write
+/// happens in the background so it never holds up the response.
+pub fn track_last_seen<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let token = AccessToken::from_request_head(req.head()).ok();
+    // the peer address includes a port, which isn't interesting here
+    let ip = req.connection_info().realip_remote_addr().map(|addr| {
+        addr.parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| addr.to_string())
+    });
+    let state = req.app_data::<Data<Arc<ServerState>>>().cloned();
+    if let (Some(token), Some(state)) = (token, state) {
+        let ts = chrono::Utc::now().timestamp_millis();
+        if state.last_seen.should_record(token.0, ts) {
+            actix_web::rt::spawn(async move {
+                if let Err(e) = record_token_use(&state, token.0, ip.as_deref(), ts).await {
+                    tracing::warn!("Failed to record token use: {}", e);
+                }
+            });
+        }
+    }
+    srv.call(req)
+}
+
 #[get("/login")]
 #[instrument]
 pub async fn get_supported_login_types() -> Json<serde_json::Value> {
@@ -226,3 +311,361 @@ pub async fn register(
         "device_id": device_id
     })))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct AvailableRequest {
+    username: String,
+}
+
+#[get("/register/available")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn get_username_available(
+    state: Data<Arc<ServerState>>,
+    req: Query<AvailableRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let username = &req.username;
+    if username.is_empty() {
+        return Err(ErrorKind::InvalidUsername(String::from("it is empty")).into());
+    }
+    MatrixId::validate_parts(username, &state.config.domain)
+        .map_err(|e| ErrorKind::InvalidUsername(format!("{}", e)))?;
+
+    let db = state.db_pool.get_handle().await?;
+    if db.user_exists(username).await? {
+        return Err(ErrorKind::UsernameTaken.into());
+    }
+    Ok(Json(json!({ "available": true })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordAuth {
+    #[serde(rename = "type")]
+    auth_type: LoginType,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    new_password: String,
+    #[serde(default = "default_logout_devices")]
+    logout_devices: bool,
+    //TODO: this should be a proper user-interactive auth flow
+    auth: Option<PasswordAuth>,
+}
+
+fn default_logout_devices() -> bool {
+    true
+}
+
+#[post("/account/password")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn change_password(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<ChangePasswordRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let req = req.into_inner();
+    let PasswordAuth {
+        auth_type: LoginType::Password,
+        password,
+    } = req.auth.ok_or_else(|| {
+        ErrorKind::BadJson(String::from("the current password is required in auth"))
+    })?;
+    if !db.verify_password(&username, &password).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    db.set_password(&username, &req.new_password).await?;
+
+    // the device that changed the password stays logged in
+    if req.logout_devices {
+        for device in db.get_devices(&username).await? {
+            if device.device_id != device_id {
+                db.delete_device(&username, &device.device_id).await?;
+            }
+        }
+    }
+
+    tracing::info!("Password changed");
+    Ok(Json(json!({})))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeactivateRequest {
+    //TODO: this should be a proper user-interactive auth flow
+    auth: Option<PasswordAuth>,
+}
+
+#[post("/account/deactivate")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn deactivate(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<DeactivateRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let PasswordAuth {
+        auth_type: LoginType::Password,
+        password,
+    } = req.into_inner().auth.ok_or_else(|| {
+        ErrorKind::BadJson(String::from("the current password is required in auth"))
+    })?;
+    if !db.verify_password(&username, &password).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    db.deactivate_user(&username).await?;
+
+    tracing::info!("Account deactivated");
+    // no third party identifiers are ever bound, so there is nothing to unbind
+    Ok(Json(json!({ "id_server_unbind_result": "success" })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+    use uuid::Uuid;
+
+    use super::{record_token_use, LastSeen, LAST_SEEN_INTERVAL};
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn last_seen_debounce() {
+        let last_seen = LastSeen::default();
+        let token = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        assert!(last_seen.should_record(token, 1000));
+        // nothing is debounced until it's actually been recorded
+        assert!(last_seen.should_record(token, 1001));
+        last_seen.recorded(token, 1001);
+        assert!(!last_seen.should_record(token, 1002));
+        assert!(!last_seen.should_record(token, 1001 + LAST_SEEN_INTERVAL - 1));
+        // each token is debounced separately
+        assert!(last_seen.should_record(other, 1002));
+        assert!(last_seen.should_record(token, 1001 + LAST_SEEN_INTERVAL));
+    }
+
+    #[test]
+    fn only_authenticated_token_use_is_debounced() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let last_seen = || async {
+                let device = db.get_devices("alice").await.unwrap().remove(0);
+                (device.last_seen_ip, device.last_seen_ts)
+            };
+
+            let made_up = Uuid::new_v4();
+            record_token_use(&state, made_up, None, 1000).await.unwrap();
+            assert!(state.last_seen.should_record(made_up, 1001));
+
+            record_token_use(&state, token, Some("192.0.2.1"), 1000)
+                .await
+                .unwrap();
+            assert_eq!(
+                last_seen().await,
+                (Some(String::from("192.0.2.1")), Some(1000))
+            );
+            for ts in (1001..).step_by(10_000).take(5) {
+                record_token_use(&state, token, Some("192.0.2.2"), ts)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(
+                last_seen().await,
+                (Some(String::from("192.0.2.1")), Some(1000))
+            );
+            record_token_use(&state, token, Some("192.0.2.2"), 1000 + LAST_SEEN_INTERVAL)
+                .await
+                .unwrap();
+            assert_eq!(
+                last_seen().await,
+                (
+                    Some(String::from("192.0.2.2")),
+                    Some(1000 + LAST_SEEN_INTERVAL)
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn default_display_name() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new().with_default_displayname_to_localpart(true);
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/register?kind=user")
+                .set_json(&json!({
+                    "auth": {},
+                    "bind_email": false,
+                    "bind_msisdn": false,
+                    "username": "alice",
+                    "password": "password",
+                    "initial_device_display_name": "phone",
+                    "inhibit_login": true,
+                }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/profile/@alice:example.org/displayname")
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["displayname"], "alice");
+        });
+    }
+
+    #[test]
+    fn change_password() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "old password").await.unwrap();
+            let phone = db.create_access_token("alice", "phone").await.unwrap();
+            let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let change_password = |current: &str| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/account/password")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", phone))
+                    .set_json(&json!({
+                        "new_password": "new password",
+                        "auth": { "type": "m.login.password", "password": current },
+                    }))
+                    .to_request()
+            };
+
+            let res = test::call_service(&mut app, change_password("wrong password")).await;
+            assert_eq!(res.status(), 403);
+            let res: JsonValue = test::read_body_json(res).await;
+            assert_eq!(res["errcode"], "M_FORBIDDEN");
+            assert!(db.verify_password("alice", "old password").await.unwrap());
+            assert!(db.try_auth(laptop).await.unwrap().is_some());
+
+            let res = test::call_service(&mut app, change_password("old password")).await;
+            assert!(res.status().is_success());
+            assert!(!db.verify_password("alice", "old password").await.unwrap());
+            assert!(db.verify_password("alice", "new password").await.unwrap());
+            // every other device was logged out
+            assert!(db.try_auth(phone).await.unwrap().is_some());
+            assert!(db.try_auth(laptop).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn deactivate_account() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let phone = db.create_access_token("alice", "phone").await.unwrap();
+            let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/account/deactivate")
+                .header(header::AUTHORIZATION, format!("Bearer {}", phone))
+                .set_json(&json!({
+                    "auth": { "type": "m.login.password", "password": "password" },
+                }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            assert!(db.try_auth(phone).await.unwrap().is_none());
+            assert!(db.try_auth(laptop).await.unwrap().is_none());
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/login")
+                .set_json(&json!({
+                    "type": "m.login.password",
+                    "identifier": { "type": "m.id.user", "user": "alice" },
+                    "password": "password",
+                    "initial_device_display_name": "phone",
+                }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 403);
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/register?kind=user")
+                .set_json(&json!({
+                    "username": "alice",
+                    "password": "password",
+                    "auth": { "type": "m.login.dummy" },
+                    "bind_email": false,
+                    "bind_msisdn": false,
+                    "initial_device_display_name": "phone",
+                    "inhibit_login": false,
+                }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["errcode"], "M_USER_IN_USE");
+        });
+    }
+
+    #[test]
+    fn username_availability() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let available = |username: &str| {
+                test::TestRequest::get()
+                    .uri(&format!(
+                        "/_matrix/client/r0/register/available?username={}",
+                        username
+                    ))
+                    .to_request()
+            };
+
+            let res: JsonValue = test::read_response_json(&mut app, available("bob")).await;
+            assert_eq!(res, json!({ "available": true }));
+            let res = test::call_service(&mut app, available("alice")).await;
+            assert_eq!(res.status(), 403);
+            let res: JsonValue = test::read_body_json(res).await;
+            assert_eq!(res["errcode"], "M_USER_IN_USE");
+            for invalid in &["Bob", "b%20b", ""] {
+                let res = test::call_service(&mut app, available(invalid)).await;
+                assert_eq!(res.status(), 400);
+                let res: JsonValue = test::read_body_json(res).await;
+                assert_eq!(res["errcode"], "M_INVALID_USERNAME");
+            }
+        });
+    }
+}
@@ -0,0 +1,200 @@
+use actix_web::{
+    delete, get, put,
+    web::{Data, Json, Path},
+};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::Device,
+    ServerState,
+};
+
+#[get("/devices")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_devices(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let devices = db.get_devices(&username).await?;
+    Ok(Json(json!({ "devices": devices })))
+}
+
+#[get("/devices/{device_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+) -> Result<Json<Device>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let device = db
+        .get_device(&username, &device_id)
+        .await?
+        .ok_or(ErrorKind::NotFound)?;
+    Ok(Json(device))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateDeviceRequest {
+    display_name: Option<String>,
+}
+
+#[put("/devices/{device_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn update_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+    req: Json<UpdateDeviceRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if db.get_device(&username, &device_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+    if let Some(display_name) = &req.display_name {
+        db.set_device_display_name(&username, &device_id, display_name)
+            .await?;
+    }
+    Ok(Json(json!({})))
+}
+
+#[delete("/devices/{device_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn delete_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    //TODO: the spec wants user-interactive auth here, which we don't have yet
+    if db.get_device(&username, &device_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+    db.delete_device(&username, &device_id).await?;
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+    use std::time::Duration;
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, Storage, StorageManager},
+    };
+
+    /// Waits a little for any background work spawned by a request to finish.
+    async fn settle() {
+        actix_web::rt::time::delay_for(Duration::from_millis(20)).await;
+    }
+
+    async fn last_seen(db: &dyn Storage) -> (Option<String>, Option<i64>) {
+        let device = db.get_devices("alice").await.unwrap().remove(0);
+        (device.last_seen_ip, device.last_seen_ts)
+    }
+
+    #[test]
+    fn token_use_updates_last_seen() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            assert_eq!(db.get_devices("alice").await.unwrap()[0].last_seen_ts, None);
+
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = || {
+                test::TestRequest::get()
+                    .uri("/_matrix/client/r0/devices/phone")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .peer_addr("192.0.2.1:4000".parse().unwrap())
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, req()).await;
+            assert_eq!(res["device_id"], "phone");
+            settle().await;
+            let (ip, ts) = last_seen(&*db).await;
+            assert_eq!(ip.as_deref(), Some("192.0.2.1"));
+            assert!(ts.is_some());
+
+            let res: JsonValue = test::read_response_json(&mut app, req()).await;
+            assert_eq!(res["last_seen_ip"], "192.0.2.1");
+            assert_eq!(res["last_seen_ts"], ts.unwrap());
+        });
+    }
+
+    #[test]
+    fn rename_and_delete_devices() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let phone = db.create_access_token("alice", "phone").await.unwrap();
+            let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", laptop));
+
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/devices/phone")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "display_name": "Alice's phone" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/devices")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let devices = res["devices"].as_array().unwrap();
+            assert_eq!(devices.len(), 2);
+            let phone_device = devices.iter().find(|d| d["device_id"] == "phone").unwrap();
+            assert_eq!(phone_device["display_name"], "Alice's phone");
+
+            let delete = || {
+                test::TestRequest::delete()
+                    .uri("/_matrix/client/r0/devices/phone")
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, delete()).await;
+            assert!(res.status().is_success());
+            let res = test::call_service(&mut app, delete()).await;
+            assert_eq!(res.status(), 404);
+
+            // only the deleted device was logged out
+            assert!(db.try_auth(phone).await.unwrap().is_none());
+            assert!(db.try_auth(laptop).await.unwrap().is_some());
+        });
+    }
+}
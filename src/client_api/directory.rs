@@ -0,0 +1,724 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{Data, Json, Path, Query},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::{convert::TryFrom, sync::Arc};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    events::{
+        room::{
+            CanonicalAlias, Create, GuestAccess, GuestAccessType, HistoryVisibility,
+            HistoryVisibilityType, Membership, Name, PowerLevels, Topic,
+        },
+        EventContent,
+    },
+    storage::Storage,
+    util::{storage::NewEvent, MatrixId, RoomAlias, RoomId, StorageExt},
+    ServerState,
+};
+
+/// Looks up the room that a room alias points to.
+pub(crate) async fn resolve_room_alias(
+    db: &dyn Storage,
+    state: &ServerState,
+    alias: &RoomAlias,
+) -> Result<String, Error> {
+    //TODO: ask other servers about their aliases once there's federation
+    if alias.domain() != state.config.domain {
+        return Err(ErrorKind::NotFound.into());
+    }
+    Ok(db
+        .get_room_alias(alias.as_str())
+        .await?
+        .ok_or(ErrorKind::NotFound)?)
+}
+
+/// Returns whether the user is allowed to change how a room appears in the directory.
+async fn may_edit_directory(
+    db: &dyn Storage,
+    state: &ServerState,
+    room_id: &str,
+    user_id: &MatrixId,
+) -> Result<bool, Error> {
+    if db
+        .get_membership(user_id, room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Ok(false);
+    }
+
+    // listing a room is about as significant as choosing its canonical alias, so require the
+    // same power level
+    let room_state = state.state_resolver.resolve_current(room_id).await?;
+    let creator = room_state
+        .get_content::<Create>(db, "")
+        .await?
+        .ok_or(ErrorKind::RoomNotFound)?
+        .creator;
+    let power_levels = room_state
+        .get_content::<PowerLevels>(db, "")
+        .await?
+        .unwrap_or_else(|| PowerLevels::no_event_default_levels(&creator));
+    Ok(power_levels.get_user_level(user_id)
+        >= power_levels.get_event_level("m.room.canonical_alias", true))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoomVisibility {
+    visibility: Visibility,
+}
+
+#[get("/directory/list/room/{room_id}")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn get_room_visibility(
+    state: Data<Arc<ServerState>>,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomVisibility>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    if !db.get_rooms().await?.contains(&room_id) {
+        return Err(ErrorKind::RoomNotFound.into());
+    }
+
+    let visibility = if db.get_public_rooms().await?.contains(&room_id) {
+        Visibility::Public
+    } else {
+        Visibility::Private
+    };
+    Ok(Json(RoomVisibility { visibility }))
+}
+
+#[put("/directory/list/room/{room_id}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_room_visibility(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<RoomVisibility>,
+) -> Result<Json<()>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if !may_edit_directory(&*db, &state, &room_id, &user_id).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let public = matches!(req.visibility, Visibility::Public);
+    db.set_room_public(&room_id, public).await?;
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRoomAliasRequest {
+    room_id: RoomId,
+}
+
+#[put("/directory/room/{room_alias}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_room_alias(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_alias): Path<String>,
+    req: Json<SetRoomAliasRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    // parsed here rather than by actix, which would turn a bad alias into a 404
+    let room_alias = RoomAlias::try_from(room_alias)?;
+    if room_alias.domain() != state.config.domain {
+        return Err(ErrorKind::InvalidParam(
+            "Room alias does not belong to this homeserver".to_string(),
+        )
+        .into());
+    }
+    if db
+        .get_membership(&user_id, req.room_id.as_str(), Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    db.set_room_alias(room_alias.as_str(), req.room_id.as_str(), &user_id)
+        .await?;
+    Ok(Json(json!({})))
+}
+
+#[get("/directory/room/{room_alias}")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn get_room_alias(
+    state: Data<Arc<ServerState>>,
+    Path(room_alias): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let room_alias = RoomAlias::try_from(room_alias)?;
+    let room_id = resolve_room_alias(&*db, &state, &room_alias).await?;
+    Ok(Json(json!({
+        "room_id": room_id,
+        "servers": [state.config.domain],
+    })))
+}
+
+#[delete("/directory/room/{room_alias}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn delete_room_alias(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_alias): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let room_alias = RoomAlias::try_from(room_alias)?;
+    let room_id = resolve_room_alias(&*db, &state, &room_alias).await?;
+    // whoever created an alias may always take it back, even without any power in the room
+    let creator = db.get_room_alias_creator(room_alias.as_str()).await?;
+    let is_creator = creator.as_ref() == Some(&user_id);
+    let may_edit = may_edit_directory(&*db, &state, &room_id, &user_id).await?;
+    if !is_creator && !may_edit {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    db.delete_room_alias(room_alias.as_str()).await?;
+    // the alias is gone by now, so failing to tidy up after it mustn't fail the request
+    if may_edit {
+        if let Err(e) = remove_canonical_alias(&*db, &state, &room_id, &user_id, &room_alias).await
+        {
+            tracing::warn!(
+                "Failed to remove {} from canonical alias: {}",
+                room_alias.as_str(),
+                e
+            );
+        }
+    }
+    Ok(Json(json!({})))
+}
+
+/// Takes a deleted alias out of the room's m.room.canonical_alias event, if it's in there, so
+/// that the room doesn't advertise an address that leads nowhere. The user must be allowed to
+/// send that event.
+async fn remove_canonical_alias(
+    db: &dyn Storage,
+    state: &ServerState,
+    room_id: &str,
+    user_id: &MatrixId,
+    alias: &RoomAlias,
+) -> Result<(), Error> {
+    let room_state = state.state_resolver.resolve_current(room_id).await?;
+    let mut content = match room_state.get_content::<CanonicalAlias>(db, "").await? {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+    if content.alias.as_ref() != Some(alias) && !content.alt_aliases.contains(alias) {
+        return Ok(());
+    }
+    content.alias = content.alias.filter(|a| a != alias);
+    content.alt_aliases.retain(|a| a != alias);
+
+    db.add_event(
+        room_id,
+        NewEvent::builder(user_id.clone(), EventContent::CanonicalAlias(content))
+            .state_key("")
+            .build(),
+        &state.state_resolver,
+        &state.keys,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicRoomsResponse {
+    chunk: Vec<PublicRoomsChunk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    total_room_count_estimate: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PublicRoomsChunk {
+    room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    num_joined_members: usize,
+    world_readable: bool,
+    guest_can_join: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicRoomsRequest {
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    since: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilteredPublicRoomsRequest {
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    since: Option<String>,
+    #[serde(default)]
+    filter: PublicRoomsFilter,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PublicRoomsFilter {
+    /// Only rooms whose name or topic contains this, ignoring case, are listed.
+    #[serde(default)]
+    generic_search_term: Option<String>,
+}
+
+/// A position in the list of public rooms. The list is recomputed for every request, so rooms
+/// that become public or private in between can shift it a little.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DirectoryToken(usize);
+
+impl std::fmt::Display for DirectoryToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "d{}", self.0)
+    }
+}
+
+impl std::str::FromStr for DirectoryToken {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let idx = s.strip_prefix('d').ok_or(())?;
+        idx.parse().map(DirectoryToken).map_err(|_| ())
+    }
+}
+
+#[get("/publicRooms")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn public_rooms(
+    state: Data<Arc<ServerState>>,
+    req: Query<PublicRoomsRequest>,
+) -> Result<Json<PublicRoomsResponse>, Error> {
+    let req = req.into_inner();
+    list_public_rooms(&state, req.limit, req.since.as_deref(), None).await
+}
+
+#[post("/publicRooms")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn filtered_public_rooms(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<FilteredPublicRoomsRequest>,
+) -> Result<Json<PublicRoomsResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let req = req.into_inner();
+    list_public_rooms(
+        &state,
+        req.limit,
+        req.since.as_deref(),
+        req.filter.generic_search_term.as_deref(),
+    )
+    .await
+}
+
+async fn list_public_rooms(
+    state: &ServerState,
+    limit: Option<usize>,
+    since: Option<&str>,
+    search_term: Option<&str>,
+) -> Result<Json<PublicRoomsResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let since = since
+        .map(|since| {
+            since
+                .parse::<DirectoryToken>()
+                .map_err(|_| Error::from(ErrorKind::InvalidParam(String::from("since"))))
+        })
+        .transpose()?
+        .map_or(0, |token| token.0);
+    let search_term = search_term.map(str::to_lowercase);
+
+    let mut chunk = Vec::new();
+    for room_id in db.get_public_rooms().await? {
+        let room_state = state.state_resolver.resolve_current(&room_id).await?;
+        let name = room_state
+            .get_content::<Name>(&*db, "")
+            .await?
+            .and_then(|c| c.name);
+        let topic = room_state
+            .get_content::<Topic>(&*db, "")
+            .await?
+            .and_then(|c| c.topic);
+        if let Some(search_term) = &search_term {
+            let matches =
+                |s: &Option<String>| matches!(s, Some(s) if s.to_lowercase().contains(search_term));
+            if !matches(&name) && !matches(&topic) {
+                continue;
+            }
+        }
+        let world_readable = matches!(
+            room_state
+                .get_content::<HistoryVisibility>(&*db, "")
+                .await?,
+            Some(HistoryVisibility {
+                history_visibility: HistoryVisibilityType::WorldReadable,
+            })
+        );
+        let guest_can_join = matches!(
+            room_state.get_content::<GuestAccess>(&*db, "").await?,
+            Some(GuestAccess {
+                guest_access: Some(GuestAccessType::CanJoin),
+            })
+        );
+        let (num_joined_members, _) = db.get_room_member_counts(&room_id).await?;
+        chunk.push(PublicRoomsChunk {
+            room_id,
+            name,
+            topic,
+            num_joined_members,
+            world_readable,
+            guest_can_join,
+        });
+    }
+    // the room id breaks ties, so that pages don't overlap
+    chunk.sort_by(|a, b| {
+        b.num_joined_members
+            .cmp(&a.num_joined_members)
+            .then_with(|| a.room_id.cmp(&b.room_id))
+    });
+
+    let total_room_count_estimate = chunk.len();
+    let start = since.min(chunk.len());
+    let end = match limit {
+        Some(limit) => start.saturating_add(limit).min(chunk.len()),
+        None => chunk.len(),
+    };
+    let prev_batch = match (start, limit) {
+        (0, _) => None,
+        (_, Some(limit)) => Some(DirectoryToken(start.saturating_sub(limit))),
+        (_, None) => Some(DirectoryToken(0)),
+    };
+    let next_batch = if end < chunk.len() {
+        Some(DirectoryToken(end))
+    } else {
+        None
+    };
+    let chunk = chunk.drain(start..end).collect();
+
+    Ok(Json(PublicRoomsResponse {
+        chunk,
+        next_batch: next_batch.map(|t| t.to_string()),
+        prev_batch: prev_batch.map(|t| t.to_string()),
+        total_room_count_estimate,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn make_room_public() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private", "name": "hideout" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let list = || {
+                test::TestRequest::get()
+                    .uri("/_matrix/client/r0/publicRooms")
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, list()).await;
+            assert_eq!(res["chunk"], json!([]));
+
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/directory/list/room/{}",
+                    room_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let res: JsonValue = test::read_response_json(&mut app, list()).await;
+            assert_eq!(res["chunk"][0]["room_id"], room_id.as_str());
+            assert_eq!(res["chunk"][0]["name"], "hideout");
+            assert_eq!(res["chunk"][0]["num_joined_members"], 1);
+        });
+    }
+
+    #[test]
+    fn public_rooms_pagination_and_search() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            for (name, topic) in &[
+                ("Rust", "systems programming"),
+                ("Gardening", "mostly tomatoes"),
+                ("Cooking", "also tomatoes"),
+            ] {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/createRoom")
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "visibility": "public", "name": name, "topic": topic }))
+                    .to_request();
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+            }
+            let names = |res: &JsonValue| {
+                let mut names = res["chunk"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|room| room["name"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            };
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/publicRooms?limit=2")
+                .to_request();
+            let first: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(first["chunk"].as_array().unwrap().len(), 2);
+            assert_eq!(first["total_room_count_estimate"], 3);
+            assert!(first.get("prev_batch").is_none());
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/publicRooms?limit=2&since={}",
+                    first["next_batch"].as_str().unwrap()
+                ))
+                .to_request();
+            let second: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(second["chunk"].as_array().unwrap().len(), 1);
+            assert!(second.get("next_batch").is_none());
+            assert!(second["prev_batch"].is_string());
+            let mut all = names(&first);
+            all.extend(names(&second));
+            all.sort();
+            assert_eq!(all, vec!["Cooking", "Gardening", "Rust"]);
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/publicRooms")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "filter": { "generic_search_term": "TOMATOES" } }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(names(&res), vec!["Cooking", "Gardening"]);
+            assert_eq!(res["chunk"][0]["num_joined_members"], 1);
+        });
+    }
+
+    #[test]
+    fn room_aliases() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let auth = |token| (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth(alice).0, auth(alice).1)
+                .set_json(&json!({
+                    "visibility": "public",
+                    "power_level_content_override": {
+                        "events": {},
+                        "users": { "@alice:example.org": 100 },
+                        "users_default": 0,
+                    },
+                }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let put_alias = |alias: &str| {
+                test::TestRequest::put()
+                    .uri(&format!("/_matrix/client/r0/directory/room/{}", alias))
+                    .header(auth(alice).0, auth(alice).1)
+                    .set_json(&json!({ "room_id": room_id }))
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, put_alias("hangout:example.org")).await;
+            assert_eq!(res.status(), 400);
+            let res = test::call_service(&mut app, put_alias("%23hangout:example.org")).await;
+            assert!(res.status().is_success());
+            let res = test::call_service(&mut app, put_alias("%23hangout:example.org")).await;
+            assert_eq!(res.status(), 409);
+            let res: JsonValue = test::read_body_json(res).await;
+            assert_eq!(res["errcode"], "M_ROOM_IN_USE");
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23hangout:example.org")
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["room_id"], room_id.as_str());
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/join/%23hangout:example.org")
+                .header(auth(bob).0, auth(bob).1)
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["room_id"], room_id.as_str());
+
+            // bob is in the room now, but he doesn't have the power to remove its alias
+            let delete_alias = |token| {
+                test::TestRequest::delete()
+                    .uri("/_matrix/client/r0/directory/room/%23hangout:example.org")
+                    .header(auth(token).0, auth(token).1)
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, delete_alias(bob)).await;
+            assert_eq!(res.status(), 403);
+
+            // but he can remove one that he made himself
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/directory/room/%23bobs-place:example.org")
+                .header(auth(bob).0, auth(bob).1)
+                .set_json(&json!({ "room_id": room_id }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let req = test::TestRequest::delete()
+                .uri("/_matrix/client/r0/directory/room/%23bobs-place:example.org")
+                .header(auth(bob).0, auth(bob).1)
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23bobs-place:example.org")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 404);
+
+            let res = test::call_service(&mut app, delete_alias(alice)).await;
+            assert!(res.status().is_success());
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23hangout:example.org")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 404);
+        });
+    }
+
+    #[test]
+    fn deleting_canonical_alias() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private", "room_alias_name": "plans" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::delete()
+                .uri("/_matrix/client/r0/directory/room/%23plans:example.org")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23plans:example.org")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 404);
+
+            // the room stops advertising the alias as its main address
+            let canonical_alias = db
+                .get_state_event(
+                    &room_id,
+                    "m.room.canonical_alias",
+                    "",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(canonical_alias.event_content.content_as_json(), json!({}));
+        });
+    }
+}
@@ -1,5 +1,5 @@
 use actix_web::{
-    put,
+    post, put,
     web::{Data, Json, Path},
 };
 use serde::Deserialize;
@@ -10,6 +10,8 @@ use tracing::{field::Empty, instrument, Level, Span};
 use crate::{
     client_api::auth::AccessToken,
     error::{Error, ErrorKind},
+    events::room::Membership,
+    storage::Storage,
     util::MatrixId,
     ServerState,
 };
@@ -41,3 +43,423 @@ pub async fn typing(
         .await?;
     Ok(Json(json!({})))
 }
+
+#[post("/rooms/{room_id}/receipt/{receipt_type}/{event_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn receipt(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, receipt_type, event_id)): Path<(String, String, String)>,
+) -> Result<Json<Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if receipt_type != "m.read" && receipt_type != "m.read.private" {
+        return Err(ErrorKind::InvalidParam(String::from("receipt_type")).into());
+    }
+    check_readable(&*db, &state, &user_id, &room_id, &event_id).await?;
+
+    db.set_receipt(&room_id, &user_id, &receipt_type, &event_id)
+        .await?;
+    Ok(Json(json!({})))
+}
+
+/// Checks that the user is in the room and that the event they claim to have read exists.
+async fn check_readable(
+    db: &dyn Storage,
+    state: &ServerState,
+    user_id: &MatrixId,
+    room_id: &str,
+    event_id: &str,
+) -> Result<(), Error> {
+    if db
+        .get_membership(user_id, room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if db.get_pdu(room_id, event_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ReadMarkersRequest {
+    #[serde(rename = "m.fully_read")]
+    fully_read: String,
+    #[serde(rename = "m.read")]
+    read: Option<String>,
+}
+
+#[post("/rooms/{room_id}/read_markers")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn read_markers(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<ReadMarkersRequest>,
+) -> Result<Json<Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let req = req.into_inner();
+    check_readable(&*db, &state, &user_id, &room_id, &req.fully_read).await?;
+    if let Some(event_id) = &req.read {
+        check_readable(&*db, &state, &user_id, &room_id, event_id).await?;
+    }
+
+    // the fully read marker is private to the user, so it lives in their room account data
+    db.set_room_account_data(
+        &username,
+        &room_id,
+        "m.fully_read",
+        json!({ "event_id": req.fully_read }),
+    )
+    .await?;
+    if let Some(event_id) = &req.read {
+        db.set_receipt(&room_id, &user_id, "m.read", event_id)
+            .await?;
+    }
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::Service, http::header, test, web, App};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn receipt_wakes_sync() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&serde_json::json!({ "visibility": "private" }))
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+            let event_id = res["rooms"]["join"][&room_id]["timeline"]["events"]
+                .as_array()
+                .unwrap()
+                .last()
+                .unwrap()["event_id"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+
+            // nothing new has happened in the room, so this sync waits until the receipt arrives
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/sync?since={}&timeout=5000",
+                    next_batch
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let sync_res = app.call(req);
+            let req = test::TestRequest::post()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/receipt/m.read/{}",
+                    room_id, event_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let receipt_res = app.call(req);
+            let (sync_res, receipt_res) = futures::join!(
+                tokio::time::timeout(Duration::from_secs(4), sync_res),
+                async {
+                    actix_web::rt::time::delay_for(Duration::from_millis(50)).await;
+                    receipt_res.await
+                }
+            );
+            assert!(receipt_res.unwrap().status().is_success());
+            let sync_res = sync_res.expect("sync wasn't woken").unwrap();
+            let res: Value = test::read_body_json(sync_res).await;
+            let ephemeral = &res["rooms"]["join"][&room_id]["ephemeral"]["events"];
+            let receipt = ephemeral
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["type"] == "m.receipt")
+                .unwrap();
+            assert!(receipt["content"][&event_id]["m.read"]["@alice:example.org"]["ts"].is_i64());
+        });
+    }
+
+    #[test]
+    fn read_markers_persist_across_syncs() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&serde_json::json!({ "visibility": "private" }))
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+            let event_id = res["rooms"]["join"][&room_id]["timeline"]["events"]
+                .as_array()
+                .unwrap()
+                .last()
+                .unwrap()["event_id"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+
+            let read_markers = |body: Value| {
+                test::TestRequest::post()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/read_markers",
+                        room_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&body)
+                    .to_request()
+            };
+            let res = test::call_service(
+                &mut app,
+                read_markers(serde_json::json!({ "m.fully_read": "$nonexistent" })),
+            )
+            .await;
+            assert_eq!(res.status(), 404);
+            let res = test::call_service(
+                &mut app,
+                read_markers(serde_json::json!({
+                    "m.fully_read": event_id,
+                    "m.read": event_id,
+                })),
+            )
+            .await;
+            assert!(res.status().is_success());
+
+            // the marker is still there after moving on to a later batch
+            for since in &[None, Some(next_batch)] {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}&timeout=0", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                let req = test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request();
+                let res: Value = test::read_response_json(&mut app, req).await;
+                let room = &res["rooms"]["join"][&room_id];
+                let fully_read = room["account_data"]["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|e| e["type"] == "m.fully_read")
+                    .unwrap();
+                assert_eq!(fully_read["content"]["event_id"], event_id.as_str());
+                let receipt = room["ephemeral"]["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|e| e["type"] == "m.receipt")
+                    .unwrap();
+                assert!(receipt["content"][&event_id]["m.read"]["@alice:example.org"].is_object());
+            }
+        });
+    }
+
+    #[test]
+    fn unread_notifications_reset_after_receipt() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&serde_json::json!({ "visibility": "public" }))
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let mut txn_id = 0;
+            let mut send = |body: &str| {
+                txn_id += 1;
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+                        room_id, txn_id
+                    ))
+                    .header(bob.0.clone(), bob.1.clone())
+                    .set_json(&serde_json::json!({ "msgtype": "m.text", "body": body }))
+                    .to_request()
+            };
+            let sync = || {
+                test::TestRequest::get()
+                    .uri("/_matrix/client/r0/sync")
+                    .header(alice.0.clone(), alice.1.clone())
+                    .to_request()
+            };
+            let counts = |res: &Value| {
+                let unread = &res["rooms"]["join"][&room_id]["unread_notifications"];
+                (
+                    unread["notification_count"].as_u64().unwrap(),
+                    unread["highlight_count"].as_u64().unwrap(),
+                )
+            };
+
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (0, 0));
+
+            test::call_service(&mut app, send("hello")).await;
+            test::call_service(&mut app, send("hey alice!")).await;
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (2, 1));
+            // later syncs carry on from the earlier counts
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (2, 1));
+            let res: Value = test::read_response_json(&mut app, send("you there?")).await;
+            let event_id = res["event_id"].as_str().unwrap().to_owned();
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (3, 1));
+
+            let req = test::TestRequest::post()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/receipt/m.read/{}",
+                    room_id, event_id
+                ))
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (0, 0));
+
+            test::call_service(&mut app, send("anyone there?")).await;
+            let res: Value = test::read_response_json(&mut app, sync()).await;
+            assert_eq!(counts(&res), (1, 0));
+        });
+    }
+
+    #[test]
+    fn unread_notifications_start_at_join() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(bob.0.clone(), bob.1.clone())
+                .set_json(&serde_json::json!({ "visibility": "public" }))
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let mut txn_id = 0;
+            let mut send = |body: &str| {
+                txn_id += 1;
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+                        room_id, txn_id
+                    ))
+                    .header(bob.0.clone(), bob.1.clone())
+                    .set_json(&serde_json::json!({ "msgtype": "m.text", "body": body }))
+                    .to_request()
+            };
+
+            // alice wasn't there for these, so they were never unread for her
+            test::call_service(&mut app, send("hello")).await;
+            test::call_service(&mut app, send("is alice coming?")).await;
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            test::call_service(&mut app, send("welcome")).await;
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: Value = test::read_response_json(&mut app, req).await;
+            let unread = &res["rooms"]["join"][&room_id]["unread_notifications"];
+            assert_eq!(unread["notification_count"], 1);
+            assert_eq!(unread["highlight_count"], 0);
+        });
+    }
+}
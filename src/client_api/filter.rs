@@ -0,0 +1,134 @@
+use actix_web::{
+    get, post,
+    web::{Data, Json, Path},
+};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::Storage,
+    util::MatrixId,
+    ServerState,
+};
+
+/// The parts of a client's filter that sync knows how to apply. Filters are stored as the client
+/// sent them, so anything else is kept but ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    #[serde(default)]
+    pub room: RoomFilter,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomFilter {
+    /// Rooms to include. If this is `None` all rooms are included.
+    pub rooms: Option<Vec<String>>,
+    /// Rooms to exclude. Exclusion takes priority over `rooms`.
+    #[serde(default)]
+    pub not_rooms: Vec<String>,
+    #[serde(default)]
+    pub timeline: RoomEventFilter,
+}
+
+impl RoomFilter {
+    pub fn allows(&self, room_id: &str) -> bool {
+        if self.not_rooms.iter().any(|r| r == room_id) {
+            return false;
+        }
+        match &self.rooms {
+            Some(rooms) => rooms.iter().any(|r| r == room_id),
+            None => true,
+        }
+    }
+}
+
+//TODO: wildcards in types, and empty lists meaning "nothing" rather than "everything"
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomEventFilter {
+    /// The maximum number of events to return per room.
+    pub limit: Option<usize>,
+    pub types: Option<Vec<String>>,
+    #[serde(default)]
+    pub not_types: Vec<String>,
+    pub senders: Option<Vec<MatrixId>>,
+    #[serde(default)]
+    pub not_senders: Vec<MatrixId>,
+    /// Whether to send the membership events of the senders of the returned events alongside
+    /// them. Only /messages knows how to do this.
+    #[serde(default)]
+    pub lazy_load_members: bool,
+}
+
+/// Gets the filter referred to by the `filter` param of a sync request, which is either the ID
+/// of a stored filter or a filter encoded inline as JSON.
+pub async fn load_filter(
+    db: &dyn Storage,
+    username: &str,
+    filter: Option<&str>,
+) -> Result<Filter, Error> {
+    let filter = match filter {
+        None => return Ok(Filter::default()),
+        Some(inline) if inline.starts_with('{') => serde_json::from_str(inline)?,
+        Some(filter_id) => db
+            .get_filter(username, filter_id)
+            .await?
+            .ok_or(ErrorKind::NotFound)?,
+    };
+    serde_json::from_value(filter).map_err(|e| ErrorKind::BadJson(e.to_string()).into())
+}
+
+#[post("/user/{user_id}/filter")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn create_filter(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(req_id): Path<MatrixId>,
+    body: Json<JsonValue>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    let filter = body.into_inner();
+    // check that sync will be able to use it later
+    serde_json::from_value::<Filter>(filter.clone())
+        .map_err(|e| ErrorKind::BadJson(e.to_string()))?;
+    let filter_id = db.create_filter(&username, filter).await?;
+    Ok(Json(json!({ "filter_id": filter_id })))
+}
+
+#[get("/user/{user_id}/filter/{filter_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_filter(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, filter_id)): Path<(MatrixId, String)>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    let filter = db
+        .get_filter(&username, &filter_id)
+        .await?
+        .ok_or(ErrorKind::NotFound)?;
+    Ok(Json(filter))
+}
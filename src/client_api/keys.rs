@@ -0,0 +1,276 @@
+use actix_web::{
+    post,
+    web::{Data, Json},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    util::MatrixId,
+    ServerState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct UploadKeysRequest {
+    #[serde(default)]
+    device_keys: Option<JsonValue>,
+    #[serde(default)]
+    one_time_keys: HashMap<String, JsonValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadKeysResponse {
+    one_time_key_counts: HashMap<String, usize>,
+}
+
+#[post("/keys/upload")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn upload_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<UploadKeysRequest>,
+) -> Result<Json<UploadKeysResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let req = req.into_inner();
+    if let Some(device_keys) = req.device_keys {
+        // the keys are opaque, but they have to at least claim to be this device's
+        if device_keys["user_id"] != user_id.as_str() {
+            return Err(ErrorKind::InvalidParam(String::from("device_keys.user_id")).into());
+        }
+        if device_keys["device_id"] != device_id.as_str() {
+            return Err(ErrorKind::InvalidParam(String::from("device_keys.device_id")).into());
+        }
+        db.set_device_keys(&username, &device_id, device_keys)
+            .await?;
+    }
+    if req.one_time_keys.keys().any(|key_id| !key_id.contains(':')) {
+        return Err(ErrorKind::InvalidParam(String::from("one_time_keys")).into());
+    }
+    if !req.one_time_keys.is_empty() {
+        db.add_one_time_keys(&username, &device_id, req.one_time_keys)
+            .await?;
+    }
+
+    let one_time_key_counts = db.count_one_time_keys(&username, &device_id).await?;
+    Ok(Json(UploadKeysResponse {
+        one_time_key_counts,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryKeysRequest {
+    /// user_id -> the devices to get keys for, or all of them if empty
+    device_keys: HashMap<MatrixId, Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryKeysResponse {
+    /// user_id -> device_id -> identity keys
+    device_keys: HashMap<String, HashMap<String, JsonValue>>,
+    /// server name -> why its users' keys couldn't be fetched
+    failures: HashMap<String, JsonValue>,
+}
+
+#[post("/keys/query")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn query_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<QueryKeysRequest>,
+) -> Result<Json<QueryKeysResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let mut device_keys = HashMap::new();
+    let mut failures = HashMap::new();
+    for (user_id, device_ids) in req.into_inner().device_keys {
+        //TODO: ask other servers once there's federation
+        if user_id.domain() != state.config.domain {
+            failures.insert(
+                user_id.domain().to_string(),
+                json!({ "errcode": "M_UNKNOWN", "error": "Federation is not supported" }),
+            );
+            continue;
+        }
+        let mut keys = db.get_device_keys(user_id.localpart()).await?;
+        if !device_ids.is_empty() {
+            keys.retain(|device_id, _| device_ids.contains(device_id));
+        }
+        device_keys.insert(user_id.to_string(), keys);
+    }
+    Ok(Json(QueryKeysResponse {
+        device_keys,
+        failures,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimKeysRequest {
+    /// user_id -> device_id -> the algorithm of the key to claim
+    one_time_keys: HashMap<MatrixId, HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClaimKeysResponse {
+    /// user_id -> device_id -> key_id -> one-time key
+    one_time_keys: HashMap<String, HashMap<String, HashMap<String, JsonValue>>>,
+    /// server name -> why its users' keys couldn't be claimed
+    failures: HashMap<String, JsonValue>,
+}
+
+#[post("/keys/claim")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn claim_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<ClaimKeysRequest>,
+) -> Result<Json<ClaimKeysResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let mut one_time_keys = HashMap::new();
+    let mut failures = HashMap::new();
+    for (user_id, devices) in req.into_inner().one_time_keys {
+        //TODO: ask other servers once there's federation
+        if user_id.domain() != state.config.domain {
+            failures.insert(
+                user_id.domain().to_string(),
+                json!({ "errcode": "M_UNKNOWN", "error": "Federation is not supported" }),
+            );
+            continue;
+        }
+        // devices that have run out of keys are left out
+        let mut claimed = HashMap::new();
+        for (device_id, algorithm) in devices {
+            if let Some((key_id, key)) = db
+                .claim_one_time_key(user_id.localpart(), &device_id, &algorithm)
+                .await?
+            {
+                claimed.insert(device_id, std::iter::once((key_id, key)).collect());
+            }
+        }
+        one_time_keys.insert(user_id.to_string(), claimed);
+    }
+    Ok(Json(ClaimKeysResponse {
+        one_time_keys,
+        failures,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn upload_query_and_claim() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "laptop").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let device_keys = json!({
+                "user_id": "@alice:example.org",
+                "device_id": "phone",
+                "algorithms": ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"],
+                "keys": { "curve25519:phone": "abc", "ed25519:phone": "def" },
+                "signatures": { "@alice:example.org": { "ed25519:phone": "ghi" } },
+            });
+            let upload = |body: JsonValue| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/keys/upload")
+                    .header(alice.0.clone(), alice.1.clone())
+                    .set_json(&body)
+                    .to_request()
+            };
+            let mut wrong_device = device_keys.clone();
+            wrong_device["device_id"] = json!("laptop");
+            let res =
+                test::call_service(&mut app, upload(json!({ "device_keys": wrong_device }))).await;
+            assert_eq!(res.status(), 400);
+            let req = upload(json!({
+                "device_keys": device_keys,
+                "one_time_keys": {
+                    "signed_curve25519:AAAA": { "key": "one" },
+                    "signed_curve25519:AAAB": { "key": "two" },
+                },
+            }));
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["one_time_key_counts"]["signed_curve25519"], 2);
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/query")
+                .header(bob.0.clone(), bob.1.clone())
+                .set_json(&json!({ "device_keys": { "@alice:example.org": [] } }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(
+                res["device_keys"]["@alice:example.org"]["phone"],
+                device_keys
+            );
+
+            let claim = || {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/keys/claim")
+                    .header(bob.0.clone(), bob.1.clone())
+                    .set_json(&json!({
+                        "one_time_keys": {
+                            "@alice:example.org": { "phone": "signed_curve25519" },
+                        },
+                    }))
+                    .to_request()
+            };
+            let mut claimed = Vec::new();
+            for _ in 0..3 {
+                let res: JsonValue = test::read_response_json(&mut app, claim()).await;
+                let keys = res["one_time_keys"]["@alice:example.org"]["phone"].clone();
+                claimed.extend(keys.as_object().into_iter().flat_map(|keys| keys.clone()));
+            }
+            // each key is handed out once, and then there are none left
+            claimed.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                claimed,
+                vec![
+                    (
+                        String::from("signed_curve25519:AAAA"),
+                        json!({ "key": "one" })
+                    ),
+                    (
+                        String::from("signed_curve25519:AAAB"),
+                        json!({ "key": "two" })
+                    ),
+                ]
+            );
+            let res: JsonValue = test::read_response_json(&mut app, upload(json!({}))).await;
+            assert_eq!(res["one_time_key_counts"], json!({}));
+        });
+    }
+}
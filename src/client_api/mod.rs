@@ -1,23 +1,46 @@
 use actix_web::{
     get,
-    web::{self, Json},
+    web::{self, Data, Json},
+};
+use serde_json::{json, Value as JsonValue};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::room_version::SUPPORTED_VERSIONS,
+    ServerState,
 };
-use serde_json::json;
 
 mod auth;
+mod device;
+mod directory;
 mod ephemeral;
+mod filter;
+mod keys;
+mod presence;
 mod room;
 mod room_events;
+mod tags;
+mod to_device;
 mod user;
 
+pub use auth::{AccessToken, LastSeen};
+pub use room::InviteLimits;
+pub use room_events::NotificationCache;
+
 pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
     cfg.service(versions);
     let r0 = web::scope("/r0")
+        .service(capabilities)
         .service(auth::get_supported_login_types)
         .service(auth::login)
         .service(auth::logout)
         .service(auth::logout_all)
         .service(auth::register)
+        .service(auth::get_username_available)
+        .service(auth::change_password)
+        .service(auth::deactivate)
         .service(user::get_avatar_url)
         .service(user::set_avatar_url)
         .service(user::get_display_name)
@@ -25,18 +48,56 @@ pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
         .service(user::get_profile)
         .service(user::search_user_directory)
         .service(user::get_3pids)
+        .service(user::set_account_data)
+        .service(user::set_room_account_data)
+        .service(tags::get_tags)
+        .service(tags::set_tag)
+        .service(tags::delete_tag)
+        .service(device::get_devices)
+        .service(device::get_device)
+        .service(device::update_device)
+        .service(device::delete_device)
+        .service(keys::upload_keys)
+        .service(keys::query_keys)
+        .service(keys::claim_keys)
+        .service(filter::create_filter)
+        .service(filter::get_filter)
         .service(room::create_room)
+        .service(room::upgrade_room)
+        .service(directory::get_room_visibility)
+        .service(directory::set_room_visibility)
+        .service(directory::public_rooms)
+        .service(directory::filtered_public_rooms)
+        .service(directory::set_room_alias)
+        .service(directory::get_room_alias)
+        .service(directory::delete_room_alias)
         .service(room::invite)
         .service(room::join_by_id_or_alias)
+        .service(room::leave)
+        .service(room::kick)
+        .service(room::ban)
+        .service(room::unban)
         .service(room_events::sync)
         .service(room_events::get_event)
+        .service(room_events::get_annotations)
         .service(room_events::get_state_event_no_key)
         .service(room_events::get_state_event_key)
         .service(room_events::get_state)
+        .service(room_events::get_summary)
         .service(room_events::get_members)
-        .service(room_events::send_state_event)
+        .service(room_events::get_joined_members)
+        .service(room_events::get_messages)
+        .service(room_events::send_state_event_no_key)
+        .service(room_events::send_state_event_key)
         .service(room_events::send_event)
+        .service(room_events::redact)
         .service(ephemeral::typing)
+        .service(ephemeral::receipt)
+        .service(ephemeral::read_markers)
+        .service(to_device::send_to_device)
+        .service(presence::set_presence)
+        .service(presence::get_presence)
+        .wrap_fn(auth::track_last_seen)
         .wrap(
             actix_cors::Cors::default()
                 .send_wildcard()
@@ -62,3 +123,89 @@ async fn versions() -> Json<serde_json::Value> {
         ]
     }))
 }
+
+#[get("/capabilities")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+async fn capabilities(
+    state: Data<Arc<ServerState>>,
+    token: auth::AccessToken,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let available = SUPPORTED_VERSIONS
+        .iter()
+        .map(|version| (*version, "stable"))
+        .collect::<HashMap<_, _>>();
+    Ok(Json(json!({
+        "capabilities": {
+            "m.change_password": { "enabled": true },
+            "m.room_versions": {
+                "default": state.config.default_room_version,
+                "available": available,
+            },
+        }
+    })))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use ring::signature::Ed25519KeyPair;
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        events::room_version::DEFAULT_VERSION,
+        state::StateResolver,
+        storage::{mem::MemStorageManager, StorageManager},
+        Config, ServerState,
+    };
+
+    /// The config of a server on example.org with everything optional left off.
+    pub(crate) fn test_config() -> Config {
+        Config {
+            domain: String::from("example.org"),
+            bind_address: String::new(),
+            storage: String::from("mem"),
+            tls: None,
+            storage_handle_limit: None,
+            federation: false,
+            registration_shared_secret: None,
+            password_hashing: Default::default(),
+            trusted_key_servers: Vec::new(),
+            clock_skew_tolerance_secs: 300,
+            default_room_version: String::from(DEFAULT_VERSION),
+            signing_key_path: String::new(),
+            max_rooms_per_user: None,
+            default_displayname_to_localpart: false,
+            user_directory_search_all_users: false,
+        }
+    }
+
+    /// Builds the state of a server on example.org backed by the given in-memory storage.
+    pub(crate) async fn server_state(db_pool: MemStorageManager) -> Arc<ServerState> {
+        server_state_with_config(db_pool, test_config()).await
+    }
+
+    pub(crate) async fn server_state_with_config(
+        db_pool: MemStorageManager,
+        config: Config,
+    ) -> Arc<ServerState> {
+        Arc::new(ServerState {
+            config,
+            state_resolver: StateResolver::new(db_pool.get_handle().await.unwrap()),
+            db_pool: Box::new(db_pool),
+            last_seen: Default::default(),
+            registration_nonces: Default::default(),
+            invite_limits: Default::default(),
+            notification_counts: Default::default(),
+            remote_keys: Default::default(),
+            keys: vec![(
+                String::from("ed25519:test"),
+                Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap(),
+            )]
+            .into_iter()
+            .collect::<HashMap<_, _>>(),
+        })
+    }
+}
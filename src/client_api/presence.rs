@@ -0,0 +1,283 @@
+use actix_web::{
+    get, put,
+    web::{Data, Json, Path},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::{Presence, PresenceState},
+    util::MatrixId,
+    ServerState,
+};
+
+/// The content of an m.presence event, and what GET /presence/{user_id}/status returns.
+#[derive(Debug, Serialize)]
+pub struct PresenceContent {
+    presence: PresenceState,
+    /// How long ago the presence was set, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_active_ago: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_msg: Option<String>,
+    currently_active: bool,
+}
+
+impl PresenceContent {
+    /// Describes the stored presence as it stands at `now`, in milliseconds since the unix epoch.
+    /// Users who have never set their presence are offline.
+    pub fn new(presence: Option<Presence>, now: i64) -> Self {
+        match presence {
+            Some(presence) => PresenceContent {
+                presence: presence.state,
+                last_active_ago: Some((now - presence.last_active_ts).max(0)),
+                status_msg: presence.status_msg,
+                currently_active: presence.state == PresenceState::Online,
+            },
+            None => PresenceContent {
+                presence: PresenceState::Offline,
+                last_active_ago: None,
+                status_msg: None,
+                currently_active: false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresenceEvent {
+    pub sender: MatrixId,
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    pub content: PresenceContent,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPresenceRequest {
+    presence: PresenceState,
+    #[serde(default)]
+    status_msg: Option<String>,
+}
+
+#[put("/presence/{user_id}/status")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_presence(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(user_id): Path<MatrixId>,
+    req: Json<SetPresenceRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || user_id.domain() != state.config.domain {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let req = req.into_inner();
+    let now = chrono::Utc::now().timestamp_millis();
+    db.set_presence(&username, req.presence, req.status_msg, now)
+        .await?;
+    Ok(Json(json!({})))
+}
+
+#[get("/presence/{user_id}/status")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_presence(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(user_id): Path<MatrixId>,
+) -> Result<Json<PresenceContent>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    //TODO: ask other servers once there's federation
+    if user_id.domain() != state.config.domain || !db.user_exists(user_id.localpart()).await? {
+        return Err(ErrorKind::NotFound.into());
+    }
+
+    let presence = db.get_presence(user_id.localpart()).await?;
+    let now = chrono::Utc::now().timestamp_millis();
+    Ok(Json(PresenceContent::new(presence, now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::Service, http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+    use std::time::Duration;
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn presence_reaches_room_members() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let set = |auth: &(header::HeaderName, String), user_id: &str| {
+                test::TestRequest::put()
+                    .uri(&format!("/_matrix/client/r0/presence/{}/status", user_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "presence": "unavailable", "status_msg": "lunch" }))
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, set(&alice, "@bob:example.org")).await;
+            assert_eq!(res.status(), 403);
+            let res = test::call_service(&mut app, set(&bob, "@bob:example.org")).await;
+            assert!(res.status().is_success());
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/presence/@bob:example.org/status")
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["presence"], "unavailable");
+            assert_eq!(res["status_msg"], "lunch");
+            assert!(res["last_active_ago"].as_i64().unwrap() >= 0);
+
+            // syncing sets the syncing user's presence too
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync?set_presence=offline")
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let events = res["presence"]["events"].as_array().unwrap();
+            let presence_of = |user_id: &str| {
+                events
+                    .iter()
+                    .find(|e| e["sender"] == user_id)
+                    .map(|e| e["content"]["presence"].clone())
+            };
+            assert_eq!(presence_of("@bob:example.org"), Some(json!("unavailable")));
+            assert_eq!(presence_of("@alice:example.org"), Some(json!("offline")));
+            assert!(events.iter().all(|e| e["type"] == "m.presence"));
+
+            // nothing has changed since, so the next sync doesn't repeat it
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/sync?set_presence=offline&timeout=0&since={}",
+                    res["next_batch"].as_str().unwrap()
+                ))
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert!(res["presence"].is_null());
+        });
+    }
+
+    #[test]
+    fn presence_wakes_sync() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            db.create_user("carol", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let carol = db.create_access_token("carol", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let carol = (header::AUTHORIZATION, format!("Bearer {}", carol));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync?set_presence=offline")
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            let set = |auth: &(header::HeaderName, String), user_id: &str| {
+                test::TestRequest::put()
+                    .uri(&format!("/_matrix/client/r0/presence/{}/status", user_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "presence": "unavailable" }))
+                    .to_request()
+            };
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/sync?set_presence=offline&timeout=5000&since={}",
+                    next_batch
+                ))
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            let sync_res = app.call(req);
+            // carol doesn't share a room with alice, so only bob's presence ends the sync
+            let carol_res = app.call(set(&carol, "@carol:example.org"));
+            let bob_res = app.call(set(&bob, "@bob:example.org"));
+            let (sync_res, (carol_res, bob_res)) = futures::join!(
+                tokio::time::timeout(Duration::from_secs(4), sync_res),
+                async {
+                    actix_web::rt::time::delay_for(Duration::from_millis(50)).await;
+                    let carol_res = carol_res.await;
+                    actix_web::rt::time::delay_for(Duration::from_millis(50)).await;
+                    (carol_res, bob_res.await)
+                }
+            );
+            assert!(carol_res.unwrap().status().is_success());
+            assert!(bob_res.unwrap().status().is_success());
+            let sync_res = sync_res.expect("sync wasn't woken").unwrap();
+            let res: JsonValue = test::read_body_json(sync_res).await;
+            let events = res["presence"]["events"].as_array().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["sender"], "@bob:example.org");
+            assert_eq!(events[0]["content"]["presence"], "unavailable");
+        });
+    }
+}
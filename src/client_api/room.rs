@@ -2,17 +2,24 @@ use actix_web::{
     post,
     web::{Data, Json, Path},
 };
+use ring::signature::Ed25519KeyPair;
 use serde::Deserialize;
 use serde_json::{json, Value as JsonValue};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tracing::{field::Empty, instrument, Level, Span};
 
 use crate::{
-    client_api::auth::AccessToken,
+    client_api::{auth::AccessToken, directory},
     error::{Error, ErrorKind},
-    events::{room, EventContent},
-    storage::UserProfile,
-    util::{storage::NewEvent, MatrixId, StorageExt},
+    events::{room, room_version::SUPPORTED_VERSIONS, EventContent},
+    state::StateResolver,
+    storage::{Storage, UserProfile},
+    util::{storage::NewEvent, MatrixId, RoomAlias, StorageExt},
     ServerState,
 };
 
@@ -40,7 +47,7 @@ enum RoomVisibility {
 }
 
 #[derive(Deserialize)]
-struct Invite3pid {
+pub struct Invite3pid {
     id_server: String,
     medium: String,
     address: String,
@@ -76,31 +83,73 @@ pub async fn create_room(
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    let room_version = req.room_version.unwrap_or("4".to_string());
-    if room_version != "4" {
+    let room_id = new_room_id(&state.config.domain);
+    create_room_inner(&*db, &state, &user_id, &room_id, req, None).await?;
+    Ok(Json(json!({ "room_id": room_id })))
+}
+
+fn new_room_id(domain: &str) -> String {
+    format!("!{:016X}:{}", rand::random::<i64>(), domain)
+}
+
+/// Creates a room with the given id as `createRoom` describes it, with `predecessor` going in its
+/// m.room.create event if it replaces another room.
+async fn create_room_inner(
+    db: &dyn Storage,
+    state: &ServerState,
+    user_id: &MatrixId,
+    room_id: &str,
+    req: CreateRoomRequest,
+    predecessor: Option<room::PreviousRoom>,
+) -> Result<(), Error> {
+    check_room_limit(db, state, user_id).await?;
+
+    let room_version = req
+        .room_version
+        .unwrap_or_else(|| state.config.default_room_version.clone());
+    if !SUPPORTED_VERSIONS.contains(&room_version.as_str()) {
         return Err(ErrorKind::UnsupportedRoomVersion.into());
     }
 
-    let room_id = format!("!{:016X}:{}", rand::random::<i64>(), state.config.domain);
+    // check the alias up front, so that we don't create a room only to fail afterwards
+    let room_alias = match req.room_alias_name {
+        Some(ref name) => {
+            let alias = RoomAlias::new(name, &state.config.domain)?;
+            if db.get_room_alias(alias.as_str()).await?.is_some() {
+                return Err(ErrorKind::RoomInUse.into());
+            }
+            Some(alias)
+        }
+        None => None,
+    };
+    for threepid in req.invite_3pid.iter().flatten() {
+        if db
+            .get_user_by_threepid(&threepid.medium, &threepid.address)
+            .await?
+            .is_none()
+        {
+            return Err(unbound_threepid(threepid));
+        }
+    }
 
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::Create(room::Create {
+        room_id,
+        NewEvent::builder(
+            user_id.clone(),
+            EventContent::Create(room::Create {
                 creator: user_id.clone(),
                 room_version: Some(room_version),
-                predecessor: None,
+                predecessor,
                 extra: match req.creation_content {
                     Some(v) => v,
                     None => HashMap::new(),
                 },
             }),
-            sender: user_id.clone(),
-            state_key: Some(String::new()),
-            redacts: None,
-            unsigned: None,
-        },
+        )
+        .state_key("")
+        .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
 
@@ -108,40 +157,40 @@ pub async fn create_room(
         let UserProfile {
             avatar_url,
             displayname,
-        } = db.get_profile(&username).await?.unwrap();
+        } = db
+            .get_profile(user_id.localpart())
+            .await?
+            .ok_or(ErrorKind::UserNotFound)?;
         room::Member {
             avatar_url,
             displayname,
             membership: room::Membership::Join,
             is_direct: req.is_direct,
+            reason: None,
+            third_party_invite: None,
         }
     };
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::Member(creator_join),
-            sender: user_id.clone(),
-            state_key: Some(user_id.clone_inner()),
-            redacts: None,
-            unsigned: None,
-        },
+        room_id,
+        NewEvent::builder(user_id.clone(), EventContent::Member(creator_join))
+            .state_key(user_id.clone_inner())
+            .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
 
     // TODO: default power levels a bit of a mess
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::PowerLevels(
-                req.power_level_content_override.unwrap_or_default(),
-            ),
-            sender: user_id.clone(),
-            state_key: Some(String::new()),
-            redacts: None,
-            unsigned: None,
-        },
+        room_id,
+        NewEvent::builder(
+            user_id.clone(),
+            EventContent::PowerLevels(req.power_level_content_override.unwrap_or_default()),
+        )
+        .state_key("")
+        .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
 
@@ -157,119 +206,325 @@ pub async fn create_room(
         }
     };
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::JoinRules(room::JoinRules { join_rule }),
-            sender: user_id.clone(),
-            state_key: Some(String::new()),
-            redacts: None,
-            unsigned: None,
-        },
+        room_id,
+        NewEvent::builder(
+            user_id.clone(),
+            EventContent::JoinRules(room::JoinRules { join_rule }),
+        )
+        .state_key("")
+        .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::HistoryVisibility(room::HistoryVisibility {
-                history_visibility,
-            }),
-            sender: user_id.clone(),
-            state_key: Some(String::new()),
-            redacts: None,
-            unsigned: None,
-        },
+        room_id,
+        NewEvent::builder(
+            user_id.clone(),
+            EventContent::HistoryVisibility(room::HistoryVisibility { history_visibility }),
+        )
+        .state_key("")
+        .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
     db.add_event(
-        &room_id,
-        NewEvent {
-            event_content: EventContent::GuestAccess(room::GuestAccess {
+        room_id,
+        NewEvent::builder(
+            user_id.clone(),
+            EventContent::GuestAccess(room::GuestAccess {
                 guest_access: Some(guest_access),
             }),
-            sender: user_id.clone(),
-            state_key: Some(String::new()),
-            redacts: None,
-            unsigned: None,
-        },
+        )
+        .state_key("")
+        .build(),
         &state.state_resolver,
+        &state.keys,
     )
     .await?;
 
     for event in req.initial_state.into_iter().flatten() {
         db.add_event(
-            &room_id,
-            NewEvent {
-                event_content: EventContent::new(&event.ty, event.content)?,
-                sender: user_id.clone(),
-                state_key: Some(event.state_key),
-                redacts: None,
-                unsigned: None,
-            },
+            room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::new(&event.ty, event.content)?,
+            )
+            .state_key(event.state_key)
+            .build(),
             &state.state_resolver,
+            &state.keys,
         )
         .await?;
     }
 
     if let Some(name) = req.name {
         db.add_event(
-            &room_id,
-            NewEvent {
-                event_content: EventContent::Name(room::Name { name: Some(name) }),
-                sender: user_id.clone(),
-                state_key: Some(String::new()),
-                redacts: None,
-                unsigned: None,
-            },
+            room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::Name(room::Name { name: Some(name) }),
+            )
+            .state_key("")
+            .build(),
             &state.state_resolver,
+            &state.keys,
         )
         .await?;
     }
 
     if let Some(topic) = req.topic {
         db.add_event(
-            &room_id,
-            NewEvent {
-                event_content: EventContent::Topic(room::Topic { topic: Some(topic) }),
-                sender: user_id.clone(),
-                state_key: Some(String::new()),
-                redacts: None,
-                unsigned: None,
-            },
+            room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::Topic(room::Topic { topic: Some(topic) }),
+            )
+            .state_key("")
+            .build(),
             &state.state_resolver,
+            &state.keys,
         )
         .await?;
     }
 
     for invitee in req.invite.into_iter().flatten() {
         db.add_event(
-            &room_id,
-            NewEvent {
-                event_content: EventContent::Member(room::Member {
+            room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::Member(room::Member {
                     avatar_url: None,
                     displayname: None,
                     membership: room::Membership::Invite,
                     is_direct: req.is_direct,
+                    reason: None,
+                    third_party_invite: None,
                 }),
-                sender: user_id.clone(),
-                state_key: Some(invitee),
-                redacts: None,
-                unsigned: None,
-            },
+            )
+            .state_key(invitee)
+            .build(),
+            &state.state_resolver,
+            &state.keys,
+        )
+        .await?;
+    }
+
+    for threepid in req.invite_3pid.into_iter().flatten() {
+        invite_3pid(
+            db,
             &state.state_resolver,
+            &state.keys,
+            &state.config.domain,
+            room_id,
+            user_id,
+            threepid,
+            req.is_direct.unwrap_or(false),
         )
         .await?;
     }
 
-    tracing::info!(room_id = room_id.as_str(), "Created room");
+    if let RoomVisibility::Public = req.visibility {
+        db.set_room_public(room_id, true).await?;
+    }
+    if let Some(alias) = room_alias {
+        db.set_room_alias(alias.as_str(), room_id, user_id).await?;
+        db.add_event(
+            room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::CanonicalAlias(room::CanonicalAlias {
+                    alias: Some(alias),
+                    alt_aliases: Vec::new(),
+                }),
+            )
+            .state_key("")
+            .build(),
+            &state.state_resolver,
+            &state.keys,
+        )
+        .await?;
+    }
 
-    Ok(Json(json!({ "room_id": room_id })))
+    tracing::info!(room_id, "Created room");
+    Ok(())
 }
 
 #[derive(Deserialize)]
-pub struct InviteRequest {
-    user_id: MatrixId,
+pub struct UpgradeRoomRequest {
+    new_version: String,
+}
+
+#[post("/rooms/{room_id}/upgrade")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn upgrade_room(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<UpgradeRoomRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let new_version = req.into_inner().new_version;
+    if !SUPPORTED_VERSIONS.contains(&new_version.as_str()) {
+        return Err(ErrorKind::UnsupportedRoomVersion.into());
+    }
+    check_room_limit(&*db, &state, &user_id).await?;
+
+    // the auth rules check this too, but only once the replacement room has been created
+    let room_state = state.state_resolver.resolve_current(&room_id).await?;
+    let creator = room_state
+        .get_content::<room::Create>(&*db, "")
+        .await?
+        .ok_or(ErrorKind::RoomNotFound)?
+        .creator;
+    let power_levels = room_state
+        .get_content::<room::PowerLevels>(&*db, "")
+        .await?
+        .unwrap_or_else(|| room::PowerLevels::no_event_default_levels(&creator));
+    if power_levels.get_user_level(&user_id)
+        < power_levels.get_event_level("m.room.tombstone", true)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    // members have to join the new room themselves, but it should at least look like the old one
+    let name = room_state
+        .get_content::<room::Name>(&*db, "")
+        .await?
+        .and_then(|c| c.name);
+    let topic = room_state
+        .get_content::<room::Topic>(&*db, "")
+        .await?
+        .and_then(|c| c.topic);
+    let preset = match room_state.get_content::<room::JoinRules>(&*db, "").await? {
+        Some(room::JoinRules {
+            join_rule: room::JoinRule::Public,
+        }) => Preset::PublicChat,
+        _ => Preset::PrivateChat,
+    };
+    let public = db.get_public_rooms().await?.contains(&room_id);
+    let (mut prev_events, _) = db.get_prev_events(&room_id).await?;
+    let last_event_id = prev_events.pop().ok_or(ErrorKind::RoomNotFound)?;
+
+    // the replacement has to exist before anything points at it, so it's created first
+    let new_room_id = new_room_id(&state.config.domain);
+    let req = CreateRoomRequest {
+        visibility: if public {
+            RoomVisibility::Public
+        } else {
+            RoomVisibility::Private
+        },
+        room_alias_name: None,
+        name,
+        topic,
+        invite: None,
+        invite_3pid: None,
+        room_version: Some(new_version),
+        creation_content: None,
+        initial_state: None,
+        preset: Some(preset),
+        is_direct: None,
+        power_level_content_override: Some(power_levels.clone()),
+    };
+    let predecessor = room::PreviousRoom {
+        room_id: room_id.clone(),
+        event_id: last_event_id,
+    };
+    create_room_inner(&*db, &state, &user_id, &new_room_id, req, Some(predecessor)).await?;
+
+    let tombstone_id = db
+        .add_event(
+            &room_id,
+            NewEvent::builder(
+                user_id.clone(),
+                EventContent::Tombstone(room::Tombstone {
+                    body: Some(String::from("This room has been replaced")),
+                    replacement_room: Some(new_room_id.clone()),
+                }),
+            )
+            .state_key("")
+            .build(),
+            &state.state_resolver,
+            &state.keys,
+        )
+        .await?;
+    let tombstone = db
+        .get_pdu(&room_id, &tombstone_id)
+        .await?
+        .ok_or(ErrorKind::RoomNotFound)?;
+    if !tombstone.did_pass_auth() {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    // stop ordinary members from talking or inviting in the old room, so that everyone moves on.
+    // If the upgrader can't change power levels, this is rejected and the old room stays as it was
+    let restricted_level = std::cmp::max(50, power_levels.users_default() + 1);
+    let mut old_power_levels = power_levels;
+    old_power_levels.events_default = Some(restricted_level);
+    old_power_levels.invite = Some(restricted_level);
+    db.add_event(
+        &room_id,
+        NewEvent::builder(user_id.clone(), EventContent::PowerLevels(old_power_levels))
+            .state_key("")
+            .build(),
+        &state.state_resolver,
+        &state.keys,
+    )
+    .await?;
+    // the new room takes the old one's place in the directory
+    if public {
+        db.set_room_public(&room_id, false).await?;
+    }
+
+    Ok(Json(json!({ "replacement_room": new_room_id })))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum InviteRequest {
+    UserId { user_id: MatrixId },
+    ThirdParty(Invite3pid),
+}
+
+/// How many invites a user may send within `INVITE_WINDOW`.
+const INVITES_PER_WINDOW: usize = 20;
+const INVITE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Remembers when each user sent their latest invites, so that nobody can flood others with
+/// them.
+#[derive(Debug, Default)]
+pub struct InviteLimits {
+    sent: Mutex<HashMap<MatrixId, VecDeque<Instant>>>,
+}
+
+impl InviteLimits {
+    /// Returns whether `inviter` may send another invite now.
+    pub fn may_invite(&self, inviter: &MatrixId) -> bool {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        // forget about invites that have left the window, so this doesn't grow forever
+        sent.retain(|_, times| {
+            while matches!(times.front(), Some(t) if now.duration_since(*t) >= INVITE_WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        sent.get(inviter)
+            .map_or(true, |times| times.len() < INVITES_PER_WINDOW)
+    }
+
+    /// Counts an invite that `inviter` has sent towards their limit.
+    pub fn count_invite(&self, inviter: &MatrixId) {
+        let mut sent = self.sent.lock().unwrap();
+        sent.entry(inviter.clone())
+            .or_default()
+            .push_back(Instant::now());
+    }
 }
 
 #[post("/rooms/{room_id}/invite")]
@@ -284,29 +539,168 @@ pub async fn invite(
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
-    let invitee = req.into_inner().user_id;
-    let invitee_profile = db
-        .get_profile(&invitee.localpart())
+
+    let room_state = state.state_resolver.resolve_current(&room_id).await?;
+    let creator = room_state
+        .get_content::<room::Create>(&*db, "")
         .await?
-        .unwrap_or_default();
+        .ok_or(ErrorKind::RoomNotFound)?
+        .creator;
+    let power_levels = room_state
+        .get_content::<room::PowerLevels>(&*db, "")
+        .await?
+        .unwrap_or_else(|| room::PowerLevels::no_event_default_levels(&creator));
+    if power_levels.get_user_level(&user_id) < power_levels.invite() {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if !state.invite_limits.may_invite(&user_id) {
+        return Err(ErrorKind::LimitExceeded.into());
+    }
+
+    let sent = match req.into_inner() {
+        InviteRequest::UserId { user_id: invitee } => {
+            invite_user(
+                &*db,
+                &state.state_resolver,
+                &state.keys,
+                &room_id,
+                &user_id,
+                &invitee,
+                false,
+            )
+            .await?
+        }
+        InviteRequest::ThirdParty(threepid) => {
+            invite_3pid(
+                &*db,
+                &state.state_resolver,
+                &state.keys,
+                &state.config.domain,
+                &room_id,
+                &user_id,
+                threepid,
+                false,
+            )
+            .await?
+        }
+    };
+    // inviting someone who's already invited doesn't send anything, so it isn't counted
+    if sent {
+        state.invite_limits.count_invite(&user_id);
+    }
+
+    Ok(Json(json!({})))
+}
+
+/// Invites someone by their user id. Returns whether an invite was sent, which it isn't if they
+/// were already invited.
+async fn invite_user(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Ed25519KeyPair>,
+    room_id: &str,
+    sender: &MatrixId,
+    invitee: &MatrixId,
+    is_direct: bool,
+) -> Result<bool, Error> {
+    match db
+        .get_membership(invitee, room_id, Some(state_resolver))
+        .await?
+    {
+        // inviting someone again doesn't change anything, so don't send another event
+        Some(room::Membership::Invite) => return Ok(false),
+        Some(room::Membership::Join) => return Err(ErrorKind::Forbidden.into()),
+        _ => {}
+    }
+
+    //TODO: ask the invitee's server for their profile once there's federation
+    let invitee_profile = if invitee.domain() == sender.domain() {
+        db.get_profile(&invitee.localpart())
+            .await?
+            .ok_or(ErrorKind::UserNotFound)?
+    } else {
+        UserProfile::default()
+    };
 
     let invite_event = NewEvent {
         event_content: EventContent::Member(room::Member {
             avatar_url: invitee_profile.avatar_url,
             displayname: invitee_profile.displayname,
             membership: room::Membership::Invite,
-            is_direct: Some(false),
+            is_direct: Some(is_direct),
+            reason: None,
+            third_party_invite: None,
         }),
-        sender: user_id.clone(),
+        sender: sender.clone(),
         state_key: Some(invitee.clone_inner()),
         redacts: None,
         unsigned: None,
     };
 
-    db.add_event(&room_id, invite_event, &state.state_resolver)
+    db.add_event(room_id, invite_event, state_resolver, keys)
         .await?;
+    Ok(true)
+}
 
-    Ok(Json(json!({})))
+/// Invites someone by a third party identifier, which has to be bound to a local user for now.
+/// Returns whether an invite was sent, like `invite_user`.
+pub(crate) async fn invite_3pid(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Ed25519KeyPair>,
+    domain: &str,
+    room_id: &str,
+    sender: &MatrixId,
+    threepid: Invite3pid,
+    is_direct: bool,
+) -> Result<bool, Error> {
+    if let Some(username) = db
+        .get_user_by_threepid(&threepid.medium, &threepid.address)
+        .await?
+    {
+        let invitee = MatrixId::new(&username, domain).unwrap();
+        return invite_user(
+            db,
+            state_resolver,
+            keys,
+            room_id,
+            sender,
+            &invitee,
+            is_direct,
+        )
+        .await;
+    }
+
+    // TODO: store the invite with the identity server, which is where the token and public key
+    // for an m.room.third_party_invite come from. Without them, nobody could ever claim it
+    Err(unbound_threepid(&threepid))
+}
+
+fn unbound_threepid(threepid: &Invite3pid) -> Error {
+    ErrorKind::Unrecognized(format!(
+        "can't store invites with {} yet, so only bound {} addresses can be invited",
+        threepid.id_server, threepid.medium,
+    ))
+    .into()
+}
+
+/// Fails if the user is already joined to as many rooms as `max_rooms_per_user` allows, unless
+/// they're an admin.
+async fn check_room_limit(
+    db: &dyn Storage,
+    state: &ServerState,
+    user_id: &MatrixId,
+) -> Result<(), Error> {
+    let max_rooms = match state.config.max_rooms_per_user {
+        Some(max_rooms) => max_rooms,
+        None => return Ok(()),
+    };
+    if db.count_joined_rooms(user_id).await? >= max_rooms
+        && !db.is_admin(user_id.localpart()).await?
+    {
+        return Err(ErrorKind::LimitExceeded.into());
+    }
+    Ok(())
 }
 
 #[post("/join/{room_id_or_alias}")]
@@ -316,12 +710,25 @@ pub async fn join_by_id_or_alias(
     token: AccessToken,
     Path(room_id_or_alias): Path<String>,
 ) -> Result<Json<JsonValue>, Error> {
-    //TODO: implement server_name and third_party_signed args, and room aliases
+    //TODO: implement server_name and third_party_signed args
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
-    let profile = db.get_profile(&username).await?.unwrap_or_default();
+    let room_id = if room_id_or_alias.starts_with('#') {
+        let alias = RoomAlias::try_from(room_id_or_alias)?;
+        directory::resolve_room_alias(&*db, &state, &alias).await?
+    } else {
+        room_id_or_alias
+    };
+    // joining a room again doesn't take up another place
+    if db.get_membership(&user_id, &room_id, None).await? != Some(room::Membership::Join) {
+        check_room_limit(&*db, &state, &user_id).await?;
+    }
+    let profile = db
+        .get_profile(&username)
+        .await?
+        .ok_or(ErrorKind::UserNotFound)?;
 
     let event = NewEvent {
         event_content: EventContent::Member(room::Member {
@@ -329,6 +736,8 @@ pub async fn join_by_id_or_alias(
             displayname: profile.displayname,
             membership: room::Membership::Join,
             is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
         }),
         sender: user_id.clone(),
         state_key: Some(user_id.to_string()),
@@ -336,8 +745,693 @@ pub async fn join_by_id_or_alias(
         unsigned: None,
     };
 
-    db.add_event(&room_id_or_alias, event, &state.state_resolver)
+    db.add_event(&room_id, event, &state.state_resolver, &state.keys)
         .await?;
 
-    Ok(Json(serde_json::json!({ "room_id": room_id_or_alias })))
+    Ok(Json(serde_json::json!({ "room_id": room_id })))
+}
+
+#[derive(Deserialize)]
+pub struct MembershipRequest {
+    user_id: MatrixId,
+    reason: Option<String>,
+}
+
+#[post("/rooms/{room_id}/leave")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn leave(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    set_membership(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &room_id,
+        &user_id,
+        &user_id,
+        room::Membership::Leave,
+        None,
+    )
+    .await?;
+    Ok(Json(json!({})))
+}
+
+#[post("/rooms/{room_id}/kick")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn kick(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<MembershipRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let req = req.into_inner();
+    set_membership(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &room_id,
+        &user_id,
+        &req.user_id,
+        room::Membership::Leave,
+        req.reason,
+    )
+    .await?;
+    Ok(Json(json!({})))
+}
+
+#[post("/rooms/{room_id}/ban")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn ban(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<MembershipRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let req = req.into_inner();
+    set_membership(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &room_id,
+        &user_id,
+        &req.user_id,
+        room::Membership::Ban,
+        req.reason,
+    )
+    .await?;
+    Ok(Json(json!({})))
+}
+
+#[post("/rooms/{room_id}/unban")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn unban(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<MembershipRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let req = req.into_inner();
+    // otherwise this would kick a member who isn't banned
+    if db
+        .get_membership(&req.user_id, &room_id, Some(&state.state_resolver))
+        .await?
+        != Some(room::Membership::Ban)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    set_membership(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &room_id,
+        &user_id,
+        &req.user_id,
+        room::Membership::Leave,
+        req.reason,
+    )
+    .await?;
+    Ok(Json(json!({})))
+}
+
+/// Sends an `m.room.member` event changing the target's membership. Returns `Forbidden` if the
+/// event was rejected by the auth rules.
+pub(crate) async fn set_membership(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Ed25519KeyPair>,
+    room_id: &str,
+    sender: &MatrixId,
+    target: &MatrixId,
+    membership: room::Membership,
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let event = NewEvent {
+        event_content: EventContent::Member(room::Member {
+            avatar_url: None,
+            displayname: None,
+            membership,
+            is_direct: None,
+            reason,
+            third_party_invite: None,
+        }),
+        sender: sender.clone(),
+        state_key: Some(target.clone_inner()),
+        redacts: None,
+        unsigned: None,
+    };
+
+    let event_id = db.add_event(room_id, event, state_resolver, keys).await?;
+    // add_event stores events which fail auth, so it's up to us to tell the client
+    let pdu = db
+        .get_pdu(room_id, &event_id)
+        .await?
+        .ok_or(ErrorKind::RoomNotFound)?;
+    if !pdu.did_pass_auth() {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{invite_3pid, set_membership, Invite3pid, InviteLimits, INVITES_PER_WINDOW};
+    use crate::{
+        client_api::tests::{server_state, server_state_with_config, test_config},
+        events::{
+            room::{JoinRule, JoinRules, Member, Membership, PowerLevels},
+            EventContent,
+        },
+        state::StateResolver,
+        storage::{
+            mem::MemStorageManager, tests::create_room, EventQuery, QueryType, StorageManager,
+        },
+        util::{storage::NewEvent, MatrixId, StorageExt},
+        Config,
+    };
+    use actix_web::{http::header, http::StatusCode, test, web, App, ResponseError};
+    use serde_json::{json, Value as JsonValue};
+
+    #[test]
+    fn invite_unbound_email() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let room_id = "!threepid:example.org";
+            create_room(&*db, room_id, &alice).await;
+            db.add_event(
+                room_id,
+                NewEvent {
+                    event_content: EventContent::Member(Member {
+                        avatar_url: None,
+                        displayname: None,
+                        membership: Membership::Join,
+                        is_direct: None,
+                        reason: None,
+                        third_party_invite: None,
+                    }),
+                    sender: alice.clone(),
+                    state_key: Some(alice.clone_inner()),
+                    redacts: None,
+                    unsigned: None,
+                },
+                &resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+
+            let invite = Invite3pid {
+                id_server: String::from("id.example.org"),
+                medium: String::from("email"),
+                address: String::from("bob@example.org"),
+            };
+            // there'd be no way to claim an m.room.third_party_invite without an identity server
+            let err = invite_3pid(
+                &*db,
+                &resolver,
+                &Default::default(),
+                "example.org",
+                room_id,
+                &alice,
+                invite,
+                false,
+            )
+            .await
+            .err()
+            .expect("unbound email was invited");
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+            let state = db.get_full_state(room_id, Some(&resolver)).await.unwrap();
+            assert!(!state
+                .iter()
+                .any(|event| matches!(event.event_content, EventContent::ThirdPartyInvite(_))));
+        });
+    }
+
+    #[test]
+    fn membership_changes_check_auth() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room_id = "!membership:example.org";
+            create_room(&*db, room_id, &alice).await;
+            set_membership(
+                &*db,
+                &resolver,
+                &Default::default(),
+                room_id,
+                &alice,
+                &alice,
+                Membership::Join,
+                None,
+            )
+            .await
+            .unwrap();
+            db.add_event(
+                room_id,
+                NewEvent {
+                    event_content: EventContent::JoinRules(JoinRules {
+                        join_rule: JoinRule::Invite,
+                    }),
+                    sender: alice.clone(),
+                    state_key: Some(String::new()),
+                    redacts: None,
+                    unsigned: None,
+                },
+                &resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            for (sender, target, membership) in [
+                (&alice, &bob, Membership::Invite),
+                (&bob, &bob, Membership::Join),
+            ]
+            .iter()
+            .cloned()
+            {
+                set_membership(
+                    &*db,
+                    &resolver,
+                    &Default::default(),
+                    room_id,
+                    sender,
+                    target,
+                    membership,
+                    None,
+                )
+                .await
+                .unwrap();
+            }
+
+            // bob has the default power level of 0, so can't kick the creator
+            let err = set_membership(
+                &*db,
+                &resolver,
+                &Default::default(),
+                room_id,
+                &bob,
+                &alice,
+                Membership::Leave,
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+            let reason = Some(String::from("spam"));
+            set_membership(
+                &*db,
+                &resolver,
+                &Default::default(),
+                room_id,
+                &alice,
+                &bob,
+                Membership::Ban,
+                reason,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                db.get_membership(&bob, room_id, Some(&resolver))
+                    .await
+                    .unwrap(),
+                Some(Membership::Ban)
+            );
+            let err = set_membership(
+                &*db,
+                &resolver,
+                &Default::default(),
+                room_id,
+                &bob,
+                &bob,
+                Membership::Join,
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+            set_membership(
+                &*db,
+                &resolver,
+                &Default::default(),
+                room_id,
+                &alice,
+                &bob,
+                Membership::Leave,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                db.get_membership(&bob, room_id, Some(&resolver))
+                    .await
+                    .unwrap(),
+                Some(Membership::Leave)
+            );
+        });
+    }
+
+    #[test]
+    fn created_rooms_have_the_advertised_default_version() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let config = Config {
+                default_room_version: String::from("6"),
+                ..test_config()
+            };
+            let state = server_state_with_config(db_pool, config).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/capabilities")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let versions = &res["capabilities"]["m.room_versions"];
+            assert_eq!(versions["default"], "6");
+            assert_eq!(versions["available"]["6"], "stable");
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&serde_json::json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap();
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/state/m.room.create",
+                    room_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["content"]["room_version"], versions["default"]);
+        });
+    }
+
+    #[test]
+    fn inviting_twice_sends_one_invite() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let invite_bob = || {
+                test::TestRequest::post()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/invite", room_id))
+                    .header(alice.0.clone(), alice.1.clone())
+                    .set_json(&json!({ "user_id": "@bob:example.org" }))
+                    .to_request()
+            };
+            // only the invite that was actually sent counts towards alice's limit
+            for _ in 0..INVITES_PER_WINDOW + 1 {
+                let res = test::call_service(&mut app, invite_bob()).await;
+                assert!(res.status().is_success());
+            }
+
+            let query = EventQuery {
+                query_type: QueryType::Timeline { from: 0, to: None },
+                room_id: &room_id,
+                senders: &[],
+                not_senders: &[],
+                types: &["m.room.member"],
+                not_types: &[],
+                contains_json: Some(json!({ "membership": "invite" })),
+                include_soft_failed: false,
+            };
+            let (invites, _) = db.query_pdus(query, false).await.unwrap();
+            assert_eq!(invites.len(), 1);
+
+            // once bob is in the room, there's nothing to invite him to
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let res = test::call_service(&mut app, invite_bob()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        });
+    }
+
+    #[test]
+    fn rooms_per_user_are_capped() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = Vec::new();
+            for user in ["alice", "bob", "admin"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.push((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            db.set_admin("admin", true).await.unwrap();
+            let (alice, bob, admin) = (&auth[0], &auth[1], &auth[2]);
+            let config = Config {
+                max_rooms_per_user: Some(2),
+                ..test_config()
+            };
+            let state = server_state_with_config(db_pool, config).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let create_room = |auth: &(header::HeaderName, String)| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/createRoom")
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "visibility": "public" }))
+                    .to_request()
+            };
+            let join = |auth: &(header::HeaderName, String), room_id: &str| {
+                test::TestRequest::post()
+                    .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+
+            let mut rooms = Vec::new();
+            for _ in 0..2 {
+                let res: JsonValue = test::read_response_json(&mut app, create_room(alice)).await;
+                rooms.push(res["room_id"].as_str().unwrap().to_owned());
+            }
+            let res = test::call_service(&mut app, create_room(alice)).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+            // alice can't join anything else, but she's still welcome in her own rooms
+            let res: JsonValue = test::read_response_json(&mut app, create_room(bob)).await;
+            let bobs_room = res["room_id"].as_str().unwrap().to_owned();
+            let res = test::call_service(&mut app, join(alice, &bobs_room)).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            let res = test::call_service(&mut app, join(alice, &rooms[0])).await;
+            assert!(res.status().is_success());
+
+            for _ in 0..3 {
+                let res = test::call_service(&mut app, create_room(admin)).await;
+                assert!(res.status().is_success());
+            }
+        });
+    }
+
+    #[test]
+    fn upgraded_rooms_link_to_each_other() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = Vec::new();
+            for user in ["alice", "bob"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.push((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            let (alice, bob) = (&auth[0], &auth[1]);
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({
+                    "visibility": "public",
+                    "name": "old news",
+                    "power_level_content_override": {
+                        "users": { "@alice:example.org": 100 },
+                        "users_default": 0,
+                    },
+                }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let old_room = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", old_room))
+                .header(bob.0.clone(), bob.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let upgrade = |auth: &(header::HeaderName, String), version: &str| {
+                test::TestRequest::post()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/upgrade", old_room))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "new_version": version }))
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, upgrade(bob, "6")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = test::call_service(&mut app, upgrade(alice, "1")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let (last_event_ids, _) = db.get_prev_events(&old_room).await.unwrap();
+            let res: JsonValue = test::read_response_json(&mut app, upgrade(alice, "6")).await;
+            let new_room = res["replacement_room"].as_str().unwrap().to_owned();
+
+            let tombstone = db
+                .get_state_event(
+                    &old_room,
+                    "m.room.tombstone",
+                    "",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .expect("old room has no tombstone");
+            let tombstone = match tombstone.event_content {
+                EventContent::Tombstone(tombstone) => tombstone,
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                tombstone.replacement_room.as_deref(),
+                Some(new_room.as_str())
+            );
+
+            let create = db
+                .get_state_event(&new_room, "m.room.create", "", Some(&state.state_resolver))
+                .await
+                .unwrap()
+                .unwrap();
+            let create = match create.event_content {
+                EventContent::Create(create) => create,
+                _ => unreachable!(),
+            };
+            assert_eq!(create.room_version.as_deref(), Some("6"));
+            let predecessor = create.predecessor.unwrap();
+            assert_eq!(predecessor.room_id, old_room);
+            assert_eq!(vec![predecessor.event_id], last_event_ids);
+
+            let alice_id = MatrixId::new("alice", "example.org").unwrap();
+            let power_levels = |room_id: String| {
+                let (db, state) = (&db, &state);
+                async move {
+                    let room_state = state.state_resolver.resolve_current(&room_id).await;
+                    room_state
+                        .unwrap()
+                        .get_content::<PowerLevels>(&**db, "")
+                        .await
+                        .unwrap()
+                        .unwrap()
+                }
+            };
+            let new_levels = power_levels(new_room.clone()).await;
+            assert_eq!(new_levels.get_user_level(&alice_id), 100);
+            // nobody but alice can talk or invite in the old room any more
+            let old_levels = power_levels(old_room.clone()).await;
+            assert_eq!(old_levels.events_default(), 50);
+            assert_eq!(old_levels.invite(), 50);
+            assert_eq!(old_levels.get_user_level(&alice_id), 100);
+
+            // bob stays where he was until he follows the tombstone himself
+            assert_eq!(
+                db.get_membership(
+                    &MatrixId::new("bob", "example.org").unwrap(),
+                    &new_room,
+                    Some(&state.state_resolver)
+                )
+                .await
+                .unwrap(),
+                None
+            );
+            assert_eq!(db.get_public_rooms().await.unwrap(), vec![new_room.clone()]);
+        });
+    }
+
+    #[test]
+    fn invites_are_rate_limited() {
+        let limits = InviteLimits::default();
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        for _ in 0..INVITES_PER_WINDOW {
+            assert!(limits.may_invite(&alice));
+            limits.count_invite(&alice);
+        }
+        assert!(!limits.may_invite(&alice));
+        assert!(limits.may_invite(&bob));
+    }
 }
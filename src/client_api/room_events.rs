@@ -1,20 +1,42 @@
 use actix_web::{
     get, put,
     web::{Data, Json, Path, Query},
+    HttpRequest,
 };
 use futures::FutureExt;
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
 use tokio::time::{delay_for, Duration};
 use tracing::{field::Empty, instrument, Level, Span};
 
 use crate::{
-    client_api::auth::AccessToken,
+    client_api::{
+        auth::AccessToken,
+        directory,
+        filter::{load_filter, RoomEventFilter},
+        presence::{PresenceContent, PresenceEvent},
+    },
     error::{Error, ErrorKind},
-    events::{room::Membership, Event, EventContent},
-    storage::{EventQuery, QueryType},
-    util::{storage::NewEvent, MatrixId, StorageExt},
+    events::{
+        room::{CanonicalAlias, EncryptionAlgorithm, Membership, Redaction},
+        Event, EventContent,
+    },
+    state::StateResolver,
+    storage::{
+        Batch, EventQuery, NotificationCounts, PresenceState, QueryType, Storage, ToDeviceMessage,
+    },
+    util::{
+        display_name::disambiguated_names,
+        push_rules::{default_push_rules, PUSH_RULES},
+        storage::NewEvent,
+        EventId, MatrixId, RoomId, StorageExt,
+    },
     ServerState,
 };
 
@@ -28,21 +50,13 @@ pub struct SyncRequest {
     #[serde(default)]
     full_state: bool,
     #[serde(default = "default_set_presence")]
-    set_presence: SetPresence,
+    set_presence: PresenceState,
     #[serde(default)]
     timeout: u32,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename = "snake_case")]
-enum SetPresence {
-    Offline,
-    Online,
-    Unavailable,
-}
-
-fn default_set_presence() -> SetPresence {
-    SetPresence::Online
+fn default_set_presence() -> PresenceState {
+    PresenceState::Online
 }
 
 #[derive(Debug, Serialize)]
@@ -51,6 +65,7 @@ pub struct SyncResponse {
     rooms: Option<Rooms>,
     presence: Option<Presence>,
     account_data: AccountData,
+    to_device: ToDevice,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -67,6 +82,60 @@ struct JoinedRoom {
     timeline: Timeline,
     ephemeral: Ephemeral,
     account_data: AccountData,
+    unread_notifications: UnreadNotifications,
+}
+
+#[derive(Debug, Serialize)]
+struct UnreadNotifications {
+    notification_count: usize,
+    highlight_count: usize,
+}
+
+impl UnreadNotifications {
+    /// Counts what the user's push rules pick out of the room since their read receipt, carrying
+    /// on from where the previous count stopped.
+    async fn load(
+        db: &dyn Storage,
+        cache: &NotificationCache,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Self, Error> {
+        let receipt = db.get_read_receipt(room_id, user_id).await?;
+        let cached = cache.get(user_id, room_id);
+        let counts = db
+            .get_notification_counts(user_id, room_id, receipt.as_deref(), cached)
+            .await?;
+        cache.set(user_id, room_id, counts);
+        Ok(UnreadNotifications {
+            notification_count: counts.notifications,
+            highlight_count: counts.highlights,
+        })
+    }
+}
+
+/// Remembers each user's latest notification counts in each of their rooms, so that a sync
+/// only has to look at the events that came in since the one before.
+#[derive(Debug, Default)]
+pub struct NotificationCache {
+    counts: Mutex<HashMap<(MatrixId, String), NotificationCounts>>,
+}
+
+impl NotificationCache {
+    fn get(&self, user_id: &MatrixId, room_id: &str) -> Option<NotificationCounts> {
+        let counts = self.counts.lock().unwrap();
+        counts.get(&(user_id.clone(), room_id.to_string())).copied()
+    }
+
+    fn set(&self, user_id: &MatrixId, room_id: &str, new: NotificationCounts) {
+        let mut counts = self.counts.lock().unwrap();
+        let entry = counts
+            .entry((user_id.clone(), room_id.to_string()))
+            .or_insert(new);
+        // two syncs can count at once; keep whichever got further
+        if new.from != entry.from || new.to >= entry.to {
+            *entry = new;
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -110,6 +179,22 @@ struct AccountData {
     events: Vec<KvPair>,
 }
 
+impl From<HashMap<String, JsonValue>> for AccountData {
+    fn from(map: HashMap<String, JsonValue>) -> Self {
+        AccountData {
+            events: map
+                .into_iter()
+                .map(|(ty, content)| KvPair { ty, content })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ToDevice {
+    events: Vec<ToDeviceMessage>,
+}
+
 #[derive(Debug, Serialize)]
 struct InvitedRoom {
     invite_state: InviteState,
@@ -137,7 +222,7 @@ struct LeftRoom {
 
 #[derive(Debug, Serialize)]
 struct Presence {
-    events: Vec<KvPair>,
+    events: Vec<PresenceEvent>,
 }
 
 #[get("/sync")]
@@ -148,121 +233,257 @@ pub async fn sync(
     req: Query<SyncRequest>,
 ) -> Result<Json<SyncResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
-    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    let mut batch = db
-        .get_batch(req.since.as_deref().unwrap_or("empty"))
-        .await?
-        .unwrap_or_default();
+    let filter = load_filter(&*db, &username, req.filter.as_deref()).await?;
+    let room_filter = &filter.room;
+    let timeline_filter = &room_filter.timeline;
+    let types = timeline_filter
+        .types
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let not_types = timeline_filter
+        .not_types
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let senders = timeline_filter.senders.iter().flatten().collect::<Vec<_>>();
+    let not_senders = timeline_filter.not_senders.iter().collect::<Vec<_>>();
+    let filtered_query = |room_id, from| EventQuery {
+        senders: &senders,
+        not_senders: &not_senders,
+        types: &types,
+        not_types: &not_types,
+        ..timeline_query(room_id, from, None)
+    };
+
+    // a token that was never handed out, or that has since been evicted, can't be synced from
+    let mut batch = match req.since.as_deref() {
+        Some(since) => db
+            .get_batch(since)
+            .await?
+            .ok_or_else(|| ErrorKind::InvalidParam(String::from("since")))?,
+        None => Batch::default(),
+    };
     let next_batch_id = format!("{:x}", rand::random::<u64>());
+    let mut account_data = db.get_account_data(&username, batch.account_data).await?;
+    batch.account_data = account_data.position;
+    let mut something_happened = !account_data.global.is_empty() || !account_data.rooms.is_empty();
+    // messages stay queued until the device syncs from a batch that comes after them, so that
+    // they aren't lost if this response is
+    let (to_device, to_device_position) = db
+        .get_to_device_messages(&username, &device_id, batch.to_device)
+        .await?;
+    batch.to_device = to_device_position;
+    if !to_device.is_empty() {
+        something_happened = true;
+    }
+    if req.since.is_none() && !account_data.global.contains_key(PUSH_RULES) {
+        // the user hasn't changed their push rules, so they are all still the defaults
+        account_data
+            .global
+            .insert(PUSH_RULES.to_string(), default_push_rules(&user_id));
+    }
     let mut res = SyncResponse {
         next_batch: next_batch_id.clone(),
         rooms: None,
         presence: None,
-        account_data: AccountData { events: Vec::new() },
+        account_data: std::mem::take(&mut account_data.global).into(),
+        to_device: ToDevice { events: to_device },
     };
 
+    let now = chrono::Utc::now().timestamp_millis();
+    // syncing only counts as setting presence if it changes something, or else everyone would be
+    // told about it every time
+    let presence = db.get_presence(&username).await?;
+    if presence.as_ref().map(|p| p.state) != Some(req.set_presence) {
+        let status_msg = presence.and_then(|p| p.status_msg);
+        db.set_presence(&username, req.set_presence, status_msg, now)
+            .await?;
+    }
+
     let rooms = db.get_rooms().await?;
     let mut memberships = HashMap::new();
-    for room_id in rooms.iter() {
-        if let Some(membership) = db.get_membership(&user_id, room_id).await? {
+    for room_id in rooms.iter().filter(|r| room_filter.allows(r)) {
+        if let Some(membership) = db
+            .get_membership(&user_id, room_id, Some(&state.state_resolver))
+            .await?
+        {
             memberships.insert(room_id, membership);
         }
     }
-    let mut something_happened = false;
-    for (&room_id, membership) in memberships.iter() {
-        match membership {
-            Membership::Join => {
-                batch.invites.remove(room_id);
-                let from = batch.rooms.get(room_id).map(|v| *v).unwrap_or(0);
-                let (events, progress) = db
-                    .query_events(
-                        EventQuery {
-                            query_type: QueryType::Timeline { from, to: None },
-                            room_id,
-                            senders: &[],
-                            not_senders: &[],
-                            types: &[],
-                            not_types: &[],
-                            contains_json: None,
-                        },
-                        false,
-                    )
-                    .await?;
-                batch.rooms.insert(room_id.clone(), progress + 1);
+    let joined_rooms = memberships
+        .iter()
+        .filter(|(_, m)| **m == Membership::Join)
+        .map(|(room_id, _)| room_id.as_str())
+        .collect::<Vec<_>>();
+    let (presence_events, presence_position) = presence_since(
+        &*db,
+        &state.config.domain,
+        &joined_rooms,
+        batch.presence_position,
+        now,
+    )
+    .await?;
+    batch.presence_position = presence_position;
+    if !presence_events.is_empty() {
+        res.presence = Some(Presence {
+            events: presence_events,
+        });
+        something_happened = true;
+    }
+    for (&room_id, _) in memberships.iter().filter(|(_, m)| **m == Membership::Join) {
+        batch.invites.remove(room_id);
+        let from = batch.rooms.get(room_id).map(|v| *v).unwrap_or(0);
+        let (events, progress) = db
+            .query_ordered_events(filtered_query(room_id, from), false)
+            .await?;
+        batch.rooms.insert(room_id.clone(), progress + 1);
 
-                let mut state_events = Vec::new();
-                if req.full_state {
-                    state_events = db.get_full_state(&room_id).await?;
-                }
+        if !events.is_empty() {
+            something_happened = true;
+        }
+        let timeline = limit_timeline(events, timeline_filter.limit, from);
+        let state_events = if req.full_state {
+            db.get_full_state(room_id, Some(&state.state_resolver))
+                .await?
+        } else {
+            state_delta(&*db, room_id, from, progress, &timeline).await?
+        };
+        if !state_events.is_empty() {
+            something_happened = true;
+        }
+        let (joined, invited) = db.get_room_member_counts(&room_id).await?;
+        let summary = RoomSummary {
+            heroes: None,
+            joined_member_count: joined,
+            invited_member_count: invited,
+        };
+        let state = State {
+            events: state_events,
+        };
+        let ephemeral = Ephemeral {
+            events: db
+                .get_all_ephemeral_for_user(room_id, &user_id)
+                .await?
+                .into_iter()
+                .map(|(k, v)| KvPair { ty: k, content: v })
+                .collect(),
+        };
+        res.rooms.get_or_insert_with(Default::default).join.insert(
+            String::from(room_id),
+            JoinedRoom {
+                summary,
+                state,
+                timeline,
+                ephemeral,
+                account_data: account_data
+                    .rooms
+                    .remove(room_id)
+                    .unwrap_or_default()
+                    .into(),
+                unread_notifications: UnreadNotifications::load(
+                    &*db,
+                    &state.notification_counts,
+                    room_id,
+                    &user_id,
+                )
+                .await?,
+            },
+        );
+    }
 
-                if !events.is_empty() || !state_events.is_empty() {
-                    something_happened = true;
-                }
-                let (joined, invited) = db.get_room_member_counts(&room_id).await?;
-                let summary = RoomSummary {
-                    heroes: None,
-                    joined_member_count: joined,
-                    invited_member_count: invited,
-                };
-                let state = State {
-                    events: state_events,
-                };
-                let timeline = Timeline {
-                    events,
-                    limited: false,
-                    prev_batch: String::from("empty"),
-                };
-                let ephemeral = Ephemeral {
-                    events: db
-                        .get_all_ephemeral(room_id)
-                        .await?
-                        .into_iter()
-                        .map(|(k, v)| KvPair { ty: k, content: v })
-                        .collect(),
-                };
-                let account_data = AccountData { events: Vec::new() };
-                res.rooms.get_or_insert_with(Default::default).join.insert(
-                    String::from(room_id),
-                    JoinedRoom {
-                        summary,
-                        state,
-                        timeline,
-                        ephemeral,
-                        account_data,
-                    },
-                );
-            }
-            Membership::Invite if !batch.invites.contains(room_id) => {
-                let events = db
-                    .get_full_state(&room_id)
-                    .await?
-                    .into_iter()
-                    .map(|e| StrippedState {
-                        content: e.event_content,
-                        state_key: e.state_key.unwrap(),
-                        sender: e.sender,
-                    })
-                    .collect();
-                res.rooms
-                    .get_or_insert_with(Default::default)
-                    .invite
-                    .insert(
-                        room_id.clone(),
-                        InvitedRoom {
-                            invite_state: InviteState { events },
-                        },
-                    );
-                batch.invites.insert(room_id.clone());
-            }
-            _ => {}
+    let invited_rooms = db.get_invited_rooms_for_user(&user_id).await?;
+    for room_id in invited_rooms.iter() {
+        if batch.invites.contains(room_id) || !room_filter.allows(room_id) {
+            continue;
         }
+        let events = db
+            .get_full_state(room_id, Some(&state.state_resolver))
+            .await?
+            .into_iter()
+            .map(|e| StrippedState {
+                content: e.event_content,
+                state_key: e.state_key.unwrap(),
+                sender: e.sender,
+            })
+            .collect();
+        res.rooms
+            .get_or_insert_with(Default::default)
+            .invite
+            .insert(
+                room_id.clone(),
+                InvitedRoom {
+                    invite_state: InviteState { events },
+                },
+            );
+        batch.invites.insert(room_id.clone());
+        something_happened = true;
+    }
+
+    // invites which the client knows about but which are no longer pending, and weren't accepted
+    // (accepted invites were already removed from the batch above), have been rescinded or
+    // rejected
+    let rescinded_invites = batch
+        .invites
+        .iter()
+        .filter(|room_id| !invited_rooms.contains(room_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    for room_id in rescinded_invites {
+        batch.invites.remove(&room_id);
+        let leave_event = db
+            .get_state_event(
+                &room_id,
+                "m.room.member",
+                user_id.as_str(),
+                Some(&state.state_resolver),
+            )
+            .await?;
+        // the leave event isn't necessarily the last one in the room, and the client mustn't see
+        // anything after it, so it paginates back from the leave event rather than from the end
+        // of the timeline
+        let leave_ordering = match leave_event.as_ref().and_then(|e| e.event_id.as_deref()) {
+            Some(event_id) => db.get_stream_ordering(&room_id, event_id).await?,
+            None => None,
+        };
+        let room_account_data = account_data
+            .rooms
+            .remove(&room_id)
+            .unwrap_or_default()
+            .into();
+        res.rooms.get_or_insert_with(Default::default).leave.insert(
+            room_id,
+            LeftRoom {
+                state: State { events: Vec::new() },
+                timeline: Timeline {
+                    events: leave_event.into_iter().collect(),
+                    limited: false,
+                    // a leave event outside the timeline has no history to paginate through
+                    prev_batch: TimelineToken(leave_ordering.unwrap_or(0)).to_string(),
+                },
+                account_data: room_account_data,
+            },
+        );
+        something_happened = true;
     }
 
     if something_happened {
-        db.set_batch(&next_batch_id, batch).await?;
+        db.set_batch(
+            &username,
+            &device_id,
+            req.since.as_deref(),
+            &next_batch_id,
+            batch,
+        )
+        .await?;
         return Ok(Json(res));
     }
 
@@ -271,34 +492,66 @@ pub async fn sync(
         let from = batch.rooms.get(room_id).map(|v| *v).unwrap_or(0);
         let room_id_clone = String::from(room_id);
         queries.push(
-            db.query_events(
-                EventQuery {
-                    query_type: QueryType::Timeline { from, to: None },
-                    room_id,
-                    senders: &[],
-                    not_senders: &[],
-                    types: &[],
-                    not_types: &[],
-                    contains_json: None,
-                },
-                true,
-            )
-            .map(move |r| (r, room_id_clone)),
+            db.query_ordered_events(filtered_query(room_id, from), true)
+                .map(move |r| (r, room_id_clone, from)),
         );
     }
-    if queries.is_empty() {
-        // user is not in any rooms. no point waiting for stuff to happen in them
-        db.set_batch(&next_batch_id, batch).await?;
-        return Ok(Json(res));
-    }
+    // a user who isn't in any rooms can still be sent to-device messages, so wait for those
+    let room_changed = async move {
+        if queries.is_empty() {
+            futures::future::pending().await
+        } else {
+            futures::future::select_all(queries).await.0
+        }
+    };
+    let mut presence_position = batch.presence_position;
+    let presence_changed = async {
+        loop {
+            db.wait_for_presence(presence_position).await?;
+            let now = chrono::Utc::now().timestamp_millis();
+            let (events, position) = presence_since(
+                &*db,
+                &state.config.domain,
+                &joined_rooms,
+                presence_position,
+                now,
+            )
+            .await?;
+            // changes from people who don't share a room with the user aren't worth waking for
+            if !events.is_empty() {
+                return Ok::<_, Error>((events, position));
+            }
+            presence_position = position;
+        }
+    };
 
     let timeout = delay_for(Duration::from_millis(req.timeout as _));
     tokio::select! {
         _ = timeout => {
-            db.set_batch(&next_batch_id, batch).await?;
+            db.set_batch(&username, &device_id, req.since.as_deref(), &next_batch_id, batch)
+            .await?;
             return Ok(Json(res));
         },
-        ((query_res, room_id), _, _) = futures::future::select_all(queries) => {
+        woken = db.wait_for_to_device(&username, &device_id, batch.to_device) => {
+            woken?;
+            let (to_device, to_device_position) = db
+                .get_to_device_messages(&username, &device_id, batch.to_device)
+                .await?;
+            batch.to_device = to_device_position;
+            res.to_device.events = to_device;
+            db.set_batch(&username, &device_id, req.since.as_deref(), &next_batch_id, batch)
+            .await?;
+            return Ok(Json(res));
+        },
+        woken = presence_changed => {
+            let (events, position) = woken?;
+            batch.presence_position = position;
+            res.presence = Some(Presence { events });
+            db.set_batch(&username, &device_id, req.since.as_deref(), &next_batch_id, batch)
+            .await?;
+            return Ok(Json(res));
+        },
+        (query_res, room_id, from) = room_changed => {
             let (events, progress) = query_res?;
             let (joined, invited) = db.get_room_member_counts(&room_id).await?;
             let summary = RoomSummary {
@@ -307,53 +560,203 @@ pub async fn sync(
                 invited_member_count: invited,
             };
             batch.rooms.insert(room_id.clone(), progress + 1);
+            let timeline = limit_timeline(events, timeline_filter.limit, from);
+            let state_events = state_delta(&*db, &room_id, from, progress, &timeline).await?;
             res.rooms.get_or_insert_with(Default::default).join.insert(
                 room_id.clone(),
                 JoinedRoom {
                     summary,
-                    timeline: Timeline {
-                        events,
-                        limited: false,
-                        prev_batch: String::from("empty"),
-                    },
-                    state: State { events: Vec::new() },
+                    timeline,
+                    state: State { events: state_events },
                     ephemeral: Ephemeral {
-                        events: db.get_all_ephemeral(&room_id).await?.into_iter().map(
+                        events: db.get_all_ephemeral_for_user(&room_id, &user_id).await?.into_iter().map(
                             |(k, v)| KvPair {
                                 ty: k,
                                 content: v,
                             }).collect()
                     },
-                    account_data: AccountData { events: Vec::new() },
+                    // any account data written since the start of the sync is left for the next
+                    account_data: HashMap::new().into(),
+                    unread_notifications: UnreadNotifications::load(
+                        &*db,
+                        &state.notification_counts,
+                        &room_id,
+                        &user_id,
+                    )
+                    .await?,
                 }
             );
-            db.set_batch(&next_batch_id, batch).await?;
+            db.set_batch(&username, &device_id, req.since.as_deref(), &next_batch_id, batch)
+            .await?;
             return Ok(Json(res));
         },
     };
 }
 
+#[derive(Debug, Deserialize)]
+pub struct GetEventRequest {
+    /// Lets admins see what a redacted event said before it was redacted. Ignored for everyone
+    /// else.
+    #[serde(default)]
+    include_unredacted: bool,
+}
+
 #[get("/rooms/{room_id}/event/{event_id}")]
 #[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
 pub async fn get_event(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path((room_id, event_id)): Path<(String, String)>,
+    Path((room_id, event_id)): Path<(RoomId, EventId)>,
+    req: Query<GetEventRequest>,
 ) -> Result<Json<Event>, Error> {
+    let (room_id, event_id) = (room_id.as_str(), event_id.as_str());
     let db = state.db_pool.get_handle().await?;
 
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    if db.get_membership(&user_id, &room_id).await? != Some(Membership::Join) {
+    let pdu = if req.include_unredacted && db.is_admin(&username).await? {
+        db.get_unredacted_pdu(room_id, event_id).await
+    } else {
+        db.get_pdu(room_id, event_id).await
+    };
+    // clients get M_NOT_FOUND either way, but it's useful to know which one it was
+    let pdu = match pdu {
+        Err(e) if matches!(e.kind(), ErrorKind::RoomNotFound) => {
+            tracing::debug!("room not found");
+            return Err(ErrorKind::NotFound.into());
+        }
+        res => res?,
+    };
+
+    if db
+        .get_membership(&user_id, room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    match pdu {
+        Some(pdu) if !pdu.soft_failed => Ok(Json(pdu.to_client_format())),
+        Some(_) => {
+            tracing::debug!("event was soft failed");
+            Err(ErrorKind::NotFound.into())
+        }
+        None => {
+            tracing::debug!("event not found");
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnotationsRequest {
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    from: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnotationsResponse {
+    chunk: Vec<AnnotationCount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct AnnotationCount {
+    #[serde(rename = "type")]
+    ty: String,
+    key: String,
+    count: usize,
+}
+
+#[get("/rooms/{room_id}/relations/{event_id}/m.annotation")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_annotations(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, event_id)): Path<(RoomId, EventId)>,
+    req: Query<AnnotationsRequest>,
+) -> Result<Json<AnnotationsResponse>, Error> {
+    let (room_id, event_id) = (room_id.as_str(), event_id.as_str());
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if db
+        .get_membership(&user_id, room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
         return Err(ErrorKind::Forbidden.into());
     }
+    // the groups are worked out afresh each time, so a token is just a position among them
+    let from = req
+        .from
+        .as_deref()
+        .map(|from| {
+            from.parse::<usize>()
+                .map_err(|_| Error::from(ErrorKind::InvalidParam(String::from("from"))))
+        })
+        .transpose()?
+        .unwrap_or(0);
 
-    match db.get_pdu(&room_id, &event_id).await? {
-        Some(pdu) => Ok(Json(pdu.to_client_format())),
-        None => Err(ErrorKind::NotFound.into()),
+    let query = EventQuery {
+        contains_json: Some(json!({
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": event_id },
+        })),
+        ..timeline_query(room_id, 0, None)
+    };
+    let (events, _) = db.query_events(query, false).await?;
+    let mut chunk = count_annotations(&events);
+
+    let start = from.min(chunk.len());
+    let end = match req.limit {
+        Some(limit) => start.saturating_add(limit).min(chunk.len()),
+        None => chunk.len(),
+    };
+    let next_batch = if end < chunk.len() {
+        Some(end.to_string())
+    } else {
+        None
+    };
+    let chunk = chunk.drain(start..end).collect();
+    Ok(Json(AnnotationsResponse { chunk, next_batch }))
+}
+
+/// Groups annotations by their type and key, counting each sender once per group even if they
+/// reacted the same way twice. The most popular groups come first.
+fn count_annotations(events: &[Event]) -> Vec<AnnotationCount> {
+    let mut senders = HashMap::<(String, String), HashSet<&MatrixId>>::new();
+    for event in events {
+        let content = event.event_content.content_as_json();
+        let key = match content["m.relates_to"]["key"].as_str() {
+            Some(key) => key.to_owned(),
+            None => continue,
+        };
+        let ty = event.event_content.get_type().to_owned();
+        senders.entry((ty, key)).or_default().insert(&event.sender);
     }
+    let mut counts = senders
+        .into_iter()
+        .map(|((ty, key), senders)| AnnotationCount {
+            ty,
+            key,
+            count: senders.len(),
+        })
+        .collect::<Vec<_>>();
+    counts.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.ty.cmp(&b.ty))
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    counts
 }
 
 #[get("/rooms/{room_id}/state/{event_id}")]
@@ -386,12 +789,21 @@ pub async fn get_state_event_inner(
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    if db.get_membership(&user_id, &room_id).await? != Some(Membership::Join) {
+    if db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
         return Err(ErrorKind::Forbidden.into());
     }
 
     match db
-        .get_state_event(&room_id, &event_type, &state_key)
+        .get_state_event(
+            &room_id,
+            &event_type,
+            &state_key,
+            Some(&state.state_resolver),
+        )
         .await?
     {
         Some(event) => Ok(Json(event)),
@@ -411,23 +823,154 @@ pub async fn get_state(
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    match db.get_membership(&user_id, &room_id).await? {
+    match db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+    {
         Some(Membership::Join) => {}
         Some(_) => return Err(ErrorKind::Unimplemented.into()),
         None => return Err(ErrorKind::Forbidden.into()),
     }
 
-    let state = db.get_full_state(&room_id).await?;
-    Ok(Json(state))
+    let room_state = state.state_resolver.resolve_current(&room_id).await?;
+    Ok(Json(room_state.to_client_events(&*db).await?))
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize)]
+pub struct SummaryResponse {
+    room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    num_joined_members: usize,
+    /// The room's encryption algorithm, if it's encrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption: Option<EncryptionAlgorithm>,
+    /// If the room has no name, the names of up to five other members, which clients can call it
+    /// by instead.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    heroes: Vec<String>,
+}
+
+#[get("/rooms/{room_id}/summary")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_summary(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+) -> Result<Json<SummaryResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let name = match db
+        .get_state_event(&room_id, "m.room.name", "", Some(&state.state_resolver))
+        .await?
+    {
+        Some(Event {
+            event_content: EventContent::Name(content),
+            ..
+        }) => content.name,
+        _ => None,
+    };
+    let topic = match db
+        .get_state_event(&room_id, "m.room.topic", "", Some(&state.state_resolver))
+        .await?
+    {
+        Some(Event {
+            event_content: EventContent::Topic(content),
+            ..
+        }) => content.topic,
+        _ => None,
+    };
+    let (num_joined_members, _) = db.get_room_member_counts(&room_id).await?;
+    let encryption = match db
+        .get_state_event(
+            &room_id,
+            "m.room.encryption",
+            "",
+            Some(&state.state_resolver),
+        )
+        .await?
+    {
+        Some(Event {
+            event_content: EventContent::Encryption(content),
+            ..
+        }) => Some(content.algorithm),
+        _ => None,
+    };
+    let heroes = match name {
+        Some(_) => Vec::new(),
+        None => heroes(&*db, &state.state_resolver, &room_id, &user_id).await?,
+    };
+
+    Ok(Json(SummaryResponse {
+        room_id,
+        name,
+        topic,
+        num_joined_members,
+        encryption,
+        heroes,
+    }))
+}
+
+/// The names of up to five members of the room other than `user_id`, ordered by user ID.
+async fn heroes(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    room_id: &str,
+    user_id: &MatrixId,
+) -> Result<Vec<String>, Error> {
+    let state_events = db.get_full_state(room_id, Some(state_resolver)).await?;
+    let mut others = disambiguated_names(&state_events)
+        .into_iter()
+        .filter(|(member_id, _)| member_id != user_id.as_str())
+        .collect::<Vec<_>>();
+    others.sort();
+    Ok(others.into_iter().take(5).map(|(_, name)| name).collect())
+}
+
+/// Provided in URL query params. `membership` and `not_membership` can each be given more than
+/// once, which serde_urlencoded can't handle, so this is parsed by hand.
+#[derive(Debug, Default)]
 pub struct MembersRequest {
-    at: String,
-    #[serde(default)]
-    membership: Option<Membership>,
-    #[serde(default)]
-    not_membership: Option<Membership>,
+    membership: Vec<Membership>,
+    not_membership: Vec<Membership>,
+}
+
+impl MembersRequest {
+    fn from_query(query: &str) -> Result<Self, Error> {
+        let mut req = MembersRequest::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let list = match key {
+                "membership" => &mut req.membership,
+                "not_membership" => &mut req.not_membership,
+                //TODO: at is ignored, so members always come from the current state
+                _ => continue,
+            };
+            let value = percent_decode_str(value).decode_utf8_lossy();
+            let membership = serde_json::from_value(JsonValue::String(value.into_owned()))
+                .map_err(|_| ErrorKind::InvalidParam(pair.to_string()))?;
+            list.push(membership);
+        }
+        Ok(req)
+    }
+
+    fn matches(&self, membership: &Membership) -> bool {
+        (self.membership.is_empty() || self.membership.contains(membership))
+            && !self.not_membership.contains(membership)
+    }
 }
 
 #[derive(Serialize)]
@@ -436,37 +979,34 @@ pub struct MembersResponse {
 }
 
 #[get("/rooms/{room_id}/members")]
-#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+#[instrument(skip(state, token, http_req), fields(username = Empty), err = Level::DEBUG)]
 pub async fn get_members(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
     Path(room_id): Path<String>,
-    req: Query<MembersRequest>,
+    http_req: HttpRequest,
 ) -> Result<Json<MembersResponse>, Error> {
+    let req = MembersRequest::from_query(http_req.query_string())?;
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    match db.get_membership(&user_id, &room_id).await? {
+    match db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+    {
         Some(Membership::Join) => {}
         Some(_) => return Err(ErrorKind::Unimplemented.into()),
         None => return Err(ErrorKind::Forbidden.into()),
     }
 
-    let mut state = db.get_full_state(&room_id).await?;
+    let mut state = db
+        .get_full_state(&room_id, Some(&state.state_resolver))
+        .await?;
     state.retain(|event| {
         if let EventContent::Member(ref content) = &event.event_content {
-            let membership = &content.membership;
-            (if let Some(filter) = &req.membership {
-                membership == filter
-            } else {
-                true
-            } && if let Some(exclude) = &req.not_membership {
-                membership != exclude
-            } else {
-                true
-            })
+            req.matches(&content.membership)
         } else {
             false
         }
@@ -476,67 +1016,1969 @@ pub async fn get_members(
 }
 
 #[derive(Serialize)]
-pub struct SendEventResponse {
-    event_id: String,
+pub struct JoinedMembersResponse {
+    joined: HashMap<String, JoinedMember>,
 }
 
-#[put("/rooms/{room_id}/state/{event_type}/{state_key}")]
-#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
-pub async fn send_state_event(
+#[derive(Serialize)]
+struct JoinedMember {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+#[get("/rooms/{room_id}/joined_members")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_joined_members(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path((room_id, event_type, state_key)): Path<(String, String, String)>,
-    event_content: Json<JsonValue>,
-) -> Result<Json<SendEventResponse>, Error> {
+    Path(room_id): Path<String>,
+) -> Result<Json<JoinedMembersResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    let event = NewEvent {
-        event_content: EventContent::new(&event_type, event_content.into_inner())?,
-        sender: user_id,
-        state_key: Some(state_key),
-        redacts: None,
-        unsigned: None,
-    };
+    if db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+        != Some(Membership::Join)
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
 
-    let event_id = db.add_event(&room_id, event, &state.state_resolver).await?;
+    let state_events = db
+        .get_full_state(&room_id, Some(&state.state_resolver))
+        .await?;
+    let names = disambiguated_names(&state_events);
+    let mut joined = HashMap::new();
+    for event in state_events {
+        if let (EventContent::Member(member), Some(member_id)) =
+            (event.event_content, event.state_key)
+        {
+            if member.membership != Membership::Join {
+                continue;
+            }
+            let display_name = member.displayname.map(|_| names[&member_id].clone());
+            let avatar_url = member.avatar_url;
+            joined.insert(
+                member_id,
+                JoinedMember {
+                    display_name,
+                    avatar_url,
+                },
+            );
+        }
+    }
 
-    tracing::trace!(event_id = &event_id.as_str(), "Added event");
+    Ok(Json(JoinedMembersResponse { joined }))
+}
 
-    Ok(Json(SendEventResponse { event_id }))
+/// A position in a room's timeline, given to clients as an opaque pagination token. It points
+/// between two events: paginating forwards from `n` starts at event `n`, and paginating backwards
+/// starts at event `n - 1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TimelineToken(usize);
+
+impl std::fmt::Display for TimelineToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "t{}", self.0)
+    }
 }
 
-#[put("/rooms/{room_id}/send/{event_type}/{txn_id}")]
-#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
-pub async fn send_event(
+impl std::str::FromStr for TimelineToken {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let idx = s.strip_prefix('t').ok_or(())?;
+        idx.parse().map(TimelineToken).map_err(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesRequest {
+    from: String,
+    #[serde(default)]
+    to: Option<String>,
+    dir: Direction,
+    #[serde(default)]
+    limit: Option<usize>,
+    /// A `RoomEventFilter` encoded as JSON.
+    #[serde(default)]
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+enum Direction {
+    #[serde(rename = "b")]
+    Backwards,
+    #[serde(rename = "f")]
+    Forwards,
+}
+
+#[derive(Serialize)]
+pub struct MessagesResponse {
+    start: String,
+    end: String,
+    chunk: Vec<Event>,
+    /// Membership events for the senders in `chunk`, if the filter asked to lazy load members.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    state: Vec<Event>,
+}
+
+#[get("/rooms/{room_id}/messages")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_messages(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
-    event_content: Json<JsonValue>,
-) -> Result<Json<SendEventResponse>, Error> {
+    Path(room_id): Path<String>,
+    req: Query<MessagesRequest>,
+) -> Result<Json<MessagesResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
-    if !db.record_txn(token.0, txn_id.clone()).await? {
-        return Err(ErrorKind::TxnIdExists.into());
-    }
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    let event = NewEvent {
-        event_content: EventContent::new(&event_type, event_content.into_inner())?,
-        sender: user_id.clone(),
-        state_key: None,
-        redacts: None,
-        unsigned: Some(json!({ "transaction_id": txn_id })),
-    };
-
-    //TODO: is this right in the eyes of the spec? also does it matter?
+    match db
+        .get_membership(&user_id, &room_id, Some(&state.state_resolver))
+        .await?
+    {
+        Some(Membership::Join) => {}
+        Some(_) => return Err(ErrorKind::Unimplemented.into()),
+        None => return Err(ErrorKind::Forbidden.into()),
+    }
+
+    let parse_token = |name: &str, token: &str| {
+        token
+            .parse::<TimelineToken>()
+            .map_err(|_| Error::from(ErrorKind::InvalidParam(name.to_string())))
+    };
+    let from = parse_token("from", &req.from)?;
+    let to = req
+        .to
+        .as_deref()
+        .map(|to| parse_token("to", to))
+        .transpose()?;
+    let limit = req.limit.unwrap_or(10);
+    let filter = match req.filter.as_deref() {
+        Some(filter) => serde_json::from_str::<RoomEventFilter>(filter)
+            .map_err(|e| ErrorKind::BadJson(e.to_string()))?,
+        None => RoomEventFilter::default(),
+    };
+    let types = filter
+        .types
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let not_types = filter
+        .not_types
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let senders = filter.senders.iter().flatten().collect::<Vec<_>>();
+    let not_senders = filter.not_senders.iter().collect::<Vec<_>>();
+
+    // this always returns no events, but it tells us where the timeline ends
+    let (_, last) = db
+        .query_pdus(timeline_query(&room_id, usize::MAX, None), false)
+        .await?;
+    let from = from.0.min(last + 1);
+
+    // the range of events to return, inclusive of lower and exclusive of upper
+    let (lower, upper) = match req.dir {
+        Direction::Forwards => {
+            let upper = from.saturating_add(limit).min(last + 1);
+            (from, to.map_or(upper, |to| upper.min(to.0)))
+        }
+        Direction::Backwards => {
+            let lower = from.saturating_sub(limit);
+            (to.map_or(lower, |to| lower.max(to.0)), from)
+        }
+    };
+    if lower >= upper {
+        return Ok(Json(MessagesResponse {
+            start: TimelineToken(from).to_string(),
+            end: TimelineToken(from).to_string(),
+            chunk: Vec::new(),
+            state: Vec::new(),
+        }));
+    }
+
+    let query = EventQuery {
+        senders: &senders,
+        not_senders: &not_senders,
+        types: &types,
+        not_types: &not_types,
+        ..timeline_query(&room_id, lower, Some(upper - 1))
+    };
+    let (mut chunk, _) = db.query_events(query, false).await?;
+    let end = match req.dir {
+        Direction::Forwards => upper,
+        Direction::Backwards => {
+            chunk.reverse();
+            lower
+        }
+    };
+
+    let members = if filter.lazy_load_members {
+        lazy_loaded_members(&*db, &state.state_resolver, &room_id, &chunk).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(Json(MessagesResponse {
+        start: TimelineToken(from).to_string(),
+        end: TimelineToken(end).to_string(),
+        chunk,
+        state: members,
+    }))
+}
+
+/// Returns the m.room.member events of everyone who sent one of `events`, as they were when each
+/// event was sent, so that clients which don't load the whole member list can still show who sent
+/// them.
+async fn lazy_loaded_members(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    room_id: &str,
+    events: &[Event],
+) -> Result<Vec<Event>, Error> {
+    let mut member_ids = BTreeSet::new();
+    for event in events {
+        let event_id = event
+            .event_id
+            .clone()
+            .ok_or_else(|| ErrorKind::Unknown(String::from("Timeline event has no id")))?;
+        let state = state_resolver.resolve(room_id, &[event_id]).await?;
+        if let Some(member_id) = state.get(("m.room.member", event.sender.as_str())) {
+            member_ids.insert(member_id.to_owned());
+        }
+    }
+    let member_ids = member_ids.into_iter().collect::<Vec<_>>();
+    let members = db
+        .get_pdus(room_id, &member_ids)
+        .await?
+        .into_iter()
+        .zip(&member_ids)
+        .map(|(member, member_id)| {
+            member.ok_or_else(|| {
+                ErrorKind::Unknown(format!("Event in state doesn't exist: {}", member_id))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    db.state_events_to_client_format(members).await
+}
+
+/// Truncates a timeline of events since `from`, along with their stream orderings, to the most
+/// recent `limit` events, if there are more than that. `prev_batch` points at the first event that
+/// is kept, since filtered timelines can have gaps between the events in them.
+fn limit_timeline(mut events: Vec<(usize, Event)>, limit: Option<usize>, from: usize) -> Timeline {
+    let limited = match limit {
+        Some(limit) if events.len() > limit => {
+            events.drain(..events.len() - limit);
+            true
+        }
+        _ => false,
+    };
+    let prev_batch = TimelineToken(events.first().map_or(from, |(ordering, _)| *ordering));
+    Timeline {
+        events: events.into_iter().map(|(_, event)| event).collect(),
+        limited,
+        prev_batch: prev_batch.to_string(),
+    }
+}
+
+/// Returns the state events between `from` and `progress` that aren't in the timeline, because
+/// they were cut off or filtered out of it, so that the client's view of the room's state stays
+/// up to date. Only the last event for each (type, state_key) pair is returned.
+async fn state_delta(
+    db: &dyn Storage,
+    room_id: &str,
+    from: usize,
+    progress: usize,
+    timeline: &Timeline,
+) -> Result<Vec<Event>, Error> {
+    let (events, _) = db
+        .query_events(timeline_query(room_id, from, Some(progress)), false)
+        .await?;
+    let in_timeline = timeline
+        .events
+        .iter()
+        .filter_map(|e| e.event_id.as_deref())
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    let mut delta = events
+        .into_iter()
+        .rev()
+        .filter(|e| match (&e.event_id, &e.state_key) {
+            (Some(event_id), Some(state_key)) => {
+                !in_timeline.contains(event_id.as_str())
+                    && seen.insert((e.event_content.get_type().to_string(), state_key.clone()))
+            }
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+    delta.reverse();
+    Ok(delta)
+}
+
+/// Returns the presence of the local users joined to any of the rooms that was set after `since`
+/// in the presence stream, along with the position that it goes up to.
+async fn presence_since(
+    db: &dyn Storage,
+    domain: &str,
+    rooms: &[&str],
+    since: u64,
+    now: i64,
+) -> Result<(Vec<PresenceEvent>, u64), Error> {
+    let (changes, position) = db.get_presence_changes(since).await?;
+    let mut events = Vec::new();
+    //TODO: presence from other servers once there's federation
+    for (username, presence) in changes {
+        let user_id = MatrixId::new(&username, domain).unwrap();
+        // only users who share a room get to see each other's presence
+        let shares_room = db
+            .get_joined_rooms_for_user(&user_id)
+            .await?
+            .iter()
+            .any(|room_id| rooms.contains(&room_id.as_str()));
+        if !shares_room {
+            continue;
+        }
+        events.push(PresenceEvent {
+            sender: user_id,
+            ty: "m.presence",
+            content: PresenceContent::new(Some(presence), now),
+        });
+    }
+    Ok((events, position))
+}
+
+fn timeline_query(room_id: &str, from: usize, to: Option<usize>) -> EventQuery<'_> {
+    EventQuery {
+        query_type: QueryType::Timeline { from, to },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &[],
+        not_types: &[],
+        contains_json: None,
+        include_soft_failed: false,
+    }
+}
+
+#[derive(Serialize)]
+pub struct SendEventResponse {
+    event_id: String,
+}
+
+#[put("/rooms/{room_id}/state/{event_type}")]
+pub async fn send_state_event_no_key(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    path_args: Path<(String, String)>,
+    event_content: Json<JsonValue>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let (room_id, event_type) = path_args.into_inner();
+    send_state_event(
+        state,
+        token,
+        (room_id, event_type, String::new()),
+        event_content,
+    )
+    .await
+}
+
+#[put("/rooms/{room_id}/state/{event_type}/{state_key}")]
+pub async fn send_state_event_key(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    path_args: Path<(String, String, String)>,
+    event_content: Json<JsonValue>,
+) -> Result<Json<SendEventResponse>, Error> {
+    send_state_event(state, token, path_args.into_inner(), event_content).await
+}
+
+#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
+pub async fn send_state_event(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    (room_id, event_type, state_key): (String, String, String),
+    event_content: Json<JsonValue>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let event_content = EventContent::new(&event_type, event_content.into_inner())?;
+    if let EventContent::CanonicalAlias(content) = &event_content {
+        check_canonical_alias(&*db, &state, &room_id, content).await?;
+    }
+    let event = NewEvent {
+        event_content,
+        sender: user_id,
+        state_key: Some(state_key),
+        redacts: None,
+        unsigned: None,
+    };
+
+    let event_id = db
+        .add_event(&room_id, event, &state.state_resolver, &state.keys)
+        .await?;
+
+    tracing::trace!(event_id = &event_id.as_str(), "Added event");
+
+    Ok(Json(SendEventResponse { event_id }))
+}
+
+/// Checks that every alias named by an m.room.canonical_alias event points to the room that it's
+/// being sent to.
+async fn check_canonical_alias(
+    db: &dyn Storage,
+    state: &ServerState,
+    room_id: &str,
+    content: &CanonicalAlias,
+) -> Result<(), Error> {
+    for alias in content.alias.iter().chain(&content.alt_aliases) {
+        let target = match directory::resolve_room_alias(db, state, alias).await {
+            Ok(target) => Some(target),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if target.as_deref() != Some(room_id) {
+            tracing::debug!("{} doesn't point to {}", alias.as_str(), room_id);
+            return Err(ErrorKind::BadAlias.into());
+        }
+    }
+    Ok(())
+}
+
+#[put("/rooms/{room_id}/send/{event_type}/{txn_id}")]
+#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
+pub async fn send_event(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+    event_content: Json<JsonValue>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if !db
+        .record_txn(&username, &device_id, "room", txn_id.clone())
+        .await?
+    {
+        return Err(ErrorKind::TxnIdExists.into());
+    }
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let event = NewEvent {
+        event_content: EventContent::new(&event_type, event_content.into_inner())?,
+        sender: user_id.clone(),
+        state_key: None,
+        redacts: None,
+        unsigned: Some(json!({ "transaction_id": txn_id })),
+    };
+
+    //TODO: is this right in the eyes of the spec? also does it matter?
     db.set_typing(&room_id, &user_id, false, 0).await?;
-    let event_id = db.add_event(&room_id, event, &state.state_resolver).await?;
+    let event_id = db
+        .add_event(&room_id, event, &state.state_resolver, &state.keys)
+        .await?;
 
     tracing::trace!(event_id = &event_id.as_str(), "Added event");
 
     Ok(Json(SendEventResponse { event_id }))
 }
+
+#[derive(Deserialize)]
+pub struct RedactRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[put("/rooms/{room_id}/redact/{event_id}/{txn_id}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn redact(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, event_id, txn_id)): Path<(String, String, String)>,
+    req: Json<RedactRequest>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if !db
+        .record_txn(&username, &device_id, "room", txn_id.clone())
+        .await?
+    {
+        return Err(ErrorKind::TxnIdExists.into());
+    }
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if db.get_pdu(&room_id, &event_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+
+    let event = NewEvent {
+        event_content: EventContent::Redaction(Redaction {
+            reason: req.into_inner().reason,
+        }),
+        sender: user_id,
+        state_key: None,
+        redacts: Some(event_id),
+        unsigned: Some(json!({ "transaction_id": txn_id })),
+    };
+    let event_id = db
+        .add_event(&room_id, event, &state.state_resolver, &state.keys)
+        .await?;
+
+    Ok(Json(SendEventResponse { event_id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, web, App,
+    };
+    use serde_json::{json, Value as JsonValue};
+
+    use super::{limit_timeline, TimelineToken};
+    use crate::{
+        client_api::tests::server_state,
+        events::{room, Event, EventContent},
+        storage::{mem::MemStorageManager, StorageManager, BATCHES_PER_DEVICE},
+        util::{storage::NewEvent, MatrixId, StorageExt},
+    };
+
+    fn message(body: &str) -> Event {
+        Event {
+            event_content: EventContent::new("m.room.message", serde_json::json!({ "body": body }))
+                .unwrap(),
+            sender: MatrixId::new("alice", "example.org").unwrap(),
+            event_id: None,
+            room_id: None,
+            state_key: None,
+            unsigned: None,
+            redacts: None,
+            origin_server_ts: None,
+        }
+    }
+
+    #[test]
+    fn timeline_limit() {
+        // a timeline since 3, which a filter has left gaps in
+        let events = || {
+            [5, 6, 8, 11, 12]
+                .iter()
+                .map(|&i| (i, message(&i.to_string())))
+                .collect::<Vec<_>>()
+        };
+
+        let timeline = limit_timeline(events(), None, 3);
+        assert_eq!(timeline.events.len(), 5);
+        assert!(!timeline.limited);
+        assert_eq!(timeline.prev_batch, "t5");
+
+        let timeline = limit_timeline(events(), Some(2), 3);
+        assert!(timeline.limited);
+        assert_eq!(timeline.prev_batch, "t11");
+        let bodies = timeline
+            .events
+            .into_iter()
+            .map(|e| serde_json::to_value(e).unwrap()["content"]["body"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, vec!["11", "12"]);
+
+        let timeline = limit_timeline(Vec::new(), Some(2), 3);
+        assert!(!timeline.limited);
+        assert_eq!(timeline.prev_batch, "t3");
+
+        let token = "t11".parse::<TimelineToken>().unwrap();
+        assert_eq!(token.0, 11);
+        assert!("8".parse::<TimelineToken>().is_err());
+    }
+
+    #[test]
+    fn state_is_client_ready() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private", "topic": "secret plans" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/state/m.room.member/@alice:example.org",
+                    room_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "membership": "join", "displayname": "Alice" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let get_state = || {
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/state", room_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let find = |state: &JsonValue, ty: &str| {
+                state
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|e| e["type"] == ty)
+                    .unwrap()
+                    .clone()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, get_state()).await;
+            let topic = find(&res, "m.room.topic");
+            assert_eq!(topic["content"]["topic"], "secret plans");
+            let member = find(&res, "m.room.member");
+            assert_eq!(member["content"]["displayname"], "Alice");
+            assert_eq!(member["unsigned"]["prev_content"]["membership"], "join");
+            assert_eq!(member["unsigned"]["prev_content"].get("displayname"), None);
+
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/redact/{}/1",
+                    room_id,
+                    topic["event_id"].as_str().unwrap()
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({}))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let res: JsonValue = test::read_response_json(&mut app, get_state()).await;
+            let topic = find(&res, "m.room.topic");
+            assert_eq!(topic["content"], json!({}));
+        });
+    }
+
+    #[test]
+    fn incremental_sync_only_sends_changed_account_data() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let set_account_data = |path: String, content: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/user/@alice:example.org/{}",
+                        path
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&content)
+                    .to_request()
+            };
+            let sync = |since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}&timeout=0", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let types = |account_data: &JsonValue| {
+                let mut types = account_data["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|e| e["type"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                types.sort();
+                types
+            };
+
+            for (path, content) in [
+                (String::from("account_data/m.direct"), json!({})),
+                (
+                    String::from("account_data/im.vector.setting"),
+                    json!({ "x": 1 }),
+                ),
+                (
+                    format!("rooms/{}/account_data/m.tag", room_id),
+                    json!({ "tags": {} }),
+                ),
+            ] {
+                let res = test::call_service(&mut app, set_account_data(path, content)).await;
+                assert!(res.status().is_success());
+            }
+            let res: JsonValue = test::read_response_json(&mut app, sync(None)).await;
+            assert_eq!(
+                types(&res["account_data"]),
+                vec!["im.vector.setting", "m.direct", "m.push_rules"]
+            );
+            assert_eq!(
+                types(&res["rooms"]["join"][&room_id]["account_data"]),
+                vec!["m.tag"]
+            );
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            let path = String::from("account_data/im.vector.setting");
+            let res = test::call_service(&mut app, set_account_data(path, json!({ "x": 2 }))).await;
+            assert!(res.status().is_success());
+            let res: JsonValue = test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+            assert_eq!(types(&res["account_data"]), vec!["im.vector.setting"]);
+            assert_eq!(res["account_data"]["events"][0]["content"]["x"], 2);
+            assert!(types(&res["rooms"]["join"][&room_id]["account_data"]).is_empty());
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+            assert!(types(&res["account_data"]).is_empty());
+        });
+    }
+
+    #[test]
+    fn limited_sync_timeline() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let send = |body: usize| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+                        room_id, body
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "msgtype": "m.text", "body": body.to_string() }))
+                    .to_request()
+            };
+            let mut event_ids = Vec::new();
+            for body in 1..=3 {
+                let res: JsonValue = test::read_response_json(&mut app, send(body)).await;
+                event_ids.push(res["event_id"].as_str().unwrap().to_owned());
+            }
+
+            // a timeline limit of 2, as a percent-encoded inline filter
+            let limited = "%7B%22room%22%3A%7B%22timeline%22%3A%7B%22limit%22%3A2%7D%7D%7D";
+            let sync = |since: Option<&str>, filter: &str| {
+                let mut uri = format!("/_matrix/client/r0/sync?timeout=0&filter={}", filter);
+                if let Some(since) = since {
+                    uri.push_str(&format!("&since={}", since));
+                }
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let bodies = |timeline: &JsonValue| {
+                timeline["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|e| e["content"]["body"].clone())
+                    .collect::<Vec<_>>()
+            };
+            // prev_batch points at the first event that was kept, so paginating back from it
+            // picks up right before the timeline
+            let ordering = db
+                .get_stream_ordering(&room_id, &event_ids[1])
+                .await
+                .unwrap();
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(None, limited)).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+            let timeline = &res["rooms"]["join"][&room_id]["timeline"];
+            assert_eq!(timeline["limited"], true);
+            assert_eq!(bodies(timeline), vec!["2", "3"]);
+            assert_eq!(
+                timeline["prev_batch"],
+                TimelineToken(ordering.unwrap()).to_string()
+            );
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=1",
+                    room_id,
+                    timeline["prev_batch"].as_str().unwrap()
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["chunk"][0]["content"]["body"], "1");
+
+            // the same goes for incremental syncs
+            for body in 4..=6 {
+                let res: JsonValue = test::read_response_json(&mut app, send(body)).await;
+                event_ids.push(res["event_id"].as_str().unwrap().to_owned());
+            }
+            let ordering = db
+                .get_stream_ordering(&room_id, &event_ids[4])
+                .await
+                .unwrap();
+            let res: JsonValue =
+                test::read_response_json(&mut app, sync(Some(&next_batch), limited)).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+            let timeline = &res["rooms"]["join"][&room_id]["timeline"];
+            assert_eq!(timeline["limited"], true);
+            assert_eq!(bodies(timeline), vec!["5", "6"]);
+            assert_eq!(
+                timeline["prev_batch"],
+                TimelineToken(ordering.unwrap()).to_string()
+            );
+
+            // and for filtered timelines, where the events kept aren't next to each other
+            for body in 7..=9 {
+                let req = test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/send/org.example.noise/noise{}",
+                        room_id, body
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({}))
+                    .to_request();
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+                let res: JsonValue = test::read_response_json(&mut app, send(body)).await;
+                event_ids.push(res["event_id"].as_str().unwrap().to_owned());
+            }
+            let ordering = db
+                .get_stream_ordering(&room_id, &event_ids[7])
+                .await
+                .unwrap();
+            // the same limit, only for messages
+            let messages = "%7B%22room%22%3A%7B%22timeline%22%3A%7B%22limit%22%3A2%2C%22types%22%3A%5B%22m.room.message%22%5D%7D%7D%7D";
+            let res: JsonValue =
+                test::read_response_json(&mut app, sync(Some(&next_batch), messages)).await;
+            let timeline = &res["rooms"]["join"][&room_id]["timeline"];
+            assert_eq!(timeline["limited"], true);
+            assert_eq!(bodies(timeline), vec!["8", "9"]);
+            assert_eq!(
+                timeline["prev_batch"],
+                TimelineToken(ordering.unwrap()).to_string()
+            );
+        });
+    }
+
+    #[test]
+    fn unknown_since_token_is_rejected() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let sync = |since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}&timeout=0", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(None)).await;
+            let first = res["next_batch"].as_str().unwrap().to_owned();
+            let mut next_batch = first.clone();
+            for _ in 0..BATCHES_PER_DEVICE {
+                let res: JsonValue =
+                    test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+                next_batch = res["next_batch"].as_str().unwrap().to_owned();
+            }
+
+            // neither an evicted token nor a made up one may turn into an initial sync
+            for since in [first.as_str(), "nonsense"].iter() {
+                let res = test::call_service(&mut app, sync(Some(since))).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            }
+            let res = test::call_service(&mut app, sync(Some(&next_batch))).await;
+            assert!(res.status().is_success());
+        });
+    }
+
+    #[test]
+    fn incremental_sync_includes_state_changes() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let set_name = |name: &str| {
+                NewEvent::builder(MatrixId::new("alice", "example.org").unwrap(), EventContent::Name(room::Name {
+                        name: Some(name.to_string()),
+                    })).state_key("").build()
+            };
+            // a timeline limit of 1, as a percent-encoded inline filter
+            let limited = "%7B%22room%22%3A%7B%22timeline%22%3A%7B%22limit%22%3A1%7D%7D%7D";
+            let sync = |since: Option<&str>, filter: Option<&str>| {
+                let mut uri = String::from("/_matrix/client/r0/sync?timeout=0");
+                if let Some(since) = since {
+                    uri.push_str(&format!("&since={}", since));
+                }
+                if let Some(filter) = filter {
+                    uri.push_str(&format!("&filter={}", filter));
+                }
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let names = |events: &JsonValue| {
+                events["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|e| e["type"] == "m.room.name")
+                    .map(|e| e["content"]["name"].clone())
+                    .collect::<Vec<_>>()
+            };
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(None, None)).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            db.add_event(
+                &room_id,
+                set_name("plans"),
+                &state.state_resolver,
+                &state.keys,
+            )
+            .await
+            .unwrap();
+            let res: JsonValue =
+                test::read_response_json(&mut app, sync(Some(&next_batch), None)).await;
+            let room = &res["rooms"]["join"][&room_id];
+            assert_eq!(names(&room["timeline"]), vec!["plans"]);
+            assert!(names(&room["state"]).is_empty());
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            // the name changes fall outside the timeline, so they have to be sent as state
+            db.add_event(
+                &room_id,
+                set_name("secret plans"),
+                &state.state_resolver,
+                &state.keys,
+            )
+            .await
+            .unwrap();
+            db.add_event(
+                &room_id,
+                set_name("top secret plans"),
+                &state.state_resolver,
+                &state.keys,
+            )
+            .await
+            .unwrap();
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/send/m.room.message/1",
+                    room_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "msgtype": "m.text", "body": "hi" }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let res: JsonValue =
+                test::read_response_json(&mut app, sync(Some(&next_batch), Some(limited))).await;
+            let room = &res["rooms"]["join"][&room_id];
+            assert_eq!(room["timeline"]["limited"], true);
+            assert_eq!(room["timeline"]["events"][0]["content"]["body"], "hi");
+            assert_eq!(names(&room["state"]), vec!["top secret plans"]);
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            // a name change that the filter leaves out of the timeline still has to be sent, even
+            // though it comes after the start of the timeline
+            // {"room":{"timeline":{"types":["m.room.message"]}}}, percent-encoded
+            let messages_only = "%7B%22room%22%3A%7B%22timeline%22%3A%7B%22types%22%3A%5B%22m.room.message%22%5D%7D%7D%7D";
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/send/m.room.message/2",
+                    room_id
+                ))
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "msgtype": "m.text", "body": "bye" }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            db.add_event(
+                &room_id,
+                set_name("no plans"),
+                &state.state_resolver,
+                &state.keys,
+            )
+            .await
+            .unwrap();
+            let res: JsonValue =
+                test::read_response_json(&mut app, sync(Some(&next_batch), Some(messages_only)))
+                    .await;
+            let room = &res["rooms"]["join"][&room_id];
+            assert_eq!(room["timeline"]["limited"], false);
+            assert_eq!(room["timeline"]["events"][0]["content"]["body"], "bye");
+            assert!(names(&room["timeline"]).is_empty());
+            assert_eq!(names(&room["state"]), vec!["no plans"]);
+        });
+    }
+
+    #[test]
+    fn state_event_content_is_checked_for_known_types() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let send_state = |ty: &str, content: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/state/{}/@alice:example.org",
+                        room_id, ty
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&content)
+                    .to_request()
+            };
+
+            let req = send_state("m.room.member", json!({ "membership": "dancing" }));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 400);
+            let res: JsonValue = test::read_body_json(res).await;
+            assert_eq!(res["errcode"], "M_BAD_JSON");
+            let member = db
+                .get_state_event(
+                    &room_id,
+                    "m.room.member",
+                    "@alice:example.org",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(member.event_content.content_as_json()["membership"], "join");
+
+            // nothing is known about custom types, so anything goes
+            let content = json!({ "membership": "dancing" });
+            let req = send_state("org.example.status", content.clone());
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let status = db
+                .get_state_event(
+                    &room_id,
+                    "org.example.status",
+                    "@alice:example.org",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(status.event_content.content_as_json(), content);
+        });
+    }
+
+    #[test]
+    fn canonical_alias_must_point_to_room() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let create_room = |body: JsonValue| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/createRoom")
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&body)
+                    .to_request()
+            };
+            let req = create_room(json!({ "visibility": "private", "room_alias_name": "plans" }));
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let plans = res["room_id"].as_str().unwrap().to_owned();
+            let req = create_room(json!({ "visibility": "private" }));
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let other = res["room_id"].as_str().unwrap().to_owned();
+
+            // creating a room with an alias makes it the room's main address
+            let canonical_alias = db
+                .get_state_event(
+                    &plans,
+                    "m.room.canonical_alias",
+                    "",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                canonical_alias.event_content.content_as_json(),
+                json!({ "alias": "#plans:example.org" })
+            );
+
+            let set_alias = |room_id: &str, content: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias",
+                        room_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&content)
+                    .to_request()
+            };
+            for content in [
+                json!({ "alias": "#plans:example.org" }),
+                json!({ "alias": "#nowhere:example.org" }),
+                json!({ "alt_aliases": ["#plans:example.org"] }),
+            ]
+            .iter()
+            {
+                let res = test::call_service(&mut app, set_alias(&other, content.clone())).await;
+                assert_eq!(res.status(), 400);
+                let res: JsonValue = test::read_body_json(res).await;
+                assert_eq!(res["errcode"], "M_BAD_ALIAS");
+            }
+
+            let req = set_alias(&plans, json!({ "alt_aliases": ["#plans:example.org"] }));
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            // and a room doesn't need a main address at all
+            let req = set_alias(&other, json!({}));
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+        });
+    }
+
+    #[test]
+    fn encryption_state() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let summary = || {
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/summary", room_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, summary()).await;
+            assert!(res.get("encryption").is_none());
+
+            let set_encryption = |content: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/state/m.room.encryption",
+                        room_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&content)
+                    .to_request()
+            };
+            let req = set_encryption(json!({ "algorithm": "org.example.rot13" }));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 400);
+            let req = set_encryption(json!({ "algorithm": "m.megolm.v1.aes-sha2" }));
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/state", room_id))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let encryption = res
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|event| event["type"] == "m.room.encryption")
+                .expect("no m.room.encryption in state");
+            assert_eq!(
+                encryption["content"],
+                json!({ "algorithm": "m.megolm.v1.aes-sha2" })
+            );
+            let res: JsonValue = test::read_response_json(&mut app, summary()).await;
+            assert_eq!(res["encryption"], "m.megolm.v1.aes-sha2");
+
+            // sync's room summaries only have what the spec gives them
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let summary = &res["rooms"]["join"][&room_id]["summary"];
+            assert!(summary
+                .as_object()
+                .unwrap()
+                .keys()
+                .all(|k| k.starts_with("m.")));
+        });
+    }
+
+    #[test]
+    fn direct_invite_state_has_is_direct() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .set_json(&json!({
+                    "visibility": "private",
+                    "invite": ["@bob:example.org"],
+                    "is_direct": true,
+                }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(header::AUTHORIZATION, format!("Bearer {}", bob))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let invite = res["rooms"]["invite"][&room_id]["invite_state"]["events"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["type"] == "m.room.member" && e["state_key"] == "@bob:example.org")
+                .expect("no invite in stripped state")
+                .clone();
+            assert_eq!(invite["sender"], "@alice:example.org");
+            assert_eq!(invite["content"]["membership"], "invite");
+            assert_eq!(invite["content"]["is_direct"], true);
+        });
+    }
+
+    #[test]
+    fn push_rules_in_sync_account_data() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let sync = |since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}&timeout=0", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let push_rules = |res: &JsonValue| {
+                res["account_data"]["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|e| e["type"] == "m.push_rules")
+                    .map(|e| e["content"].clone())
+            };
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(None)).await;
+            let rules = push_rules(&res).expect("no push rules in initial sync");
+            assert_eq!(
+                rules["global"]["content"][0]["rule_id"],
+                ".m.rule.contains_user_name"
+            );
+            assert_eq!(rules["global"]["content"][0]["pattern"], "alice");
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            // the defaults aren't sent again when nothing has changed
+            let res: JsonValue = test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+            assert_eq!(push_rules(&res), None);
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            let custom = json!({
+                "global": { "override": [], "content": [], "room": [], "sender": [], "underride": [] }
+            });
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/user/@alice:example.org/account_data/m.push_rules")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&custom)
+                .to_request();
+            assert!(test::call_service(&mut app, req).await.status().is_success());
+            let res: JsonValue = test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+            assert_eq!(push_rules(&res), Some(custom.clone()));
+            let res: JsonValue = test::read_response_json(&mut app, sync(None)).await;
+            assert_eq!(push_rules(&res), Some(custom));
+        });
+    }
+
+    #[test]
+    fn members_filtered_by_several_memberships() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("carol", "password").await.unwrap();
+            db.create_user("dave", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let carol = db.create_access_token("carol", "phone").await.unwrap();
+            let dave = db.create_access_token("dave", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let post = |token, uri: String, body: JsonValue| {
+                test::TestRequest::post()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .set_json(&body)
+                    .to_request()
+            };
+
+            let req = post(
+                alice,
+                String::from("/_matrix/client/r0/createRoom"),
+                json!({ "visibility": "private" }),
+            );
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let room_uri =
+                |endpoint: &str| format!("/_matrix/client/r0/rooms/{}/{}", room_id, endpoint);
+            for user_id in &[
+                "@bob:example.org",
+                "@carol:example.org",
+                "@dave:example.org",
+            ] {
+                let req = post(alice, room_uri("invite"), json!({ "user_id": user_id }));
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+            }
+            let req = post(
+                carol,
+                format!("/_matrix/client/r0/join/{}", room_id),
+                json!({}),
+            );
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            // dave turns down the invite
+            let req = post(dave, room_uri("leave"), json!({}));
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let get_members = |query: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("{}?{}", room_uri("members"), query))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                    .to_request()
+            };
+            let state_keys = |res: JsonValue| {
+                let mut members = res["chunk"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|e| e["state_key"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                members.sort();
+                members
+            };
+
+            let req = get_members("membership=join&membership=invite");
+            let res = test::read_response_json(&mut app, req).await;
+            assert_eq!(
+                state_keys(res),
+                vec![
+                    "@alice:example.org",
+                    "@bob:example.org",
+                    "@carol:example.org"
+                ]
+            );
+            let req = get_members("membership=invite");
+            let res = test::read_response_json(&mut app, req).await;
+            assert_eq!(state_keys(res), vec!["@bob:example.org"]);
+            let req = get_members("not_membership=join&not_membership=invite");
+            let res = test::read_response_json(&mut app, req).await;
+            assert_eq!(state_keys(res), vec!["@dave:example.org"]);
+            let res = test::read_response_json(&mut app, get_members("")).await;
+            assert_eq!(state_keys(res).len(), 4);
+        });
+    }
+
+    #[test]
+    fn rejected_invite_stops_at_the_leave_event() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let post = |token, uri: String, body: JsonValue| {
+                test::TestRequest::post()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .set_json(&body)
+                    .to_request()
+            };
+            let sync = |since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {}", bob))
+                    .to_request()
+            };
+
+            let req = post(
+                alice,
+                String::from("/_matrix/client/r0/createRoom"),
+                json!({ "visibility": "private", "invite": ["@bob:example.org"] }),
+            );
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let res: JsonValue = test::read_response_json(&mut app, sync(None)).await;
+            assert!(res["rooms"]["invite"][&room_id].is_object());
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            let req = post(
+                bob,
+                format!("/_matrix/client/r0/rooms/{}/leave", room_id),
+                json!({}),
+            );
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/send/m.room.message/1",
+                    room_id
+                ))
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .set_json(&json!({ "msgtype": "m.text", "body": "bob's gone" }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(Some(&next_batch))).await;
+            let timeline = &res["rooms"]["leave"][&room_id]["timeline"];
+            let events = timeline["events"].as_array().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["content"]["membership"], "leave");
+            let leave_ordering = db
+                .get_stream_ordering(&room_id, events[0]["event_id"].as_str().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                timeline["prev_batch"],
+                TimelineToken(leave_ordering).to_string()
+            );
+        });
+    }
+
+    #[test]
+    fn messages_lazy_load_members() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("carol", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let carol = db.create_access_token("carol", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let post = |token, uri: String, body: JsonValue| {
+                test::TestRequest::post()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .set_json(&body)
+                    .to_request()
+            };
+
+            let req = post(
+                alice,
+                String::from("/_matrix/client/r0/createRoom"),
+                json!({ "visibility": "private", "invite": ["@carol:example.org"] }),
+            );
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let room_uri =
+                |endpoint: &str| format!("/_matrix/client/r0/rooms/{}/{}", room_id, endpoint);
+            let req = post(
+                carol,
+                format!("/_matrix/client/r0/join/{}", room_id),
+                json!({}),
+            );
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+            for (token, body) in [(carol, "hi"), (alice, "hello")].iter() {
+                let req = test::TestRequest::put()
+                    .uri(&room_uri(&format!("send/m.room.message/{}", body)))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .set_json(&json!({ "msgtype": "m.text", "body": body }))
+                    .to_request();
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+            }
+
+            // carol's name changes after "hi", which mustn't affect the member event sent with it
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/profile/@carol:example.org/displayname")
+                .header(header::AUTHORIZATION, format!("Bearer {}", carol))
+                .set_json(&json!({ "displayname": "Caroline" }))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            // {"lazy_load_members":true}, percent-encoded
+            let lazy = "%7B%22lazy_load_members%22%3Atrue%7D";
+            let messages = |from: &str, limit: usize, filter: Option<&str>| {
+                let mut uri = room_uri(&format!("messages?from={}&dir=b&limit={}", from, limit));
+                if let Some(filter) = filter {
+                    uri.push_str(&format!("&filter={}", filter));
+                }
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                    .to_request()
+            };
+            let members = |res: &JsonValue| {
+                res["state"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|e| {
+                        assert_eq!(e["type"], "m.room.member");
+                        e["state_key"].as_str().unwrap().to_owned()
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let res: JsonValue =
+                test::read_response_json(&mut app, messages("t1000", 1, Some(lazy))).await;
+            assert_eq!(res["chunk"][0]["type"], "m.room.member");
+            let end = res["end"].as_str().unwrap().to_owned();
+            let res: JsonValue =
+                test::read_response_json(&mut app, messages(&end, 1, Some(lazy))).await;
+            assert_eq!(res["chunk"][0]["content"]["body"], "hello");
+            assert_eq!(members(&res), vec!["@alice:example.org"]);
+            let end = res["end"].as_str().unwrap().to_owned();
+
+            let res: JsonValue =
+                test::read_response_json(&mut app, messages(&end, 1, Some(lazy))).await;
+            assert_eq!(res["chunk"][0]["content"]["body"], "hi");
+            assert_eq!(members(&res), vec!["@carol:example.org"]);
+            assert_ne!(res["state"][0]["content"]["displayname"], "Caroline");
+            let res: JsonValue = test::read_response_json(&mut app, messages(&end, 1, None)).await;
+            assert!(res.get("state").is_none());
+        });
+    }
+
+    #[test]
+    fn heroes_with_the_same_name_are_disambiguated() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut tokens = Vec::new();
+            for username in &["alice", "bob", "carol"] {
+                db.create_user(username, "password").await.unwrap();
+                tokens.push(db.create_access_token(username, "phone").await.unwrap());
+            }
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+            let request = |req: test::TestRequest, token| {
+                req.header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .to_request()
+            };
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .set_json(&json!({
+                    "visibility": "private",
+                    "invite": ["@bob:example.org", "@carol:example.org"],
+                }));
+            let res: JsonValue = test::read_response_json(&mut app, request(req, tokens[0])).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            for (username, token) in [("bob", tokens[1]), ("carol", tokens[2])].iter() {
+                let req = test::TestRequest::post()
+                    .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                    .set_json(&json!({}));
+                assert!(test::call_service(&mut app, request(req, *token))
+                    .await
+                    .status()
+                    .is_success());
+                let req = test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/profile/@{}:example.org/displayname",
+                        username
+                    ))
+                    .set_json(&json!({ "displayname": "Sam" }));
+                assert!(test::call_service(&mut app, request(req, *token))
+                    .await
+                    .status()
+                    .is_success());
+            }
+
+            let room_uri =
+                |endpoint: &str| format!("/_matrix/client/r0/rooms/{}/{}", room_id, endpoint);
+            let req = test::TestRequest::get().uri(&room_uri("summary"));
+            let res: JsonValue = test::read_response_json(&mut app, request(req, tokens[0])).await;
+            assert_eq!(
+                res["heroes"],
+                json!(["Sam (@bob:example.org)", "Sam (@carol:example.org)"])
+            );
+
+            let req = test::TestRequest::get().uri(&room_uri("joined_members"));
+            let res: JsonValue = test::read_response_json(&mut app, request(req, tokens[1])).await;
+            let joined = &res["joined"];
+            assert_eq!(
+                joined["@carol:example.org"]["display_name"],
+                "Sam (@carol:example.org)"
+            );
+            assert!(joined["@alice:example.org"].get("display_name").is_none());
+        });
+    }
+
+    #[test]
+    fn only_admins_see_unredacted_events() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            db.set_admin("alice", true).await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let alice = (header::AUTHORIZATION, format!("Bearer {}", alice));
+            let bob = (header::AUTHORIZATION, format!("Bearer {}", bob));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(bob.0.clone(), bob.1.clone())
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header(alice.0.clone(), alice.1.clone())
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/send/m.room.message/1",
+                    room_id
+                ))
+                .header(bob.0.clone(), bob.1.clone())
+                .set_json(&json!({ "msgtype": "m.text", "body": "something rude" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let event_id = res["event_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::put()
+                .uri(&format!(
+                    "/_matrix/client/r0/rooms/{}/redact/{}/2",
+                    room_id, event_id
+                ))
+                .header(bob.0.clone(), bob.1.clone())
+                .set_json(&json!({}))
+                .to_request();
+            assert!(test::call_service(&mut app, req)
+                .await
+                .status()
+                .is_success());
+
+            let get_event = |auth: &(header::HeaderName, String)| {
+                test::TestRequest::get()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/event/{}?include_unredacted=true",
+                        room_id, event_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, get_event(&alice)).await;
+            assert_eq!(res["content"]["body"], "something rude");
+            let res: JsonValue = test::read_response_json(&mut app, get_event(&bob)).await;
+            assert_eq!(res["content"], json!({}));
+        });
+    }
+
+    #[test]
+    fn annotations_are_counted_once_per_user() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = Vec::new();
+            for user in ["alice", "bob", "carol"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.push((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            let (alice, bob, carol) = (&auth[0], &auth[1], &auth[2]);
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(alice.0.clone(), alice.1.clone())
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            for auth in [bob, carol].iter() {
+                let req = test::TestRequest::post()
+                    .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .to_request();
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+            }
+
+            let mut txn_id = 0;
+            let mut send = |auth: &(header::HeaderName, String), ty: &str, content: JsonValue| {
+                txn_id += 1;
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/send/{}/{}",
+                        room_id, ty, txn_id
+                    ))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&content)
+                    .to_request()
+            };
+            let mut event_ids = Vec::new();
+            for body in ["lunch?", "dinner?"].iter() {
+                let req = send(
+                    alice,
+                    "m.room.message",
+                    json!({ "msgtype": "m.text", "body": body }),
+                );
+                let res: JsonValue = test::read_response_json(&mut app, req).await;
+                event_ids.push(res["event_id"].as_str().unwrap().to_owned());
+            }
+            let react = |event_id: &str, key: &str| {
+                json!({
+                    "m.relates_to": { "rel_type": "m.annotation", "event_id": event_id, "key": key },
+                })
+            };
+            for (auth, event_id, key) in [
+                (alice, &event_ids[0], "👍"),
+                (bob, &event_ids[0], "👍"),
+                // bob's second thumbs up doesn't count
+                (bob, &event_ids[0], "👍"),
+                (bob, &event_ids[0], "🍕"),
+                (carol, &event_ids[0], "🍕"),
+                (carol, &event_ids[0], "👍"),
+                (carol, &event_ids[1], "👎"),
+            ]
+            .iter()
+            {
+                let req = send(auth, "m.reaction", react(event_id, key));
+                assert!(test::call_service(&mut app, req)
+                    .await
+                    .status()
+                    .is_success());
+            }
+
+            let annotations = |query: &str| {
+                test::TestRequest::get()
+                    .uri(&format!(
+                        "/_matrix/client/r0/rooms/{}/relations/{}/m.annotation{}",
+                        room_id, event_ids[0], query
+                    ))
+                    .header(alice.0.clone(), alice.1.clone())
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, annotations("")).await;
+            assert_eq!(
+                res,
+                json!({
+                    "chunk": [
+                        { "type": "m.reaction", "key": "👍", "count": 3 },
+                        { "type": "m.reaction", "key": "🍕", "count": 2 },
+                    ],
+                })
+            );
+
+            let res: JsonValue = test::read_response_json(&mut app, annotations("?limit=1")).await;
+            assert_eq!(res["chunk"].as_array().unwrap().len(), 1);
+            assert_eq!(res["chunk"][0]["key"], "👍");
+            let query = format!("?limit=1&from={}", res["next_batch"].as_str().unwrap());
+            let res: JsonValue = test::read_response_json(&mut app, annotations(&query)).await;
+            assert_eq!(res["chunk"][0]["key"], "🍕");
+            assert!(res.get("next_batch").is_none());
+        });
+    }
+}
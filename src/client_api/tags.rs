@@ -0,0 +1,203 @@
+use actix_web::{
+    delete, get, put,
+    web::{Data, Json, Path},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::Storage,
+    util::MatrixId,
+    ServerState,
+};
+
+/// The content of the m.tag room account data event, which is where a user's tags for a room
+/// are kept.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Tags {
+    #[serde(default)]
+    tags: BTreeMap<String, Tag>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tag {
+    /// Where the room goes among others with the same tag, from 0 to 1.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<f64>,
+    /// Anything else that the client put in the tag, which is kept as it is.
+    #[serde(flatten)]
+    extra: Map<String, JsonValue>,
+}
+
+async fn load_tags(db: &dyn Storage, username: &str, room_id: &str) -> Result<Tags, Error> {
+    match db
+        .get_room_account_data(username, room_id)
+        .await?
+        .remove("m.tag")
+    {
+        Some(content) => Ok(serde_json::from_value(content)?),
+        None => Ok(Tags::default()),
+    }
+}
+
+#[get("/user/{user_id}/rooms/{room_id}/tags")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_tags(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, room_id)): Path<(MatrixId, String)>,
+) -> Result<Json<Tags>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    Ok(Json(load_tags(&*db, &username, &room_id).await?))
+}
+
+#[put("/user/{user_id}/rooms/{room_id}/tags/{tag}")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_tag(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, room_id, tag)): Path<(MatrixId, String, String)>,
+    body: Json<Tag>,
+) -> Result<Json<()>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    let mut tags = load_tags(&*db, &username, &room_id).await?;
+    tags.tags.insert(tag, body.into_inner());
+    db.set_room_account_data(&username, &room_id, "m.tag", serde_json::to_value(tags)?)
+        .await?;
+    Ok(Json(()))
+}
+
+#[delete("/user/{user_id}/rooms/{room_id}/tags/{tag}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn delete_tag(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, room_id, tag)): Path<(MatrixId, String, String)>,
+) -> Result<Json<()>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    let mut tags = load_tags(&*db, &username, &room_id).await?;
+    // deleting a tag that isn't there is fine, and doesn't need to tell anyone
+    if tags.tags.remove(&tag).is_some() {
+        db.set_room_account_data(&username, &room_id, "m.tag", serde_json::to_value(tags)?)
+            .await?;
+    }
+    Ok(Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn tags_round_trip_and_reach_sync() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({}))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let tags_uri = format!(
+                "/_matrix/client/r0/user/@alice:example.org/rooms/{}/tags",
+                room_id
+            );
+
+            let put = |tag: &str, body: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!("{}/{}", tags_uri, tag))
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&body)
+                    .to_request()
+            };
+            let res =
+                test::call_service(&mut app, put("m.favourite", json!({ "order": 0.25 }))).await;
+            assert!(res.status().is_success());
+            let res = test::call_service(&mut app, put("m.lowpriority", json!({}))).await;
+            assert!(res.status().is_success());
+            let res =
+                test::call_service(&mut app, put("u.work", json!({ "order": "first" }))).await;
+            assert_eq!(res.status(), 400);
+            let req = test::TestRequest::delete()
+                .uri(&format!("{}/m.lowpriority", tags_uri))
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let expected = json!({ "tags": { "m.favourite": { "order": 0.25 } } });
+            let req = test::TestRequest::get()
+                .uri(&tags_uri)
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res, expected);
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(auth.0.clone(), auth.1.clone())
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let account_data = &res["rooms"]["join"][&room_id]["account_data"]["events"];
+            let tag_event = account_data
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["type"] == "m.tag")
+                .unwrap();
+            assert_eq!(tag_event["content"], expected);
+        });
+    }
+}
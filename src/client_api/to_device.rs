@@ -0,0 +1,203 @@
+use actix_web::{
+    put,
+    web::{Data, Json, Path},
+};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{field::Empty, instrument, Level, Span};
+
+use crate::{
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::ToDeviceMessage,
+    util::MatrixId,
+    ServerState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SendToDeviceRequest {
+    /// user_id -> device_id, or "*" for all of the user's devices -> content
+    messages: HashMap<MatrixId, HashMap<String, JsonValue>>,
+}
+
+#[put("/sendToDevice/{event_type}/{txn_id}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn send_to_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((event_type, txn_id)): Path<(String, String)>,
+    req: Json<SendToDeviceRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let (username, device_id) = db
+        .try_auth_full(token.0)
+        .await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    // these are separate from the transaction ids for room events
+    if !db
+        .record_txn(&username, &device_id, "to_device", txn_id)
+        .await?
+    {
+        return Err(ErrorKind::TxnIdExists.into());
+    }
+    let sender = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    for (user_id, messages) in req.into_inner().messages {
+        //TODO: send to other servers once there's federation
+        if user_id.domain() != state.config.domain {
+            continue;
+        }
+        let devices = db.get_devices(user_id.localpart()).await?;
+        for (target, content) in messages {
+            // messages for devices that don't exist would never be picked up
+            let targets = devices
+                .iter()
+                .map(|device| device.device_id.as_str())
+                .filter(|device_id| target == "*" || target == *device_id);
+            for device_id in targets {
+                let message = ToDeviceMessage {
+                    sender: sender.clone(),
+                    ty: event_type.clone(),
+                    content: content.clone(),
+                };
+                db.send_to_device(user_id.localpart(), device_id, message)
+                    .await?;
+            }
+        }
+    }
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::Service, http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+    use std::time::Duration;
+
+    use crate::{
+        client_api::tests::server_state,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn messages_are_kept_until_acknowledged() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let phone = db.create_access_token("alice", "phone").await.unwrap();
+            let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+            let bob = db.create_access_token("bob", "desktop").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let send = |txn_id: &str, messages: JsonValue| {
+                test::TestRequest::put()
+                    .uri(&format!(
+                        "/_matrix/client/r0/sendToDevice/m.room_key_request/{}",
+                        txn_id
+                    ))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", bob))
+                    .set_json(&json!({ "messages": messages }))
+                    .to_request()
+            };
+            let everywhere = json!({ "@alice:example.org": { "*": { "n": 1 } } });
+            let res = test::call_service(&mut app, send("1", everywhere.clone())).await;
+            assert!(res.status().is_success());
+            let res = test::call_service(&mut app, send("1", everywhere)).await;
+            assert_eq!(res.status(), 400);
+            let phone_only = json!({ "@alice:example.org": { "phone": { "n": 2 } } });
+            let res = test::call_service(&mut app, send("2", phone_only)).await;
+            assert!(res.status().is_success());
+
+            let sync = |token, since: Option<&str>| {
+                let query = since.map(|s| format!("?since={}", s)).unwrap_or_default();
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/sync{}", query))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .to_request()
+            };
+            let res: JsonValue = test::read_response_json(&mut app, sync(phone, None)).await;
+            let events = &res["to_device"]["events"];
+            assert_eq!(events.as_array().unwrap().len(), 2);
+            assert_eq!(events[0]["sender"], "@bob:example.org");
+            assert_eq!(events[0]["type"], "m.room_key_request");
+            assert_eq!(events[0]["content"], json!({ "n": 1 }));
+            assert_eq!(events[1]["content"], json!({ "n": 2 }));
+            let first_batch = res["next_batch"].as_str().unwrap().to_string();
+
+            // a retried sync gets the messages again, but syncing past them acknowledges them
+            let res: JsonValue = test::read_response_json(&mut app, sync(phone, None)).await;
+            assert_eq!(res["to_device"]["events"].as_array().unwrap().len(), 2);
+            let req = sync(phone, Some(&first_batch));
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["to_device"]["events"], json!([]));
+
+            let res: JsonValue = test::read_response_json(&mut app, sync(laptop, None)).await;
+            let events = &res["to_device"]["events"];
+            assert_eq!(events.as_array().unwrap().len(), 1);
+            assert_eq!(events[0]["content"], json!({ "n": 1 }));
+        });
+    }
+
+    #[test]
+    fn message_wakes_sync() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "desktop").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let next_batch = res["next_batch"].as_str().unwrap().to_owned();
+
+            // alice isn't in any rooms, so only the message can end this sync early
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/_matrix/client/r0/sync?since={}&timeout=5000",
+                    next_batch
+                ))
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .to_request();
+            let sync_res = app.call(req);
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/sendToDevice/m.room_key_request/1")
+                .header(header::AUTHORIZATION, format!("Bearer {}", bob))
+                .set_json(&json!({
+                    "messages": { "@alice:example.org": { "phone": { "n": 1 } } }
+                }))
+                .to_request();
+            let send_res = app.call(req);
+            let (sync_res, send_res) = futures::join!(
+                tokio::time::timeout(Duration::from_secs(4), sync_res),
+                async {
+                    actix_web::rt::time::delay_for(Duration::from_millis(50)).await;
+                    send_res.await
+                }
+            );
+            assert!(send_res.unwrap().status().is_success());
+            let sync_res = sync_res.expect("sync wasn't woken").unwrap();
+            let res: JsonValue = test::read_body_json(sync_res).await;
+            let events = &res["to_device"]["events"];
+            assert_eq!(events.as_array().unwrap().len(), 1);
+            assert_eq!(events[0]["content"], json!({ "n": 1 }));
+        });
+    }
+}
@@ -4,14 +4,15 @@ use actix_web::{
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use tracing::{field::Empty, instrument, Level, Span};
 
 use crate::{
     client_api::auth::AccessToken,
     error::{Error, ErrorKind},
-    storage::UserProfile,
-    util::MatrixId,
+    events::{room::Membership, EventContent},
+    storage::{Storage, UserProfile},
+    util::{storage::NewEvent, MatrixId, StorageExt},
     ServerState,
 };
 
@@ -29,7 +30,7 @@ pub async fn get_avatar_url(
     let avatar_url = match db
         .get_profile(&user_id.localpart())
         .await?
-        .unwrap()
+        .ok_or(ErrorKind::NotFound)?
         .avatar_url
     {
         Some(v) => v,
@@ -65,7 +66,9 @@ pub async fn set_avatar_url(
         .ok_or(ErrorKind::BadJson(String::from(
             "avatar_url should be a string",
         )))?;
-    db.set_avatar_url(&username, avatar_url).await?;
+    if db.set_avatar_url(&username, avatar_url).await? {
+        propagate_profile(&state, &*db, &req_id).await?;
+    }
     Ok(Json(()))
 }
 
@@ -83,7 +86,7 @@ pub async fn get_display_name(
     let displayname = match db
         .get_profile(&user_id.localpart())
         .await?
-        .unwrap()
+        .ok_or(ErrorKind::NotFound)?
         .displayname
     {
         Some(v) => v,
@@ -119,10 +122,49 @@ pub async fn set_display_name(
         .ok_or(ErrorKind::BadJson(String::from(
             "displayname should be a string",
         )))?;
-    db.set_display_name(&username, &display_name).await?;
+    if db.set_display_name(&username, display_name).await? {
+        propagate_profile(&state, &*db, &req_id).await?;
+    }
     Ok(Json(()))
 }
 
+/// Sends a new m.room.member event to every room that the user has joined, so that everyone else
+/// sees their new profile.
+async fn propagate_profile(
+    state: &ServerState,
+    db: &dyn Storage,
+    user_id: &MatrixId,
+) -> Result<(), Error> {
+    let profile = db
+        .get_profile(user_id.localpart())
+        .await?
+        .ok_or(ErrorKind::UserNotFound)?;
+    for room_id in db.get_joined_rooms_for_user(user_id).await? {
+        let event = db
+            .get_state_event(
+                &room_id,
+                "m.room.member",
+                user_id.as_str(),
+                Some(&state.state_resolver),
+            )
+            .await?;
+        let mut member = match event.map(|e| e.event_content) {
+            Some(EventContent::Member(member)) if member.membership == Membership::Join => member,
+            _ => continue,
+        };
+        member.displayname = profile.displayname.clone();
+        member.avatar_url = profile.avatar_url.clone();
+        member.reason = None;
+        member.third_party_invite = None;
+        let event = NewEvent::builder(user_id.clone(), EventContent::Member(member))
+            .state_key(user_id.as_str())
+            .build();
+        db.add_event(&room_id, event, &state.state_resolver, &state.keys)
+            .await?;
+    }
+    Ok(())
+}
+
 #[get("/profile/{user_id}")]
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn get_profile(
@@ -137,7 +179,10 @@ pub async fn get_profile(
     let UserProfile {
         avatar_url,
         displayname,
-    } = db.get_profile(&user_id.localpart()).await?.unwrap();
+    } = db
+        .get_profile(&user_id.localpart())
+        .await?
+        .ok_or(ErrorKind::NotFound)?;
     let mut response = serde_json::Map::new();
     if let Some(v) = avatar_url {
         response.insert("avatar_url".into(), v.into());
@@ -152,8 +197,12 @@ pub async fn get_profile(
 #[derive(Deserialize)]
 pub struct UserDirSearchRequest {
     search_term: String,
-    #[serde(default)]
-    limit: Option<usize>,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    10
 }
 
 #[derive(Serialize)]
@@ -171,32 +220,56 @@ struct User {
     display_name: Option<String>,
 }
 
-//TODO: actually implement this
 #[post("/user_directory/search")]
-#[instrument(skip_all, err = Level::DEBUG)]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
 pub async fn search_user_directory(
     state: Data<Arc<ServerState>>,
+    token: AccessToken,
     req: Json<UserDirSearchRequest>,
 ) -> Result<Json<UserDirSearchResponse>, Error> {
-    let req = req.into_inner();
     let db = state.db_pool.get_handle().await?;
-    let searched_user = MatrixId::new(&req.search_term, &state.config.domain)
-        .map_err(|e| ErrorKind::Unknown(e.to_string()))?;
-    let user_profile = db.get_profile(searched_user.localpart()).await?;
-    match user_profile {
-        Some(p) => Ok(Json(UserDirSearchResponse {
-            results: vec![User {
-                user_id: searched_user,
-                avatar_url: p.avatar_url,
-                display_name: p.displayname,
-            }],
-            limited: false,
-        })),
-        None => Ok(Json(UserDirSearchResponse {
-            results: Vec::new(),
-            limited: false,
-        })),
-    }
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+    let req = req.into_inner();
+
+    // one more than was asked for, to tell whether the results were cut short
+    let wanted = req.limit.saturating_add(1);
+    let mut users = if state.config.user_directory_search_all_users {
+        db.search_users(&req.search_term, Some(wanted)).await?
+    } else {
+        // users can only be found if they are joined to a public room or to one of the
+        // searcher's rooms
+        let mut visible_rooms = db
+            .get_public_rooms()
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        visible_rooms.extend(db.get_joined_rooms_for_user(&user_id).await?);
+        let mut users = Vec::new();
+        for (username, profile) in db.search_users(&req.search_term, None).await? {
+            if users.len() == wanted {
+                break;
+            }
+            let candidate = MatrixId::new(&username, &state.config.domain).unwrap();
+            let rooms = db.get_joined_rooms_for_user(&candidate).await?;
+            if rooms.iter().any(|room_id| visible_rooms.contains(room_id)) {
+                users.push((username, profile));
+            }
+        }
+        users
+    };
+    let limited = users.len() > req.limit;
+    users.truncate(req.limit);
+    let results = users
+        .into_iter()
+        .map(|(username, profile)| User {
+            user_id: MatrixId::new(&username, &state.config.domain).unwrap(),
+            avatar_url: profile.avatar_url,
+            display_name: profile.displayname,
+        })
+        .collect();
+    Ok(Json(UserDirSearchResponse { results, limited }))
 }
 
 #[derive(Serialize)]
@@ -230,3 +303,209 @@ pub async fn get_3pids(
         threepids: Vec::new(),
     }))
 }
+
+#[put("/user/{user_id}/account_data/{type}")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, event_type)): Path<(MatrixId, String)>,
+    body: Json<JsonValue>,
+) -> Result<Json<()>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    db.set_user_account_data(&username, &event_type, body.into_inner())
+        .await?;
+    Ok(Json(()))
+}
+
+#[put("/user/{user_id}/rooms/{room_id}/account_data/{type}")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_room_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((req_id, room_id, event_type)): Path<(MatrixId, String, String)>,
+    body: Json<JsonValue>,
+) -> Result<Json<()>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if req_id.localpart() != username {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if req_id.domain() != state.config.domain {
+        return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
+    }
+
+    db.set_room_account_data(&username, &room_id, &event_type, body.into_inner())
+        .await?;
+    Ok(Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        client_api::tests::server_state,
+        events::EventContent,
+        storage::{mem::MemStorageManager, StorageManager},
+    };
+
+    #[test]
+    fn missing_and_empty_profiles() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let get = |uri: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0{}", uri))
+                    .to_request()
+            };
+            let res: JsonValue =
+                test::read_response_json(&mut app, get("/profile/@alice:example.org")).await;
+            assert_eq!(res, json!({}));
+
+            for uri in &[
+                "/profile/@nobody:example.org",
+                "/profile/@nobody:example.org/displayname",
+                "/profile/@nobody:example.org/avatar_url",
+            ] {
+                let res = test::call_service(&mut app, get(uri)).await;
+                assert_eq!(res.status(), 404);
+                let res: JsonValue = test::read_body_json(res).await;
+                assert_eq!(res["errcode"], "M_NOT_FOUND");
+            }
+        });
+    }
+
+    #[test]
+    fn search_by_display_name() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            for username in &["alice", "bob", "carol"] {
+                db.create_user(username, "password").await.unwrap();
+            }
+            db.set_display_name("bob", "Bobby Tables").await.unwrap();
+            db.set_display_name("carol", "Bobbie").await.unwrap();
+            let alice = db.create_access_token("alice", "phone").await.unwrap();
+            let bob = db.create_access_token("bob", "phone").await.unwrap();
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            // bob is in a public room, but carol doesn't share a room with anyone
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(header::AUTHORIZATION, format!("Bearer {}", bob))
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/user_directory/search")
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .set_json(&json!({ "search_term": "BOBB" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(
+                res,
+                json!({
+                    "results": [{ "user_id": "@bob:example.org", "display_name": "Bobby Tables" }],
+                    "limited": false,
+                })
+            );
+
+            // the limit is checked by asking for one more result, which mustn't overflow
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/user_directory/search")
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .set_json(&json!({ "search_term": "BOBB", "limit": usize::MAX }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["results"].as_array().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn display_name_reaches_joined_rooms_once() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let auth = (header::AUTHORIZATION, format!("Bearer {}", token));
+            let state = server_state(db_pool).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                web::scope("/_matrix/client").configure(crate::client_api::configure_endpoints),
+            ))
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(auth.0.clone(), auth.1.clone())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+            let set_name = || {
+                test::TestRequest::put()
+                    .uri("/_matrix/client/r0/profile/@alice:example.org/displayname")
+                    .header(auth.0.clone(), auth.1.clone())
+                    .set_json(&json!({ "displayname": "Alice" }))
+                    .to_request()
+            };
+
+            assert!(test::call_service(&mut app, set_name())
+                .await
+                .status()
+                .is_success());
+            let member = db
+                .get_state_event(
+                    &room_id,
+                    "m.room.member",
+                    "@alice:example.org",
+                    Some(&state.state_resolver),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            match member.event_content {
+                EventContent::Member(member) => {
+                    assert_eq!(member.displayname.as_deref(), Some("Alice"))
+                }
+                _ => panic!("not a member event"),
+            }
+
+            // setting the same name again doesn't send another member event
+            let (_, depth) = db.get_prev_events(&room_id).await.unwrap();
+            assert!(test::call_service(&mut app, set_name())
+                .await
+                .status()
+                .is_success());
+            assert_eq!(db.get_prev_events(&room_id).await.unwrap().1, depth);
+        });
+    }
+}
@@ -8,7 +8,7 @@ use displaydoc::Display;
 use serde_json::{json, Error as JsonError};
 use tracing_error::SpanTrace;
 
-use crate::util::storage::AddEventError;
+use crate::util::{storage::AddEventError, IdError};
 
 // All-seeing all-knowing error type
 #[derive(Debug)]
@@ -27,6 +27,12 @@ impl<T: Into<ErrorKind>> From<T> for Error {
     }
 }
 
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.inner
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}\n{}", self.inner, self.spantrace)
@@ -41,6 +47,8 @@ pub enum ErrorKind {
     UnknownToken,
     /// No access token was specified for the request.
     MissingToken,
+    /// The request's signature could not be verified.
+    Unauthorized,
     /// Request contained valid JSON, but it was malformed in some way, e.g. missing required keys,
     /// invalid values for keys: {0}
     BadJson(String),
@@ -54,6 +62,12 @@ pub enum ErrorKind {
     RoomNotFound,
     /// That username is already taken.
     UsernameTaken,
+    /// That username is not valid: {0}
+    InvalidUsername(String),
+    /// That room alias is already taken.
+    RoomInUse,
+    /// An alias in the event doesn't point to the room that it was sent to.
+    BadAlias,
     /// Too many requests have been sent in a short period of time.
     LimitExceeded,
     /// A required URL parameter was missing from the request: {0}
@@ -77,6 +91,8 @@ pub enum ErrorKind {
     PasswordError(argon2::Error),
     /// The requested feature is unimplemented.
     Unimplemented,
+    /// The server doesn't know how to handle this request: {0}
+    Unrecognized(String),
     /// An invalid event was sent to a room: {0}
     AddEventError(AddEventError),
     /// An unknown error occurred: {0}
@@ -88,16 +104,21 @@ impl ResponseError for Error {
         use ErrorKind::*;
         match self.inner {
             Forbidden | UnknownToken | MissingToken | UsernameTaken => StatusCode::FORBIDDEN,
+            Unauthorized => StatusCode::UNAUTHORIZED,
             NotFound | UserNotFound | RoomNotFound => StatusCode::NOT_FOUND,
             BadJson(_)
             | NotJson(_)
+            | InvalidUsername(_)
             | MissingParam(_)
             | InvalidParam(_)
             | UnsupportedRoomVersion
+            | BadAlias
             | UrlNotUtf8(_)
             | PasswordError(_)
             | Unknown(_)
+            | Unrecognized(_)
             | TxnIdExists => StatusCode::BAD_REQUEST,
+            RoomInUse => StatusCode::CONFLICT,
             LimitExceeded => StatusCode::TOO_MANY_REQUESTS,
             AddEventError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             #[cfg(feature = "storage-sled")]
@@ -111,14 +132,19 @@ impl ResponseError for Error {
             Forbidden => "M_FORBIDDEN",
             UnknownToken => "M_UNKNOWN_TOKEN",
             MissingToken => "M_MISSING_TOKEN",
+            Unauthorized => "M_UNAUTHORIZED",
             BadJson(_) => "M_BAD_JSON",
             NotJson(_) => "M_NOT_JSON",
             NotFound | UserNotFound | RoomNotFound => "M_NOT_FOUND",
             UsernameTaken => "M_USER_IN_USE",
+            InvalidUsername(_) => "M_INVALID_USERNAME",
+            RoomInUse => "M_ROOM_IN_USE",
+            BadAlias => "M_BAD_ALIAS",
             LimitExceeded => "M_LIMIT_EXCEEDED",
             MissingParam(_) => "M_MISSING_PARAM",
             InvalidParam(_) => "M_INVALID_PARAM",
             UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            Unrecognized(_) => "M_UNRECOGNIZED",
             TxnIdExists | UrlNotUtf8(_) | PasswordError(_) | Unimplemented | AddEventError(_)
             | Unknown(_) => "M_UNKNOWN",
             #[cfg(feature = "storage-sled")]
@@ -172,6 +198,12 @@ impl From<argon2::Error> for ErrorKind {
     }
 }
 
+impl From<IdError> for ErrorKind {
+    fn from(e: IdError) -> Self {
+        ErrorKind::InvalidParam(format!("{}", e))
+    }
+}
+
 impl From<AddEventError> for ErrorKind {
     fn from(e: AddEventError) -> Self {
         ErrorKind::AddEventError(e)
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use crate::util::MatrixId;
 
@@ -8,3 +8,55 @@ use crate::util::MatrixId;
 pub struct Typing {
     pub user_ids: HashSet<MatrixId>,
 }
+
+/// `m.receipt`
+///
+/// event_id -> receipt type -> user_id -> receipt
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Receipts(pub HashMap<String, HashMap<String, HashMap<MatrixId, Receipt>>>);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Receipt {
+    pub ts: i64,
+}
+
+impl Receipts {
+    /// Sets a user's receipt of the given type, replacing the one they had before.
+    pub fn set(&mut self, event_id: &str, receipt_type: &str, user_id: &MatrixId, ts: i64) {
+        for receipts in self.0.values_mut() {
+            if let Some(users) = receipts.get_mut(receipt_type) {
+                users.remove(user_id);
+            }
+        }
+        self.0
+            .entry(event_id.to_string())
+            .or_default()
+            .entry(receipt_type.to_string())
+            .or_default()
+            .insert(user_id.clone(), Receipt { ts });
+    }
+
+    /// Returns the event that the user's receipt of the given type is on, and when it was sent.
+    pub fn get(&self, receipt_type: &str, user_id: &MatrixId) -> Option<(&str, i64)> {
+        self.0.iter().find_map(|(event_id, receipts)| {
+            let receipt = receipts.get(receipt_type)?.get(user_id)?;
+            Some((event_id.as_str(), receipt.ts))
+        })
+    }
+
+    /// Copies a user's receipts from another set of receipts into this one.
+    pub fn merge_user(&mut self, other: &Receipts, user_id: &MatrixId) {
+        for (event_id, receipts) in other.0.iter() {
+            for (receipt_type, users) in receipts.iter() {
+                if let Some(receipt) = users.get(user_id) {
+                    self.0
+                        .entry(event_id.clone())
+                        .or_default()
+                        .entry(receipt_type.clone())
+                        .or_default()
+                        .insert(user_id.clone(), receipt.clone());
+                }
+            }
+        }
+    }
+}
@@ -66,6 +66,7 @@ macro_rules! define_event_content {
 
         impl EventContent {
             pub fn new(ty: &str, content: JsonValue) -> Result<Self, serde_json::Error> {
+                check_required_fields(ty, &content)?;
                 match ty {
                     $(
                     $ty => Ok(EventContent::$variant_name(serde_json::from_value(content)?)),
@@ -161,6 +162,16 @@ macro_rules! define_event_content {
     };
 }
 
+/// Catches the mistakes that clients make most often, which serde would only describe vaguely.
+fn check_required_fields(ty: &str, content: &JsonValue) -> Result<(), serde_json::Error> {
+    if ty == "m.room.member" && content.get("membership").is_none() {
+        return Err(serde_json::Error::custom(
+            "m.room.member requires membership",
+        ));
+    }
+    Ok(())
+}
+
 define_event_content! {
     #[derive(Clone, Debug)]
     pub enum EventContent {
@@ -176,12 +187,28 @@ define_event_content! {
         Name(room::Name),
         #[ty = "m.room.topic"]
         Topic(room::Topic),
+        #[ty = "m.room.avatar"]
+        Avatar(room::Avatar),
+        #[ty = "m.room.aliases"]
+        Aliases(room::Aliases),
+        #[ty = "m.room.canonical_alias"]
+        CanonicalAlias(room::CanonicalAlias),
+        #[ty = "m.room.encryption"]
+        Encryption(room::Encryption),
+        #[ty = "m.room.tombstone"]
+        Tombstone(room::Tombstone),
         #[ty = "m.room.power_levels"]
         PowerLevels(room::PowerLevels),
         #[ty = "m.room.member"]
         Member(room::Member),
         #[ty = "m.room.redaction"]
         Redaction(room::Redaction),
+        #[ty = "m.room.third_party_invite"]
+        ThirdPartyInvite(room::ThirdPartyInvite),
+        #[ty = "m.room.pinned_events"]
+        PinnedEvents(room::PinnedEvents),
+        #[ty = "m.room.message"]
+        Message(room::Message),
 
         Unknown {
             ty: String,
@@ -195,6 +222,9 @@ pub struct Event {
     #[serde(flatten)]
     pub event_content: EventContent,
     pub sender: MatrixId,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
     /// Sometimes this is present outside this struct, in which case None is used
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -9,6 +9,11 @@ use super::{room_version::VersionedPdu, Event};
 pub struct StoredPdu {
     pub inner: VersionedPdu,
     pub auth_status: AuthStatus,
+    /// Whether the event passed auth against the state before it, but not against the room's
+    /// current state when it arrived. Soft failed events stay in the room's graph, but aren't
+    /// shown to clients.
+    #[serde(default)]
+    pub soft_failed: bool,
 }
 
 impl StoredPdu {
@@ -72,6 +77,7 @@ impl StoredPdu {
         StoredPdu {
             inner: self.inner.redact(),
             auth_status: self.auth_status,
+            soft_failed: self.soft_failed,
         }
     }
 
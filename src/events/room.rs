@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 
-use crate::util::MatrixId;
+use crate::util::{MatrixId, RoomAlias};
 
 use super::Redactable;
 
@@ -131,17 +131,150 @@ impl Redactable for Topic {
     }
 }
 
+/// m.room.aliases, which room version 6 deprecated in favour of m.room.canonical_alias
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Aliases {
+    /// expected to only be None when redacted in a version 6 room
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aliases: Option<Vec<String>>,
+}
+
+impl Redactable for Aliases {
+    // the aliases are kept up to room version 5; version 6 removes them in PduV4::redact_v6
+    fn redact(self) -> Self {
+        self
+    }
+}
+
+/// m.room.avatar
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Avatar {
+    /// expected to only be None when redacted, or when the room's avatar has been removed
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// metadata about the image, such as its size and mimetype
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<JsonValue>,
+}
+
+impl Redactable for Avatar {
+    fn redact(self) -> Self {
+        Avatar {
+            url: None,
+            info: None,
+        }
+    }
+}
+
+/// m.room.pinned_events
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PinnedEvents {
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<Vec<String>>,
+}
+
+impl Redactable for PinnedEvents {
+    fn redact(self) -> Self {
+        PinnedEvents { pinned: None }
+    }
+}
+
+/// m.room.canonical_alias
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CanonicalAlias {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<RoomAlias>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alt_aliases: Vec<RoomAlias>,
+}
+
+impl Redactable for CanonicalAlias {
+    fn redact(self) -> Self {
+        CanonicalAlias {
+            alias: None,
+            alt_aliases: Vec::new(),
+        }
+    }
+}
+
+/// m.room.tombstone, which says that the room has been replaced by another one
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tombstone {
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replacement_room: Option<String>,
+}
+
+impl Redactable for Tombstone {
+    fn redact(self) -> Self {
+        Tombstone {
+            body: None,
+            replacement_room: None,
+        }
+    }
+}
+
+/// m.room.encryption
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Encryption {
+    pub algorithm: EncryptionAlgorithm,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation_period_ms: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation_period_msgs: Option<u64>,
+}
+
+/// Only megolm is allowed, since clients wouldn't know what to do with anything else.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum EncryptionAlgorithm {
+    #[serde(rename = "m.megolm.v1.aes-sha2")]
+    MegolmV1AesSha2,
+}
+
+impl Redactable for Encryption {
+    fn redact(self) -> Self {
+        Encryption {
+            algorithm: self.algorithm,
+            rotation_period_ms: None,
+            rotation_period_msgs: None,
+        }
+    }
+}
+
 /// m.room.power_levels
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PowerLevels {
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub ban: Option<u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub invite: Option<u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub kick: Option<u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub redact: Option<u32>,
+    #[serde(deserialize_with = "level::deserialize_map")]
     pub events: HashMap<String, u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub events_default: Option<u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub state_default: Option<u32>,
+    #[serde(deserialize_with = "level::deserialize_map")]
     pub users: HashMap<MatrixId, u32>,
+    #[serde(default, deserialize_with = "level::deserialize_opt")]
     pub users_default: Option<u32>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -183,7 +316,7 @@ impl PowerLevels {
     }
 
     pub fn redact(&self) -> u32 {
-        self.kick.unwrap_or(50)
+        self.redact.unwrap_or(50)
     }
 
     pub fn events_default(&self) -> u32 {
@@ -238,7 +371,54 @@ impl Redactable for PowerLevels {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Notifications {
-    room: u32,
+    #[serde(deserialize_with = "level::deserialize")]
+    pub room: u32,
+}
+
+/// Some clients send power levels as strings, e.g. `"50"`, so accept those as well as integers.
+mod level {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::{collections::HashMap, hash::Hash};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(u32),
+        String(String),
+    }
+
+    impl IntOrString {
+        fn into_level<E: Error>(self) -> Result<u32, E> {
+            match self {
+                IntOrString::Int(v) => Ok(v),
+                IntOrString::String(s) => s
+                    .trim()
+                    .parse()
+                    .map_err(|_| E::custom(format!("invalid power level {:?}", s))),
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        IntOrString::deserialize(d)?.into_level()
+    }
+
+    pub fn deserialize_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+        Option::<IntOrString>::deserialize(d)?
+            .map(IntOrString::into_level)
+            .transpose()
+    }
+
+    pub fn deserialize_map<'de, D, K>(d: D) -> Result<HashMap<K, u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Eq + Hash,
+    {
+        HashMap::<K, IntOrString>::deserialize(d)?
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.into_level()?)))
+            .collect()
+    }
 }
 
 impl Default for PowerLevels {
@@ -277,6 +457,33 @@ pub struct Member {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_direct: Option<bool>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Present when the membership comes from an m.room.third_party_invite.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub third_party_invite: Option<MemberThirdPartyInvite>,
+}
+
+/// Proof that the user owns the third party identifier that an m.room.third_party_invite was
+/// sent to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MemberThirdPartyInvite {
+    pub display_name: String,
+    pub signed: SignedThirdPartyInvite,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedThirdPartyInvite {
+    pub mxid: String,
+    /// The state key of the m.room.third_party_invite.
+    pub token: String,
+    /// server name -> key id -> signature, made with the invite's public key
+    pub signatures: HashMap<String, HashMap<String, String>>,
+    /// Any other fields the identity server signed, which the signature has to be checked over.
+    #[serde(flatten)]
+    pub extra: HashMap<String, JsonValue>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -296,6 +503,8 @@ impl Redactable for Member {
             displayname: None,
             membership: self.membership,
             is_direct: None,
+            reason: None,
+            third_party_invite: None,
         }
     }
 }
@@ -331,10 +540,65 @@ impl ToString for Membership {
     }
 }
 
+/// m.room.third_party_invite
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThirdPartyInvite {
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_validity_url: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+impl Redactable for ThirdPartyInvite {
+    fn redact(self) -> Self {
+        ThirdPartyInvite {
+            display_name: None,
+            key_validity_url: None,
+            public_key: None,
+        }
+    }
+}
+
+/// m.room.message
+///
+/// Only the fields common to all msgtypes are parsed; the rest (`url`, `format`, `info` etc.) are
+/// kept in `extra` so that any msgtype round-trips.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Message {
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msgtype: Option<String>,
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, JsonValue>,
+}
+
+impl Redactable for Message {
+    fn redact(self) -> Self {
+        Message {
+            msgtype: None,
+            body: None,
+            extra: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Redaction {
+    #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    reason: Option<String>,
+    pub reason: Option<String>,
 }
 
 impl Redactable for Redaction {
@@ -342,3 +606,138 @@ impl Redactable for Redaction {
         Redaction { reason: None }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PowerLevels;
+    use crate::{
+        error::{Error, ErrorKind},
+        events::EventContent,
+        util::MatrixId,
+    };
+
+    #[test]
+    fn power_levels_from_strings() {
+        let content = serde_json::json!({
+            "ban": "50",
+            "kick": 50,
+            "events": { "m.room.name": "100" },
+            "users": { "@alice:example.org": "100", "@bob:example.org": 20 },
+            "users_default": "0",
+            "notifications": { "room": "50" }
+        });
+        let levels = match EventContent::new("m.room.power_levels", content).unwrap() {
+            EventContent::PowerLevels(levels) => levels,
+            _ => panic!("not a power levels event"),
+        };
+        assert_eq!(levels.ban, Some(50));
+        assert_eq!(levels.kick, Some(50));
+        assert_eq!(levels.invite, None);
+        assert_eq!(levels.get_event_level("m.room.name", true), 100);
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        assert_eq!(levels.get_user_level(&alice), 100);
+        assert_eq!(levels.get_user_level(&bob), 20);
+        assert_eq!(levels.users_default, Some(0));
+
+        let bad = serde_json::json!({ "ban": "lots", "events": {}, "users": {} });
+        assert!(serde_json::from_value::<PowerLevels>(bad).is_err());
+    }
+
+    #[test]
+    fn redact_level_is_not_kick_level() {
+        let content = serde_json::json!({
+            "kick": 25,
+            "redact": 75,
+            "events": {},
+            "users": {}
+        });
+        let levels = serde_json::from_value::<PowerLevels>(content).unwrap();
+        assert_eq!(levels.kick(), 25);
+        assert_eq!(levels.redact(), 75);
+
+        let defaults = serde_json::json!({ "events": {}, "users": {} });
+        let levels = serde_json::from_value::<PowerLevels>(defaults).unwrap();
+        assert_eq!(levels.redact(), 50);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        for content in [
+            serde_json::json!({ "msgtype": "m.text", "body": "hello" }),
+            serde_json::json!({ "msgtype": "m.notice", "body": "beep", "format": "org.matrix.custom.html", "formatted_body": "<b>beep</b>" }),
+            serde_json::json!({ "msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/cat", "info": { "w": 100, "h": 100 } }),
+            serde_json::json!({ "msgtype": "org.example.custom", "body": "?", "custom": [1, 2, 3] }),
+        ]
+        .iter()
+        {
+            let event = EventContent::new("m.room.message", content.clone()).unwrap();
+            assert!(matches!(event, EventContent::Message(_)));
+            assert_eq!(&event.content_as_json(), content);
+            assert_eq!(event.redact().content_as_json(), serde_json::json!({}));
+        }
+    }
+
+    #[test]
+    fn avatar_and_pins_round_trip() {
+        let avatar = serde_json::json!({
+            "url": "mxc://example.org/avatar",
+            "info": { "w": 64, "h": 64, "mimetype": "image/png", "size": 1024 },
+        });
+        let event = EventContent::new("m.room.avatar", avatar.clone()).unwrap();
+        assert!(matches!(event, EventContent::Avatar(_)));
+        assert_eq!(event.content_as_json(), avatar);
+        assert_eq!(event.redact().content_as_json(), serde_json::json!({}));
+
+        for pins in [
+            serde_json::json!({ "pinned": ["$one", "$two"] }),
+            serde_json::json!({ "pinned": [] }),
+        ]
+        .iter()
+        {
+            let event = EventContent::new("m.room.pinned_events", pins.clone()).unwrap();
+            assert!(matches!(event, EventContent::PinnedEvents(_)));
+            assert_eq!(&event.content_as_json(), pins);
+            assert_eq!(event.redact().content_as_json(), serde_json::json!({}));
+        }
+    }
+
+    #[test]
+    fn encryption_algorithms() {
+        let content = serde_json::json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "rotation_period_ms": 604800000,
+            "rotation_period_msgs": 100,
+        });
+        let event = EventContent::new("m.room.encryption", content.clone()).unwrap();
+        assert!(matches!(event, EventContent::Encryption(_)));
+        assert_eq!(event.content_as_json(), content);
+        assert_eq!(
+            event.redact().content_as_json(),
+            serde_json::json!({ "algorithm": "m.megolm.v1.aes-sha2" })
+        );
+
+        let content = serde_json::json!({ "algorithm": "m.olm.v1.curve25519-aes-sha2" });
+        assert!(EventContent::new("m.room.encryption", content).is_err());
+    }
+
+    #[test]
+    fn member_without_membership() {
+        let err = EventContent::new(
+            "m.room.member",
+            serde_json::json!({ "displayname": "Alice" }),
+        )
+        .unwrap_err();
+        match Error::from(err).kind() {
+            ErrorKind::BadJson(msg) => assert_eq!(msg, "m.room.member requires membership"),
+            kind => panic!("wrong error: {}", kind),
+        }
+
+        let err = EventContent::new(
+            "m.room.member",
+            serde_json::json!({ "membership": "dance" }),
+        )
+        .unwrap_err();
+        assert!(matches!(Error::from(err).kind(), ErrorKind::BadJson(_)));
+    }
+}
@@ -1,5 +1,7 @@
+use ring::signature::Ed25519KeyPair;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 
 use crate::util::MatrixId;
 
@@ -7,83 +9,151 @@ use super::{room_version::v4::PduV4, Event, EventContent};
 
 pub mod v4;
 
+/// The room versions that rooms can be created with.
+pub const SUPPORTED_VERSIONS: &[&str] = &["4", "5", "6"];
+
+/// The room version that rooms are created with unless the config or the client says otherwise.
+pub const DEFAULT_VERSION: &str = "4";
+
+/// A PDU belonging to a room of a specific version.
+///
+/// Versions 5 and 6 use the same PDU format as version 4; they only differ in how events are
+/// validated and redacted. The room version isn't part of a PDU, so it is stored alongside it.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(untagged)]
+#[serde(tag = "room_version", content = "pdu")]
 pub enum VersionedPdu {
+    #[serde(rename = "4")]
     V4(PduV4),
+    /// Enforces the validity period of signing keys, which doesn't affect us yet.
+    #[serde(rename = "5")]
+    V5(PduV4),
+    /// Rejects events containing floats or integers outside of the range canonical JSON allows,
+    /// and strips the content of m.room.aliases events when redacting them.
+    #[serde(rename = "6")]
+    V6(PduV4),
+}
+
+impl VersionedPdu {
+    /// Wraps a PDU in the variant for the given room version, or returns None if that version
+    /// isn't supported.
+    pub fn new(room_version: &str, pdu: PduV4) -> Option<Self> {
+        match room_version {
+            "4" => Some(VersionedPdu::V4(pdu)),
+            "5" => Some(VersionedPdu::V5(pdu)),
+            "6" => Some(VersionedPdu::V6(pdu)),
+            _ => None,
+        }
+    }
+
+    pub fn room_version(&self) -> &'static str {
+        match self {
+            VersionedPdu::V4(_) => "4",
+            VersionedPdu::V5(_) => "5",
+            VersionedPdu::V6(_) => "6",
+        }
+    }
 }
 
 /// Getter functions for all non-version-specific fields
 impl VersionedPdu {
     pub fn event_content(&self) -> &EventContent {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.event_content,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                &pdu.event_content
+            }
         }
     }
 
     pub fn room_id(&self) -> &str {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.room_id,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => &pdu.room_id,
         }
     }
 
     pub fn sender(&self) -> &MatrixId {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.sender,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => &pdu.sender,
         }
     }
 
     pub fn state_key(&self) -> Option<&str> {
         match self {
-            VersionedPdu::V4(pdu) => pdu.state_key.as_deref(),
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                pdu.state_key.as_deref()
+            }
         }
     }
 
     pub fn unsigned(&self) -> Option<&JsonValue> {
         match self {
-            VersionedPdu::V4(pdu) => pdu.unsigned.as_ref(),
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                pdu.unsigned.as_ref()
+            }
         }
     }
 
     pub fn redacts(&self) -> Option<&str> {
         match self {
-            VersionedPdu::V4(pdu) => pdu.redacts.as_deref(),
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                pdu.redacts.as_deref()
+            }
         }
     }
 
     pub fn origin(&self) -> &str {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.origin,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => &pdu.origin,
         }
     }
 
     pub fn origin_server_ts(&self) -> i64 {
         match self {
-            VersionedPdu::V4(pdu) => pdu.origin_server_ts,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                pdu.origin_server_ts
+            }
         }
     }
 
     pub fn prev_events(&self) -> &[String] {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.prev_events,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                &pdu.prev_events
+            }
         }
     }
 
     pub fn auth_events(&self) -> &[String] {
         match self {
-            VersionedPdu::V4(pdu) => &pdu.auth_events,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                &pdu.auth_events
+            }
         }
     }
 
     pub(super) fn depth(&self) -> i64 {
         match self {
-            VersionedPdu::V4(pdu) => pdu.depth,
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => pdu.depth,
         }
     }
 
     pub fn redact(self) -> Self {
         match self {
             VersionedPdu::V4(pdu) => VersionedPdu::V4(pdu.redact()),
+            VersionedPdu::V5(pdu) => VersionedPdu::V5(pdu.redact()),
+            VersionedPdu::V6(pdu) => VersionedPdu::V6(pdu.redact_v6()),
+        }
+    }
+
+    /// Signs the PDU as `server_name` with each of the given keys, over the event as this room
+    /// version redacts it.
+    pub fn sign(self, server_name: &str, keys: &HashMap<String, Ed25519KeyPair>) -> Self {
+        match self {
+            VersionedPdu::V4(pdu) => VersionedPdu::V4(pdu.sign(server_name, keys)),
+            VersionedPdu::V5(pdu) => VersionedPdu::V5(pdu.sign(server_name, keys)),
+            VersionedPdu::V6(pdu) => {
+                let redacted = pdu.clone().redact_v6();
+                VersionedPdu::V6(pdu.sign_redacted(redacted, server_name, keys))
+            }
         }
     }
 
@@ -91,7 +161,9 @@ impl VersionedPdu {
     // event_id should probably be stored in StoredPdu because it is not part of a pdu
     pub fn event_id(&self) -> String {
         match self {
-            VersionedPdu::V4(pdu) => pdu.event_id(),
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) => pdu.event_id(),
+            // the reference hash is taken over the event as this room version redacts it
+            VersionedPdu::V6(pdu) => pdu.clone().redact_v6().reference_hash(),
         }
     }
 }
@@ -100,7 +172,59 @@ impl VersionedPdu {
 impl VersionedPdu {
     pub fn to_client_format(self) -> Event {
         match self {
-            VersionedPdu::V4(pdu) => pdu.to_client_format(),
+            VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                pdu.to_client_format()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{v4::UnhashedPdu, VersionedPdu};
+    use crate::{events::EventContent, util::MatrixId};
+
+    fn aliases_event(room_version: &str) -> VersionedPdu {
+        let pdu = UnhashedPdu {
+            event_content: EventContent::new(
+                "m.room.aliases",
+                json!({ "aliases": ["#room:example.org"] }),
+            )
+            .unwrap(),
+            room_id: String::from("!room:example.org"),
+            sender: MatrixId::new("alice", "example.org").unwrap(),
+            state_key: Some(String::from("example.org")),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: vec![String::from("$prev")],
+            depth: 1,
+            auth_events: vec![String::from("$create")],
+        }
+        .finalize();
+        VersionedPdu::new(room_version, pdu).unwrap()
+    }
+
+    #[test]
+    fn room_version_survives_serialization() {
+        for room_version in super::SUPPORTED_VERSIONS {
+            let json = serde_json::to_string(&aliases_event(room_version)).unwrap();
+            let pdu: VersionedPdu = serde_json::from_str(&json).unwrap();
+            assert_eq!(pdu.room_version(), *room_version);
         }
     }
+
+    #[test]
+    fn v6_redaction_strips_aliases() {
+        let content = |pdu: VersionedPdu| pdu.redact().event_content().content_as_json();
+        assert_eq!(
+            content(aliases_event("5")),
+            json!({ "aliases": ["#room:example.org"] })
+        );
+        assert_eq!(content(aliases_event("6")), json!({}));
+        assert_ne!(aliases_event("5").event_id(), aliases_event("6").event_id());
+    }
 }
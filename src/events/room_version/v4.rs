@@ -1,10 +1,15 @@
-use ring::digest::{digest, SHA256};
+use ring::{
+    digest::{digest, SHA256},
+    signature::Ed25519KeyPair,
+};
 use serde::{Deserialize, Serialize};
 use serde_canonical::ser::to_string as to_canonical_json;
 use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
 
 use crate::{
     events::{Event, EventContent},
+    server_api::keys::sign_json,
     util::MatrixId,
 };
 
@@ -71,7 +76,7 @@ impl UnhashedPdu {
         let json = to_canonical_json(&self).expect("event doesn't meet canonical json reqs");
         let content_hash = base64::encode_config(
             digest(&SHA256, json.as_bytes()).as_ref(),
-            base64::URL_SAFE_NO_PAD,
+            base64::STANDARD_NO_PAD,
         );
         PduV4 {
             event_content: self.event_content,
@@ -96,10 +101,12 @@ impl UnhashedPdu {
 impl PduV4 {
     /// Turns a PDU into a format which is suitable for clients.
     pub fn to_client_format(self) -> Event {
+        let event_id = self.event_id();
         Event {
             event_content: self.event_content,
             room_id: Some(self.room_id),
             sender: self.sender,
+            event_id: Some(event_id),
             state_key: self.state_key,
             unsigned: self.unsigned,
             redacts: self.redacts,
@@ -125,11 +132,46 @@ impl PduV4 {
         }
     }
 
+    /// Signs the PDU as `server_name` with each of the given keys. The signatures cover the
+    /// redacted event, so that they can still be checked after it has been redacted.
+    pub fn sign(self, server_name: &str, keys: &HashMap<String, Ed25519KeyPair>) -> Self {
+        let redacted = self.clone().redact();
+        self.sign_redacted(redacted, server_name, keys)
+    }
+
+    /// Signs the PDU over the given redaction of it, which depends on the room version.
+    pub(super) fn sign_redacted(
+        mut self,
+        redacted: PduV4,
+        server_name: &str,
+        keys: &HashMap<String, Ed25519KeyPair>,
+    ) -> Self {
+        let mut redacted = serde_json::to_value(redacted).unwrap();
+        sign_json(&mut redacted, server_name, keys)
+            .expect("event doesn't meet canonical json reqs");
+        self.signatures = redacted["signatures"].as_object().cloned();
+        self
+    }
+
+    /// Redacts the PDU according to room version 6, which unlike earlier versions doesn't keep
+    /// the aliases of an m.room.aliases event.
+    pub fn redact_v6(self) -> Self {
+        let mut redacted = self.redact();
+        if let EventContent::Aliases(aliases) = &mut redacted.event_content {
+            aliases.aliases = None;
+        }
+        redacted
+    }
+
     pub fn event_id(&self) -> String {
-        let mut redacted = self.clone().redact();
-        redacted.signatures = None;
+        self.clone().redact().reference_hash()
+    }
+
+    /// Hashes an already redacted PDU to get its event id.
+    pub(super) fn reference_hash(mut self) -> String {
+        self.signatures = None;
         // age_ts doesn't exist, and unsigned already got blasted in redact()
-        let json = to_canonical_json(&redacted).expect("event doesn't meet canonical json reqs");
+        let json = to_canonical_json(&self).expect("event doesn't meet canonical json reqs");
         let mut event_id = base64::encode_config(
             digest(&SHA256, json.as_bytes()).as_ref(),
             base64::URL_SAFE_NO_PAD,
@@ -138,3 +180,67 @@ impl PduV4 {
         event_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    use super::{PduV4, UnhashedPdu};
+    use crate::{events::EventContent, server_api::keys::verify_signature, util::MatrixId};
+
+    fn unhashed(unsigned: Option<serde_json::Value>) -> UnhashedPdu {
+        UnhashedPdu {
+            event_content: EventContent::new("m.room.message", json!({ "body": "hi" })).unwrap(),
+            room_id: String::from("!unsigned:example.org"),
+            sender: MatrixId::new("alice", "example.org").unwrap(),
+            state_key: None,
+            unsigned,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: vec![String::from("$prev")],
+            depth: 1,
+            auth_events: vec![String::from("$create")],
+        }
+    }
+
+    #[test]
+    fn unsigned_is_not_hashed() {
+        let plain = unhashed(None).finalize();
+        let with_unsigned = unhashed(Some(json!({
+            "transaction_id": "txn",
+            "m.relations": { "m.annotation": { "chunk": [] } },
+        })))
+        .finalize();
+        assert_eq!(plain.hashes.sha256, with_unsigned.hashes.sha256);
+        assert_eq!(plain.event_id(), with_unsigned.event_id());
+        assert!(with_unsigned.unsigned.is_some());
+    }
+
+    #[test]
+    fn signatures_round_trip() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let public_key = base64::encode_config(key.public_key().as_ref(), base64::STANDARD_NO_PAD);
+        let keys = vec![(String::from("ed25519:test"), key)]
+            .into_iter()
+            .collect();
+        let unsigned = unhashed(Some(json!({ "age": 5 }))).finalize();
+        let event_id = unsigned.event_id();
+
+        let signed = unsigned.sign("example.org", &keys);
+        assert_eq!(signed.event_id(), event_id);
+        let json = serde_json::to_string(&signed).unwrap();
+        let received: PduV4 = serde_json::from_str(&json).unwrap();
+        let verify_keys = vec![(String::from("ed25519:test"), public_key)]
+            .into_iter()
+            .collect();
+        let redacted = serde_json::to_value(received.redact()).unwrap();
+        assert!(verify_signature(&redacted, "example.org", &verify_keys).is_ok());
+        assert!(verify_signature(&redacted, "remote.example.org", &verify_keys).is_err());
+
+        let mut tampered = serde_json::to_value(signed.redact()).unwrap();
+        tampered["origin_server_ts"] = json!(1);
+        assert!(verify_signature(&tampered, "example.org", &verify_keys).is_err());
+    }
+}
@@ -6,14 +6,21 @@ use actix_web::{
     App,
 };
 use error::Error;
+use ring::signature::Ed25519KeyPair;
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, ServerConfig,
+};
 use serde::Deserialize;
 use state::StateResolver;
-use std::sync::Arc;
+use std::{collections::HashMap, fs::File, io::BufReader, sync::Arc, time::Duration};
 use tracing_subscriber::EnvFilter;
 
+mod admin_api;
 mod client_api;
 mod error;
 mod events;
+mod server_api;
 mod state;
 mod storage;
 mod util;
@@ -27,12 +34,101 @@ pub struct Config {
     domain: String,
     bind_address: String,
     storage: String,
+    /// If present, the server speaks HTTPS directly instead of plain HTTP.
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// The maximum number of storage handles that can be in use at once. Requests beyond this
+    /// are rejected with M_LIMIT_EXCEEDED.
+    #[serde(default)]
+    storage_handle_limit: Option<usize>,
+    /// Whether to serve the federation API. Off by default, since most of it doesn't exist yet.
+    #[serde(default)]
+    federation: bool,
+    /// If set, admins can create users by proving knowledge of this secret to
+    /// /_synapse/admin/v1/register, even though open registration is off.
+    #[serde(default)]
+    registration_shared_secret: Option<String>,
+    /// The argon2 parameters used to hash passwords.
+    #[serde(default)]
+    password_hashing: storage::PasswordParams,
+    /// Servers that are trusted to vouch for other servers' signing keys, which is how keys are
+    /// fetched for servers that can't be reached directly.
+    #[serde(default)]
+    trusted_key_servers: Vec<TrustedKeyServer>,
+    /// How far in the future, in seconds, events from other servers may be timestamped. Anything
+    /// later is rejected, since it would distort the ordering of the room.
+    #[serde(default = "default_clock_skew_tolerance_secs")]
+    clock_skew_tolerance_secs: u64,
+    /// The room version that rooms are created with when the client doesn't ask for one. It has
+    /// to be one of `SUPPORTED_VERSIONS`.
+    #[serde(default = "default_room_version")]
+    default_room_version: String,
+    /// The file that the server's ed25519 signing keys are kept in. A new key is generated there
+    /// if it doesn't exist.
+    #[serde(default = "default_signing_key_path")]
+    signing_key_path: String,
+    /// The most rooms that a user can be joined to at once, including the ones they create. Admins
+    /// aren't limited. If unset, there is no limit.
+    #[serde(default)]
+    max_rooms_per_user: Option<usize>,
+    /// Whether new users start out with their localpart as their display name, rather than with
+    /// none at all.
+    #[serde(default)]
+    default_displayname_to_localpart: bool,
+    /// Whether the user directory searches every local user. Otherwise it only finds users who
+    /// share a room with the searcher or are in a public room.
+    #[serde(default)]
+    user_directory_search_all_users: bool,
+}
+
+fn default_clock_skew_tolerance_secs() -> u64 {
+    300
+}
+
+fn default_room_version() -> String {
+    String::from(events::room_version::DEFAULT_VERSION)
+}
+
+fn default_signing_key_path() -> String {
+    String::from("signing.key")
+}
+
+impl Config {
+    pub fn clock_skew_tolerance(&self) -> Duration {
+        Duration::from_secs(self.clock_skew_tolerance_secs)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    /// Path to a PEM file containing the certificate chain, leaf first.
+    cert_path: String,
+    /// Path to a PEM file containing the private key, in PKCS#8 or RSA format.
+    key_path: String,
+}
+
+#[derive(Deserialize)]
+pub struct TrustedKeyServer {
+    server_name: String,
+    /// key id -> ed25519 public key in unpadded base64, which the server's responses must be
+    /// signed with.
+    verify_keys: HashMap<String, String>,
+    /// Where the server can be reached, if not at https://{server_name}.
+    #[serde(default)]
+    url: Option<String>,
 }
 
 pub struct ServerState {
     pub config: Config,
     pub db_pool: Box<dyn StorageManager>,
     pub state_resolver: StateResolver,
+    pub last_seen: client_api::LastSeen,
+    pub registration_nonces: admin_api::RegistrationNonces,
+    pub invite_limits: client_api::InviteLimits,
+    pub notification_counts: client_api::NotificationCache,
+    /// key id -> the key that we sign things with
+    pub keys: HashMap<String, Ed25519KeyPair>,
+    pub remote_keys: server_api::keys::KeyCache,
 }
 
 fn init_tracing() {
@@ -42,6 +138,29 @@ fn init_tracing() {
         .init();
 }
 
+fn load_tls_config(tls: &TlsConfig) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+    let cert_chain = certs(&mut BufReader::new(File::open(&tls.cert_path)?))
+        .map_err(|_| format!("invalid certificate file {}", tls.cert_path))?;
+    if cert_chain.is_empty() {
+        return Err(format!("no certificates found in {}", tls.cert_path).into());
+    }
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&tls.key_path)?))
+        .map_err(|_| format!("invalid private key file {}", tls.key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(&tls.key_path)?))
+            .map_err(|_| format!("invalid private key file {}", tls.key_path))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("no private keys found in {}", tls.key_path))?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_single_cert(cert_chain, key)?;
+    Ok(server_config)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     run().await.map_err(|e| {
@@ -54,33 +173,89 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
     init_tracing();
 
     let config: Config = toml::from_slice(&std::fs::read("config.toml")?)?;
+    if !events::room_version::SUPPORTED_VERSIONS.contains(&&*config.default_room_version) {
+        return Err(format!(
+            "Room version {} is not supported",
+            config.default_room_version
+        )
+        .into());
+    }
+    // load this up front so that bad certs are reported before we touch the database
+    let tls_config = config.tls.as_ref().map(load_tls_config).transpose()?;
+    let keys = server_api::keys::load_signing_keys(&config.signing_key_path).map_err(|e| {
+        format!(
+            "Couldn't load signing keys from {}: {}",
+            config.signing_key_path, e
+        )
+    })?;
+    tracing::info!(
+        "Loaded signing keys {}",
+        keys.keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
     let db_pool = match &*config.storage {
         "mem" => {
-            let storage =
-                Box::new(storage::mem::MemStorageManager::new()) as Box<dyn StorageManager>;
+            let mut storage = storage::mem::MemStorageManager::new()
+                .with_password_params(config.password_hashing)
+                .with_default_displayname_to_localpart(config.default_displayname_to_localpart);
+            if let Some(limit) = config.storage_handle_limit {
+                storage = storage.with_handle_limit(limit);
+            }
             storage.get_handle().await?.create_test_users().await?;
-            storage
+            Box::new(storage) as Box<dyn StorageManager>
+        }
+        "sled" => {
+            let mut storage = storage::sled::SledStorage::new("sled")?
+                .with_password_params(config.password_hashing)
+                .with_default_displayname_to_localpart(config.default_displayname_to_localpart);
+            if let Some(limit) = config.storage_handle_limit {
+                storage = storage.with_handle_limit(limit);
+            }
+            storage.migrate().await?;
+            Box::new(storage) as _
         }
-        "sled" => Box::new(storage::sled::SledStorage::new("sled")?) as _,
         _ => panic!("invalid storage type"),
     };
-    let state_resolver = StateResolver::new(db_pool.get_handle().await?);
+    let state_resolver = StateResolver::new(db_pool.get_unlimited_handle().await?);
     let server_state = Arc::new(ServerState {
         config,
         db_pool,
         state_resolver,
+        last_seen: Default::default(),
+        registration_nonces: Default::default(),
+        invite_limits: Default::default(),
+        notification_counts: Default::default(),
+        remote_keys: Default::default(),
+        keys,
     });
 
     let server_state2 = Arc::clone(&server_state);
-    actix_web::HttpServer::new(move || {
+    let server = actix_web::HttpServer::new(move || {
+        let federation = server_state.config.federation;
         App::new()
             .data(Arc::clone(&server_state))
             .data(JsonConfig::default().error_handler(|e, _req| Error::from(e).into()))
             .service(web::scope("/_matrix/client").configure(client_api::configure_endpoints))
+            .service(web::scope("/_synapse/admin").configure(admin_api::configure_endpoints))
+            .configure(|cfg| {
+                if federation {
+                    cfg.service(
+                        web::scope("/_matrix/federation")
+                            .configure(server_api::configure_endpoints),
+                    )
+                    .service(
+                        web::scope("/_matrix/key").configure(server_api::keys::configure_endpoints),
+                    );
+                }
+            })
             .service(util::print_the_world)
-    })
-    .bind(&server_state2.config.bind_address)?
-    .run()
-    .await?;
+    });
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls(&server_state2.config.bind_address, tls_config)?,
+        None => server.bind(&server_state2.config.bind_address)?,
+    };
+    server.run().await?;
     Ok(())
 }
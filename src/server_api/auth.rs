@@ -0,0 +1,250 @@
+use actix_web::{
+    dev::Payload,
+    http::header,
+    web::{Bytes, Data},
+    FromRequest, HttpRequest,
+};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+
+use crate::{
+    error::{Error, ErrorKind},
+    server_api::keys::verify_signature,
+    ServerState,
+};
+
+/// The parameters of an `Authorization: X-Matrix ...` header, which servers sign their requests
+/// to each other with.
+#[derive(Debug, PartialEq)]
+pub struct XMatrix {
+    pub origin: String,
+    pub key: String,
+    pub sig: String,
+}
+
+impl XMatrix {
+    pub fn parse(header: &str) -> Result<Self, ErrorKind> {
+        let params = header
+            .strip_prefix("X-Matrix ")
+            .ok_or(ErrorKind::MissingToken)?;
+        let (mut origin, mut key, mut sig) = (None, None, None);
+        for param in params.split(',') {
+            let mut parts = param.trim().splitn(2, '=');
+            let name = parts.next().unwrap_or_default();
+            let value = parts.next().ok_or(ErrorKind::MissingToken)?;
+            let value = value.trim_matches('"').to_string();
+            match name {
+                "origin" => origin = Some(value),
+                "key" => key = Some(value),
+                "sig" => sig = Some(value),
+                // e.g. destination, which newer servers send and we already know
+                _ => {}
+            }
+        }
+        match (origin, key, sig) {
+            (Some(origin), Some(key), Some(sig)) => Ok(XMatrix { origin, key, sig }),
+            _ => Err(ErrorKind::MissingToken),
+        }
+    }
+}
+
+/// Checks that a request was signed by the server that it says it's from, and returns the name
+/// of that server. `content` is the request's JSON body, if it has one.
+///
+/// A server may send one `Authorization` header for each of its keys, in which case one valid
+/// signature is enough.
+pub async fn verify_request(
+    state: &ServerState,
+    req: &HttpRequest,
+    content: Option<&JsonValue>,
+) -> Result<String, Error> {
+    let mut headers = Vec::new();
+    for value in req.headers().get_all(header::AUTHORIZATION) {
+        let value = value.to_str().map_err(|_| ErrorKind::MissingToken)?;
+        headers.push(XMatrix::parse(value)?);
+    }
+    let origin = match headers.first() {
+        Some(auth) => auth.origin.clone(),
+        None => return Err(ErrorKind::MissingToken.into()),
+    };
+    if headers.iter().any(|auth| auth.origin != origin) {
+        tracing::debug!("Request is signed by more than one server");
+        return Err(ErrorKind::Unauthorized.into());
+    }
+
+    let uri = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path(), |p| p.as_str());
+    let mut signed = json!({
+        "method": req.method().as_str(),
+        "uri": uri,
+        "origin": origin,
+        "destination": state.config.domain,
+    });
+    for auth in headers {
+        signed["signatures"][&origin][auth.key] = json!(auth.sig);
+    }
+    if let Some(content) = content {
+        signed["content"] = content.clone();
+    }
+
+    let keys = state
+        .remote_keys
+        .get(&state.config.trusted_key_servers, &origin)
+        .await?;
+    verify_signature(&signed, &origin, &keys).map_err(|e| {
+        tracing::debug!("{}", e);
+        ErrorKind::Unauthorized
+    })?;
+    Ok(origin)
+}
+
+/// Extracts a request's JSON body, after checking that it was signed by the server that sent it.
+/// Requests without a body can use `Signed<()>`.
+#[derive(Debug)]
+pub struct Signed<T> {
+    /// The server that sent the request.
+    pub origin: String,
+    pub content: T,
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Signed<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = Bytes::from_request(&req, payload);
+        async move {
+            let body = body
+                .await
+                .map_err(|e| ErrorKind::Unknown(format!("{}", e)))?;
+            let content: Option<JsonValue> = if body.is_empty() {
+                None
+            } else {
+                Some(serde_json::from_slice(&body)?)
+            };
+            let state = req
+                .app_data::<Data<Arc<ServerState>>>()
+                .ok_or_else(|| ErrorKind::Unknown(String::from("Server state is missing")))?;
+            let origin = verify_request(state, &req, content.as_ref()).await?;
+            let content = serde_json::from_value(content.unwrap_or(JsonValue::Null))?;
+            Ok(Signed { origin, content })
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{get, http::header, test, App};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    use super::{Signed, XMatrix};
+    use crate::{
+        client_api::tests::{server_state_with_config, test_config},
+        server_api::keys::tests::{encode, notary_key, origin_key, query, sign},
+        storage::mem::MemStorageManager,
+        Config, TrustedKeyServer,
+    };
+
+    #[get("/whoami")]
+    async fn whoami(auth: Signed<()>) -> String {
+        auth.origin
+    }
+
+    /// An `Authorization` header for a GET of `/whoami` from remote.example.org.
+    fn x_matrix(key_id: &str, key: &Ed25519KeyPair) -> String {
+        let mut request = json!({
+            "method": "GET",
+            "uri": "/whoami",
+            "origin": "remote.example.org",
+            "destination": "example.org",
+        });
+        sign(&mut request, "remote.example.org", key_id, key);
+        format!(
+            r#"X-Matrix origin=remote.example.org,key="{}",sig={}"#,
+            key_id, request["signatures"]["remote.example.org"][key_id]
+        )
+    }
+
+    #[test]
+    fn parse_x_matrix() {
+        let auth =
+            XMatrix::parse(r#"X-Matrix origin=remote.example.org,key="ed25519:a_1",sig="c2ln""#)
+                .unwrap();
+        assert_eq!(
+            auth,
+            XMatrix {
+                origin: String::from("remote.example.org"),
+                key: String::from("ed25519:a_1"),
+                sig: String::from("c2ln"),
+            }
+        );
+        let with_destination = XMatrix::parse(
+            r#"X-Matrix origin="remote.example.org",destination="example.org",key="ed25519:a_1",sig="c2ln""#,
+        )
+        .unwrap();
+        assert_eq!(with_destination, auth);
+
+        assert!(XMatrix::parse("Bearer abc").is_err());
+        assert!(XMatrix::parse(r#"X-Matrix origin=remote.example.org,sig="c2ln""#).is_err());
+    }
+
+    #[test]
+    fn signed_requests() {
+        actix_web::rt::System::new("test").block_on(async {
+            let notary = test::start(|| App::new().service(query));
+            let config = Config {
+                trusted_key_servers: vec![TrustedKeyServer {
+                    server_name: String::from("notary.example.org"),
+                    verify_keys: vec![(
+                        String::from("ed25519:notary"),
+                        encode(notary_key().public_key().as_ref()),
+                    )]
+                    .into_iter()
+                    .collect::<HashMap<_, _>>(),
+                    url: Some(notary.url("")),
+                }],
+                ..test_config()
+            };
+            let state = server_state_with_config(MemStorageManager::new(), config).await;
+            let mut app = test::init_service(App::new().data(state).service(whoami)).await;
+            let whoami = |headers: &[String]| {
+                headers
+                    .iter()
+                    .fold(test::TestRequest::get().uri("/whoami"), |req, value| {
+                        req.header(header::AUTHORIZATION, value.as_str())
+                    })
+                    .to_request()
+            };
+            let impostor = Ed25519KeyPair::from_seed_unchecked(&[3; 32]).unwrap();
+
+            let res = test::call_service(&mut app, whoami(&[])).await;
+            assert_eq!(res.status(), 403);
+            let res =
+                test::call_service(&mut app, whoami(&[x_matrix("ed25519:origin", &impostor)]))
+                    .await;
+            assert_eq!(res.status(), 401);
+
+            // one good signature is enough, whatever the other headers say
+            let headers = [
+                x_matrix("ed25519:old", &impostor),
+                x_matrix("ed25519:origin", &origin_key()),
+            ];
+            let res = test::read_response(&mut app, whoami(&headers)).await;
+            assert_eq!(res, "remote.example.org");
+
+            // the keys are remembered, so the notary isn't needed any more
+            drop(notary);
+            let headers = [x_matrix("ed25519:origin", &origin_key())];
+            let res = test::read_response(&mut app, whoami(&headers)).await;
+            assert_eq!(res, "remote.example.org");
+        });
+    }
+}
@@ -0,0 +1,493 @@
+use actix_web::{
+    client::Client,
+    get,
+    web::{self, Data, Json},
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use serde_canonical::ser::to_string as to_canonical_json;
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, ErrorKind},
+    ServerState, TrustedKeyServer,
+};
+
+/// How long other servers may cache our keys for before fetching them again.
+const KEY_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
+    let v2 = web::scope("/v2").service(server_keys);
+
+    cfg.service(v2);
+}
+
+/// Serves our own signing keys, signed with themselves.
+#[get("/server")]
+async fn server_keys(state: Data<Arc<ServerState>>) -> Result<Json<JsonValue>, Error> {
+    let verify_keys = state
+        .keys
+        .iter()
+        .map(|(key_id, key)| {
+            let key = base64::encode_config(key.public_key().as_ref(), base64::STANDARD_NO_PAD);
+            (key_id.clone(), json!({ "key": key }))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let valid_until_ts = chrono::Utc::now().timestamp_millis() + KEY_VALIDITY.as_millis() as i64;
+    let mut res = json!({
+        "server_name": state.config.domain,
+        "verify_keys": verify_keys,
+        "old_verify_keys": {},
+        "valid_until_ts": valid_until_ts,
+    });
+    sign_json(&mut res, &state.config.domain, &state.keys)?;
+    Ok(Json(res))
+}
+
+/// Loads our signing keys from a file in the same format as Synapse's, with one key per line:
+/// `ed25519 <version> <unpadded base64 seed>`. If the file doesn't exist, a new key is generated
+/// and saved there. The keys are returned by key id, e.g. `ed25519:a_1b2c`.
+pub fn load_signing_keys(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<String, Ed25519KeyPair>, Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        let seed: [u8; 32] = rand::random();
+        let line = format!(
+            "ed25519 a_{:04x} {}\n",
+            rand::random::<u16>(),
+            base64::encode_config(&seed, base64::STANDARD_NO_PAD)
+        );
+        std::fs::write(path, line)?;
+        tracing::info!("Generated a new signing key in {}", path.display());
+    }
+
+    let mut keys = HashMap::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("invalid signing key on line {}", i + 1);
+        let mut parts = line.split_whitespace();
+        let (algorithm, version, seed) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(algorithm), Some(version), Some(seed)) => (algorithm, version, seed),
+            _ => return Err(invalid().into()),
+        };
+        if algorithm != "ed25519" {
+            return Err(format!("unsupported signing key algorithm {:?}", algorithm).into());
+        }
+        let seed = base64::decode_config(seed.trim_end_matches('='), base64::STANDARD_NO_PAD)
+            .map_err(|_| invalid())?;
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed).map_err(|_| invalid())?;
+        keys.insert(format!("ed25519:{}", version), key);
+    }
+    if keys.is_empty() {
+        // don't quietly replace keys that someone deliberately emptied out
+        return Err("no signing keys found; delete the file to generate a new one".into());
+    }
+    Ok(keys)
+}
+
+/// Signs a JSON object as `signer` with each of the given keys, adding to any signatures it
+/// already has.
+pub fn sign_json(
+    object: &mut JsonValue,
+    signer: &str,
+    keys: &HashMap<String, Ed25519KeyPair>,
+) -> Result<(), Error> {
+    let mut unsigned = object.clone();
+    let signatures = match unsigned.as_object_mut() {
+        Some(unsigned) => {
+            unsigned.remove("unsigned");
+            unsigned.remove("signatures")
+        }
+        None => return Err(ErrorKind::Unknown(String::from("Only objects can be signed")).into()),
+    };
+    let message = to_canonical_json(&unsigned)
+        .map_err(|_| ErrorKind::Unknown(String::from("Object isn't canonical JSON")))?;
+
+    let mut signatures = signatures.unwrap_or_else(|| json!({}));
+    for (key_id, key) in keys {
+        let signature = key.sign(message.as_bytes());
+        signatures[signer][key_id] = json!(base64::encode_config(
+            signature.as_ref(),
+            base64::STANDARD_NO_PAD
+        ));
+    }
+    object["signatures"] = signatures;
+    Ok(())
+}
+
+/// A server's signing keys.
+#[derive(Debug, Deserialize)]
+pub struct ServerKeys {
+    pub server_name: String,
+    /// Milliseconds since the unix epoch after which the keys should be fetched again.
+    pub valid_until_ts: i64,
+    /// key id -> key
+    pub verify_keys: HashMap<String, VerifyKey>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyKey {
+    /// An ed25519 public key, in unpadded base64.
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+struct QueryResponse {
+    server_keys: Vec<JsonValue>,
+}
+
+/// Fetches a server's signing keys by asking each of the trusted key servers in turn, until one
+/// of them answers with keys that both it and the server itself have signed. If none of them do,
+/// the server is asked for its keys directly.
+pub async fn fetch_server_keys(
+    trusted_key_servers: &[TrustedKeyServer],
+    server_name: &str,
+) -> Result<ServerKeys, Error> {
+    let client = Client::default();
+    for notary in trusted_key_servers {
+        match query_notary(&client, notary, server_name).await {
+            Ok(keys) => return Ok(keys),
+            Err(e) => tracing::warn!(
+                notary = notary.server_name.as_str(),
+                "Failed to fetch keys for {}: {}",
+                server_name,
+                e
+            ),
+        }
+    }
+    //TODO: find the server through .well-known and SRV records
+    let base_url = format!("https://{}", server_name);
+    query_server(&client, &base_url, server_name).await
+}
+
+/// Other servers' signing keys that we've already fetched, so that we don't have to ask a notary
+/// again until they expire.
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    /// server name -> (valid_until_ts, key id -> key)
+    keys: Mutex<HashMap<String, (i64, HashMap<String, String>)>>,
+}
+
+impl KeyCache {
+    /// Gets a server's verify keys, as a map of key ids to ed25519 public keys in unpadded base64,
+    /// fetching them if we don't have any that are still valid.
+    pub async fn get(
+        &self,
+        trusted_key_servers: &[TrustedKeyServer],
+        server_name: &str,
+    ) -> Result<HashMap<String, String>, Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        if let Some((valid_until_ts, keys)) = self.keys.lock().unwrap().get(server_name) {
+            if *valid_until_ts > now {
+                return Ok(keys.clone());
+            }
+        }
+
+        let fetched = fetch_server_keys(trusted_key_servers, server_name).await?;
+        let keys: HashMap<_, _> = fetched
+            .verify_keys
+            .into_iter()
+            .map(|(key_id, key)| (key_id, key.key))
+            .collect();
+        self.keys.lock().unwrap().insert(
+            server_name.to_string(),
+            (fetched.valid_until_ts, keys.clone()),
+        );
+        Ok(keys)
+    }
+}
+
+/// Asks a server at `base_url` for its own keys.
+async fn query_server(
+    client: &Client,
+    base_url: &str,
+    server_name: &str,
+) -> Result<ServerKeys, Error> {
+    let url = format!("{}/_matrix/key/v2/server", base_url.trim_end_matches('/'));
+    let mut res = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| ErrorKind::Unknown(format!("{}", e)))?;
+    if !res.status().is_success() {
+        return Err(ErrorKind::Unknown(format!("{} responded with {}", url, res.status())).into());
+    }
+    let signed: JsonValue = res
+        .json()
+        .await
+        .map_err(|e| ErrorKind::Unknown(format!("{}", e)))?;
+    check_own_keys(&signed, server_name)
+}
+
+async fn query_notary(
+    client: &Client,
+    notary: &TrustedKeyServer,
+    server_name: &str,
+) -> Result<ServerKeys, Error> {
+    let base_url = match &notary.url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => format!("https://{}", notary.server_name),
+    };
+    let url = format!("{}/_matrix/key/v2/query/{}", base_url, server_name);
+    let mut res = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| ErrorKind::Unknown(format!("{}", e)))?;
+    if !res.status().is_success() {
+        return Err(ErrorKind::Unknown(format!("{} responded with {}", url, res.status())).into());
+    }
+    let res: QueryResponse = res
+        .json()
+        .await
+        .map_err(|e| ErrorKind::Unknown(format!("{}", e)))?;
+
+    for signed in res.server_keys {
+        if signed["server_name"] != server_name {
+            continue;
+        }
+        // the notary vouches for the keys, and they have to be the server's own
+        verify_signature(&signed, &notary.server_name, &notary.verify_keys)?;
+        return check_own_keys(&signed, server_name);
+    }
+    Err(ErrorKind::Unknown(format!(
+        "{} has no keys for {}",
+        notary.server_name, server_name
+    ))
+    .into())
+}
+
+/// Checks that keys belong to `server_name`, are signed by it and haven't expired.
+fn check_own_keys(signed: &JsonValue, server_name: &str) -> Result<ServerKeys, Error> {
+    let keys: ServerKeys = serde_json::from_value(signed.clone())?;
+    if keys.server_name != server_name {
+        return Err(ErrorKind::Unknown(format!(
+            "Asked for keys for {}, got keys for {}",
+            server_name, keys.server_name
+        ))
+        .into());
+    }
+    if keys.valid_until_ts <= chrono::Utc::now().timestamp_millis() {
+        return Err(ErrorKind::Unknown(format!("Keys for {} have expired", server_name)).into());
+    }
+    let own_keys = keys
+        .verify_keys
+        .iter()
+        .map(|(key_id, key)| (key_id.clone(), key.key.clone()))
+        .collect();
+    verify_signature(signed, server_name, &own_keys)?;
+    Ok(keys)
+}
+
+/// Checks that `signer` has signed the object with one of the given keys, which map key ids to
+/// ed25519 public keys in unpadded base64.
+pub fn verify_signature(
+    object: &JsonValue,
+    signer: &str,
+    keys: &HashMap<String, String>,
+) -> Result<(), Error> {
+    let signatures = object["signatures"][signer]
+        .as_object()
+        .ok_or_else(|| ErrorKind::Unknown(format!("Keys aren't signed by {}", signer)))?;
+    let mut unsigned = object.clone();
+    if let Some(object) = unsigned.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+    let message = to_canonical_json(&unsigned)
+        .map_err(|_| ErrorKind::Unknown(String::from("Keys aren't canonical JSON")))?;
+
+    for (key_id, signature) in signatures {
+        let key = match keys.get(key_id) {
+            Some(key) => key,
+            None => continue,
+        };
+        let key = base64::decode_config(key, base64::STANDARD_NO_PAD);
+        let signature = signature
+            .as_str()
+            .map(|s| base64::decode_config(s, base64::STANDARD_NO_PAD));
+        if let (Ok(key), Some(Ok(signature))) = (key, signature) {
+            let key = UnparsedPublicKey::new(&ED25519, key);
+            if key.verify(message.as_bytes(), &signature).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+    Err(ErrorKind::Unknown(format!("Bad signature from {}", signer)).into())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use actix_web::{client::Client, get, test, web::Path, App, HttpResponse};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_canonical::ser::to_string as to_canonical_json;
+    use serde_json::{json, Value as JsonValue};
+    use std::collections::HashMap;
+
+    use super::{check_own_keys, fetch_server_keys, query_server, verify_signature};
+    use crate::{
+        client_api::tests::server_state, storage::mem::MemStorageManager, TrustedKeyServer,
+    };
+
+    pub(crate) fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+    }
+
+    pub(crate) fn sign(object: &mut JsonValue, signer: &str, key_id: &str, key: &Ed25519KeyPair) {
+        let mut unsigned = object.clone();
+        unsigned.as_object_mut().unwrap().remove("signatures");
+        let message = to_canonical_json(&unsigned).unwrap();
+        let signature = encode(key.sign(message.as_bytes()).as_ref());
+        object["signatures"][signer][key_id] = json!(signature);
+    }
+
+    /// The key that every server which the notary knows of signs with.
+    pub(crate) fn origin_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap()
+    }
+
+    pub(crate) fn notary_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[2; 32]).unwrap()
+    }
+
+    /// `origin_key()` as `server_name`'s keys, signed by itself.
+    fn own_keys(server_name: &str, valid_until_ts: i64) -> JsonValue {
+        let mut keys = json!({
+            "server_name": server_name,
+            "valid_until_ts": valid_until_ts,
+            "verify_keys": {
+                "ed25519:origin": { "key": encode(origin_key().public_key().as_ref()) },
+            },
+            "old_verify_keys": {},
+        });
+        sign(&mut keys, server_name, "ed25519:origin", &origin_key());
+        keys
+    }
+
+    fn in_an_hour() -> i64 {
+        chrono::Utc::now().timestamp_millis() + 60 * 60 * 1000
+    }
+
+    #[get("/_matrix/key/v2/query/{server_name}")]
+    pub(crate) async fn query(Path(server_name): Path<String>) -> HttpResponse {
+        let mut keys = own_keys(&server_name, in_an_hour());
+        sign(
+            &mut keys,
+            "notary.example.org",
+            "ed25519:notary",
+            &notary_key(),
+        );
+        HttpResponse::Ok().json(json!({ "server_keys": [keys] }))
+    }
+
+    #[test]
+    fn fetch_keys_through_notary() {
+        actix_web::rt::System::new("test").block_on(async {
+            let notary = test::start(|| App::new().service(query));
+            let trusted = |key: &Ed25519KeyPair| TrustedKeyServer {
+                server_name: String::from("notary.example.org"),
+                verify_keys: vec![(
+                    String::from("ed25519:notary"),
+                    encode(key.public_key().as_ref()),
+                )]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+                url: Some(notary.url("")),
+            };
+
+            let keys = fetch_server_keys(&[trusted(&notary_key())], "remote.example.org")
+                .await
+                .unwrap();
+            assert_eq!(keys.server_name, "remote.example.org");
+            assert_eq!(
+                keys.verify_keys["ed25519:origin"].key,
+                encode(origin_key().public_key().as_ref())
+            );
+
+            // a notary that isn't who it claims to be isn't believed
+            let impostor = Ed25519KeyPair::from_seed_unchecked(&[3; 32]).unwrap();
+            assert!(
+                fetch_server_keys(&[trusted(&impostor)], "remote.example.org")
+                    .await
+                    .is_err()
+            );
+        });
+    }
+
+    /// Serves keys as remote.example.org.
+    #[get("/_matrix/key/v2/server")]
+    async fn remote_server_keys() -> HttpResponse {
+        HttpResponse::Ok().json(own_keys("remote.example.org", in_an_hour()))
+    }
+
+    #[test]
+    fn fetch_keys_directly() {
+        actix_web::rt::System::new("test").block_on(async {
+            let remote = test::start(|| App::new().service(remote_server_keys));
+            let client = Client::default();
+
+            let keys = query_server(&client, &remote.url(""), "remote.example.org")
+                .await
+                .unwrap();
+            assert_eq!(
+                keys.verify_keys["ed25519:origin"].key,
+                encode(origin_key().public_key().as_ref())
+            );
+            // a server can't answer for another one
+            assert!(query_server(&client, &remote.url(""), "other.example.org")
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn expired_keys_are_rejected() {
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!(check_own_keys(
+            &own_keys("remote.example.org", now + 1000),
+            "remote.example.org"
+        )
+        .is_ok());
+        assert!(check_own_keys(
+            &own_keys("remote.example.org", now - 1000),
+            "remote.example.org"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn server_keys_are_self_signed() {
+        actix_web::rt::System::new("test").block_on(async {
+            let state = server_state(MemStorageManager::new()).await;
+            let mut app = test::init_service(App::new().data(state.clone()).service(
+                actix_web::web::scope("/_matrix/key").configure(super::configure_endpoints),
+            ))
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/_matrix/key/v2/server")
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+
+            assert_eq!(res["server_name"], "example.org");
+            assert!(
+                res["valid_until_ts"].as_i64().unwrap() > chrono::Utc::now().timestamp_millis()
+            );
+            let public_key = encode(state.keys["ed25519:test"].public_key().as_ref());
+            assert_eq!(res["verify_keys"]["ed25519:test"]["key"], public_key);
+            let own_keys = vec![(String::from("ed25519:test"), public_key)]
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            verify_signature(&res, "example.org", &own_keys).unwrap();
+        });
+    }
+}
@@ -0,0 +1,451 @@
+use actix_web::{
+    get, put,
+    web::{self, Data, Json, Path, PayloadConfig},
+};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_canonical::ser::to_string as to_canonical_json;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use tracing::{instrument, Level};
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::{
+        room_version::{v4::PduV4, VersionedPdu},
+        EventContent,
+    },
+    storage::Storage,
+    util::{storage::AddEventError, StorageExt},
+    ServerState,
+};
+
+mod auth;
+pub mod keys;
+
+/// The most PDUs that a transaction may contain.
+const MAX_PDUS_PER_TXN: usize = 50;
+
+/// Transactions can be much bigger than what clients send.
+const MAX_TXN_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
+    let v1 = web::scope("/v1")
+        .app_data(PayloadConfig::new(MAX_TXN_SIZE))
+        .service(version)
+        .service(send_transaction);
+
+    cfg.service(v1);
+}
+
+#[get("/version")]
+async fn version() -> Json<serde_json::Value> {
+    Json(json!({
+        "server": {
+            "name": "kerux",
+            "version": env!("CARGO_PKG_VERSION")
+        }
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    origin: String,
+    pdus: Vec<JsonValue>,
+    //TODO: handle EDUs
+}
+
+#[put("/send/{txn_id}")]
+#[instrument(skip(state, txn), err = Level::DEBUG)]
+async fn send_transaction(
+    state: Data<Arc<ServerState>>,
+    Path(txn_id): Path<String>,
+    txn: auth::Signed<Transaction>,
+) -> Result<Json<JsonValue>, Error> {
+    let auth::Signed {
+        origin,
+        content: txn,
+    } = txn;
+    if txn.origin != origin {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if txn.pdus.len() > MAX_PDUS_PER_TXN {
+        return Err(ErrorKind::BadJson(format!(
+            "transactions can't contain more than {} PDUs",
+            MAX_PDUS_PER_TXN
+        ))
+        .into());
+    }
+
+    let db = state.db_pool.get_handle().await?;
+    let mut results = serde_json::Map::new();
+    for json in txn.pdus {
+        // without a valid PDU there's no event id to report the problem under
+        let pdu: PduV4 = match serde_json::from_value(json.clone()) {
+            Ok(pdu) => pdu,
+            Err(e) => {
+                tracing::debug!("Ignoring malformed PDU: {}", e);
+                continue;
+            }
+        };
+        // event ids are hashes of canonical JSON, which not everything can be turned into
+        if to_canonical_json(&pdu).is_err() {
+            tracing::debug!("Ignoring PDU that isn't canonical JSON");
+            continue;
+        }
+        let event_id = pdu.event_id();
+        let result = match accept_pdu(&*db, &state, &origin, pdu, &json).await {
+            Ok(()) => json!({}),
+            Err(e) => {
+                tracing::debug!(event_id = event_id.as_str(), "Rejected PDU: {}", e);
+                json!({ "error": e.kind().to_string() })
+            }
+        };
+        results.insert(event_id, result);
+    }
+    Ok(Json(json!({ "pdus": results })))
+}
+
+/// Auth checks and stores a PDU that `origin` sent us. Fails if the PDU is rejected, including
+/// when it is stored but didn't pass auth. `json` is the PDU as it was sent, which is what its
+/// content hash was taken over.
+async fn accept_pdu(
+    db: &dyn Storage,
+    state: &ServerState,
+    origin: &str,
+    pdu: PduV4,
+    json: &JsonValue,
+) -> Result<(), Error> {
+    if pdu.sender.domain() != origin {
+        tracing::debug!("{} can't send events for {}", origin, pdu.sender.as_str());
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if let EventContent::Create(_) = pdu.event_content {
+        tracing::debug!("Rooms can't be created over federation");
+        return Err(ErrorKind::Forbidden.into());
+    }
+    let (room_id, event_id) = (pdu.room_id.clone(), pdu.event_id());
+    if let Ok(Some(_)) = db.get_pdu(&room_id, &event_id).await {
+        // the origin is retrying, so it didn't see our answer last time
+        return Ok(());
+    }
+
+    let mut room_version = None;
+    for auth_event in &pdu.auth_events {
+        let create_events = state.state_resolver.create_events();
+        if let Some(create) = create_events.get(db, &room_id, auth_event).await? {
+            // a missing room version means version 1, which we don't support
+            room_version = Some(create.room_version.unwrap_or_else(|| "1".into()));
+            break;
+        }
+    }
+    let room_version = room_version.ok_or(ErrorKind::RoomNotFound)?;
+    let pdu = VersionedPdu::new(&room_version, pdu).ok_or(ErrorKind::UnsupportedRoomVersion)?;
+
+    check_content_hash(json)?;
+    // the signature covers the redacted event, so that it outlives a redaction
+    let keys = state
+        .remote_keys
+        .get(&state.config.trusted_key_servers, origin)
+        .await?;
+    let redacted = match pdu.clone().redact() {
+        VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+            serde_json::to_value(pdu)?
+        }
+    };
+    if let Err(e) = keys::verify_signature(&redacted, origin, &keys) {
+        tracing::debug!("{}", e);
+        return Err(ErrorKind::from(AddEventError::BadSignature).into());
+    }
+
+    // we can't fetch events from other servers yet, so an event that refers to one we haven't
+    // got is turned away rather than stored with a hole in its history
+    //TODO: fetch missing events from the origin
+    let referenced: Vec<String> = pdu
+        .prev_events()
+        .iter()
+        .chain(pdu.auth_events())
+        .cloned()
+        .collect();
+    let found = db.get_pdus(&room_id, &referenced).await?;
+    if let Some((event_id, _)) = referenced
+        .iter()
+        .zip(&found)
+        .find(|(_, found)| found.is_none())
+    {
+        return Err(ErrorKind::from(AddEventError::MissingEvent(event_id.clone())).into());
+    }
+
+    db.receive_pdu(
+        pdu,
+        &state.state_resolver,
+        state.config.clock_skew_tolerance(),
+    )
+    .await?;
+    match db.get_pdu(&room_id, &event_id).await? {
+        Some(pdu) if pdu.did_pass_auth() => Ok(()),
+        _ => Err(ErrorKind::Forbidden.into()),
+    }
+}
+
+/// Checks a PDU's content hash, which is taken over everything but its unsigned data, signatures
+/// and the hash itself.
+fn check_content_hash(json: &JsonValue) -> Result<(), Error> {
+    let expected = json["hashes"]["sha256"].as_str();
+    let mut unhashed = json.clone();
+    if let Some(object) = unhashed.as_object_mut() {
+        object.remove("unsigned");
+        object.remove("signatures");
+        object.remove("hashes");
+    }
+    let unhashed = to_canonical_json(&unhashed)
+        .map_err(|_| ErrorKind::BadJson(String::from("PDU isn't canonical JSON")))?;
+    let actual = base64::encode_config(
+        digest(&SHA256, unhashed.as_bytes()).as_ref(),
+        base64::STANDARD_NO_PAD,
+    );
+    if expected != Some(actual.as_str()) {
+        return Err(ErrorKind::from(AddEventError::BadContentHash).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test, web, App};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::{json, Value as JsonValue};
+    use std::collections::HashMap;
+
+    use super::keys::tests::{encode, notary_key, origin_key, query, sign};
+    use crate::{
+        client_api::tests::{server_state_with_config, test_config},
+        events::{room_version::v4::UnhashedPdu, EventContent},
+        storage::{mem::MemStorageManager, StorageManager},
+        util::{
+            storage::{calc_auth_events, NewEvent},
+            MatrixId,
+        },
+        Config, TrustedKeyServer,
+    };
+
+    /// Signs a transaction from remote.example.org with the given key, which is only the right
+    /// one if it's `origin_key()`.
+    fn signed_txn(txn_id: &str, pdus: Vec<JsonValue>, key: &Ed25519KeyPair) -> test::TestRequest {
+        let uri = format!("/_matrix/federation/v1/send/{}", txn_id);
+        let content = json!({
+            "origin": "remote.example.org",
+            "origin_server_ts": 0,
+            "pdus": pdus,
+        });
+        let mut request = json!({
+            "method": "PUT",
+            "uri": uri,
+            "origin": "remote.example.org",
+            "destination": "example.org",
+            "content": content,
+        });
+        sign(&mut request, "remote.example.org", "ed25519:origin", key);
+        let sig = request["signatures"]["remote.example.org"]["ed25519:origin"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        test::TestRequest::put()
+            .uri(&uri)
+            .header(
+                header::AUTHORIZATION,
+                format!(
+                    r#"X-Matrix origin=remote.example.org,key="ed25519:origin",sig="{}""#,
+                    sig
+                ),
+            )
+            .set_json(&content)
+    }
+
+    #[test]
+    fn version_reports_crate_version() {
+        actix_web::rt::System::new("test").block_on(async {
+            let mut app =
+                test::init_service(App::new().service(
+                    web::scope("/_matrix/federation").configure(super::configure_endpoints),
+                ))
+                .await;
+            let req = test::TestRequest::get()
+                .uri("/_matrix/federation/v1/version")
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["server"]["name"], "kerux");
+            assert_eq!(res["server"]["version"], env!("CARGO_PKG_VERSION"));
+        });
+    }
+
+    #[test]
+    fn receive_transaction() {
+        actix_web::rt::System::new("test").block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let notary = test::start(|| App::new().service(query));
+            let config = Config {
+                trusted_key_servers: vec![TrustedKeyServer {
+                    server_name: String::from("notary.example.org"),
+                    verify_keys: vec![(
+                        String::from("ed25519:notary"),
+                        encode(notary_key().public_key().as_ref()),
+                    )]
+                    .into_iter()
+                    .collect::<HashMap<_, _>>(),
+                    url: Some(notary.url("")),
+                }],
+                ..test_config()
+            };
+            let state = server_state_with_config(db_pool, config).await;
+            let mut app = test::init_service(
+                App::new()
+                    .data(state.clone())
+                    .service(
+                        web::scope("/_matrix/client")
+                            .configure(crate::client_api::configure_endpoints),
+                    )
+                    .service(
+                        web::scope("/_matrix/federation").configure(super::configure_endpoints),
+                    ),
+            )
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .set_json(&json!({ "visibility": "public" }))
+                .to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap().to_owned();
+
+            // builds an event from carol that follows on from the end of the room, which still has
+            // to be hashed and signed
+            let carol = MatrixId::new("carol", "remote.example.org").unwrap();
+            let remote_pdu = |event: NewEvent| {
+                let db = &db;
+                let state = &state;
+                let room_id = &room_id;
+                async move {
+                    let (prev_events, depth) = db.get_prev_events(room_id).await.unwrap();
+                    let state_before = state
+                        .state_resolver
+                        .resolve(room_id, &prev_events)
+                        .await
+                        .unwrap();
+                    UnhashedPdu {
+                        auth_events: calc_auth_events(&event, &state_before),
+                        event_content: event.event_content,
+                        room_id: room_id.clone(),
+                        sender: event.sender,
+                        state_key: event.state_key,
+                        unsigned: None,
+                        redacts: None,
+                        origin: String::from("remote.example.org"),
+                        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+                        prev_events,
+                        depth: depth + 1,
+                    }
+                }
+            };
+            let finish = |pdu: UnhashedPdu, key: Ed25519KeyPair| {
+                let pdu = pdu.finalize();
+                let keys = vec![(String::from("ed25519:origin"), key)]
+                    .into_iter()
+                    .collect();
+                let pdu = pdu.sign("remote.example.org", &keys);
+                (pdu.event_id(), serde_json::to_value(pdu).unwrap())
+            };
+
+            let join = NewEvent::builder(
+                carol.clone(),
+                EventContent::new("m.room.member", json!({ "membership": "join" })).unwrap(),
+            )
+            .state_key(carol.as_str())
+            .build();
+            let (join_id, join) = finish(remote_pdu(join).await, origin_key());
+            let req = signed_txn("1", vec![join], &origin_key()).to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["pdus"][&join_id], json!({}));
+
+            let message = NewEvent::builder(
+                carol.clone(),
+                EventContent::new("m.room.message", json!({ "body": "hi" })).unwrap(),
+            )
+            .build();
+            let (message_id, message) = finish(remote_pdu(message).await, origin_key());
+            let takeover = NewEvent::builder(
+                carol.clone(),
+                EventContent::new(
+                    "m.room.power_levels",
+                    json!({
+                        "events": {},
+                        "users": { "@carol:remote.example.org": 100 },
+                    }),
+                )
+                .unwrap(),
+            )
+            .state_key("")
+            .build();
+            let (takeover_id, takeover) = finish(remote_pdu(takeover).await, origin_key());
+            let req = signed_txn("2", vec![message, takeover], &origin_key()).to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["pdus"][&message_id], json!({}));
+            assert!(res["pdus"][&takeover_id]["error"].is_string());
+            let message = db.get_pdu(&room_id, &message_id).await.unwrap().unwrap();
+            assert!(message.did_pass_auth());
+
+            // alice's server can't vouch for remote.example.org's events
+            let (_, forged) = finish(
+                remote_pdu(
+                    NewEvent::builder(
+                        MatrixId::new("alice", "example.org").unwrap(),
+                        EventContent::new("m.room.message", json!({ "body": "hi" })).unwrap(),
+                    )
+                    .build(),
+                )
+                .await,
+                origin_key(),
+            );
+            let req = signed_txn("3", vec![forged], &origin_key()).to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            let results = res["pdus"].as_object().unwrap();
+            assert!(results.values().all(|result| result["error"].is_string()));
+
+            // events have to match their hashes, be signed by the origin and only refer to
+            // events that we have
+            let message = || {
+                NewEvent::builder(
+                    carol.clone(),
+                    EventContent::new("m.room.message", json!({ "body": "hi" })).unwrap(),
+                )
+                .build()
+            };
+            let (tampered_id, mut tampered) = finish(remote_pdu(message()).await, origin_key());
+            tampered["content"]["body"] = json!("bye");
+            let impostor = Ed25519KeyPair::from_seed_unchecked(&[3; 32]).unwrap();
+            let mut unsigned = remote_pdu(message()).await;
+            // so that it doesn't get the same event id as the tampered event
+            unsigned.origin_server_ts += 1;
+            let (unsigned_id, unsigned) = finish(unsigned, impostor);
+            let mut orphan = remote_pdu(message()).await;
+            orphan.prev_events = vec![String::from("$missing")];
+            let (orphan_id, orphan) = finish(orphan, origin_key());
+            let req = signed_txn("4", vec![tampered, unsigned, orphan], &origin_key()).to_request();
+            let res: JsonValue = test::read_response_json(&mut app, req).await;
+            for event_id in &[tampered_id, unsigned_id, orphan_id] {
+                assert!(res["pdus"][event_id]["error"].is_string());
+                assert!(db.get_pdu(&room_id, event_id).await.unwrap().is_none());
+            }
+
+            // and nobody else can pretend to be remote.example.org
+            let impostor = Ed25519KeyPair::from_seed_unchecked(&[3; 32]).unwrap();
+            let req = signed_txn("5", Vec::new(), &impostor).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), 401);
+        });
+    }
+}
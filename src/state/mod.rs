@@ -7,19 +7,20 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use futures::stream::{StreamExt, TryStreamExt};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use tracing::trace;
 
 use crate::{
-    error::Error,
+    error::{Error, ErrorKind},
     events::{
         pdu::StoredPdu,
         room::{Member, Membership},
         room_version::VersionedPdu,
-        EventContent, EventType,
+        Event, EventContent, EventType,
     },
-    storage::Storage,
-    validate::auth::AuthStatus,
+    storage::{StateMap, Storage},
+    util::storage::AddEventError,
+    validate::auth::{AuthStatus, CreateEvents},
 };
 
 use super::StorageExt;
@@ -36,6 +37,10 @@ impl State {
         (Cow::from(event_type), Cow::from(state_key))
     }
 
+    pub fn room_id(&self) -> &str {
+        &self.room_id
+    }
+
     pub fn get<'s, 'k: 's>(&'s self, key_strs: (&'k str, &'k str)) -> Option<&'s str> {
         let key = Self::key(key_strs);
         self.map
@@ -65,6 +70,46 @@ impl State {
         Ok(None)
     }
 
+    /// Fetches every event in this state from the database, in client format. The events are
+    /// sorted by type and state key, so that the same state always gives the same output.
+    pub async fn to_client_events(&self, db: &dyn Storage) -> Result<Vec<Event>, Error> {
+        let mut entries = self.map.iter().collect::<Vec<_>>();
+        entries.sort();
+        let event_ids = entries
+            .into_iter()
+            .map(|(_, event_id)| event_id.clone())
+            .collect::<Vec<_>>();
+        let pdus = db
+            .get_pdus(&self.room_id, &event_ids)
+            .await?
+            .into_iter()
+            .zip(&event_ids)
+            .map(|(pdu, event_id)| pdu.ok_or_else(|| missing_event(event_id)))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.state_events_to_client_format(pdus).await
+    }
+
+    fn from_map(room_id: &str, map: StateMap) -> Self {
+        State {
+            room_id: room_id.to_owned(),
+            map: map
+                .into_iter()
+                .map(|((ty, state_key), event_id)| {
+                    ((Cow::from(ty), Cow::from(state_key)), event_id)
+                })
+                .collect(),
+        }
+    }
+
+    fn to_map(&self) -> StateMap {
+        self.map
+            .iter()
+            .map(|((ty, state_key), event_id)| {
+                ((ty.to_string(), state_key.to_string()), event_id.clone())
+            })
+            .collect()
+    }
+
     pub fn insert_event(&mut self, pdu: &VersionedPdu) {
         self.map.insert(
             (
@@ -76,25 +121,137 @@ impl State {
     }
 }
 
+/// How many storage requests state resolution makes at once, when it has several independent ones
+/// to make.
+const FETCH_CONCURRENCY: usize = 16;
+
+/// How many resolved states `StateResolver` keeps in memory. Anything else has to come from
+/// storage.
+const STATE_CACHE_CAPACITY: usize = 1024;
+
+/// The most recently used resolved states, up to `STATE_CACHE_CAPACITY` of them.
+#[derive(Default)]
+struct StateCache {
+    /// [event_id] -> (state after those events res({S'(E1), S'(E2)}), when it was last used)
+    entries: HashMap<BTreeSet<String>, (State, u64)>,
+    clock: u64,
+}
+
+impl StateCache {
+    fn get(&mut self, key: &BTreeSet<String>) -> Option<State> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(state, last_used)| {
+            *last_used = clock;
+            state.clone()
+        })
+    }
+
+    fn insert(&mut self, key: BTreeSet<String>, state: State) {
+        if self.entries.len() >= STATE_CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            let least_recent = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                self.entries.remove(&least_recent);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (state, self.clock));
+    }
+}
+
 pub struct StateResolver {
-    /// [event_id] -> state after those events res({S'(E1), S'(E2)})
-    cache: Arc<Mutex<HashMap<BTreeSet<String>, State>>>,
+    cache: Arc<Mutex<StateCache>>,
+    create_events: CreateEvents,
     // TODO: do we want to keep this around, or pass it by function arguments?
+    /// Not counted towards the storage handle limit, since the resolver only does work on behalf
+    /// of requests that already hold a handle.
     db: Box<dyn Storage>,
+    /// How many times storage has been used. An auth check counts once, even though it may look
+    /// up a few events.
+    #[cfg(test)]
+    round_trips: std::sync::atomic::AtomicUsize,
 }
 
 impl StateResolver {
     pub fn new(db: Box<dyn Storage>) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(HashMap::new())),
+            cache: Default::default(),
+            create_events: CreateEvents::default(),
             db,
+            #[cfg(test)]
+            round_trips: Default::default(),
         }
     }
 
+    /// The storage handle. Every use of storage goes through here, so that tests can count them.
+    fn db(&self) -> &dyn Storage {
+        #[cfg(test)]
+        self.round_trips
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        &*self.db
+    }
+
+    /// Fetches events from the room in one go, in the same order as `event_ids`.
+    async fn fetch(
+        &self,
+        room_id: &str,
+        event_ids: &[String],
+    ) -> Result<Vec<Option<StoredPdu>>, Error> {
+        self.db().get_pdus(room_id, event_ids).await
+    }
+
+    /// Fetches events that are known to be needed, failing if any of them haven't been received.
+    async fn fetch_all(
+        &self,
+        room_id: &str,
+        event_ids: &[String],
+    ) -> Result<Vec<StoredPdu>, Error> {
+        let pdus = self.fetch(room_id, event_ids).await?;
+        event_ids
+            .iter()
+            .zip(pdus)
+            .map(|(event_id, pdu)| pdu.ok_or_else(|| missing_event(event_id)))
+            .collect()
+    }
+
+    async fn fetch_one(&self, room_id: &str, event_id: &str) -> Result<StoredPdu, Error> {
+        let mut pdus = self.fetch_all(room_id, &[event_id.to_string()]).await?;
+        Ok(pdus.pop().unwrap())
+    }
+
+    /// The create events of the rooms that this resolver has seen, for auth checks to share.
+    pub fn create_events(&self) -> &CreateEvents {
+        &self.create_events
+    }
+
     pub async fn resolve(&self, room_id: &str, events: &[String]) -> Result<State, Error> {
         self.resolve_v2(room_id, events).await
     }
 
+    /// Resolves the current state of a room, i.e. the state after all of its forward
+    /// extremities. This is cached like any other resolution, so it stays cheap until a new event
+    /// changes the extremities.
+    pub async fn resolve_current(&self, room_id: &str) -> Result<State, Error> {
+        let (extremities, _) = self.db().get_prev_events(room_id).await?;
+        self.resolve(room_id, &extremities).await
+    }
+
+    /// Resolves the current state of a room and fetches all of its events, in client format.
+    pub async fn current_state_events(&self, room_id: &str) -> Result<Vec<Event>, Error> {
+        let state = self.resolve_current(room_id).await?;
+        state.to_client_events(self.db()).await
+    }
+
+    #[cfg(test)]
+    fn is_cached(&self, events: &[String]) -> bool {
+        let key = BTreeSet::from_iter(events.iter().map(ToOwned::to_owned));
+        self.cache.lock().unwrap().entries.contains_key(&key)
+    }
+
     #[tracing::instrument(level = tracing::Level::DEBUG, skip(self))]
     #[async_recursion::async_recursion]
     pub async fn resolve_v2(&self, room_id: &str, events: &[String]) -> Result<State, Error> {
@@ -105,28 +262,110 @@ impl StateResolver {
             });
         }
 
-        let key = BTreeSet::from_iter(events.iter().map(ToOwned::to_owned));
-        if let Some(state) = self.cache.lock().unwrap().get(&key) {
-            trace!("state cache hit");
-            return Ok(state.clone());
+        if let Some(state) = self.cached(room_id, events).await? {
+            return Ok(state);
         }
+        let state = self.resolve_v2_uncached(room_id, events).await?;
+        self.remember(events, &state).await?;
+        Ok(state)
+    }
 
-        if events.len() == 1 {
-            let event = self.db.get_pdu(room_id, &events[0]).await?.unwrap();
-            let mut state = self.resolve_v2(room_id, event.prev_events()).await?;
-            if event.did_pass_auth() && event.state_key().is_some() {
-                trace!(
-                    event_type = event.event_content().get_type(),
-                    state_key = event.state_key().unwrap(),
-                    "applying one event on top of state"
-                );
-                state.insert_event(&event.inner());
+    /// Looks for the resolved state of `events` in memory, then in storage.
+    async fn cached(&self, room_id: &str, events: &[String]) -> Result<Option<State>, Error> {
+        let mut cached = self.cached_many(room_id, &[events.to_vec()]).await?;
+        Ok(cached.pop().unwrap())
+    }
+
+    /// Like `cached`, but for several sets of events at once, in the same order. Whatever isn't
+    /// in memory is looked up in storage in one go.
+    async fn cached_many(
+        &self,
+        room_id: &str,
+        keys: &[Vec<String>],
+    ) -> Result<Vec<Option<State>>, Error> {
+        let mut ret = Vec::with_capacity(keys.len());
+        let mut not_in_memory = Vec::new();
+        for (i, events) in keys.iter().enumerate() {
+            let key = BTreeSet::from_iter(events.iter().cloned());
+            let state = self.cache.lock().unwrap().get(&key);
+            if state.is_some() {
+                trace!("state cache hit");
+            } else {
+                not_in_memory.push(i);
             }
-            self.cache
-                .lock()
-                .unwrap()
-                .insert(BTreeSet::from_iter([events[0].clone()]), state.clone());
-            return Ok(state);
+            ret.push(state);
+        }
+        if not_in_memory.is_empty() {
+            return Ok(ret);
+        }
+
+        let to_look_up = not_in_memory
+            .iter()
+            .map(|&i| keys[i].clone())
+            .collect::<Vec<_>>();
+        let stored = self.db().get_cached_states(&to_look_up).await?;
+        for (i, map) in not_in_memory.into_iter().zip(stored) {
+            if let Some(map) = map {
+                trace!("stored state cache hit");
+                let state = State::from_map(room_id, map);
+                let key = BTreeSet::from_iter(keys[i].iter().cloned());
+                self.cache.lock().unwrap().insert(key, state.clone());
+                ret[i] = Some(state);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Caches the resolved state of `events`, both in memory and in storage.
+    async fn remember(&self, events: &[String], state: &State) -> Result<(), Error> {
+        self.remember_many(vec![(events.to_vec(), state.clone())])
+            .await
+    }
+
+    /// Like `remember`, but for several states at once, which are stored in one go.
+    async fn remember_many(&self, states: Vec<(Vec<String>, State)>) -> Result<(), Error> {
+        let maps = states
+            .iter()
+            .map(|(events, state)| (events.clone(), state.to_map()))
+            .collect();
+        self.db().set_cached_states(maps).await?;
+        let mut cache = self.cache.lock().unwrap();
+        for (events, state) in states {
+            cache.insert(BTreeSet::from_iter(events), state);
+        }
+        Ok(())
+    }
+
+    /// The state after a single event: the resolved state before it, plus the event itself if it
+    /// is a state event that passed auth and wasn't soft failed.
+    async fn state_after(&self, room_id: &str, event: &StoredPdu) -> Result<State, Error> {
+        let state = self.resolve_v2(room_id, event.prev_events()).await?;
+        Ok(apply_event(state, event))
+    }
+
+    /// Called when an event has just been stored, with the state before it. If the event changes
+    /// the state, the state after it is cached straight away, since the next sync is going to
+    /// want it.
+    pub async fn note_new_event(
+        &self,
+        event: &StoredPdu,
+        state_before: &State,
+    ) -> Result<(), Error> {
+        if !changes_state(event) {
+            return Ok(());
+        }
+        let mut state = state_before.clone();
+        state.insert_event(event.inner());
+        self.remember(&[event.event_id()], &state).await
+    }
+
+    /// Does the work of `resolve_v2` for a non-empty set of events, without looking in the
+    /// caches first. Resolving the states that it builds on is still cached.
+    #[async_recursion::async_recursion]
+    async fn resolve_v2_uncached(&self, room_id: &str, events: &[String]) -> Result<State, Error> {
+        if events.len() == 1 {
+            let event = self.fetch_one(room_id, &events[0]).await?;
+            return self.state_after(room_id, &event).await;
         }
 
         trace!("sad path");
@@ -134,23 +373,52 @@ impl StateResolver {
         // event_id -> state
         let mut scratch = HashMap::new();
 
-        // get as many entries from the cache as possible
-        {
-            let cache = self.cache.lock().unwrap();
-            for event_id in events.iter() {
-                if let Some(state) = cache.get(&BTreeSet::from_iter([event_id.clone()])) {
-                    scratch.insert(event_id.to_string(), state.clone());
+        // get as many entries from the caches as possible
+        let keys = events
+            .iter()
+            .map(|event_id| vec![event_id.clone()])
+            .collect::<Vec<_>>();
+        let mut missing = Vec::new();
+        for (event_id, state) in events.iter().zip(self.cached_many(room_id, &keys).await?) {
+            match state {
+                Some(state) => {
+                    scratch.insert(event_id.clone(), state);
                 }
+                None => missing.push(event_id.clone()),
             }
         }
 
-        // fill in the gaps - in a separate loop so we don't lock for long
-        for event_id in events.iter() {
-            if scratch.contains_key(event_id) {
-                continue;
+        // fill in the gaps, fetching all of the missing events at once. Branches often share
+        // their prev events, so each distinct set of them is only resolved once
+        if !missing.is_empty() {
+            let missing_events = self.fetch_all(room_id, &missing).await?;
+            let prev_sets = missing_events
+                .iter()
+                .map(|event| BTreeSet::from_iter(event.prev_events().iter().cloned()))
+                .collect::<BTreeSet<_>>();
+            let mut states_before = Vec::new();
+            for prev_events in prev_sets {
+                states_before.push(async move {
+                    let prev_events = prev_events.into_iter().collect::<Vec<_>>();
+                    let state = self.resolve_v2(room_id, &prev_events).await?;
+                    Ok::<_, Error>((BTreeSet::from_iter(prev_events), state))
+                });
+            }
+            let states_before = stream::iter(states_before)
+                .buffer_unordered(FETCH_CONCURRENCY)
+                .try_collect::<HashMap<_, _>>()
+                .await?;
+            let mut gaps = Vec::with_capacity(missing.len());
+            for (event_id, event) in missing.into_iter().zip(missing_events) {
+                let prev_events = BTreeSet::from_iter(event.prev_events().iter().cloned());
+                let state = apply_event(states_before[&prev_events].clone(), &event);
+                gaps.push((vec![event_id], state));
             }
-            let state = self.resolve_v2(room_id, &[event_id.clone()]).await?;
-            scratch.insert(event_id.clone(), state);
+            self.remember_many(gaps.clone()).await?;
+            scratch.extend(
+                gaps.into_iter()
+                    .map(|(mut event_id, state)| (event_id.pop().unwrap(), state)),
+            );
         }
 
         // STEP 1
@@ -185,7 +453,7 @@ impl StateResolver {
         }
 
         let auth_difference = self.auth_difference(room_id, events).await?;
-        let mut full_conflicted_set = conflicted_state_set
+        let full_conflicted_set = conflicted_state_set
             .union(&auth_difference)
             .map(Clone::clone)
             .collect::<HashSet<_>>();
@@ -195,20 +463,19 @@ impl StateResolver {
         // either it's not important, they get implicitly taken due to some subtle relationship
         // that I don't understand, or Synapse is broken.
         // Or I can't read
-        let mut power_events = HashSet::new();
-        for event_id in full_conflicted_set.iter() {
-            let event = self.db.get_pdu(room_id, event_id).await?.unwrap();
+        let conflicted_ids = full_conflicted_set.iter().cloned().collect::<Vec<_>>();
+        let mut power_events = Vec::new();
+        let mut conflicted_events = HashMap::new();
+        for event in self.fetch_all(room_id, &conflicted_ids).await? {
             if is_power_event(&event.inner()) {
-                power_events.insert(event_id.clone());
+                power_events.push(event);
+            } else {
+                conflicted_events.insert(event.event_id(), event);
             }
         }
 
-        for event_id in power_events.iter() {
-            full_conflicted_set.remove(&**event_id);
-        }
-
-        let ordered_power_event_ids = self
-            .reverse_topological_power_ordering(room_id, power_events)
+        let ordered_power_events = self
+            .reverse_topological_power_ordering(power_events)
             .await?;
 
         // STEP 2
@@ -219,17 +486,6 @@ impl StateResolver {
             map: unconflicted_state_map.clone(),
         };
 
-        // fetch all of the power events
-        // TODO: fetch them further up, and pass a list of events to rtpo, instead of a set of
-        // event ids
-        let future_iter = ordered_power_event_ids
-            .iter()
-            .map(|event_id| self.db.get_pdu(&room_id, event_id));
-        let ordered_power_events = futures::stream::iter(future_iter)
-            .then(|f| f)
-            .try_filter_map(|opt| async { Ok(opt) }) // Ok(Some(x)) -> Ok(x), Ok(None) filtered out
-            .try_collect::<Vec<_>>()
-            .await?;
         let partially_resolved_state = self
             .iterative_auth_checks(
                 partially_resolved_state,
@@ -244,61 +500,71 @@ impl StateResolver {
         // mainline ordering D:
 
         let get_power_levels = |event: VersionedPdu| async move {
-            let auth_events = event.auth_events();
-            for auth_event_id in auth_events.iter() {
-                let auth_event = self.db.get_pdu(room_id, auth_event_id).await?.unwrap();
-                match auth_event.event_content() {
-                    EventContent::PowerLevels(_) => {
-                        return Result::<Option<String>, Error>::Ok(Some(auth_event_id.clone()))
-                    }
-                    _ => continue,
+            let auth_events = self.fetch_all(room_id, event.auth_events()).await?;
+            for auth_event in auth_events {
+                if let EventContent::PowerLevels(_) = auth_event.event_content() {
+                    return Result::<Option<String>, Error>::Ok(Some(auth_event.event_id()));
                 }
             }
             // probably shouldn't happen
             Result::<Option<String>, Error>::Ok(None)
         };
 
-        // i seriously have no idea what to do if this doesn't exist
-        let mainline_starting_point = partially_resolved_state
-            .get(("m.room.power_levels", ""))
-            .expect("oh no");
-        let mut mainline = vec![mainline_starting_point.to_owned()];
-        let mut current = mainline_starting_point;
-        while let Some(parent) = get_power_levels(
-            self.db
-                .get_pdu(room_id, &current)
-                .await?
-                .unwrap()
-                .inner()
-                .clone(),
-        )
-        .await?
-        {
-            mainline.push(parent.clone());
-            current = &mainline.last().unwrap();
-        }
-
-        // Tuple of event_id and index of closest mainline event to that event
-        let mut events_with_closest_mainlines = Vec::new();
-        for event_id in full_conflicted_set.iter() {
-            let mut current = event_id.clone();
-            let closest_mainline = 'inner: loop {
-                if let Some((index, _)) =
-                    mainline.iter().enumerate().find(|(_, id)| **id == current)
+        // A room that hasn't sent its power levels yet has no mainline, and the creator-based
+        // defaults can't change in the meantime. In that case every event is equally far from it,
+        // and the ordering falls back to timestamps and event ids.
+        let mainline = match partially_resolved_state.get(("m.room.power_levels", "")) {
+            Some(mainline_starting_point) => {
+                let mut mainline = vec![mainline_starting_point.to_owned()];
+                let mut current = mainline_starting_point;
+                while let Some(parent) =
+                    get_power_levels(self.fetch_one(room_id, current).await?.inner).await?
                 {
-                    break 'inner index;
-                }
-
-                let current_event = self.db.get_pdu(room_id, &current).await?.unwrap();
-                match get_power_levels(current_event.inner().clone()).await? {
-                    Some(id) => current = id.clone(),
-                    None => break 'inner std::usize::MAX,
+                    mainline.push(parent.clone());
+                    current = mainline.last().unwrap();
                 }
-            };
+                Some(mainline)
+            }
+            None => {
+                trace!("no power levels in partially resolved state, skipping mainline");
+                None
+            }
+        };
 
-            let event = self.db.get_pdu(room_id, event_id).await?.unwrap();
-            events_with_closest_mainlines.push((event, closest_mainline));
+        // Tuple of event and index of closest mainline event to that event
+        let mainline = &mainline;
+        let mut closest_mainlines = Vec::new();
+        for (event_id, event) in conflicted_events {
+            closest_mainlines.push(async move {
+                let closest_mainline = match mainline {
+                    Some(mainline) => {
+                        let mut current = event_id;
+                        // the conflicted event itself has already been fetched
+                        let mut current_event = Some(event.inner().clone());
+                        loop {
+                            if let Some(index) = mainline.iter().position(|id| *id == current) {
+                                break index;
+                            }
+
+                            let event = match current_event.take() {
+                                Some(event) => event,
+                                None => self.fetch_one(room_id, &current).await?.inner,
+                            };
+                            match get_power_levels(event).await? {
+                                Some(id) => current = id,
+                                None => break usize::MAX,
+                            }
+                        }
+                    }
+                    None => usize::MAX,
+                };
+                Ok::<_, Error>((event, closest_mainline))
+            });
         }
+        let mut events_with_closest_mainlines = stream::iter(closest_mainlines)
+            .buffer_unordered(FETCH_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
 
         events_with_closest_mainlines.sort_by(mainline_cmp);
 
@@ -322,30 +588,47 @@ impl StateResolver {
         Ok(partially_resolved_state) // not partially anymore lmao
     }
 
+    /// The auth chain of each of the events, in the same order. The chains are walked side by
+    /// side, so that each step fetches what all of them need at once.
     async fn auth_chains(
         &self,
         room_id: &str,
         event_ids: &[String],
-    ) -> Result<HashSet<String>, Error> {
-        let mut ret = HashSet::new();
-        let mut to_check = Vec::new();
-        to_check.extend_from_slice(event_ids);
-        while !to_check.is_empty() {
-            let event_id = to_check.pop().unwrap();
-            let pdu = self
-                .db
-                .get_pdu(room_id, &event_id)
-                .await?
-                .expect("event not found");
-            for auth_event_id in pdu.auth_events() {
-                if !ret.contains(auth_event_id) {
-                    to_check.push(auth_event_id.clone());
+    ) -> Result<Vec<HashSet<String>>, Error> {
+        let mut chains = vec![HashSet::new(); event_ids.len()];
+        let mut to_check = event_ids
+            .iter()
+            .map(|event_id| vec![event_id.clone()])
+            .collect::<Vec<_>>();
+        // event_id -> auth_events
+        let mut auth_events = HashMap::new();
+        while to_check.iter().any(|ids| !ids.is_empty()) {
+            let to_fetch = to_check
+                .iter()
+                .flatten()
+                .filter(|event_id| !auth_events.contains_key(*event_id))
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            if !to_fetch.is_empty() {
+                for pdu in self.fetch_all(room_id, &to_fetch).await? {
+                    auth_events.insert(pdu.event_id(), pdu.auth_events().to_vec());
+                }
+            }
+
+            for (chain, to_check) in chains.iter_mut().zip(to_check.iter_mut()) {
+                for event_id in std::mem::take(to_check) {
+                    for auth_event_id in auth_events[&event_id].iter() {
+                        if chain.insert(auth_event_id.clone()) {
+                            to_check.push(auth_event_id.clone());
+                        }
+                    }
                 }
-                ret.insert(auth_event_id.clone());
             }
         }
 
-        Ok(ret)
+        Ok(chains)
     }
 
     async fn auth_difference(
@@ -357,10 +640,7 @@ impl StateResolver {
         if event_ids.len() == 1 {
             return Ok(HashSet::new());
         }
-        let mut chains = Vec::new();
-        for event_id in event_ids {
-            chains.push(self.auth_chains(room_id, &[event_id.clone()]).await?);
-        }
+        let chains = self.auth_chains(room_id, event_ids).await?;
         // these unwraps are gucci because we already panicked at the start
         let intersection = {
             let mut iter = chains.iter();
@@ -376,45 +656,44 @@ impl StateResolver {
 
     async fn reverse_topological_power_ordering(
         &self,
-        room_id: &str,
-        event_ids: HashSet<String>,
-    ) -> Result<Vec<String>, Error> {
-        let mut events = HashMap::new();
-        for event_id in event_ids {
-            let event = self
-                .db
-                .get_pdu(room_id, &event_id)
-                .await?
-                .expect("event not found");
-            events.insert(event_id, event);
-        }
+        events: Vec<StoredPdu>,
+    ) -> Result<Vec<StoredPdu>, Error> {
+        let mut events = events
+            .into_iter()
+            .map(|event| (event.event_id(), event))
+            .collect::<HashMap<_, _>>();
 
         let mut ret = Vec::new();
-        let mut candidates = Vec::new();
         while events.len() > 0 {
-            // get the events in the graph with no parents
-            'outer: for (id1, event1) in events.iter() {
-                for (_id2, event2) in events.iter() {
-                    if event2.auth_events().contains(&id1) {
-                        continue 'outer;
-                    }
-                }
-                candidates.push(event1);
-            }
+            // get the events whose auth events have all been ordered already
+            let mut candidates = events
+                .values()
+                .filter(|event| {
+                    !event
+                        .auth_events()
+                        .iter()
+                        .any(|auth_event_id| events.contains_key(auth_event_id))
+                })
+                .collect::<Vec<_>>();
 
-            let mut sender_power_levels = HashMap::new();
+            let mut power_levels = Vec::new();
             for event in candidates.iter() {
-                let power_level = self
-                    .db
-                    .get_sender_power_level(&event.room_id(), &event.event_id())
-                    .await?;
-                sender_power_levels.insert(event.event_id(), power_level);
+                let (room_id, event_id) = (event.room_id(), event.event_id());
+                power_levels.push(async move {
+                    let power_level = self.db().get_sender_power_level(room_id, &event_id).await?;
+                    Ok::<_, Error>((event_id, power_level))
+                });
             }
+            let sender_power_levels = stream::iter(power_levels)
+                .buffer_unordered(FETCH_CONCURRENCY)
+                .try_collect::<HashMap<_, _>>()
+                .await?;
 
             candidates.sort_by(|a, b| {
+                // higher power levels go first
                 let a_power_level = sender_power_levels.get(&a.event_id());
                 let b_power_level = sender_power_levels.get(&b.event_id());
-                let power_level_ordering = a_power_level.cmp(&b_power_level);
+                let power_level_ordering = b_power_level.cmp(&a_power_level);
                 if power_level_ordering != Ordering::Equal {
                     return power_level_ordering;
                 }
@@ -428,7 +707,13 @@ impl StateResolver {
                 return a.event_id().cmp(&b.event_id());
             });
 
-            ret.extend(candidates.drain(..).map(|pdu| pdu.event_id().to_string()));
+            let ordered = candidates
+                .into_iter()
+                .map(|pdu| pdu.event_id())
+                .collect::<Vec<_>>();
+            for event_id in ordered.iter() {
+                ret.push(events.remove(event_id).unwrap());
+            }
         }
 
         Ok(ret)
@@ -439,35 +724,59 @@ impl StateResolver {
         mut state: State,
         state_events: impl Iterator<Item = &'pdu VersionedPdu>,
     ) -> Result<State, Error> {
+        let state_events = state_events.collect::<Vec<_>>();
+        // fetch everything referenced in the events' auth_events up front
+        let auth_event_ids = state_events
+            .iter()
+            .flat_map(|event| event.auth_events())
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut fetched = HashMap::new();
+        if !auth_event_ids.is_empty() {
+            // some of them legitimately don't exist; see below
+            for pdu in self
+                .fetch(&state.room_id, &auth_event_ids)
+                .await?
+                .into_iter()
+                .flatten()
+            {
+                fetched.insert(pdu.event_id(), pdu);
+            }
+        }
+
         for event in state_events {
-            // fetch everything referenced in event.auth_events
-            let future_iter = event
+            let auth_events = event
                 .auth_events()
                 .iter()
-                .map(|event_id| self.db.get_pdu(&state.room_id, event_id));
-            let auth_events = futures::stream::iter(future_iter)
-                .then(|f| f)
-                .try_filter_map(|opt| async { Ok(opt) }) // Ok(Some(x)) -> Ok(x), Ok(None) filtered out
-                .try_collect::<Vec<_>>()
-                .await?;
+                .filter_map(|event_id| fetched.get(event_id))
+                .collect::<Vec<_>>();
 
-            // for auth checking, prefer events from state, otherwise fall back to auth_events
+            // for auth checking, prefer events from state, otherwise fall back to auth_events.
+            // some of them legitimately don't exist yet (e.g. power levels early in a room), in
+            // which case the auth rules use their defaults
             let mut frankenstate = state.clone();
             for auth_key in auth_types_for_event(event) {
                 if !frankenstate.map.contains_key(&State::key(auth_key)) {
-                    let fallback_event = auth_events
-                        .iter()
-                        .find(|pdu| {
-                            pdu.event_content().get_type() == auth_key.0
-                                && pdu.state_key() == Some(auth_key.1)
-                        })
-                        .expect("auth event wasn't present in db");
-                    frankenstate.insert_event(&fallback_event.inner());
+                    let fallback_event = auth_events.iter().find(|pdu| {
+                        pdu.event_content().get_type() == auth_key.0
+                            && pdu.state_key() == Some(auth_key.1)
+                    });
+                    if let Some(fallback_event) = fallback_event {
+                        frankenstate.insert_event(fallback_event.inner());
+                    }
                 }
             }
 
             // if it passes auth now, we can add it to the state
-            if crate::validate::auth::auth_check_v1(&*self.db, &event, &frankenstate).await?
+            if crate::validate::auth::auth_check_v1(
+                self.db(),
+                &event,
+                &frankenstate,
+                &self.create_events,
+            )
+            .await?
                 == AuthStatus::Pass
             {
                 state.insert_event(&event);
@@ -478,6 +787,31 @@ impl StateResolver {
     }
 }
 
+/// Applies an event on top of the state before it, if it is a state event that passed auth and
+/// wasn't soft failed.
+fn apply_event(mut state: State, event: &StoredPdu) -> State {
+    if changes_state(event) {
+        trace!(
+            event_type = event.event_content().get_type(),
+            state_key = event.state_key().unwrap(),
+            "applying one event on top of state"
+        );
+        state.insert_event(event.inner());
+    }
+    state
+}
+
+/// The error for an event that state resolution needs, but which hasn't been received.
+fn missing_event(event_id: &str) -> Error {
+    ErrorKind::from(AddEventError::MissingEvent(event_id.to_owned())).into()
+}
+
+/// Whether the event is a state event that gets applied on top of the state before it. Soft
+/// failed events keep their place in the graph, but don't change the state.
+fn changes_state(event: &StoredPdu) -> bool {
+    event.did_pass_auth() && !event.soft_failed && event.state_key().is_some()
+}
+
 fn is_power_event(pdu: &VersionedPdu) -> bool {
     match pdu.event_content() {
         EventContent::PowerLevels(_) | EventContent::JoinRules(_) => true,
@@ -511,7 +845,9 @@ fn auth_types_for_event(pdu: &VersionedPdu) -> HashSet<(&str, &str)> {
             ret.insert(("m.room.join_rules", ""));
         }
 
-        // TODO: third party invites. see synapse event_auth.py
+        if let Some(invite) = &member.third_party_invite {
+            ret.insert(("m.room.third_party_invite", &invite.signed.token));
+        }
     }
 
     ret
@@ -540,13 +876,18 @@ fn mainline_cmp(x: &(StoredPdu, usize), y: &(StoredPdu, usize)) -> Ordering {
 
 #[cfg(test)]
 mod tests {
+    use serde_canonical::ser::to_string as to_canonical_json;
+    use serde_json::json;
     use std::collections::HashMap;
 
     use crate::{
         error::Error,
         events::{
             pdu::StoredPdu,
-            room::{Create, Member, Membership, Name},
+            room::{
+                Aliases, Create, JoinRule, JoinRules, Member, MemberThirdPartyInvite, Membership,
+                Name, PowerLevels, Redaction, ThirdPartyInvite,
+            },
             room_version::{v4::UnhashedPdu, VersionedPdu},
             EventContent,
         },
@@ -554,11 +895,13 @@ mod tests {
         util::{storage::NewEvent, MatrixId, StorageExt},
     };
 
-    use super::StateResolver;
+    use super::{State, StateResolver};
+    use crate::validate::auth::AuthStatus;
 
     struct TestRoom<'db> {
         db: &'db dyn Storage,
         room_id: String,
+        room_version: String,
         /// depth -> list of events at that depth
         depth_map: Vec<Vec<String>>,
     }
@@ -569,11 +912,20 @@ mod tests {
             db: &'db dyn Storage,
             room_id: &str,
             creator: &MatrixId,
+        ) -> Result<TestRoom<'db>, Error> {
+            TestRoom::create_with_version(db, room_id, creator, "4").await
+        }
+
+        async fn create_with_version<'db>(
+            db: &'db dyn Storage,
+            room_id: &str,
+            creator: &MatrixId,
+            room_version: &str,
         ) -> Result<TestRoom<'db>, Error> {
             let creation = UnhashedPdu {
                 event_content: EventContent::Create(Create {
                     creator: creator.clone(),
-                    room_version: Some(String::from("4")),
+                    room_version: Some(String::from(room_version)),
                     predecessor: None,
                     extra: HashMap::new(),
                 }),
@@ -589,15 +941,18 @@ mod tests {
                 auth_events: Vec::new(),
             }
             .finalize();
+            let creation = VersionedPdu::new(room_version, creation).unwrap();
             let creation_id = creation.event_id();
             db.add_pdus(&[StoredPdu {
-                inner: VersionedPdu::V4(creation),
+                inner: creation,
                 auth_status: crate::validate::auth::AuthStatus::Pass,
+                soft_failed: false,
             }])
             .await?;
             Ok(TestRoom {
                 db,
                 room_id: room_id.to_owned(),
+                room_version: room_version.to_owned(),
                 depth_map: vec![vec![creation_id]],
             })
         }
@@ -611,6 +966,20 @@ mod tests {
             content: impl Into<EventContent>,
             state_key: Option<&str>,
             state_resolver: &StateResolver,
+        ) -> Result<String, Error> {
+            self.add_with_soft_failed(depth, sender, content, state_key, false, state_resolver)
+                .await
+        }
+
+        /// Like `add`, but lets you say whether the event was soft failed.
+        async fn add_with_soft_failed(
+            &mut self,
+            depth: usize,
+            sender: &MatrixId,
+            content: impl Into<EventContent>,
+            state_key: Option<&str>,
+            soft_failed: bool,
+            state_resolver: &StateResolver,
         ) -> Result<String, Error> {
             let prev_depth = depth.checked_sub(1).unwrap();
             let prev_events = &self.depth_map[prev_depth];
@@ -625,7 +994,8 @@ mod tests {
             };
 
             let auth_events = crate::util::storage::calc_auth_events(&new_event, &state);
-            let pdu = VersionedPdu::V4(
+            let pdu = VersionedPdu::new(
+                &self.room_version,
                 UnhashedPdu {
                     event_content: new_event.event_content,
                     room_id: self.room_id.clone(),
@@ -640,7 +1010,8 @@ mod tests {
                     auth_events,
                 }
                 .finalize(),
-            );
+            )
+            .unwrap();
             let event_id = pdu.event_id();
 
             if self.depth_map.len() == depth {
@@ -650,11 +1021,18 @@ mod tests {
             }
             self.depth_map[depth].push(event_id.clone());
 
-            let auth_status = crate::validate::auth::auth_check_v1(self.db, &pdu, &state).await?;
+            let auth_status = crate::validate::auth::auth_check_v1(
+                self.db,
+                &pdu,
+                &state,
+                state_resolver.create_events(),
+            )
+            .await?;
             self.db
                 .add_pdus(&[StoredPdu {
                     inner: pdu,
                     auth_status,
+                    soft_failed,
                 }])
                 .await?;
 
@@ -691,6 +1069,7 @@ mod tests {
                 .finalize(),
             ),
             auth_status: crate::validate::auth::AuthStatus::Pass,
+            soft_failed: false,
         }])
         .await?;
         db.add_event(
@@ -701,6 +1080,8 @@ mod tests {
                     displayname: None,
                     membership: Membership::Join,
                     is_direct: Some(false),
+                    reason: None,
+                    third_party_invite: None,
                 }),
                 sender: alice.clone(),
                 state_key: Some(alice.clone_inner()),
@@ -708,6 +1089,7 @@ mod tests {
                 unsigned: None,
             },
             resolver,
+            &Default::default(),
         )
         .await?;
         db.add_event(
@@ -722,6 +1104,7 @@ mod tests {
                 unsigned: None,
             },
             resolver,
+            &Default::default(),
         )
         .await?;
         Ok(())
@@ -754,6 +1137,8 @@ mod tests {
                     displayname: None,
                     membership: Membership::Join,
                     is_direct: Some(false),
+                    reason: None,
+                    third_party_invite: None,
                 },
                 Some(alice.as_str()),
                 &resolver,
@@ -815,4 +1200,1152 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn soft_failed_state_is_not_applied() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(soft_failed_state_is_not_applied_inner())
+            .unwrap();
+    }
+
+    async fn soft_failed_state_is_not_applied_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!soft:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            2,
+            &alice,
+            Name {
+                name: Some(String::from("one")),
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        let soft_failed = room
+            .add_with_soft_failed(
+                3,
+                &alice,
+                Name {
+                    name: Some(String::from("two")),
+                },
+                Some(""),
+                true,
+                &resolver,
+            )
+            .await?;
+
+        // an event built on the soft failed one still sees the state from before it
+        let state = resolver.resolve(room_id, &[soft_failed]).await?;
+        assert_eq!(
+            state
+                .get_content::<Name>(&*db, "")
+                .await?
+                .unwrap()
+                .name
+                .as_deref(),
+            Some("one")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn current_state_is_cached() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(current_state_is_cached_inner()).unwrap();
+    }
+
+    async fn current_state_is_cached_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!cached:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        let name = room
+            .add(
+                2,
+                &alice,
+                Name {
+                    name: Some(String::from("one")),
+                },
+                Some(""),
+                &resolver,
+            )
+            .await?;
+
+        let first = resolver
+            .resolve_current(room_id)
+            .await?
+            .to_client_events(&*db)
+            .await?;
+        assert!(resolver.is_cached(&[name]));
+        let second = resolver
+            .resolve_current(room_id)
+            .await?
+            .to_client_events(&*db)
+            .await?;
+        assert_eq!(first.len(), 3);
+        assert_eq!(
+            serde_json::to_value(&first).unwrap(),
+            serde_json::to_value(&second).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn new_state_event_is_cached() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(new_state_event_is_cached_inner()).unwrap();
+    }
+
+    async fn new_state_event_is_cached_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!fresh:example.org";
+        TestRoom::create(&*db, room_id, &alice).await?;
+        let join = db
+            .add_event(
+                room_id,
+                NewEvent {
+                    event_content: EventContent::Member(Member {
+                        avatar_url: None,
+                        displayname: None,
+                        membership: Membership::Join,
+                        is_direct: Some(false),
+                        reason: None,
+                        third_party_invite: None,
+                    }),
+                    sender: alice.clone(),
+                    state_key: Some(alice.clone_inner()),
+                    redacts: None,
+                    unsigned: None,
+                },
+                &resolver,
+                &Default::default(),
+            )
+            .await?;
+        assert!(resolver.is_cached(&[join.clone()]));
+
+        // a sync straight afterwards only has to look up the extremities to find the current state
+        let round_trips = || {
+            resolver
+                .round_trips
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let before = round_trips();
+        let state = resolver.resolve_current(room_id).await?;
+        assert_eq!(round_trips(), before + 1);
+        assert_eq!(
+            state.get(("m.room.member", alice.as_str())),
+            Some(join.as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stored_state_matches_resolution() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(stored_state_matches_resolution_inner())
+            .unwrap();
+    }
+
+    async fn stored_state_matches_resolution_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!stored:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        let mut names = Vec::new();
+        for name in ["left", "right"].iter() {
+            let event_id = room
+                .add(
+                    2,
+                    &alice,
+                    Name {
+                        name: Some(String::from(*name)),
+                    },
+                    Some(""),
+                    &resolver,
+                )
+                .await?;
+            names.push(event_id);
+        }
+
+        let resolved = resolver.resolve(room_id, &names).await?;
+        let reversed = [names[1].clone(), names[0].clone()];
+        assert_eq!(
+            db.get_cached_state(&reversed).await?,
+            Some(resolved.to_map())
+        );
+
+        // as if the server had restarted
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+        assert!(!resolver.is_cached(&names));
+        let stored = resolver.resolve(room_id, &names).await?;
+        assert!(resolver.is_cached(&names));
+        let recomputed = resolver.resolve_v2_uncached(room_id, &names).await?;
+        assert_eq!(stored.map, recomputed.map);
+        assert_eq!(stored.map, resolved.map);
+        Ok(())
+    }
+
+    #[test]
+    fn state_event_uses_resolved_state() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(state_event_uses_resolved_state_inner())
+            .unwrap();
+    }
+
+    async fn state_event_uses_resolved_state_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!forked:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            2,
+            &alice,
+            PowerLevels::no_event_default_levels(&alice),
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        // two conflicting names on either side of a fork. everything else being equal, state
+        // resolution lets the one with the greater event id win, regardless of which came first
+        let mut names = Vec::new();
+        for name in ["left", "right"].iter() {
+            let event_id = room
+                .add(
+                    3,
+                    &alice,
+                    Name {
+                        name: Some(String::from(*name)),
+                    },
+                    Some(""),
+                    &resolver,
+                )
+                .await?;
+            names.push((event_id, *name));
+        }
+        let resolved_name = names.iter().max().unwrap().1;
+        let last_name = names.last().unwrap().1;
+        assert_ne!(resolved_name, last_name);
+
+        let get_name = |event: Option<crate::events::Event>| match event.unwrap().event_content {
+            EventContent::Name(content) => content.name.unwrap(),
+            _ => panic!("not a name event"),
+        };
+        let naive = db.get_state_event(room_id, "m.room.name", "", None).await?;
+        assert_eq!(get_name(naive), last_name);
+        let resolved = db
+            .get_state_event(room_id, "m.room.name", "", Some(&resolver))
+            .await?;
+        assert_eq!(get_name(resolved), resolved_name);
+
+        let full_state = db.get_full_state(room_id, Some(&resolver)).await?;
+        assert_eq!(full_state.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn membership_uses_resolved_state() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(membership_uses_resolved_state_inner()).unwrap();
+    }
+
+    async fn membership_uses_resolved_state_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let member = |membership| Member {
+            avatar_url: None,
+            displayname: None,
+            membership,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
+        };
+        let room_id = "!forked:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            member(Membership::Join),
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            2,
+            &alice,
+            PowerLevels::no_event_default_levels(&alice),
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            3,
+            &alice,
+            JoinRules {
+                join_rule: JoinRule::Invite,
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            4,
+            &alice,
+            member(Membership::Invite),
+            Some(bob.as_str()),
+            &resolver,
+        )
+        .await?;
+        // alice bans bob while he accepts the invite on the other side of a fork. the ban is
+        // applied first during resolution, so the join no longer passes auth
+        room.add(
+            5,
+            &alice,
+            member(Membership::Ban),
+            Some(bob.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            5,
+            &bob,
+            member(Membership::Join),
+            Some(bob.as_str()),
+            &resolver,
+        )
+        .await?;
+
+        let naive = db.get_membership(&bob, room_id, None).await?;
+        assert_eq!(naive, Some(Membership::Join));
+        let resolved = db.get_membership(&bob, room_id, Some(&resolver)).await?;
+        assert_eq!(resolved, Some(Membership::Ban));
+        Ok(())
+    }
+
+    #[test]
+    fn fork_without_power_levels() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(fork_without_power_levels_inner()).unwrap();
+    }
+
+    async fn fork_without_power_levels_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!unpowered:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        // the room forks before anyone sends m.room.power_levels, so there is no mainline to
+        // order the conflicting names by
+        let mut names = Vec::new();
+        for name in ["left", "right"].iter() {
+            let event_id = room
+                .add(
+                    2,
+                    &alice,
+                    Name {
+                        name: Some(String::from(*name)),
+                    },
+                    Some(""),
+                    &resolver,
+                )
+                .await?;
+            names.push((event_id, *name));
+        }
+        let resolved_name = names.iter().max().unwrap().1;
+
+        let left = names[0].0.clone();
+        let right = names[1].0.clone();
+        for events in [[left.clone(), right.clone()], [right, left]].iter() {
+            // skip the caches, so that they can't hide any nondeterminism
+            let state = resolver.resolve_v2_uncached(room_id, events).await?;
+            assert_eq!(
+                state
+                    .get_content::<Name>(&*db, "")
+                    .await?
+                    .unwrap()
+                    .name
+                    .as_deref(),
+                Some(resolved_name)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn join_with_third_party_invite() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(join_with_third_party_invite_inner()).unwrap();
+    }
+
+    async fn join_with_third_party_invite_inner() -> Result<(), Error> {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let member = |membership, third_party_invite| Member {
+            avatar_url: None,
+            displayname: None,
+            membership,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite,
+        };
+        let room_id = "!threepid:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            member(Membership::Join, None),
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            2,
+            &alice,
+            JoinRules {
+                join_rule: JoinRule::Invite,
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        let identity_server_key = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let public_key = base64::encode_config(
+            identity_server_key.public_key().as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        room.add(
+            3,
+            &alice,
+            ThirdPartyInvite {
+                display_name: Some(String::from("bob...")),
+                key_validity_url: Some(String::from(
+                    "https://id.example.org/_matrix/identity/api/v1/pubkey/isvalid",
+                )),
+                public_key: Some(public_key),
+            },
+            Some("sometoken"),
+            &resolver,
+        )
+        .await?;
+
+        // what the identity server gives bob, once bob proves ownership of the address
+        let signed_by = |key: &Ed25519KeyPair| {
+            let signed = json!({ "mxid": bob.as_str(), "token": "sometoken" });
+            let message = to_canonical_json(&signed).unwrap();
+            let signature = base64::encode_config(
+                key.sign(message.as_bytes()).as_ref(),
+                base64::STANDARD_NO_PAD,
+            );
+            serde_json::from_value::<MemberThirdPartyInvite>(json!({
+                "display_name": "bob...",
+                "signed": {
+                    "mxid": bob.as_str(),
+                    "token": "sometoken",
+                    "signatures": { "id.example.org": { "ed25519:0": signature } },
+                },
+            }))
+            .unwrap()
+        };
+
+        // bob was never sent an m.room.member invite, so only a genuine signature lets bob in
+        let impostor_key = Ed25519KeyPair::from_seed_unchecked(&[8; 32]).unwrap();
+        let forged = room
+            .add(
+                4,
+                &bob,
+                member(Membership::Join, Some(signed_by(&impostor_key))),
+                Some(bob.as_str()),
+                &resolver,
+            )
+            .await?;
+        assert!(!db.get_pdu(room_id, &forged).await?.unwrap().did_pass_auth());
+        let genuine = room
+            .add(
+                4,
+                &bob,
+                member(Membership::Join, Some(signed_by(&identity_server_key))),
+                Some(bob.as_str()),
+                &resolver,
+            )
+            .await?;
+        assert!(db
+            .get_pdu(room_id, &genuine)
+            .await?
+            .unwrap()
+            .did_pass_auth());
+        Ok(())
+    }
+
+    #[test]
+    fn invite_with_third_party_invite() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(invite_with_third_party_invite_inner()).unwrap();
+    }
+
+    async fn invite_with_third_party_invite_inner() -> Result<(), Error> {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let carol = MatrixId::new("carol", "example.org").unwrap();
+        let member = |membership, third_party_invite| Member {
+            avatar_url: None,
+            displayname: None,
+            membership,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite,
+        };
+        let room_id = "!threepid_invite:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            member(Membership::Join, None),
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            2,
+            &alice,
+            JoinRules {
+                join_rule: JoinRule::Public,
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        room.add(
+            3,
+            &carol,
+            member(Membership::Join, None),
+            Some(carol.as_str()),
+            &resolver,
+        )
+        .await?;
+        let identity_server_key = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let public_key = base64::encode_config(
+            identity_server_key.public_key().as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        room.add(
+            4,
+            &alice,
+            ThirdPartyInvite {
+                display_name: Some(String::from("bob...")),
+                key_validity_url: Some(String::from(
+                    "https://id.example.org/_matrix/identity/api/v1/pubkey/isvalid",
+                )),
+                public_key: Some(public_key),
+            },
+            Some("sometoken"),
+            &resolver,
+        )
+        .await?;
+
+        // identity servers sign fields that kerux doesn't know about, and those have to survive
+        // for the signature to check out
+        let signed = json!({
+            "mxid": bob.as_str(),
+            "token": "sometoken",
+            "sender": alice.as_str(),
+        });
+        let message = to_canonical_json(&signed).unwrap();
+        let signature = base64::encode_config(
+            identity_server_key.sign(message.as_bytes()).as_ref(),
+            base64::STANDARD_NO_PAD,
+        );
+        let invite = serde_json::from_value::<MemberThirdPartyInvite>(json!({
+            "display_name": "bob...",
+            "signed": {
+                "mxid": bob.as_str(),
+                "token": "sometoken",
+                "sender": alice.as_str(),
+                "signatures": { "id.example.org": { "ed25519:0": signature } },
+            },
+        }))
+        .unwrap();
+
+        // carol is in the room, but didn't send the third party invite
+        let stolen = room
+            .add(
+                5,
+                &carol,
+                member(Membership::Invite, Some(invite.clone())),
+                Some(bob.as_str()),
+                &resolver,
+            )
+            .await?;
+        assert!(!db.get_pdu(room_id, &stolen).await?.unwrap().did_pass_auth());
+        let exchanged = room
+            .add(
+                5,
+                &alice,
+                member(Membership::Invite, Some(invite)),
+                Some(bob.as_str()),
+                &resolver,
+            )
+            .await?;
+        assert!(db
+            .get_pdu(room_id, &exchanged)
+            .await?
+            .unwrap()
+            .did_pass_auth());
+        Ok(())
+    }
+
+    #[test]
+    fn redaction_needs_redact_level() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(redaction_needs_redact_level_inner()).unwrap();
+    }
+
+    async fn redaction_needs_redact_level_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let join = || Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
+        };
+        let room_id = "!redact:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(1, &alice, join(), Some(alice.as_str()), &resolver)
+            .await?;
+        // bob can kick, but not redact
+        let power_levels = serde_json::from_value::<PowerLevels>(json!({
+            "kick": 50,
+            "redact": 75,
+            "events": {},
+            "users": { alice.as_str(): 100, bob.as_str(): 50 },
+        }))
+        .unwrap();
+        room.add(2, &alice, power_levels, Some(""), &resolver)
+            .await?;
+        room.add(
+            3,
+            &alice,
+            JoinRules {
+                join_rule: JoinRule::Public,
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        room.add(4, &bob, join(), Some(bob.as_str()), &resolver)
+            .await?;
+
+        let by_bob = room
+            .add(5, &bob, Redaction { reason: None }, None, &resolver)
+            .await?;
+        assert!(!db.get_pdu(room_id, &by_bob).await?.unwrap().did_pass_auth());
+        let by_alice = room
+            .add(6, &alice, Redaction { reason: None }, None, &resolver)
+            .await?;
+        assert!(db
+            .get_pdu(room_id, &by_alice)
+            .await?
+            .unwrap()
+            .did_pass_auth());
+        Ok(())
+    }
+
+    #[test]
+    fn notifications_level_is_capped_at_sender_level() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(notifications_level_is_capped_at_sender_level_inner("4"))
+            .unwrap();
+        rt.block_on(notifications_level_is_capped_at_sender_level_inner("6"))
+            .unwrap();
+    }
+
+    async fn notifications_level_is_capped_at_sender_level_inner(
+        room_version: &str,
+    ) -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let join = || Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
+        };
+        let room_id = "!notifications:example.org";
+        let mut room = TestRoom::create_with_version(&*db, room_id, &alice, room_version).await?;
+        room.add(1, &alice, join(), Some(alice.as_str()), &resolver)
+            .await?;
+        // bob can change the power levels, but from room version 6 the notification level only
+        // up to his own level of 50
+        let power_levels = |notifications: u32| {
+            serde_json::from_value::<PowerLevels>(json!({
+                "events": { "m.room.power_levels": 50 },
+                "users": { alice.as_str(): 100, bob.as_str(): 50 },
+                "notifications": { "room": notifications },
+            }))
+            .unwrap()
+        };
+        room.add(2, &alice, power_levels(50), Some(""), &resolver)
+            .await?;
+        room.add(
+            3,
+            &alice,
+            JoinRules {
+                join_rule: JoinRule::Public,
+            },
+            Some(""),
+            &resolver,
+        )
+        .await?;
+        room.add(4, &bob, join(), Some(bob.as_str()), &resolver)
+            .await?;
+
+        let raised = room
+            .add(5, &bob, power_levels(100), Some(""), &resolver)
+            .await?;
+        let raised = db.get_pdu(room_id, &raised).await?.unwrap();
+        assert_eq!(raised.did_pass_auth(), room_version != "6");
+        let lowered = room
+            .add(6, &bob, power_levels(25), Some(""), &resolver)
+            .await?;
+        assert!(db
+            .get_pdu(room_id, &lowered)
+            .await?
+            .unwrap()
+            .did_pass_auth());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_auth_events_fail() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(malformed_auth_events_fail_inner()).unwrap();
+    }
+
+    async fn malformed_auth_events_fail_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let member = |membership| Member {
+            avatar_url: None,
+            displayname: None,
+            membership,
+            is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
+        };
+        let room_id = "!malformed:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        let create_id = room.depth_map[0][0].clone();
+        room.add(
+            1,
+            &alice,
+            member(Membership::Join),
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        let name = || Name {
+            name: Some(String::from("room")),
+        };
+        // a name event without a state key isn't a state event at all
+        let not_state = room.add(2, &alice, name(), None, &resolver).await?;
+        let state = resolver
+            .resolve(room_id, std::slice::from_ref(&not_state))
+            .await?;
+
+        let pdu = |content: EventContent, state_key: &str| {
+            VersionedPdu::V4(
+                UnhashedPdu {
+                    event_content: content,
+                    room_id: String::from(room_id),
+                    sender: alice.clone(),
+                    state_key: Some(String::from(state_key)),
+                    unsigned: None,
+                    redacts: None,
+                    origin: String::from("example.org"),
+                    origin_server_ts: 0,
+                    prev_events: vec![not_state.clone()],
+                    depth: 3,
+                    auth_events: vec![create_id.clone()],
+                }
+                .finalize(),
+            )
+        };
+        let check = |pdu: VersionedPdu, state: State| {
+            let (db, resolver) = (&*db, &resolver);
+            async move {
+                crate::validate::auth::auth_check_v1(db, &pdu, &state, resolver.create_events())
+                    .await
+            }
+        };
+        let name_change = || pdu(EventContent::Name(name()), "");
+        assert_eq!(check(name_change(), state.clone()).await?, AuthStatus::Pass);
+
+        let mut missing = state.to_map();
+        missing.insert(
+            (String::from("m.room.power_levels"), String::new()),
+            String::from("$missing:example.org"),
+        );
+        let missing = State::from_map(room_id, missing);
+        assert_eq!(check(name_change(), missing).await?, AuthStatus::Fail);
+
+        let mut misplaced = state.to_map();
+        misplaced.insert(
+            (String::from("m.room.member"), String::from(alice.as_str())),
+            not_state.clone(),
+        );
+        let misplaced = State::from_map(room_id, misplaced);
+        assert_eq!(check(name_change(), misplaced).await?, AuthStatus::Fail);
+
+        let kick = pdu(
+            EventContent::Member(member(Membership::Leave)),
+            "not a user id",
+        );
+        assert_eq!(check(kick, state).await?, AuthStatus::Fail);
+        Ok(())
+    }
+
+    #[test]
+    fn wide_fork_is_fetched_in_batches() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(wide_fork_is_fetched_in_batches_inner())
+            .unwrap();
+    }
+
+    async fn wide_fork_is_fetched_in_batches_inner() -> Result<(), Error> {
+        const WIDTH: usize = 32;
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!wide:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        let mut names = Vec::new();
+        for i in 0..WIDTH {
+            let name = format!("branch {}", i);
+            let event_id = room
+                .add(
+                    2,
+                    &alice,
+                    Name {
+                        name: Some(name.clone()),
+                    },
+                    Some(""),
+                    &resolver,
+                )
+                .await?;
+            names.push((event_id, name));
+        }
+        let resolved_name = names.iter().max().unwrap().1.clone();
+        let event_ids = names.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+
+        // a fresh resolver, so that the counter only sees this resolution
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+        let state = resolver.resolve_v2_uncached(room_id, &event_ids).await?;
+        assert_eq!(
+            state.get_content::<Name>(&*db, "").await?.unwrap().name,
+            Some(resolved_name)
+        );
+        // each conflicted event needs an auth check of its own, but everything else is fetched
+        // for all of the branches at once, where one at a time would take at least another round
+        // trip per branch
+        let round_trips = resolver
+            .round_trips
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert!(round_trips < WIDTH + 16, "{} round trips", round_trips);
+        Ok(())
+    }
+
+    #[test]
+    fn far_future_pdu_is_rejected() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(far_future_pdu_is_rejected_inner()).unwrap();
+    }
+
+    async fn far_future_pdu_is_rejected_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+        let tolerance = crate::client_api::tests::test_config().clock_skew_tolerance();
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "remote.org").unwrap();
+        let room_id = "!skewed:example.org";
+        let room = TestRoom::create(&*db, room_id, &alice).await?;
+        let prev_events = room.depth_map[0].clone();
+        let state = resolver.resolve(room_id, &prev_events).await?;
+        let remote_pdu = |origin_server_ts| {
+            let new_event = NewEvent::builder(
+                bob.clone(),
+                Name {
+                    name: Some(String::from("from afar")),
+                }
+                .into(),
+            )
+            .state_key("")
+            .build();
+            VersionedPdu::V4(
+                UnhashedPdu {
+                    auth_events: crate::util::storage::calc_auth_events(&new_event, &state),
+                    event_content: new_event.event_content,
+                    room_id: String::from(room_id),
+                    sender: new_event.sender,
+                    state_key: new_event.state_key,
+                    unsigned: None,
+                    redacts: None,
+                    origin: String::from("remote.org"),
+                    origin_server_ts,
+                    prev_events: prev_events.clone(),
+                    depth: 1,
+                }
+                .finalize(),
+            )
+        };
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let a_year = 365 * 24 * 60 * 60 * 1000;
+        let future = remote_pdu(now + a_year);
+        let future_id = future.event_id();
+        assert!(db.receive_pdu(future, &resolver, tolerance).await.is_err());
+        assert!(db.get_pdu(room_id, &future_id).await?.is_none());
+
+        // a little skew is fine
+        let event_id = db
+            .receive_pdu(remote_pdu(now + 1000), &resolver, tolerance)
+            .await?;
+        assert!(db.get_pdu(room_id, &event_id).await?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn aliases_state_key_must_match_server() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(aliases_state_key_must_match_server_inner())
+            .unwrap();
+    }
+
+    async fn aliases_state_key_must_match_server_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!aliases:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(
+            1,
+            &alice,
+            Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: Some(false),
+                reason: None,
+                third_party_invite: None,
+            },
+            Some(alice.as_str()),
+            &resolver,
+        )
+        .await?;
+        let aliases = || Aliases {
+            aliases: Some(vec![String::from("#room:example.org")]),
+        };
+        let mismatched = room
+            .add(2, &alice, aliases(), Some("evil.org"), &resolver)
+            .await?;
+        let matching = room
+            .add(3, &alice, aliases(), Some("example.org"), &resolver)
+            .await?;
+
+        for (event_id, should_pass) in [(mismatched, false), (matching, true)].iter() {
+            let pdu = db.get_pdu(room_id, event_id).await?.unwrap();
+            assert_eq!(pdu.did_pass_auth(), *should_pass);
+        }
+        Ok(())
+    }
 }
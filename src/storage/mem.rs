@@ -1,7 +1,8 @@
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
 use serde_json::Value as JsonValue;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -13,52 +14,212 @@ use uuid::Uuid;
 
 use crate::{
     error::{Error, ErrorKind},
-    events::{ephemeral::Typing, pdu::StoredPdu, EventContent},
-    storage::{Batch, EventQuery, QueryType, Storage, StorageManager, UserProfile},
+    events::{ephemeral::Typing, pdu::StoredPdu, room::Membership, EventContent},
+    storage::{
+        push_batch, retain_latest_state, set_receipt_in, state_cache_key, user_matches_search,
+        AccountDataChanges, Batch, EventQuery, HandleLimit, HandlePermit, PasswordParams, Presence,
+        PresenceState, QueryType, StateMap, Storage, StorageManager, ToDeviceMessage, TokenInfo,
+        UserProfile,
+    },
     util::MatrixId,
 };
 
 struct MemStorage {
     rooms: HashMap<String, Room>,
     users: Vec<User>,
-    access_tokens: HashMap<Uuid, String>,
+    access_tokens: HashMap<Uuid, AccessTokenData>,
     batches: HashMap<String, Batch>,
-    txn_ids: HashMap<Uuid, HashSet<String>>,
+    /// (username, device_id) -> ids of the batches kept for that device, oldest first
+    device_batches: HashMap<(String, String), VecDeque<String>>,
+    /// (username, device_id) -> (scope, txn_id)
+    txn_ids: HashMap<(String, String), HashSet<(String, String)>>,
+    /// user_id -> room_id -> current membership
+    memberships: HashMap<String, HashMap<String, Membership>>,
+    /// rooms listed in the public room directory
+    public_rooms: HashSet<String>,
+    /// alias -> room id
+    room_aliases: HashMap<String, String>,
+    room_alias_creators: HashMap<String, MatrixId>,
+    /// room_id -> id -> events that aren't in the timeline yet because some of their prev events
+    /// haven't arrived. The ids are increasing, so a room's outliers are kept oldest first.
+    outliers: HashMap<String, BTreeMap<u64, StoredPdu>>,
+    /// room_id -> prev event id -> the ids of the outliers that are waiting for it
+    outlier_children: HashMap<String, HashMap<String, Vec<u64>>>,
+    /// The id of the next outlier to arrive.
+    next_outlier: u64,
+    /// state_cache_key -> the resolved state after those events
+    state_cache: HashMap<String, StateMap>,
+    /// (username, device_id) -> stream position -> message that the device hasn't acknowledged
+    to_device: HashMap<(String, String), BTreeMap<u64, ToDeviceMessage>>,
+    /// The position of the latest to-device message sent to anyone.
+    to_device_stream: u64,
+    /// (username, device_id) -> wakes anyone waiting for a message to be sent to the device
+    to_device_notify: HashMap<(String, String), Sender<()>>,
+    /// presence stream position -> username, for the latest presence that each user has set
+    presence_stream: BTreeMap<u64, String>,
+    /// Wakes anyone waiting for someone's presence to be set.
+    presence_notify: Sender<()>,
 }
 
 #[derive(Debug)]
 struct Room {
+    /// The timeline, which is only ever appended to, so an event's index is its stream ordering
     events: Vec<StoredPdu>,
+    /// The ids of everything in `events`
+    event_ids: HashSet<String>,
+    /// event_id -> the event as it was before it was redacted
+    unredacted: HashMap<String, StoredPdu>,
     ephemeral: HashMap<String, JsonValue>,
     typing: HashMap<MatrixId, Instant>,
     notify_send: Sender<()>,
 }
 
+#[derive(Debug)]
+struct AccessTokenData {
+    username: String,
+    device_id: String,
+    last_seen_ip: Option<String>,
+    last_seen_ts: Option<i64>,
+}
+
 #[derive(Debug)]
 struct User {
     username: String,
     password_hash: String,
     profile: UserProfile,
-    account_data: HashMap<String, JsonValue>,
+    /// event_type -> (stream position of the write, content)
+    account_data: HashMap<String, (u64, JsonValue)>,
+    /// room_id -> event_type -> (stream position of the write, content)
+    room_account_data: HashMap<String, HashMap<String, (u64, JsonValue)>>,
+    /// The stream position of the user's latest account data write.
+    account_data_stream: u64,
+    filters: HashMap<String, JsonValue>,
+    is_admin: bool,
+    deactivated: bool,
+    /// device_id -> display name
+    device_names: HashMap<String, String>,
+    /// device_id -> identity keys
+    device_keys: HashMap<String, JsonValue>,
+    /// device_id -> key_id -> unclaimed one-time key
+    one_time_keys: HashMap<String, BTreeMap<String, JsonValue>>,
+    presence: Option<Presence>,
 }
 
 pub struct MemStorageManager {
     storage: Arc<RwLock<MemStorage>>,
+    password_params: PasswordParams,
+    default_displayname_to_localpart: bool,
+    handle_limit: HandleLimit,
 }
 
 pub struct MemStorageHandle {
     inner: Arc<RwLock<MemStorage>>,
+    password_params: PasswordParams,
+    default_displayname_to_localpart: bool,
+    /// Held for as long as the handle is alive.
+    _permit: HandlePermit,
 }
 
 impl Room {
     fn new() -> Self {
         Room {
             events: Vec::new(),
+            event_ids: HashSet::new(),
+            unredacted: HashMap::new(),
             ephemeral: HashMap::new(),
             typing: Default::default(),
             notify_send: channel(1).0,
         }
     }
+
+    /// Whether the room was created by a create event that passed auth. Rooms where that isn't
+    /// the case only exist as far as storing their events goes.
+    fn has_valid_create(&self) -> bool {
+        match self.events.first() {
+            Some(pdu) => {
+                matches!(pdu.event_content(), EventContent::Create(_)) && pdu.did_pass_auth()
+            }
+            None => false,
+        }
+    }
+}
+
+impl MemStorage {
+    /// Whether the event can go in its room's timeline, because everything before it is there.
+    fn can_link(&self, pdu: &StoredPdu) -> bool {
+        match self.rooms.get(pdu.room_id()) {
+            Some(room) => pdu
+                .prev_events()
+                .iter()
+                .all(|prev| room.event_ids.contains(prev)),
+            None => false,
+        }
+    }
+
+    /// Keeps an event out of the timeline until everything before it is there.
+    fn add_outlier(&mut self, pdu: StoredPdu) {
+        let id = self.next_outlier;
+        self.next_outlier += 1;
+        let linked = self.rooms.get(pdu.room_id()).map(|room| &room.event_ids);
+        let children = self
+            .outlier_children
+            .entry(pdu.room_id().to_string())
+            .or_default();
+        for prev in pdu.prev_events() {
+            if !linked.map_or(false, |linked| linked.contains(prev)) {
+                children.entry(prev.clone()).or_default().push(id);
+            }
+        }
+        self.outliers
+            .entry(pdu.room_id().to_string())
+            .or_default()
+            .insert(id, pdu);
+    }
+
+    /// Appends an event to its room's timeline, which must exist. Returns the event's id.
+    fn link_pdu(&mut self, pdu: StoredPdu) -> String {
+        let event_id = pdu.event_id();
+        let room = self.rooms.get_mut(pdu.room_id()).unwrap();
+        room.event_ids.insert(event_id.clone());
+        let room_is_valid = room.has_valid_create();
+        if let EventContent::Member(content) = pdu.event_content() {
+            if pdu.did_pass_auth() && !pdu.soft_failed && room_is_valid {
+                self.memberships
+                    .entry(pdu.state_key().unwrap().to_string())
+                    .or_default()
+                    .insert(pdu.room_id().to_string(), content.membership.clone());
+            }
+        }
+        room.events.push(pdu);
+        event_id
+    }
+
+    /// Moves outliers into the timeline for as long as there are some that passed auth and whose
+    /// prev events are all there. Only the outliers that were waiting for an event which just
+    /// joined the timeline are looked at, starting with those waiting for `linked`.
+    fn promote_outliers(&mut self, room_id: &str, linked: String) {
+        let mut linked = VecDeque::from(vec![linked]);
+        while let Some(event_id) = linked.pop_front() {
+            let children = self
+                .outlier_children
+                .get_mut(room_id)
+                .and_then(|children| children.remove(&event_id))
+                .unwrap_or_default();
+            for id in children {
+                let pdu = match self.outliers.get(room_id).and_then(|o| o.get(&id)) {
+                    Some(pdu) if pdu.did_pass_auth() && self.can_link(pdu) => pdu.clone(),
+                    // it's still waiting for something else, or it was promoted already
+                    _ => continue,
+                };
+                let outliers = self.outliers.get_mut(room_id).unwrap();
+                outliers.remove(&id);
+                if outliers.is_empty() {
+                    self.outliers.remove(room_id);
+                }
+                linked.push_back(self.link_pdu(pdu));
+            }
+        }
+    }
 }
 
 impl MemStorageManager {
@@ -69,10 +230,44 @@ impl MemStorageManager {
                 users: Vec::new(),
                 access_tokens: HashMap::new(),
                 batches: HashMap::new(),
+                device_batches: HashMap::new(),
                 txn_ids: HashMap::new(),
+                memberships: HashMap::new(),
+                public_rooms: HashSet::new(),
+                room_aliases: HashMap::new(),
+                room_alias_creators: HashMap::new(),
+                outliers: HashMap::new(),
+                outlier_children: HashMap::new(),
+                next_outlier: 0,
+                state_cache: HashMap::new(),
+                to_device: HashMap::new(),
+                to_device_stream: 0,
+                to_device_notify: HashMap::new(),
+                presence_stream: BTreeMap::new(),
+                presence_notify: channel(1).0,
             })),
+            password_params: PasswordParams::default(),
+            default_displayname_to_localpart: false,
+            handle_limit: HandleLimit::default(),
         }
     }
+
+    pub fn with_password_params(mut self, params: PasswordParams) -> Self {
+        self.password_params = params;
+        self
+    }
+
+    /// Makes new users start out with their localpart as their display name.
+    pub fn with_default_displayname_to_localpart(mut self, enabled: bool) -> Self {
+        self.default_displayname_to_localpart = enabled;
+        self
+    }
+
+    /// Limits the number of storage handles that can be in use at once. See `HandleLimit`.
+    pub fn with_handle_limit(mut self, limit: usize) -> Self {
+        self.handle_limit = HandleLimit::new(limit);
+        self
+    }
 }
 
 #[async_trait]
@@ -80,52 +275,116 @@ impl StorageManager for MemStorageManager {
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error> {
         Ok(Box::new(MemStorageHandle {
             inner: Arc::clone(&self.storage),
+            password_params: self.password_params,
+            default_displayname_to_localpart: self.default_displayname_to_localpart,
+            _permit: self.handle_limit.acquire()?,
         }))
     }
+
+    async fn get_unlimited_handle(&self) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(MemStorageHandle {
+            inner: Arc::clone(&self.storage),
+            password_params: self.password_params,
+            default_displayname_to_localpart: self.default_displayname_to_localpart,
+            _permit: HandlePermit::default(),
+        }))
+    }
+}
+
+/// Strips the stream positions from a map of account data.
+fn without_positions(map: &HashMap<String, (u64, JsonValue)>) -> HashMap<String, JsonValue> {
+    map.iter()
+        .map(|(event_type, (_, content))| (event_type.clone(), content.clone()))
+        .collect()
 }
 
 #[async_trait]
 impl Storage for MemStorageHandle {
     async fn create_user(&self, username: &str, password: &str) -> Result<(), Error> {
-        let salt: [u8; 16] = rand::random();
-        let password_hash = argon2::hash_encoded(password.as_bytes(), &salt, &Default::default())?;
+        let password_hash = self.password_params.hash(password)?;
+        let displayname = if self.default_displayname_to_localpart {
+            Some(username.to_string())
+        } else {
+            None
+        };
         let mut db = self.inner.write().await;
         if db.users.iter().find(|u| u.username == username).is_some() {
             return Err(ErrorKind::UsernameTaken.into());
         }
         db.users.push(User {
             username: username.to_string(),
-            password_hash: password_hash.to_string(),
+            password_hash,
             profile: UserProfile {
                 avatar_url: None,
-                displayname: None,
+                displayname,
             },
             account_data: HashMap::new(),
+            room_account_data: HashMap::new(),
+            account_data_stream: 0,
+            filters: HashMap::new(),
+            is_admin: false,
+            deactivated: false,
+            device_names: HashMap::new(),
+            device_keys: HashMap::new(),
+            one_time_keys: HashMap::new(),
+            presence: None,
         });
         Ok(())
     }
 
+    async fn user_exists(&self, username: &str) -> Result<bool, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter().any(|u| u.username == username))
+    }
+
     async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error> {
         let db = self.inner.read().await;
         let user = db.users.iter().find(|u| u.username == username);
-        if let Some(user) = user {
+        let outdated = if let Some(user) = user.filter(|u| !u.deactivated) {
             match argon2::verify_encoded(&user.password_hash, password.as_bytes()) {
-                Ok(true) => Ok(true),
-                Ok(false) => Ok(false),
-                Err(_) => Ok(false),
+                Ok(true) => self.password_params.is_outdated(&user.password_hash),
+                Ok(false) => return Ok(false),
+                Err(_) => return Ok(false),
             }
         } else {
-            Ok(false)
+            return Ok(false);
+        };
+        drop(db);
+        if outdated {
+            self.set_password(username, password).await?;
         }
+        Ok(true)
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> Result<(), Error> {
+        let password_hash = self.password_params.hash(password)?;
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.password_hash = password_hash;
+        Ok(())
     }
 
-    async fn create_access_token(&self, username: &str, _device_id: &str) -> Result<Uuid, Error> {
+    async fn create_access_token(&self, username: &str, device_id: &str) -> Result<Uuid, Error> {
         let mut db = self.inner.write().await;
         let token = Uuid::new_v4();
-        if db.users.iter().find(|u| u.username == username).is_none() {
-            return Err(ErrorKind::UserNotFound.into());
+        match db.users.iter().find(|u| u.username == username) {
+            Some(user) if user.deactivated => return Err(ErrorKind::Forbidden.into()),
+            Some(_) => {}
+            None => return Err(ErrorKind::UserNotFound.into()),
         }
-        db.access_tokens.insert(token, username.to_string());
+        db.access_tokens.insert(
+            token,
+            AccessTokenData {
+                username: username.to_string(),
+                device_id: device_id.to_string(),
+                last_seen_ip: None,
+                last_seen_ts: None,
+            },
+        );
         Ok(token)
     }
 
@@ -138,22 +397,264 @@ impl Storage for MemStorageHandle {
     async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         let username = match db.access_tokens.get(&token) {
-            Some(v) => v.clone(),
+            Some(v) => v.username.clone(),
             None => return Ok(()),
         };
-        db.access_tokens.retain(|_token, name| *name != username);
+        db.access_tokens
+            .retain(|_token, data| data.username != username);
         Ok(())
     }
 
-    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
+    async fn try_auth_full(&self, token: Uuid) -> Result<Option<(String, String)>, Error> {
         let db = self.inner.read().await;
-        Ok(db.access_tokens.get(&token).cloned())
+        Ok(db
+            .access_tokens
+            .get(&token)
+            .map(|data| (data.username.clone(), data.device_id.clone())))
     }
 
-    async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error> {
+    async fn update_token_last_seen(
+        &self,
+        token: Uuid,
+        ip: Option<&str>,
+        ts: i64,
+    ) -> Result<(), Error> {
         let mut db = self.inner.write().await;
-        let set = db.txn_ids.entry(token).or_insert_with(HashSet::new);
-        Ok(set.insert(txn_id))
+        if let Some(data) = db.access_tokens.get_mut(&token) {
+            data.last_seen_ip = ip.map(String::from);
+            data.last_seen_ts = Some(ts);
+        }
+        Ok(())
+    }
+
+    async fn get_tokens_for_user(&self, username: &str) -> Result<Vec<TokenInfo>, Error> {
+        let db = self.inner.read().await;
+        Ok(db
+            .access_tokens
+            .iter()
+            .filter(|(_, data)| data.username == username)
+            .map(|(token, data)| {
+                TokenInfo::new(
+                    *token,
+                    data.device_id.clone(),
+                    data.last_seen_ip.clone(),
+                    data.last_seen_ts,
+                )
+            })
+            .collect())
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.access_tokens
+            .retain(|_token, data| data.username != username || data.device_id != device_id);
+        if let Some(user) = db.users.iter_mut().find(|u| u.username == username) {
+            user.device_names.remove(device_id);
+            user.device_keys.remove(device_id);
+            user.one_time_keys.remove(device_id);
+        }
+        db.to_device
+            .remove(&(username.to_string(), device_id.to_string()));
+        Ok(())
+    }
+
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: &str,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.device_names
+            .insert(device_id.to_string(), display_name.to_string());
+        Ok(())
+    }
+
+    async fn get_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.device_names.get(device_id))
+            .cloned())
+    }
+
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.device_keys.insert(device_id.to_string(), keys);
+        Ok(())
+    }
+
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error> {
+        let db = self.inner.read().await;
+        Ok(db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .map(|u| u.device_keys.clone())
+            .unwrap_or_default())
+    }
+
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.one_time_keys
+            .entry(device_id.to_string())
+            .or_default()
+            .extend(keys);
+        Ok(())
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error> {
+        let db = self.inner.read().await;
+        let mut counts = HashMap::new();
+        let keys = db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.one_time_keys.get(device_id));
+        for key_id in keys.into_iter().flat_map(BTreeMap::keys) {
+            let algorithm = key_id.split(':').next().unwrap();
+            *counts.entry(algorithm.to_string()).or_default() += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error> {
+        let mut db = self.inner.write().await;
+        let keys = match db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .and_then(|u| u.one_time_keys.get_mut(device_id))
+        {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let prefix = format!("{}:", algorithm);
+        let key_id = match keys.keys().find(|key_id| key_id.starts_with(&prefix)) {
+            Some(key_id) => key_id.clone(),
+            None => return Ok(None),
+        };
+        Ok(keys.remove_entry(&key_id))
+    }
+
+    async fn send_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        message: ToDeviceMessage,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.to_device_stream += 1;
+        let position = db.to_device_stream;
+        let key = (username.to_string(), device_id.to_string());
+        if let Some(notify_send) = db.to_device_notify.get(&key) {
+            // fails if nobody is waiting, which is fine
+            let _ = notify_send.send(());
+        }
+        db.to_device
+            .entry(key)
+            .or_default()
+            .insert(position, message);
+        Ok(())
+    }
+
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(Vec<ToDeviceMessage>, u64), Error> {
+        let mut db = self.inner.write().await;
+        let queue = match db
+            .to_device
+            .get_mut(&(username.to_string(), device_id.to_string()))
+        {
+            Some(queue) => queue,
+            None => return Ok((Vec::new(), since)),
+        };
+        // everything after `since` is still pending
+        let pending = queue.split_off(&(since + 1));
+        *queue = pending;
+        let position = queue.keys().last().copied().unwrap_or(since);
+        Ok((queue.values().cloned().collect(), position))
+    }
+
+    async fn wait_for_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(), Error> {
+        let mut recv = {
+            let mut db = self.inner.write().await;
+            let key = (username.to_string(), device_id.to_string());
+            let last = db.to_device.get(&key).and_then(|queue| queue.keys().last());
+            if last.map_or(false, |&position| position > since) {
+                return Ok(());
+            }
+            db.to_device_notify
+                .entry(key)
+                .or_insert_with(|| channel(1).0)
+                .subscribe()
+        };
+        // an error means that messages were missed, which is something happening anyway
+        let _ = recv.recv().await;
+        Ok(())
+    }
+
+    async fn record_txn(
+        &self,
+        username: &str,
+        device_id: &str,
+        scope: &str,
+        txn_id: String,
+    ) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        let set = db
+            .txn_ids
+            .entry((username.to_string(), device_id.to_string()))
+            .or_insert_with(HashSet::new);
+        Ok(set.insert((scope.to_string(), txn_id)))
     }
 
     async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error> {
@@ -165,25 +666,155 @@ impl Storage for MemStorageHandle {
             .map(|u| u.profile.clone()))
     }
 
-    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error> {
+    async fn search_users(
+        &self,
+        term: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, UserProfile)>, Error> {
+        let db = self.inner.read().await;
+        let term = term.to_lowercase();
+        let mut users = db
+            .users
+            .iter()
+            .filter(|u| !u.deactivated && user_matches_search(&term, &u.username, &u.profile))
+            .map(|u| (u.username.clone(), u.profile.clone()))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users.truncate(limit.unwrap_or(usize::MAX));
+        Ok(users)
+    }
+
+    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<bool, Error> {
         let mut db = self.inner.write().await;
         let user = db
             .users
             .iter_mut()
             .find(|u| u.username == username)
             .ok_or(ErrorKind::UserNotFound)?;
+        if user.profile.avatar_url.as_deref() == Some(avatar_url) {
+            return Ok(false);
+        }
         user.profile.avatar_url = Some(avatar_url.to_string());
-        Ok(())
+        Ok(true)
     }
 
-    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<(), Error> {
+    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<bool, Error> {
         let mut db = self.inner.write().await;
         let user = db
             .users
             .iter_mut()
             .find(|u| u.username == username)
             .ok_or(ErrorKind::UserNotFound)?;
+        if user.profile.displayname.as_deref() == Some(display_name) {
+            return Ok(false);
+        }
         user.profile.displayname = Some(display_name.to_string());
+        Ok(true)
+    }
+
+    async fn set_presence(
+        &self,
+        username: &str,
+        state: PresenceState,
+        status_msg: Option<String>,
+        ts: i64,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        // positions only ever go up, so the last one is the latest
+        let position = db.presence_stream.keys().last().map_or(0, |p| *p) + 1;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        let old = user.presence.replace(Presence {
+            state,
+            status_msg,
+            last_active_ts: ts,
+            position,
+        });
+        if let Some(old) = old {
+            db.presence_stream.remove(&old.position);
+        }
+        db.presence_stream.insert(position, username.to_string());
+        // fails if nobody is waiting, which is fine
+        let _ = db.presence_notify.send(());
+        Ok(())
+    }
+
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error> {
+        let db = self.inner.read().await;
+        Ok(db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.presence.clone()))
+    }
+
+    async fn get_presence_changes(
+        &self,
+        since: u64,
+    ) -> Result<(Vec<(String, Presence)>, u64), Error> {
+        let db = self.inner.read().await;
+        let mut changes = Vec::new();
+        let mut position = since;
+        for (&user_position, username) in db.presence_stream.range(since + 1..) {
+            let user = db.users.iter().find(|u| &u.username == username).unwrap();
+            changes.push((username.clone(), user.presence.clone().unwrap()));
+            position = user_position;
+        }
+        Ok((changes, position))
+    }
+
+    async fn wait_for_presence(&self, since: u64) -> Result<(), Error> {
+        let mut recv = {
+            let db = self.inner.read().await;
+            if db
+                .presence_stream
+                .keys()
+                .last()
+                .map_or(false, |&p| p > since)
+            {
+                return Ok(());
+            }
+            db.presence_notify.subscribe()
+        };
+        // an error means that changes were missed, which is something happening anyway
+        let _ = recv.recv().await;
+        Ok(())
+    }
+
+    async fn is_admin(&self, username: &str) -> Result<bool, Error> {
+        let db = self.inner.read().await;
+        let user = db.users.iter().find(|u| u.username == username);
+        Ok(matches!(user, Some(u) if u.is_admin))
+    }
+
+    async fn set_admin(&self, username: &str, is_admin: bool) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.is_admin = is_admin;
+        Ok(())
+    }
+
+    async fn deactivate_user(&self, username: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.deactivated = true;
+        user.device_names.clear();
+        user.device_keys.clear();
+        user.one_time_keys.clear();
+        db.access_tokens
+            .retain(|_token, data| data.username != username);
+        db.to_device.retain(|(user, _device), _| user != username);
         Ok(())
     }
 
@@ -194,22 +825,39 @@ impl Storage for MemStorageHandle {
                 EventContent::Create(_) => {
                     db.rooms.insert(pdu.room_id().to_string(), Room::new());
                 }
+                _ if !db.can_link(pdu) => {
+                    db.add_outlier(pdu.clone());
+                    continue;
+                }
                 _ => {}
             }
-            db.rooms
-                .get_mut(pdu.room_id())
-                .ok_or(ErrorKind::RoomNotFound)?
-                .events
-                .push(pdu.clone());
+            let event_id = db.link_pdu(pdu.clone());
+            db.promote_outliers(pdu.room_id(), event_id);
         }
         Ok(())
     }
 
+    async fn get_outliers(&self, room_id: &str) -> Result<Vec<StoredPdu>, Error> {
+        let db = self.inner.read().await;
+        let outliers = db
+            .outliers
+            .get(room_id)
+            .into_iter()
+            .flat_map(BTreeMap::values);
+        Ok(outliers.cloned().collect())
+    }
+
     async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error> {
         let db = self.inner.read().await;
         let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
-        let mut prev_events = room.events.clone();
-        for event in room.events.iter() {
+        // soft failed events aren't built on, so they leave the forward extremities alone
+        let mut prev_events = room
+            .events
+            .iter()
+            .filter(|pdu| !pdu.soft_failed)
+            .cloned()
+            .collect::<Vec<_>>();
+        for event in room.events.iter().filter(|pdu| !pdu.soft_failed) {
             for prev in event.prev_events() {
                 prev_events.retain(|pdu| pdu.event_id() != *prev);
             }
@@ -226,11 +874,11 @@ impl Storage for MemStorageHandle {
         Ok((event_ids, max_depth))
     }
 
-    async fn query_pdus<'a>(
+    async fn query_ordered_pdus<'a>(
         &self,
         query: EventQuery<'a>,
         wait: bool,
-    ) -> Result<(Vec<StoredPdu>, usize), Error> {
+    ) -> Result<(Vec<(usize, StoredPdu)>, usize), Error> {
         let mut ret = Vec::new();
         let (mut from, mut to) = match &query.query_type {
             &QueryType::Timeline { from, to } => (from, to),
@@ -239,16 +887,16 @@ impl Storage for MemStorageHandle {
 
         let db = self.inner.read().await;
         let room = db.rooms.get(query.room_id).ok_or(ErrorKind::RoomNotFound)?;
-        if let None = to {
-            to = Some(room.events.len() - 1);
-        }
+        // clamp to the end of the timeline, so that out of range queries just return nothing
+        let last = room.events.len().saturating_sub(1);
+        to = Some(to.map_or(last, |to| to.min(last)));
 
         if let Some(range) = room.events.get(from..=to.unwrap()) {
             ret.extend(
-                range
-                    .iter()
-                    .filter(|pdu| query.matches(&pdu.inner()))
-                    .cloned(),
+                (from..)
+                    .zip(range)
+                    .filter(|(_, pdu)| query.matches(pdu))
+                    .map(|(ordering, pdu)| (ordering, pdu.clone())),
             );
         }
 
@@ -264,52 +912,229 @@ impl Storage for MemStorageHandle {
             from = to.unwrap();
             to = None;
         } else {
+            if query.query_type.is_state() {
+                retain_latest_state(&mut ret);
+            }
             return Ok((ret, to.unwrap()));
         }
 
         // same again
         let db = self.inner.read().await;
         let room = db.rooms.get(query.room_id).ok_or(ErrorKind::RoomNotFound)?;
-        if let None = to {
-            to = Some(room.events.len() - 1);
-        }
+        let last = room.events.len().saturating_sub(1);
+        to = Some(to.map_or(last, |to| to.min(last)));
 
         if let Some(range) = room.events.get(from..=to.unwrap()) {
             ret.extend(
-                range
-                    .iter()
-                    .filter(|pdu| query.matches(&pdu.inner()))
-                    .cloned(),
+                (from..)
+                    .zip(range)
+                    .filter(|(_, pdu)| query.matches(pdu))
+                    .map(|(ordering, pdu)| (ordering, pdu.clone())),
             );
         }
 
-        if query.query_type.is_state() {
-            ret.reverse();
-            /*            let mut seen = HashSet::new();
-            // remove pdus that are older than another pdu with the same state key
-            ret.retain(|pdu| {
-                seen.insert(pdu.state_key().to_string().unwrap())
-            });*/
-            ret.reverse();
-        }
-
         Ok((ret, to.unwrap()))
     }
 
-    async fn get_rooms(&self) -> Result<Vec<String>, Error> {
+    async fn get_stream_ordering(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<usize>, Error> {
         let db = self.inner.read().await;
-        Ok(db.rooms.keys().cloned().collect())
+        let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        Ok(room
+            .events
+            .iter()
+            .position(|pdu| pdu.event_id() == event_id))
     }
 
-    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
+    async fn stream_room_events(
+        &self,
+        room_id: &str,
+    ) -> Result<BoxStream<'static, Result<StoredPdu, Error>>, Error> {
         let db = self.inner.read().await;
-        let event = db
+        let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        // nothing is saved by streaming from memory, and the lock can't be held onto
+        Ok(stream::iter(room.events.clone().into_iter().map(Ok)).boxed())
+    }
+
+    async fn get_rooms(&self) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db
             .rooms
-            .get(room_id)
-            .map(|r| r.events.iter().find(|e| e.event_id() == event_id))
+            .iter()
+            .filter(|(_, room)| room.has_valid_create())
+            .map(|(room_id, _)| room_id.clone())
+            .collect())
+    }
+
+    async fn set_room_public(&self, room_id: &str, public: bool) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        match db.rooms.get(room_id) {
+            Some(room) if room.has_valid_create() => {}
+            _ => return Err(ErrorKind::RoomNotFound.into()),
+        }
+        if public {
+            db.public_rooms.insert(room_id.to_string());
+        } else {
+            db.public_rooms.remove(room_id);
+        }
+        Ok(())
+    }
+
+    async fn get_public_rooms(&self) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.public_rooms.iter().cloned().collect())
+    }
+
+    async fn set_room_alias(
+        &self,
+        alias: &str,
+        room_id: &str,
+        creator: &MatrixId,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        match db.rooms.get(room_id) {
+            Some(room) if room.has_valid_create() => {}
+            _ => return Err(ErrorKind::RoomNotFound.into()),
+        }
+        if db.room_aliases.contains_key(alias) {
+            return Err(ErrorKind::RoomInUse.into());
+        }
+        db.room_aliases
+            .insert(alias.to_string(), room_id.to_string());
+        db.room_alias_creators
+            .insert(alias.to_string(), creator.clone());
+        Ok(())
+    }
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.room_aliases.get(alias).cloned())
+    }
+
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<MatrixId>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.room_alias_creators.get(alias).cloned())
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        db.room_alias_creators.remove(alias);
+        Ok(db.room_aliases.remove(alias).is_some())
+    }
+
+    async fn get_local_aliases(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        let aliases = db
+            .room_aliases
+            .iter()
+            .filter(|(_, id)| *id == room_id)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        Ok(aliases)
+    }
+
+    async fn get_invited_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        let rooms = db
+            .memberships
+            .get(user_id.as_str())
+            .into_iter()
             .flatten()
-            .cloned();
-        Ok(event)
+            .filter(|(_, membership)| **membership == Membership::Invite)
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        Ok(rooms)
+    }
+
+    async fn get_joined_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        let rooms = db
+            .memberships
+            .get(user_id.as_str())
+            .into_iter()
+            .flatten()
+            .filter(|(_, membership)| **membership == Membership::Join)
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        Ok(rooms)
+    }
+
+    async fn count_joined_rooms(&self, user_id: &MatrixId) -> Result<usize, Error> {
+        let db = self.inner.read().await;
+        let count = db
+            .memberships
+            .get(user_id.as_str())
+            .into_iter()
+            .flatten()
+            .filter(|(_, membership)| **membership == Membership::Join)
+            .count();
+        Ok(count)
+    }
+
+    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
+        let db = self.inner.read().await;
+        let event = match (db.rooms.get(room_id), db.outliers.get(room_id)) {
+            (Some(room), _) => room.events.iter().find(|e| e.event_id() == event_id),
+            (None, Some(outliers)) => outliers.values().find(|e| e.event_id() == event_id),
+            (None, None) => return Err(ErrorKind::RoomNotFound.into()),
+        };
+        Ok(event.cloned())
+    }
+
+    async fn get_pdus(
+        &self,
+        room_id: &str,
+        event_ids: &[String],
+    ) -> Result<Vec<Option<StoredPdu>>, Error> {
+        let db = self.inner.read().await;
+        let events: Box<dyn Iterator<Item = &StoredPdu>> =
+            match (db.rooms.get(room_id), db.outliers.get(room_id)) {
+                (Some(room), _) => Box::new(room.events.iter()),
+                (None, Some(outliers)) => Box::new(outliers.values()),
+                (None, None) => return Err(ErrorKind::RoomNotFound.into()),
+            };
+        // one pass over the room, rather than one per event
+        let wanted = event_ids.iter().map(String::as_str).collect::<HashSet<_>>();
+        let found = events
+            .map(|e| (e.event_id(), e))
+            .filter(|(event_id, _)| wanted.contains(event_id.as_str()))
+            .collect::<HashMap<_, _>>();
+        Ok(event_ids
+            .iter()
+            .map(|event_id| found.get(event_id).map(|e| (*e).clone()))
+            .collect())
+    }
+
+    async fn redact_pdu(&self, room_id: &str, event_id: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        if let Some(pdu) = room.events.iter_mut().find(|e| e.event_id() == event_id) {
+            room.unredacted
+                .entry(event_id.to_string())
+                .or_insert_with(|| pdu.clone());
+            *pdu = pdu.clone().redact();
+        }
+        Ok(())
+    }
+
+    async fn get_unredacted_pdu(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<StoredPdu>, Error> {
+        let db = self.inner.read().await;
+        let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        match room.unredacted.get(event_id) {
+            Some(pdu) => Ok(Some(pdu.clone())),
+            None => Ok(room
+                .events
+                .iter()
+                .find(|e| e.event_id() == event_id)
+                .cloned()),
+        }
     }
 
     async fn get_all_ephemeral(&self, room_id: &str) -> Result<HashMap<String, JsonValue>, Error> {
@@ -367,6 +1192,20 @@ impl Storage for MemStorageHandle {
         Ok(())
     }
 
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: &str,
+        event_id: &str,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        set_receipt_in(&mut room.ephemeral, user_id, receipt_type, event_id)?;
+        let _ = room.notify_send.send(());
+        Ok(())
+    }
+
     async fn set_typing(
         &self,
         room_id: &str,
@@ -398,19 +1237,173 @@ impl Storage for MemStorageHandle {
             .users
             .iter()
             .find(|u| u.username == username)
-            .map(|u| u.account_data.clone())
+            .map(|u| without_positions(&u.account_data))
             .unwrap_or(HashMap::new());
         Ok(map)
     }
 
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.account_data_stream += 1;
+        user.account_data
+            .insert(event_type.to_string(), (user.account_data_stream, content));
+        Ok(())
+    }
+
+    async fn get_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let db = self.inner.read().await;
+        let map = db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.room_account_data.get(room_id))
+            .map(without_positions)
+            .unwrap_or_default();
+        Ok(map)
+    }
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.account_data_stream += 1;
+        user.room_account_data
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(event_type.to_string(), (user.account_data_stream, content));
+        Ok(())
+    }
+
+    async fn get_account_data(
+        &self,
+        username: &str,
+        since: u64,
+    ) -> Result<AccountDataChanges, Error> {
+        let db = self.inner.read().await;
+        let user = match db.users.iter().find(|u| u.username == username) {
+            Some(user) => user,
+            None => return Ok(AccountDataChanges::default()),
+        };
+        let changed = |map: &HashMap<String, (u64, JsonValue)>| {
+            map.iter()
+                .filter(|(_, (position, _))| *position > since)
+                .map(|(event_type, (_, content))| (event_type.clone(), content.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        Ok(AccountDataChanges {
+            position: user.account_data_stream,
+            global: changed(&user.account_data),
+            rooms: user
+                .room_account_data
+                .iter()
+                .map(|(room_id, map)| (room_id.clone(), changed(map)))
+                .filter(|(_, map)| !map.is_empty())
+                .collect(),
+        })
+    }
+
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        let filter_id = format!("{:x}", rand::random::<u64>());
+        user.filters.insert(filter_id.clone(), filter);
+        Ok(filter_id)
+    }
+
+    async fn get_filter(
+        &self,
+        username: &str,
+        filter_id: &str,
+    ) -> Result<Option<JsonValue>, Error> {
+        let db = self.inner.read().await;
+        let filter = db
+            .users
+            .iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.filters.get(filter_id).cloned());
+        Ok(filter)
+    }
+
     async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error> {
         let db = self.inner.read().await;
         Ok(db.batches.get(id).cloned())
     }
 
-    async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error> {
+    async fn set_batch(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Option<&str>,
+        id: &str,
+        batch: Batch,
+    ) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         let _ = db.batches.insert(String::from(id), batch);
+        let ids = db
+            .device_batches
+            .entry((username.to_string(), device_id.to_string()))
+            .or_insert_with(VecDeque::new);
+        let evicted = push_batch(ids, since, id);
+        for id in evicted {
+            db.batches.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn get_cached_state(&self, event_ids: &[String]) -> Result<Option<StateMap>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.state_cache.get(&state_cache_key(event_ids)).cloned())
+    }
+
+    async fn set_cached_state(&self, event_ids: &[String], state: StateMap) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.state_cache.insert(state_cache_key(event_ids), state);
+        Ok(())
+    }
+
+    async fn get_cached_states(
+        &self,
+        keys: &[Vec<String>],
+    ) -> Result<Vec<Option<StateMap>>, Error> {
+        let db = self.inner.read().await;
+        Ok(keys
+            .iter()
+            .map(|event_ids| db.state_cache.get(&state_cache_key(event_ids)).cloned())
+            .collect())
+    }
+
+    async fn set_cached_states(&self, states: Vec<(Vec<String>, StateMap)>) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        for (event_ids, state) in states {
+            db.state_cache.insert(state_cache_key(&event_ids), state);
+        }
         Ok(())
     }
 
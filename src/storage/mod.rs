@@ -1,16 +1,33 @@
 use async_trait::async_trait;
 use enum_extract::extract;
+use futures::stream::{BoxStream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use uuid::Uuid;
 
 use crate::{
-    error::Error,
-    events::{pdu::StoredPdu, room::Membership, room_version::VersionedPdu, Event, EventContent},
-    util::MatrixId,
+    error::{Error, ErrorKind},
+    events::{
+        ephemeral::Receipts,
+        pdu::StoredPdu,
+        room::{Membership, PowerLevels},
+        Event, EventContent,
+    },
+    state::StateResolver,
+    util::{
+        push_rules::{self, default_push_rules, PushContext, PUSH_RULES},
+        MatrixId,
+    },
 };
 
+/// (event_type, state_key) -> event_id
+pub type StateMap = HashMap<(String, String), String>;
+
 #[cfg(feature = "storage-mem")]
 pub mod mem;
 #[cfg(feature = "storage-postgres")]
@@ -18,12 +35,151 @@ pub mod postgres;
 #[cfg(feature = "storage-sled")]
 pub mod sled;
 
+/// The ephemeral event type under which private read receipts are stored. It's never sent to
+/// clients as is.
+const PRIVATE_RECEIPTS: &str = "kerux.receipt.private";
+
+/// The most sync batches that are kept for each device. Batches from before the one that a device
+/// last synced from are evicted anyway, since it has moved past them, so this only limits how many
+/// a client can pile up by retrying a sync whose responses keep getting lost.
+pub const BATCHES_PER_DEVICE: usize = 8;
+
+/// Adds a new batch to a device's batches, which are kept oldest first, and returns the ones to
+/// evict: those before `since`, which the device synced from, and then the oldest ones after it
+/// if there are too many. `since` itself is kept, in case this response is lost too.
+fn push_batch(ids: &mut VecDeque<String>, since: Option<&str>, id: &str) -> Vec<String> {
+    let mut evicted = Vec::new();
+    if let Some(pos) = since.and_then(|since| ids.iter().position(|b| b == since)) {
+        evicted.extend(ids.drain(..pos));
+    }
+    ids.push_back(String::from(id));
+    while ids.len() > BATCHES_PER_DEVICE {
+        let oldest = if ids.front().map(String::as_str) == since {
+            1
+        } else {
+            0
+        };
+        evicted.extend(ids.remove(oldest));
+    }
+    evicted
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserProfile {
     pub avatar_url: Option<String>,
     pub displayname: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Online,
+    Unavailable,
+    Offline,
+}
+
+/// What a user last said about whether they're around.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Presence {
+    pub state: PresenceState,
+    pub status_msg: Option<String>,
+    /// When the presence was set, in milliseconds since the unix epoch.
+    pub last_active_ts: i64,
+    /// The position in the presence stream that the presence was set at.
+    pub position: u64,
+}
+
+/// The argon2 parameters that passwords are hashed with. Raising them makes new hashes harder to
+/// crack, and old hashes are upgraded when their users next log in.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct PasswordParams {
+    /// How much memory hashing takes, in KiB.
+    pub memory_cost: u32,
+    /// How many passes are made over the memory.
+    pub time_cost: u32,
+    /// How many lanes the memory is split into.
+    pub parallelism: u32,
+}
+
+impl Default for PasswordParams {
+    fn default() -> Self {
+        // the same as argon2::Config::default()
+        PasswordParams {
+            memory_cost: 4096,
+            time_cost: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl PasswordParams {
+    pub fn hash(&self, password: &str) -> Result<String, Error> {
+        let salt: [u8; 16] = rand::random();
+        let config = argon2::Config {
+            mem_cost: self.memory_cost,
+            time_cost: self.time_cost,
+            lanes: self.parallelism,
+            ..Default::default()
+        };
+        Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
+    }
+
+    /// Whether an encoded hash was made with different parameters than these, in which case it
+    /// should be replaced once the password is known.
+    pub fn is_outdated(&self, encoded_hash: &str) -> bool {
+        // e.g. $argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$llvUdqp69y...
+        let prefix = format!(
+            "$argon2i$v=19$m={},t={},p={}$",
+            self.memory_cost, self.time_cost, self.parallelism
+        );
+        !encoded_hash.starts_with(&prefix)
+    }
+}
+
+/// A device that a user is logged in on, as shown in their device list.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Device {
+    pub device_id: String,
+    /// A name for the device that the user chose, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// The IP address from which the device was last used, if known.
+    pub last_seen_ip: Option<String>,
+    /// When the device was last used, in milliseconds since the unix epoch.
+    pub last_seen_ts: Option<i64>,
+}
+
+/// An access token that a user has, without the token itself.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TokenInfo {
+    /// The SHA-256 hash of the token, in unpadded base64, which tells tokens apart without being
+    /// usable as one.
+    pub token_id: String,
+    pub device_id: String,
+    /// The IP address from which the token was last used, if known.
+    pub last_seen_ip: Option<String>,
+    /// When the token was last used, in milliseconds since the unix epoch.
+    pub last_seen_ts: Option<i64>,
+}
+
+impl TokenInfo {
+    fn new(
+        token: Uuid,
+        device_id: String,
+        last_seen_ip: Option<String>,
+        last_seen_ts: Option<i64>,
+    ) -> Self {
+        let hash = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+        TokenInfo {
+            token_id: base64::encode_config(hash.as_ref(), base64::STANDARD_NO_PAD),
+            device_id,
+            last_seen_ip,
+            last_seen_ts,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EventQuery<'a> {
     pub query_type: QueryType<'a>,
@@ -44,6 +200,8 @@ pub struct EventQuery<'a> {
     pub not_types: &'a [&'a str],
     /// Only return results whose content fields have identical values to those in here.
     pub contains_json: Option<JsonValue>,
+    /// Whether to return soft failed events, which clients never get to see.
+    pub include_soft_failed: bool,
 }
 
 #[derive(Clone)]
@@ -53,6 +211,8 @@ pub enum QueryType<'a> {
     /// State queries return all of the most recent state events with unique (type, state_key)
     /// pairs, from a given point in time. This represents the full state of the room at that time.
     State {
+        /// The point in time to get the state at. `None`, or anything past the end of the room,
+        /// means the current state.
         at: Option<usize>,
         /// A list of state keys to include in the result. If the list is empty all keys are
         /// included.
@@ -65,7 +225,12 @@ pub enum QueryType<'a> {
 }
 
 impl<'a> EventQuery<'a> {
-    pub fn matches(&self, pdu: &VersionedPdu) -> bool {
+    pub fn matches(&self, pdu: &StoredPdu) -> bool {
+        if pdu.soft_failed && !self.include_soft_failed {
+            return false;
+        }
+        let pdu = pdu.inner();
+
         // We don't have access to the event's ordering in storage, so we can't test whether it
         // exists within the bounds specified in Timeline/State. Therefore we just assume it does.
         match self.query_type {
@@ -103,11 +268,9 @@ impl<'a> EventQuery<'a> {
         }
 
         if let Some(ref value) = self.contains_json {
-            let map = value.as_object().expect("contains_json must be an object");
-            for (key, value) in map.iter() {
-                if pdu.event_content().content_as_json().get(key) != Some(value) {
-                    return false;
-                }
+            assert!(value.is_object(), "contains_json must be an object");
+            if !json_contains(&pdu.event_content().content_as_json(), value) {
+                return false;
             }
         }
 
@@ -115,6 +278,75 @@ impl<'a> EventQuery<'a> {
     }
 }
 
+/// Returns whether `value` has everything in `pattern`. Objects match if each of the pattern's
+/// keys is present and matches in turn, so the value can have extra keys at any depth; anything
+/// else has to be equal.
+fn json_contains(value: &JsonValue, pattern: &JsonValue) -> bool {
+    match (value, pattern) {
+        (JsonValue::Object(value), JsonValue::Object(pattern)) => {
+            pattern.iter().all(|(key, pattern)| match value.get(key) {
+                Some(value) => json_contains(value, pattern),
+                None => false,
+            })
+        }
+        _ => value == pattern,
+    }
+}
+
+/// Whether a user directory search for `term`, which has to be lowercase already, finds the user.
+fn user_matches_search(term: &str, username: &str, profile: &UserProfile) -> bool {
+    username.to_lowercase().contains(term)
+        || matches!(&profile.displayname, Some(name) if name.to_lowercase().contains(term))
+}
+
+/// Rebuilds the membership index (user_id -> room_id -> current membership) from the events of
+/// every room, for databases that were created before the index was kept. As when it is kept up
+/// to date, only member events that passed auth count.
+pub async fn build_membership_index(
+    db: &dyn Storage,
+) -> Result<HashMap<String, HashMap<String, Membership>>, Error> {
+    let mut index: HashMap<String, HashMap<String, Membership>> = HashMap::new();
+    for room_id in db.get_rooms().await? {
+        let mut events = db.stream_room_events(&room_id).await?;
+        while let Some(pdu) = events.try_next().await? {
+            if let EventContent::Member(content) = pdu.event_content() {
+                if pdu.did_pass_auth() && !pdu.soft_failed {
+                    index
+                        .entry(pdu.state_key().unwrap().to_string())
+                        .or_default()
+                        .insert(room_id.clone(), content.membership.clone());
+                }
+            }
+        }
+    }
+    Ok(index)
+}
+
+/// The key that the state after a set of events is cached under: a hash of the sorted event IDs,
+/// so that it doesn't depend on their order.
+fn state_cache_key(event_ids: &[String]) -> String {
+    let mut event_ids = event_ids.iter().map(String::as_str).collect::<Vec<_>>();
+    event_ids.sort_unstable();
+    event_ids.dedup();
+    let hash = ring::digest::digest(&ring::digest::SHA256, event_ids.join("\0").as_bytes());
+    base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Narrows down the state events matched by a state query, oldest first, to the latest event for
+/// each (type, state_key) pair, which is what the query should return.
+fn retain_latest_state(pdus: &mut Vec<(usize, StoredPdu)>) {
+    let mut seen = HashSet::new();
+    pdus.reverse();
+    pdus.retain(|(_, pdu)| {
+        let key = (
+            pdu.event_content().get_type().to_string(),
+            pdu.state_key().map(String::from),
+        );
+        seen.insert(key)
+    });
+    pdus.reverse();
+}
+
 impl<'a> QueryType<'a> {
     pub fn is_timeline(&self) -> bool {
         match self {
@@ -137,19 +369,128 @@ pub struct Batch {
     pub rooms: HashMap<String, usize>,
     /// A set of rooms to which the user has been invited, where they are already aware of this.
     pub invites: HashSet<String>,
+    /// The position in the user's account data stream that the client has seen up to.
+    #[serde(default)]
+    pub account_data: u64,
+    /// The position of the last to-device message that the device was sent.
+    #[serde(default)]
+    pub to_device: u64,
+    /// The position in the presence stream that the client has seen up to.
+    #[serde(default)]
+    pub presence_position: u64,
+}
+
+/// A user's unread notification counts in a room. They only cover the events up to `to`, so the
+/// ones after it can be counted on top, as long as the user hasn't read any further since.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NotificationCounts {
+    /// The stream ordering that counting started from, just after the user's read receipt or
+    /// their latest membership event.
+    pub from: usize,
+    /// The stream ordering of the first event that hasn't been counted.
+    pub to: usize,
+    pub notifications: usize,
+    pub highlights: usize,
+}
+
+/// A message sent straight to one of a user's devices rather than to a room, like the keys for
+/// an encrypted room.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToDeviceMessage {
+    pub sender: MatrixId,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub content: JsonValue,
+}
+
+/// Account data that was written after some position in a user's account data stream.
+#[derive(Debug, Default)]
+pub struct AccountDataChanges {
+    /// The position of the latest write, which the next query should start from.
+    pub position: u64,
+    pub global: HashMap<String, JsonValue>,
+    /// room_id -> event_type -> content
+    pub rooms: HashMap<String, HashMap<String, JsonValue>>,
+}
+
+/// Sets a user's read receipt in a room's ephemeral data, for `Storage::set_receipt`. Backends
+/// hold the lock on the room's ephemeral data around this, so that receipts sent at the same time
+/// don't overwrite each other.
+fn set_receipt_in(
+    ephemeral: &mut HashMap<String, JsonValue>,
+    user_id: &MatrixId,
+    receipt_type: &str,
+    event_id: &str,
+) -> Result<(), Error> {
+    let key = match receipt_type {
+        "m.read.private" => PRIVATE_RECEIPTS,
+        _ => "m.receipt",
+    };
+    let mut receipts: Receipts = match ephemeral.remove(key) {
+        Some(content) => serde_json::from_value(content)?,
+        None => Receipts::default(),
+    };
+    let ts = chrono::Utc::now().timestamp_millis();
+    receipts.set(event_id, receipt_type, user_id, ts);
+    ephemeral.insert(String::from(key), serde_json::to_value(receipts)?);
+    Ok(())
+}
+
+/// Limits how many storage handles can be in use at once, if a limit is set. Once the limit is
+/// reached, taking a permit fails with `LimitExceeded` until one is given back, so that a flood of
+/// requests gets turned away instead of piling up on the database.
+#[derive(Clone, Default)]
+pub struct HandleLimit(Option<Arc<Semaphore>>);
+
+impl HandleLimit {
+    pub fn new(limit: usize) -> Self {
+        HandleLimit(Some(Arc::new(Semaphore::new(limit))))
+    }
+
+    pub fn acquire(&self) -> Result<HandlePermit, Error> {
+        let permit = match &self.0 {
+            Some(semaphore) => Some(
+                Arc::clone(semaphore)
+                    .try_acquire_owned()
+                    .map_err(|_| ErrorKind::LimitExceeded)?,
+            ),
+            None => None,
+        };
+        Ok(HandlePermit(permit.map(Arc::new)))
+    }
 }
 
+/// A place under a `HandleLimit`, which is given back once this and all of its clones are
+/// dropped.
+#[derive(Clone, Default)]
+pub struct HandlePermit(Option<Arc<OwnedSemaphorePermit>>);
+
 #[async_trait]
 pub trait StorageManager: Send + Sync {
+    /// Gets a handle, which holds a permit from the handle limit for as long as it's alive.
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error>;
+
+    /// Gets a handle that doesn't count towards the handle limit, for something long-lived like
+    /// the state resolver, which only uses it on behalf of requests that hold their own handle.
+    async fn get_unlimited_handle(&self) -> Result<Box<dyn Storage>, Error>;
 }
 
 #[async_trait]
 pub trait Storage: Send + Sync {
+    /// Creates a user with no profile, except for a display name of their localpart if the
+    /// storage was set up to give them one.
     async fn create_user(&self, username: &str, password: &str) -> Result<(), Error>;
 
+    /// Returns whether a user with this username has been created, including deactivated ones.
+    async fn user_exists(&self, username: &str) -> Result<bool, Error>;
+
+    /// Checks the user's password. If it's right but was hashed with outdated parameters, it is
+    /// rehashed with the current ones. Deactivated users never have the right password.
     async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error>;
 
+    /// Replaces the user's password. It is hashed the same way as in `create_user`.
+    async fn set_password(&self, username: &str, password: &str) -> Result<(), Error>;
+
     async fn create_access_token(&self, username: &str, device_id: &str) -> Result<Uuid, Error>;
 
     async fn delete_access_token(&self, token: Uuid) -> Result<(), Error>;
@@ -157,29 +498,240 @@ pub trait Storage: Send + Sync {
     /// Deletes all access tokens associated with the same user as this one
     async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error>;
 
+    /// Returns the username and device ID for which this token is valid, if any
+    async fn try_auth_full(&self, token: Uuid) -> Result<Option<(String, String)>, Error>;
+
+    /// Records that the token was just used, at `ts` milliseconds since the unix epoch. Does
+    /// nothing if the token doesn't exist.
+    async fn update_token_last_seen(
+        &self,
+        token: Uuid,
+        ip: Option<&str>,
+        ts: i64,
+    ) -> Result<(), Error>;
+
+    /// Returns every access token that the user has, in no particular order.
+    async fn get_tokens_for_user(&self, username: &str) -> Result<Vec<TokenInfo>, Error>;
+
+    /// Returns every device that the user has an access token for.
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, Error> {
+        let mut devices: HashMap<String, Device> = HashMap::new();
+        for token in self.get_tokens_for_user(username).await? {
+            // a device can have more than one token, in which case show the latest use
+            match devices.get(&token.device_id) {
+                Some(existing) if existing.last_seen_ts >= token.last_seen_ts => {}
+                _ => {
+                    let device = Device {
+                        display_name: self
+                            .get_device_display_name(username, &token.device_id)
+                            .await?,
+                        device_id: token.device_id.clone(),
+                        last_seen_ip: token.last_seen_ip,
+                        last_seen_ts: token.last_seen_ts,
+                    };
+                    devices.insert(token.device_id, device);
+                }
+            }
+        }
+        Ok(devices.into_values().collect())
+    }
+
+    async fn get_device(&self, username: &str, device_id: &str) -> Result<Option<Device>, Error> {
+        let devices = self.get_devices(username).await?;
+        Ok(devices.into_iter().find(|d| d.device_id == device_id))
+    }
+
+    /// Logs the device out by deleting all of its access tokens, and forgets its display name,
+    /// keys and queued to-device messages. The user's other devices stay logged in.
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error>;
+
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: &str,
+    ) -> Result<(), Error>;
+
+    async fn get_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<String>, Error>;
+
+    /// Stores the identity keys that a device uploaded, replacing any it had before. The keys are
+    /// opaque to the server.
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error>;
+
+    /// Returns device_id -> identity keys for each of the user's devices that uploaded some.
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error>;
+
+    /// Stores one-time keys for a device to hand out, by key ID (`<algorithm>:<id>`). Keys with
+    /// an ID that the device already has replace the old ones.
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error>;
+
+    /// Returns algorithm -> how many of the device's one-time keys haven't been claimed yet.
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error>;
+
+    /// Removes one of the device's one-time keys for the given algorithm and returns it with its
+    /// key ID. No key is ever handed out twice.
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error>;
+
+    /// Queues a message for the device, after any that are already waiting for it.
+    async fn send_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        message: ToDeviceMessage,
+    ) -> Result<(), Error>;
+
+    /// Forgets the device's queued messages up to and including position `since`, which it has
+    /// confirmed it received by syncing past them, and returns the rest oldest first. Also
+    /// returns the position of the last message, or `since` if there are none left.
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(Vec<ToDeviceMessage>, u64), Error>;
+
+    /// Waits until the device has a message queued after position `since`. Returns straight
+    /// away if it already does.
+    async fn wait_for_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(), Error>;
+
     /// Returns the username for which this token is valid, if any
-    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error>;
+    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
+        Ok(self
+            .try_auth_full(token)
+            .await?
+            .map(|(username, _)| username))
+    }
+
+    /// Records a transaction ID for the given user's device and returns whether it is new
+    /// (unique). `scope` names the kind of request that the ID is for, since each kind has its
+    /// own transaction IDs.
+    ///
+    /// This is scoped by device rather than access token so that a retried request is still
+    /// recognised after the device's token has changed.
+    async fn record_txn(
+        &self,
+        username: &str,
+        device_id: &str,
+        scope: &str,
+        txn_id: String,
+    ) -> Result<bool, Error>;
 
-    /// Records a transaction ID into the given access token and returns whether it is new
-    /// (unique).
-    async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error>;
+    /// Returns the username of the local user that a third party identifier (such as an email
+    /// address) is bound to. Binding identifiers to accounts isn't supported yet, so by default
+    /// nobody is found.
+    async fn get_user_by_threepid(
+        &self,
+        _medium: &str,
+        _address: &str,
+    ) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
 
-    /// Returns the given user's avatar URL and display name, if present
+    /// Returns the user's avatar URL and display name, or None if the user doesn't exist. A user
+    /// who hasn't set either still has a profile, with nothing in it.
     async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error>;
 
-    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error>;
+    /// Returns the users who haven't been deactivated and whose username or display name contains
+    /// `term`, ignoring case, with their profiles. They are ordered by username, and there are at
+    /// most `limit` of them if it is given.
+    async fn search_users(
+        &self,
+        term: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, UserProfile)>, Error>;
+
+    /// Returns whether the avatar URL changed, i.e. whether it was anything else before.
+    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<bool, Error>;
+
+    /// Returns whether the display name changed, i.e. whether it was anything else before.
+    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<bool, Error>;
+
+    /// Replaces the user's presence, which was set at `ts` milliseconds since the unix epoch, and
+    /// moves the user to the end of the presence stream.
+    async fn set_presence(
+        &self,
+        username: &str,
+        state: PresenceState,
+        status_msg: Option<String>,
+        ts: i64,
+    ) -> Result<(), Error>;
+
+    /// Returns the user's presence, or None if they have never set it.
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error>;
+
+    /// Returns the users whose presence has been set since the given position in the presence
+    /// stream, along with their presence, and the position of the latest change.
+    async fn get_presence_changes(
+        &self,
+        since: u64,
+    ) -> Result<(Vec<(String, Presence)>, u64), Error>;
+
+    /// Waits until someone's presence is set after the given position in the presence stream.
+    async fn wait_for_presence(&self, since: u64) -> Result<(), Error>;
+
+    /// Returns whether the user is a server admin. Users that don't exist aren't admins.
+    async fn is_admin(&self, username: &str) -> Result<bool, Error>;
 
-    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<(), Error>;
+    async fn set_admin(&self, username: &str, is_admin: bool) -> Result<(), Error>;
 
+    /// Logs the user out everywhere and stops them from logging in again. The username stays
+    /// taken so that nobody else can register it.
+    async fn deactivate_user(&self, username: &str) -> Result<(), Error>;
+
+    /// Stores events and adds them to their rooms' timelines. Events that some of their prev
+    /// events haven't arrived for yet, including any before the room's create event, are kept as
+    /// outliers outside of the timeline. An outlier is promoted into the timeline once all of its
+    /// prev events are there, as long as it passed auth.
     async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error>;
 
+    /// Returns the room's outliers, oldest first.
+    async fn get_outliers(&self, room_id: &str) -> Result<Vec<StoredPdu>, Error>;
+
     async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error>;
 
+    /// Like `query_pdus`, but each event comes with its stream ordering.
+    async fn query_ordered_pdus<'a>(
+        &self,
+        query: EventQuery<'a>,
+        wait: bool,
+    ) -> Result<(Vec<(usize, StoredPdu)>, usize), Error>;
+
     async fn query_pdus<'a>(
         &self,
         query: EventQuery<'a>,
         wait: bool,
-    ) -> Result<(Vec<StoredPdu>, usize), Error>;
+    ) -> Result<(Vec<StoredPdu>, usize), Error> {
+        let (pdus, next_batch) = self.query_ordered_pdus(query, wait).await?;
+        Ok((pdus.into_iter().map(|(_, pdu)| pdu).collect(), next_batch))
+    }
 
     async fn query_events<'a>(
         &self,
@@ -193,33 +745,94 @@ pub trait Storage: Send + Sync {
         ));
     }
 
+    /// Like `query_events`, but each event comes with its stream ordering, which is what
+    /// pagination tokens point at.
+    async fn query_ordered_events<'a>(
+        &self,
+        query: EventQuery<'a>,
+        wait: bool,
+    ) -> Result<(Vec<(usize, Event)>, usize), Error> {
+        let (pdus, next_batch) = self.query_ordered_pdus(query, wait).await?;
+        Ok((
+            pdus.into_iter()
+                .map(|(ordering, pdu)| (ordering, pdu.to_client_format()))
+                .collect(),
+            next_batch,
+        ))
+    }
+
+    /// Returns the event's stream ordering, or None if it isn't in the room's timeline, e.g.
+    /// because it is an outlier.
+    ///
+    /// A room's stream orderings count up from 0 at its create event, in the order that events
+    /// join the timeline. Once an event has one it never changes, including across restarts, so
+    /// pagination tokens, read receipts and notification counts are all measured in it. It has
+    /// nothing to do with the event's depth, which comes from the room's DAG and can be shared by
+    /// concurrent events.
+    async fn get_stream_ordering(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<usize>, Error>;
+
+    /// Returns every event in the room's timeline, oldest first. Unlike a timeline query, the
+    /// events are fetched as the stream is polled, so that indexes can be rebuilt over rooms that
+    /// are too big to load at once.
+    async fn stream_room_events(
+        &self,
+        room_id: &str,
+    ) -> Result<BoxStream<'static, Result<StoredPdu, Error>>, Error>;
+
+    /// Returns every room whose create event passed auth.
     async fn get_rooms(&self) -> Result<Vec<String>, Error>;
 
+    /// Sets whether a room is listed in the public room directory.
+    async fn set_room_public(&self, room_id: &str, public: bool) -> Result<(), Error>;
+
+    /// Returns the IDs of all rooms listed in the public room directory.
+    async fn get_public_rooms(&self) -> Result<Vec<String>, Error>;
+
+    /// Points a room alias at a room on behalf of `creator`. Fails with `RoomInUse` if the alias
+    /// is already taken, even if it points to the same room.
+    async fn set_room_alias(
+        &self,
+        alias: &str,
+        room_id: &str,
+        creator: &MatrixId,
+    ) -> Result<(), Error>;
+
+    /// Returns the ID of the room that the alias points to, if any.
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error>;
+
+    /// Returns the user who created the alias, if it exists and its creator is known.
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<MatrixId>, Error>;
+
+    /// Removes a room alias, returning whether it existed.
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error>;
+
+    /// Returns all of the aliases on this server which point to the given room.
+    async fn get_local_aliases(&self, room_id: &str) -> Result<Vec<String>, Error>;
+
+    /// Returns the IDs of all rooms to which the given user has a pending invite.
+    async fn get_invited_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error>;
+
+    /// Returns the IDs of all rooms that the given user is joined to.
+    async fn get_joined_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error>;
+
+    /// Returns the number of rooms that the given user is joined to.
+    async fn count_joined_rooms(&self, user_id: &MatrixId) -> Result<usize, Error>;
+
+    /// Returns the user's current membership in the room, if they have one. See `get_full_state`
+    /// for what passing a state resolver changes.
     async fn get_membership(
         &self,
         user_id: &MatrixId,
         room_id: &str,
+        resolver: Option<&StateResolver>,
     ) -> Result<Option<Membership>, Error> {
         let event = self
-            .query_events(
-                EventQuery {
-                    query_type: QueryType::State {
-                        at: None,
-                        state_keys: &[user_id.as_str()],
-                        not_state_keys: &[],
-                    },
-                    room_id,
-                    senders: &[],
-                    not_senders: &[],
-                    types: &["m.room.member"],
-                    not_types: &[],
-                    contains_json: None,
-                },
-                false,
-            )
-            .await?
-            .0
-            .pop();
+            .get_state_event(room_id, "m.room.member", user_id.as_str(), resolver)
+            .await?;
         let membership = event.map(|e| {
             extract!(EventContent::Member(_), e.event_content)
                 .unwrap()
@@ -246,6 +859,7 @@ pub trait Storage: Send + Sync {
             contains_json: Some(serde_json::json!({
                 "membership": "join"
             })),
+            include_soft_failed: false,
         };
         let mut invited_query = join_query.clone();
         invited_query.contains_json = Some(serde_json::json!({
@@ -258,9 +872,21 @@ pub trait Storage: Send + Sync {
         Ok((join_count, invited_count))
     }
 
-    async fn get_full_state(&self, room_id: &str) -> Result<Vec<Event>, Error> {
-        let (ret, _) = self
-            .query_events(
+    /// Returns the events making up the current state of the room.
+    ///
+    /// Given a state resolver, this is the resolved state at the room's forward extremities.
+    /// Otherwise, state events are simply ordered by depth, which is cheap and correct for linear
+    /// rooms but can disagree with state resolution once the room has forked.
+    async fn get_full_state(
+        &self,
+        room_id: &str,
+        resolver: Option<&StateResolver>,
+    ) -> Result<Vec<Event>, Error> {
+        if let Some(resolver) = resolver {
+            return resolver.current_state_events(room_id).await;
+        }
+        let (pdus, _) = self
+            .query_pdus(
                 EventQuery {
                     query_type: QueryType::State {
                         at: None,
@@ -273,21 +899,100 @@ pub trait Storage: Send + Sync {
                     types: &[],
                     not_types: &[],
                     contains_json: None,
+                    include_soft_failed: false,
                 },
                 false,
             )
             .await?;
+        self.state_events_to_client_format(pdus).await
+    }
+
+    /// Converts a state event into the format that clients expect. See
+    /// `state_events_to_client_format`.
+    async fn state_event_to_client_format(&self, pdu: StoredPdu) -> Result<Event, Error> {
+        let mut events = self.state_events_to_client_format(vec![pdu]).await?;
+        Ok(events.pop().unwrap())
+    }
+
+    /// Converts state events from one room into the format that clients expect. Redactions have
+    /// already been applied to stored events, and member events also get the content of the
+    /// membership they replaced as `prev_content` in their unsigned data. Those are all looked up
+    /// at once, since this is on the way to every sync.
+    async fn state_events_to_client_format(
+        &self,
+        pdus: Vec<StoredPdu>,
+    ) -> Result<Vec<Event>, Error> {
+        let room_id = match pdus.first() {
+            Some(pdu) => pdu.room_id().to_owned(),
+            None => return Ok(Vec::new()),
+        };
+        // the target's previous membership is always one of the auth events of a new one
+        let auth_event_ids = pdus
+            .iter()
+            .filter(|pdu| matches!(pdu.event_content(), EventContent::Member(_)))
+            .flat_map(|pdu| pdu.auth_events().iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let auth_events = self
+            .get_pdus(&room_id, &auth_event_ids)
+            .await?
+            .into_iter()
+            .flatten()
+            .map(|event| (event.event_id(), event))
+            .collect::<HashMap<_, _>>();
+
+        let mut ret = Vec::with_capacity(pdus.len());
+        for pdu in pdus {
+            let prev_content = match pdu.event_content() {
+                EventContent::Member(_) => pdu
+                    .auth_events()
+                    .iter()
+                    .filter_map(|auth_event_id| auth_events.get(auth_event_id))
+                    .find(|auth_event| {
+                        matches!(auth_event.event_content(), EventContent::Member(_))
+                            && auth_event.state_key() == pdu.state_key()
+                    })
+                    .map(|auth_event| auth_event.event_content().content_as_json()),
+                _ => None,
+            };
+
+            let mut event = pdu.to_client_format();
+            if let Some(prev_content) = prev_content {
+                let unsigned = event
+                    .unsigned
+                    .get_or_insert_with(|| JsonValue::Object(Default::default()));
+                if let JsonValue::Object(unsigned) = unsigned {
+                    unsigned.insert(String::from("prev_content"), prev_content);
+                }
+            }
+            ret.push(event);
+        }
         Ok(ret)
     }
 
+    /// Returns the current state event with the given type and state key, if any. See
+    /// `get_full_state` for what passing a state resolver changes.
     async fn get_state_event(
         &self,
         room_id: &str,
         event_type: &str,
         state_key: &str,
+        resolver: Option<&StateResolver>,
     ) -> Result<Option<Event>, Error> {
-        let ret = self
-            .query_events(
+        if let Some(resolver) = resolver {
+            let state = resolver.resolve_current(room_id).await?;
+            let pdu = match state.get((event_type, state_key)) {
+                Some(event_id) => self.get_pdu(room_id, event_id).await?,
+                None => None,
+            };
+            return match pdu {
+                Some(pdu) => Ok(Some(self.state_event_to_client_format(pdu).await?)),
+                None => Ok(None),
+            };
+        }
+        let pdu = self
+            .query_pdus(
                 EventQuery {
                     query_type: QueryType::State {
                         at: None,
@@ -300,17 +1005,50 @@ pub trait Storage: Send + Sync {
                     types: &[event_type],
                     not_types: &[],
                     contains_json: None,
+                    include_soft_failed: false,
                 },
                 false,
             )
             .await?
             .0
             .pop();
-        Ok(ret)
+        match pdu {
+            Some(pdu) => Ok(Some(self.state_event_to_client_format(pdu).await?)),
+            None => Ok(None),
+        }
     }
 
+    /// Returns `Ok(None)` if the event doesn't exist, and `RoomNotFound` if the room doesn't.
+    /// Outliers can be found before their room exists.
     async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error>;
 
+    /// Fetches several PDUs at once, in the same order as `event_ids`. Fails like `get_pdu`.
+    async fn get_pdus(
+        &self,
+        room_id: &str,
+        event_ids: &[String],
+    ) -> Result<Vec<Option<StoredPdu>>, Error> {
+        let mut ret = Vec::with_capacity(event_ids.len());
+        for event_id in event_ids {
+            ret.push(self.get_pdu(room_id, event_id).await?);
+        }
+        Ok(ret)
+    }
+
+    /// Replaces a stored PDU with its redacted form. Its event id stays the same, since that is
+    /// calculated from the redacted form anyway. Does nothing if the PDU doesn't exist.
+    ///
+    /// The original is kept aside, for `get_unredacted_pdu`.
+    async fn redact_pdu(&self, room_id: &str, event_id: &str) -> Result<(), Error>;
+
+    /// Gets a PDU as it was before it was redacted, if it was. This is only meant for admins
+    /// reviewing what was removed; everyone else gets `get_pdu`.
+    async fn get_unredacted_pdu(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<StoredPdu>, Error>;
+
     async fn get_all_ephemeral(&self, room_id: &str) -> Result<HashMap<String, JsonValue>, Error>;
 
     async fn get_ephemeral(
@@ -326,37 +1064,297 @@ pub trait Storage: Send + Sync {
         content: Option<JsonValue>,
     ) -> Result<(), Error>;
 
-    async fn set_typing(
+    /// Sets a user's read receipt in a room. Private receipts are stored apart from the public
+    /// ones, so that they're only ever shown to the user who sent them.
+    async fn set_receipt(
         &self,
         room_id: &str,
         user_id: &MatrixId,
-        is_typing: bool,
-        timeout: u32,
+        receipt_type: &str,
+        event_id: &str,
     ) -> Result<(), Error>;
 
-    async fn get_user_account_data(
+    /// Like `get_all_ephemeral`, but only includes the private receipts belonging to the given
+    /// user.
+    async fn get_all_ephemeral_for_user(
         &self,
-        username: &str,
-    ) -> Result<HashMap<String, JsonValue>, Error>;
-
-    async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error>;
-
-    async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error>;
-
-    async fn print_the_world(&self) -> Result<(), Error> {
-        Ok(())
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let mut ephemeral = self.get_all_ephemeral(room_id).await?;
+        let private: Receipts = match ephemeral.remove(PRIVATE_RECEIPTS) {
+            Some(content) => serde_json::from_value(content)?,
+            None => return Ok(ephemeral),
+        };
+        let mut receipts: Receipts = match ephemeral.remove("m.receipt") {
+            Some(content) => serde_json::from_value(content)?,
+            None => Receipts::default(),
+        };
+        receipts.merge_user(&private, user_id);
+        if !receipts.0.is_empty() {
+            ephemeral.insert(String::from("m.receipt"), serde_json::to_value(receipts)?);
+        }
+        Ok(ephemeral)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Storage, StorageManager};
+    /// Returns the event that the user last marked as read in the room, whether they did so
+    /// publicly or privately.
+    async fn get_read_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Option<String>, Error> {
+        let mut latest: Option<(String, i64)> = None;
+        for (key, receipt_type) in &[
+            ("m.receipt", "m.read"),
+            (PRIVATE_RECEIPTS, "m.read.private"),
+        ] {
+            let receipts: Receipts = match self.get_ephemeral(room_id, key).await? {
+                Some(content) => serde_json::from_value(content)?,
+                None => continue,
+            };
+            if let Some((event_id, ts)) = receipts.get(receipt_type, user_id) {
+                if !matches!(latest, Some((_, latest_ts)) if latest_ts >= ts) {
+                    latest = Some((event_id.to_string(), ts));
+                }
+            }
+        }
+        Ok(latest.map(|(event_id, _)| event_id))
+    }
 
-    #[cfg(feature = "storage-mem")]
-    #[test]
-    fn mem_backend_user_accounts() {
-        let mut rt = tokio::runtime::Builder::new()
-            .basic_scheduler()
+    /// Counts the events after `since_receipt` in the room's timeline that the user's push rules
+    /// say they should be notified about, and how many of those are highlights. Nothing from
+    /// before the user's latest membership event counts, so without a receipt, or if it isn't in
+    /// the timeline, counting starts there. The user's own events never count.
+    ///
+    /// If `cached` counts started from the same place, only the events after them are looked at.
+    async fn get_notification_counts(
+        &self,
+        user_id: &MatrixId,
+        room_id: &str,
+        since_receipt: Option<&str>,
+        cached: Option<NotificationCounts>,
+    ) -> Result<NotificationCounts, Error> {
+        let member_event = self
+            .get_state_event(room_id, "m.room.member", user_id.as_str(), None)
+            .await?;
+        let joined_at = match member_event.as_ref().and_then(|e| e.event_id.as_deref()) {
+            Some(event_id) => self.get_stream_ordering(room_id, event_id).await?,
+            None => None,
+        };
+        let read = match since_receipt {
+            Some(event_id) => self.get_stream_ordering(room_id, event_id).await?,
+            None => None,
+        };
+        let from = read
+            .into_iter()
+            .chain(joined_at)
+            .max()
+            .map_or(0, |seen| seen + 1);
+        let mut counts = match cached {
+            Some(counts) if counts.from == from => counts,
+            _ => NotificationCounts {
+                from,
+                to: from,
+                ..Default::default()
+            },
+        };
+
+        let not_senders = [user_id];
+        let (unread, progress) = self
+            .query_events(
+                EventQuery {
+                    query_type: QueryType::Timeline {
+                        from: counts.to,
+                        to: None,
+                    },
+                    room_id,
+                    senders: &[],
+                    not_senders: &not_senders,
+                    types: &[],
+                    not_types: &[],
+                    contains_json: None,
+                    include_soft_failed: false,
+                },
+                false,
+            )
+            .await?;
+        counts.to = counts.to.max(progress + 1);
+        // most of the time nothing has happened since the last count
+        if unread.is_empty() {
+            return Ok(counts);
+        }
+
+        let rules = match self
+            .get_user_account_data(user_id.localpart())
+            .await?
+            .remove(PUSH_RULES)
+        {
+            Some(rules) => rules,
+            None => default_push_rules(user_id),
+        };
+        let display_name = member_event
+            .and_then(|e| extract!(EventContent::Member(_), e.event_content))
+            .and_then(|member| member.displayname);
+        let power_levels = match self
+            .get_state_event(room_id, "m.room.power_levels", "", None)
+            .await?
+            .and_then(|e| extract!(EventContent::PowerLevels(_), e.event_content))
+        {
+            Some(power_levels) => power_levels,
+            None => match self
+                .get_state_event(room_id, "m.room.create", "", None)
+                .await?
+                .and_then(|e| extract!(EventContent::Create(_), e.event_content))
+            {
+                Some(create) => PowerLevels::no_event_default_levels(&create.creator),
+                None => return Ok(counts),
+            },
+        };
+        let (joined_member_count, _) = self.get_room_member_counts(room_id).await?;
+        let ctx = PushContext {
+            display_name: display_name.as_deref(),
+            joined_member_count,
+            power_levels: &power_levels,
+        };
+
+        for event in unread {
+            let mut event = serde_json::to_value(event)?;
+            event["room_id"] = JsonValue::from(room_id);
+            let actions = push_rules::evaluate(&rules, &event, &ctx);
+            if actions.notify {
+                counts.notifications += 1;
+            }
+            if actions.highlight {
+                counts.highlights += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn set_typing(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        is_typing: bool,
+        timeout: u32,
+    ) -> Result<(), Error>;
+
+    async fn get_user_account_data(
+        &self,
+        username: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error>;
+
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error>;
+
+    async fn get_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error>;
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error>;
+
+    /// Gets the account data, both global and per-room, that the user has written since the
+    /// given stream position. Each write moves the user's stream on by one, so passing 0 gets
+    /// all of it.
+    async fn get_account_data(
+        &self,
+        username: &str,
+        since: u64,
+    ) -> Result<AccountDataChanges, Error>;
+
+    /// Stores a sync filter for the user and returns its ID.
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error>;
+
+    async fn get_filter(&self, username: &str, filter_id: &str)
+        -> Result<Option<JsonValue>, Error>;
+
+    async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error>;
+
+    /// Returns the resolved state after the given events, if it was stored with
+    /// `set_cached_state`. The order of the events doesn't matter.
+    async fn get_cached_state(&self, event_ids: &[String]) -> Result<Option<StateMap>, Error>;
+
+    /// Stores the resolved state after the given events, so that it survives restarts.
+    async fn set_cached_state(&self, event_ids: &[String], state: StateMap) -> Result<(), Error>;
+
+    /// Like `get_cached_state`, but for several sets of events at once, in the same order.
+    async fn get_cached_states(
+        &self,
+        keys: &[Vec<String>],
+    ) -> Result<Vec<Option<StateMap>>, Error> {
+        let mut ret = Vec::with_capacity(keys.len());
+        for event_ids in keys {
+            ret.push(self.get_cached_state(event_ids).await?);
+        }
+        Ok(ret)
+    }
+
+    /// Like `set_cached_state`, but stores several states at once.
+    async fn set_cached_states(&self, states: Vec<(Vec<String>, StateMap)>) -> Result<(), Error> {
+        for (event_ids, state) in states {
+            self.set_cached_state(&event_ids, state).await?;
+        }
+        Ok(())
+    }
+
+    /// Stores a sync batch created for the given device when it synced from `since`. Batches which
+    /// that device can no longer be expected to sync from are evicted; see `BATCHES_PER_DEVICE`.
+    async fn set_batch(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Option<&str>,
+        id: &str,
+        batch: Batch,
+    ) -> Result<(), Error>;
+
+    async fn print_the_world(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    use futures::{stream::TryStreamExt, FutureExt};
+
+    use super::{
+        build_membership_index, json_contains, Batch, EventQuery, Presence, PresenceState,
+        QueryType, Storage, StorageManager, ToDeviceMessage, BATCHES_PER_DEVICE,
+    };
+    use crate::{
+        error::ErrorKind,
+        events::{
+            pdu::StoredPdu,
+            room::{Create, Member, Membership, Name, PowerLevels, Redaction},
+            room_version::{v4::UnhashedPdu, VersionedPdu},
+            EventContent,
+        },
+        state::StateResolver,
+        util::{storage::NewEvent, MatrixId, StorageExt},
+        validate::auth::AuthStatus,
+    };
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_user_accounts() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
             .build()
             .unwrap();
         let db_pool = super::mem::MemStorageManager::new();
@@ -396,6 +1394,8 @@ mod tests {
         db.create_user("bob", "password1")
             .await
             .expect("failed to create second user");
+        assert!(db.user_exists("alice").await.unwrap());
+        assert!(!db.user_exists("carol").await.unwrap());
 
         assert!(db.verify_password("alice", "password1").await.unwrap() == true);
         assert!(db.verify_password("alice", "password2").await.unwrap() == false);
@@ -475,6 +1475,249 @@ mod tests {
                 .as_deref(),
             Some("bob")
         );
+
+        db.set_password("bob", "password2")
+            .await
+            .expect("failed to change password");
+        assert!(db.verify_password("bob", "password1").await.unwrap() == false);
+        assert!(db.verify_password("bob", "password2").await.unwrap() == true);
+        db.set_password("nobody", "password")
+            .await
+            .expect_err("succeeded changing password for nobody");
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_tokens_for_user() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            tokens_for_user(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_tokens_for_user() {
+        let path = "sled-test-tokens-for-user";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            tokens_for_user(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn tokens_for_user(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("alicia", "password").await.unwrap();
+        let phone = db.create_access_token("alice", "phone").await.unwrap();
+        let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+        db.create_access_token("alice", "laptop").await.unwrap();
+        db.create_access_token("alicia", "phone").await.unwrap();
+        db.update_token_last_seen(laptop, Some("127.0.0.1"), 1000)
+            .await
+            .unwrap();
+
+        let mut tokens = db.get_tokens_for_user("alice").await.unwrap();
+        tokens.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        let device_ids = tokens.iter().map(|t| &*t.device_id).collect::<Vec<_>>();
+        assert_eq!(device_ids, vec!["laptop", "laptop", "phone"]);
+        assert_ne!(tokens[0].token_id, tokens[1].token_id);
+        // the token itself mustn't be given away
+        assert!(tokens.iter().all(|t| t.token_id != phone.to_string()));
+        let laptop_info = tokens
+            .iter()
+            .find(|t| t.last_seen_ts == Some(1000))
+            .unwrap();
+        assert_eq!(laptop_info.last_seen_ip.as_deref(), Some("127.0.0.1"));
+
+        let mut devices = db.get_devices("alice").await.unwrap();
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].last_seen_ts, Some(1000));
+
+        db.delete_device("alice", "laptop").await.unwrap();
+        let tokens = db.get_tokens_for_user("alice").await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        db.delete_all_access_tokens(phone).await.unwrap();
+        assert!(db.get_tokens_for_user("alice").await.unwrap().is_empty());
+        assert_eq!(db.get_tokens_for_user("alicia").await.unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_device_keys() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            device_keys(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_device_keys() {
+        let path = "sled-test-device-keys";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            device_keys(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn device_keys(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("alicia", "password").await.unwrap();
+        db.set_device_keys("alice", "phone", json!({ "keys": "phone" }))
+            .await
+            .unwrap();
+        db.set_device_keys("alice", "laptop", json!({ "keys": "laptop" }))
+            .await
+            .unwrap();
+        db.set_device_keys("alicia", "phone", json!({ "keys": "alicia" }))
+            .await
+            .unwrap();
+        let keys = db.get_device_keys("alice").await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["phone"], json!({ "keys": "phone" }));
+
+        let otks = vec![
+            (String::from("curve25519:1"), json!("a")),
+            (String::from("curve25519:2"), json!("b")),
+            (String::from("signed_curve25519:1"), json!({ "key": "c" })),
+        ];
+        db.add_one_time_keys("alice", "phone", otks.into_iter().collect())
+            .await
+            .unwrap();
+        let counts = db.count_one_time_keys("alice", "phone").await.unwrap();
+        assert_eq!(counts["curve25519"], 2);
+        assert_eq!(counts["signed_curve25519"], 1);
+        assert!(db
+            .count_one_time_keys("alice", "laptop")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut claimed = Vec::new();
+        while let Some((key_id, _)) = db
+            .claim_one_time_key("alice", "phone", "curve25519")
+            .await
+            .unwrap()
+        {
+            claimed.push(key_id);
+        }
+        claimed.sort();
+        assert_eq!(claimed, vec!["curve25519:1", "curve25519:2"]);
+        let counts = db.count_one_time_keys("alice", "phone").await.unwrap();
+        assert_eq!(counts.get("curve25519"), None);
+        assert_eq!(counts["signed_curve25519"], 1);
+
+        db.delete_device("alice", "phone").await.unwrap();
+        assert_eq!(db.get_device_keys("alice").await.unwrap().len(), 1);
+        assert!(db
+            .count_one_time_keys("alice", "phone")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_to_device() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            to_device(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_to_device() {
+        let path = "sled-test-to-device";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            to_device(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn to_device(db: &dyn Storage) {
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let message = |n: u64| ToDeviceMessage {
+            sender: bob.clone(),
+            ty: String::from("m.room_key"),
+            content: json!({ "n": n }),
+        };
+        db.send_to_device("alice", "phone", message(1))
+            .await
+            .unwrap();
+        db.send_to_device("alice", "laptop", message(2))
+            .await
+            .unwrap();
+        db.send_to_device("alice", "phone", message(3))
+            .await
+            .unwrap();
+
+        let (messages, last) = db
+            .get_to_device_messages("alice", "phone", 0)
+            .await
+            .unwrap();
+        let contents = messages.iter().map(|m| &m.content).collect::<Vec<_>>();
+        assert_eq!(contents, vec![&json!({ "n": 1 }), &json!({ "n": 3 })]);
+        // nothing is forgotten until the device syncs past it
+        let (messages, again) = db
+            .get_to_device_messages("alice", "phone", 0)
+            .await
+            .unwrap();
+        assert_eq!((messages.len(), again), (2, last));
+        let (messages, since) = db
+            .get_to_device_messages("alice", "phone", last)
+            .await
+            .unwrap();
+        assert_eq!((messages.len(), since), (0, last));
+        let (messages, _) = db
+            .get_to_device_messages("alice", "phone", 0)
+            .await
+            .unwrap();
+        assert!(messages.is_empty());
+
+        db.delete_device("alice", "laptop").await.unwrap();
+        let (messages, _) = db
+            .get_to_device_messages("alice", "laptop", 0)
+            .await
+            .unwrap();
+        assert!(messages.is_empty());
     }
 
     #[cfg(feature = "storage-mem")]
@@ -510,24 +1753,2400 @@ mod tests {
 
     async fn transactions(db: &dyn Storage) {
         db.create_user("alice", "password").await.unwrap();
-        let token = db.create_access_token("alice", "phone").await.unwrap();
         assert_eq!(
-            db.record_txn(token, String::from("txn1"))
+            db.record_txn("alice", "phone", "room", String::from("txn1"))
                 .await
                 .expect("failed to record transaction"),
             true
         );
         assert_eq!(
-            db.record_txn(token, String::from("txn1"))
+            db.record_txn("alice", "phone", "room", String::from("txn1"))
                 .await
                 .expect("failed to record transaction"),
             false
         );
         assert_eq!(
-            db.record_txn(token, String::from("txn2"))
+            db.record_txn("alice", "phone", "room", String::from("txn2"))
+                .await
+                .expect("failed to record transaction"),
+            true
+        );
+        assert_eq!(
+            db.record_txn("alice", "laptop", "room", String::from("txn1"))
+                .await
+                .expect("failed to record transaction"),
+            true
+        );
+        // to-device sends keep their own transaction ids
+        assert_eq!(
+            db.record_txn("alice", "phone", "to_device", String::from("txn1"))
                 .await
                 .expect("failed to record transaction"),
             true
         );
     }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_transactions_across_tokens() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            transactions_across_tokens(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_transactions_across_tokens() {
+        let path = "sled-test-transactions-across-tokens";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            transactions_across_tokens(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn transactions_across_tokens(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        let old_token = db.create_access_token("alice", "phone").await.unwrap();
+        let (username, device_id) = db.try_auth_full(old_token).await.unwrap().unwrap();
+        assert_eq!((username.as_str(), device_id.as_str()), ("alice", "phone"));
+        assert!(db
+            .record_txn(&username, &device_id, "room", String::from("txn1"))
+            .await
+            .expect("failed to record transaction"));
+
+        // the device gets a new token, then retries the same request
+        db.delete_access_token(old_token).await.unwrap();
+        let new_token = db.create_access_token("alice", "phone").await.unwrap();
+        let (username, device_id) = db.try_auth_full(new_token).await.unwrap().unwrap();
+        assert!(!db
+            .record_txn(&username, &device_id, "room", String::from("txn1"))
+            .await
+            .expect("failed to record transaction"));
+    }
+
+    /// Adds a creation event for a new room, without any other state.
+    pub(crate) async fn create_room(db: &dyn Storage, room_id: &str, creator: &MatrixId) {
+        let create = UnhashedPdu {
+            event_content: EventContent::Create(Create {
+                creator: creator.clone(),
+                room_version: Some(String::from("4")),
+                predecessor: None,
+                extra: HashMap::new(),
+            }),
+            room_id: String::from(room_id),
+            sender: creator.clone(),
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: Vec::new(),
+            depth: 0,
+            auth_events: Vec::new(),
+        }
+        .finalize();
+        db.add_pdus(&[StoredPdu {
+            inner: VersionedPdu::V4(create),
+            auth_status: AuthStatus::Pass,
+            soft_failed: false,
+        }])
+        .await
+        .expect("failed to create room");
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_invites() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            invites(&*db, &resolver).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_invites() {
+        let path = "sled-test-invites";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            invites(&*db, &resolver).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn invites(db: &dyn Storage, resolver: &StateResolver) {
+        let room_id = "!invites:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let member = |sender: &MatrixId, target: &MatrixId, membership| NewEvent {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: None,
+                membership,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+            }),
+            sender: sender.clone(),
+            state_key: Some(target.clone_inner()),
+            redacts: None,
+            unsigned: None,
+        };
+
+        create_room(db, room_id, &alice).await;
+        db.add_event(
+            room_id,
+            member(&alice, &alice, Membership::Join),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .expect("failed to join room");
+        assert!(db
+            .get_invited_rooms_for_user(&bob)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.count_joined_rooms(&alice).await.unwrap(), 1);
+
+        db.add_event(
+            room_id,
+            member(&alice, &bob, Membership::Invite),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .expect("failed to invite");
+        assert_eq!(
+            db.get_invited_rooms_for_user(&bob).await.unwrap(),
+            vec![String::from(room_id)]
+        );
+        assert!(db
+            .get_invited_rooms_for_user(&alice)
+            .await
+            .unwrap()
+            .is_empty());
+        // invites don't count as being in the room
+        assert_eq!(db.count_joined_rooms(&bob).await.unwrap(), 0);
+
+        // alice changes her mind
+        db.add_event(
+            room_id,
+            member(&alice, &bob, Membership::Leave),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .expect("failed to rescind invite");
+        assert!(db
+            .get_invited_rooms_for_user(&bob)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_failed_create() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            failed_create(&*db).await;
+        });
+    }
+
+    async fn failed_create(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        create_room(db, "!valid:example.org", &alice).await;
+        let create = UnhashedPdu {
+            event_content: EventContent::Create(Create {
+                creator: alice.clone(),
+                room_version: Some(String::from("4")),
+                predecessor: None,
+                extra: HashMap::new(),
+            }),
+            room_id: String::from("!invalid:example.org"),
+            sender: alice.clone(),
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: Vec::new(),
+            depth: 0,
+            auth_events: Vec::new(),
+        }
+        .finalize();
+        db.add_pdus(&[StoredPdu {
+            inner: VersionedPdu::V4(create),
+            auth_status: AuthStatus::Fail,
+            soft_failed: false,
+        }])
+        .await
+        .unwrap();
+
+        let rooms = db.get_rooms().await.unwrap();
+        assert_eq!(rooms, vec![String::from("!valid:example.org")]);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_outliers() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            outliers(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_outliers() {
+        let path = "sled-test-outliers";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            outliers(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn outliers(db: &dyn Storage) {
+        let room_id = "!outliers:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let create = UnhashedPdu {
+            event_content: EventContent::Create(Create {
+                creator: alice.clone(),
+                room_version: Some(String::from("4")),
+                predecessor: None,
+                extra: HashMap::new(),
+            }),
+            room_id: String::from(room_id),
+            sender: alice.clone(),
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: Vec::new(),
+            depth: 0,
+            auth_events: Vec::new(),
+        }
+        .finalize();
+        let join = UnhashedPdu {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+            }),
+            room_id: String::from(room_id),
+            sender: alice.clone(),
+            state_key: Some(alice.clone_inner()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 1,
+            prev_events: vec![create.event_id()],
+            depth: 1,
+            auth_events: vec![create.event_id()],
+        }
+        .finalize();
+        let join_id = join.event_id();
+
+        // the join arrives first, so there's nowhere to put it yet
+        db.add_pdus(&[StoredPdu {
+            inner: VersionedPdu::V4(join),
+            auth_status: AuthStatus::Pass,
+            soft_failed: false,
+        }])
+        .await
+        .unwrap();
+        assert!(db.get_rooms().await.unwrap().is_empty());
+        assert!(db.get_pdu(room_id, &join_id).await.unwrap().is_some());
+        assert!(db
+            .get_invited_rooms_for_user(&alice)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.add_pdus(&[StoredPdu {
+            inner: VersionedPdu::V4(create),
+            auth_status: AuthStatus::Pass,
+            soft_failed: false,
+        }])
+        .await
+        .unwrap();
+        assert_eq!(db.get_rooms().await.unwrap(), vec![String::from(room_id)]);
+        let (timeline, _) = db
+            .query_pdus(
+                EventQuery {
+                    query_type: QueryType::Timeline { from: 0, to: None },
+                    room_id,
+                    senders: &[],
+                    not_senders: &[],
+                    types: &[],
+                    not_types: &[],
+                    contains_json: None,
+                    include_soft_failed: false,
+                },
+                false,
+            )
+            .await
+            .unwrap();
+        let event_ids = timeline
+            .iter()
+            .map(|pdu| pdu.event_id())
+            .collect::<Vec<_>>();
+        assert_eq!(event_ids.len(), 2);
+        assert_eq!(event_ids[1], join_id);
+        assert_eq!(
+            db.get_membership(&alice, room_id, None).await.unwrap(),
+            Some(Membership::Join)
+        );
+        let (prev_events, _) = db.get_prev_events(room_id).await.unwrap();
+        assert_eq!(prev_events, vec![join_id]);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_outlier_promotion() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            outlier_promotion(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_outlier_promotion() {
+        let path = "sled-test-outlier-promotion";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            outlier_promotion(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn outlier_promotion(db: &dyn Storage) {
+        let room_id = "!promotion:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        create_room(db, room_id, &alice).await;
+        let (create_id, _) = db.get_prev_events(room_id).await.unwrap();
+        let topic = |topic: &str, prev_events: Vec<String>, depth| {
+            UnhashedPdu {
+                event_content: EventContent::new(
+                    "m.room.topic",
+                    serde_json::json!({ "topic": topic }),
+                )
+                .unwrap(),
+                room_id: String::from(room_id),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                unsigned: None,
+                redacts: None,
+                origin: String::from("example.org"),
+                origin_server_ts: depth,
+                prev_events,
+                depth,
+                auth_events: create_id.clone(),
+            }
+            .finalize()
+        };
+        let first = topic("first", create_id.clone(), 1);
+        let second = topic("second", vec![first.event_id()], 2);
+        let rejected = topic("rejected", vec![first.event_id()], 2);
+        let (first_id, second_id) = (first.event_id(), second.event_id());
+        let add = |pdu, auth_status| async move {
+            db.add_pdus(&[StoredPdu {
+                inner: VersionedPdu::V4(pdu),
+                auth_status,
+                soft_failed: false,
+            }])
+            .await
+            .unwrap()
+        };
+        let timeline = || async {
+            let query = EventQuery {
+                query_type: QueryType::Timeline { from: 0, to: None },
+                room_id,
+                senders: &[],
+                not_senders: &[],
+                types: &[],
+                not_types: &[],
+                contains_json: None,
+                include_soft_failed: false,
+            };
+            let (pdus, _) = db.query_pdus(query, false).await.unwrap();
+            pdus.iter().map(|pdu| pdu.event_id()).collect::<Vec<_>>()
+        };
+        let outliers = || async {
+            let pdus = db.get_outliers(room_id).await.unwrap();
+            pdus.iter().map(|pdu| pdu.event_id()).collect::<Vec<_>>()
+        };
+
+        // both of these come before their prev event, as they might during backfill
+        add(second, AuthStatus::Pass).await;
+        add(rejected.clone(), AuthStatus::Fail).await;
+        assert_eq!(timeline().await, create_id);
+        assert_eq!(
+            outliers().await,
+            vec![second_id.clone(), rejected.event_id()]
+        );
+        assert_eq!(db.get_prev_events(room_id).await.unwrap().0, create_id);
+
+        add(first, AuthStatus::Pass).await;
+        let mut expected = create_id.clone();
+        expected.extend(vec![first_id, second_id.clone()]);
+        assert_eq!(timeline().await, expected);
+        // events that failed auth stay outliers
+        assert_eq!(outliers().await, vec![rejected.event_id()]);
+        assert_eq!(
+            db.get_prev_events(room_id).await.unwrap().0,
+            vec![second_id]
+        );
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_timeline_out_of_range() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            timeline_out_of_range(&*db).await;
+        });
+    }
+
+    async fn timeline_out_of_range(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!timeline:example.org";
+        create_room(db, room_id, &alice).await;
+        let query = |from, to| EventQuery {
+            query_type: QueryType::Timeline { from, to },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+            include_soft_failed: false,
+        };
+
+        let (events, last) = db.query_pdus(query(0, Some(100)), false).await.unwrap();
+        assert_eq!((events.len(), last), (1, 0));
+        let (events, last) = db.query_pdus(query(100, None), false).await.unwrap();
+        assert_eq!((events.len(), last), (0, 0));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_event_ordering() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            event_ordering(&*db, &resolver).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_event_ordering() {
+        let path = "sled-test-event-ordering";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            event_ordering(&*db, &resolver).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn event_ordering(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!ordering:example.org";
+        create_room(db, room_id, &alice).await;
+        let join = NewEvent::builder(
+            alice.clone(),
+            EventContent::new("m.room.member", json!({ "membership": "join" })).unwrap(),
+        )
+        .state_key(alice.as_str())
+        .build();
+        db.add_event(room_id, join, resolver, &Default::default())
+            .await
+            .unwrap();
+        for body in ["one", "two", "three"].iter() {
+            let message = NewEvent::builder(
+                alice.clone(),
+                EventContent::new("m.room.message", json!({ "body": body })).unwrap(),
+            )
+            .build();
+            db.add_event(room_id, message, resolver, &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let query = EventQuery {
+            query_type: QueryType::Timeline { from: 0, to: None },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &["m.room.message"],
+            not_types: &[],
+            contains_json: None,
+            include_soft_failed: false,
+        };
+        let (events, last) = db.query_ordered_events(query, false).await.unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(events.last().unwrap().0, last);
+        for (ordering, event) in &events {
+            let event_id = event.event_id.as_deref().unwrap();
+            assert_eq!(
+                db.get_stream_ordering(room_id, event_id).await.unwrap(),
+                Some(*ordering)
+            );
+        }
+        assert_eq!(
+            db.get_stream_ordering(room_id, "$nonexistent")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_stream_ordering_survives_restart() {
+        let path = "sled-test-stream-ordering-restart";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!restart:example.org";
+        let message = |body: &str| {
+            NewEvent::builder(
+                alice.clone(),
+                EventContent::new("m.room.message", json!({ "body": body })).unwrap(),
+            )
+            .build()
+        };
+        let query = EventQuery {
+            query_type: QueryType::Timeline { from: 0, to: None },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+            include_soft_failed: false,
+        };
+        let timeline = |pdus: Vec<(usize, StoredPdu)>| {
+            pdus.into_iter()
+                .map(|(ordering, pdu)| (ordering, pdu.event_id(), pdu.depth()))
+                .collect::<Vec<_>>()
+        };
+
+        let before = rt.block_on(async {
+            let db_pool = super::sled::SledStorage::new(path).unwrap();
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            create_room(&*db, room_id, &alice).await;
+            let join = NewEvent::builder(
+                alice.clone(),
+                EventContent::new("m.room.member", json!({ "membership": "join" })).unwrap(),
+            )
+            .state_key(alice.as_str())
+            .build();
+            db.add_event(room_id, join, &resolver, &Default::default())
+                .await
+                .unwrap();
+            db.add_event(room_id, message("one"), &resolver, &Default::default())
+                .await
+                .unwrap();
+            db.add_event(room_id, message("two"), &resolver, &Default::default())
+                .await
+                .unwrap();
+            let (pdus, _) = db.query_ordered_pdus(query.clone(), false).await.unwrap();
+            timeline(pdus)
+        });
+        // the stream starts at the create event and counts up, while depth follows the DAG
+        let orderings = before.iter().map(|(ordering, ..)| *ordering);
+        assert!(orderings.eq(0..4));
+        assert!(before.windows(2).all(|pair| pair[1].2 == pair[0].2 + 1));
+
+        rt.block_on(async {
+            let db_pool = super::sled::SledStorage::new(path).unwrap();
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            for (ordering, event_id, _) in &before {
+                assert_eq!(
+                    db.get_stream_ordering(room_id, event_id).await.unwrap(),
+                    Some(*ordering)
+                );
+            }
+            let three = db
+                .add_event(room_id, message("three"), &resolver, &Default::default())
+                .await
+                .unwrap();
+            let (pdus, last) = db.query_ordered_pdus(query, false).await.unwrap();
+            let after = timeline(pdus);
+            assert_eq!(after[..before.len()], before[..]);
+            assert_eq!(after[before.len()], (4, three, before[3].2 + 1));
+            assert_eq!(last, 4);
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_state_query_latest() {
+        let path = "sled-test-state-query-latest";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            state_query_latest(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn state_query_latest(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!state:example.org";
+        create_room(db, room_id, &alice).await;
+        for (depth, name) in [(1, "first"), (2, "second")] {
+            let (prev_events, _) = db.get_prev_events(room_id).await.unwrap();
+            let pdu = UnhashedPdu {
+                event_content: EventContent::Name(Name {
+                    name: Some(String::from(name)),
+                }),
+                room_id: String::from(room_id),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                unsigned: None,
+                redacts: None,
+                origin: String::from("example.org"),
+                origin_server_ts: depth,
+                prev_events,
+                depth,
+                auth_events: Vec::new(),
+            }
+            .finalize();
+            db.add_pdus(&[StoredPdu {
+                inner: VersionedPdu::V4(pdu),
+                auth_status: AuthStatus::Pass,
+                soft_failed: false,
+            }])
+            .await
+            .unwrap();
+        }
+        let query = |at| EventQuery {
+            query_type: QueryType::State {
+                at,
+                state_keys: &[],
+                not_state_keys: &[],
+            },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &["m.room.name"],
+            not_types: &[],
+            contains_json: None,
+            include_soft_failed: false,
+        };
+        let name = |pdu: &StoredPdu| pdu.event_content().content_as_json()["name"].clone();
+
+        let (events, last) = db.query_pdus(query(None), false).await.unwrap();
+        assert_eq!(last, 2);
+        assert_eq!(events.len(), 1);
+        assert_eq!(name(&events[0]), "second");
+        // as of the first name event, the second hasn't happened yet
+        let (events, _) = db.query_pdus(query(Some(1)), false).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(name(&events[0]), "first");
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_state_query_latest() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            state_query_latest(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_full_state_deduplicated() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            full_state_deduplicated(&*db, &resolver).await;
+        });
+    }
+
+    async fn full_state_deduplicated(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!topics:example.org";
+        create_room(db, room_id, &alice).await;
+        for topic in &["first", "second", "third"] {
+            let event = NewEvent {
+                event_content: EventContent::new(
+                    "m.room.topic",
+                    serde_json::json!({ "topic": topic }),
+                )
+                .unwrap(),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                redacts: None,
+                unsigned: None,
+            };
+            db.add_event(room_id, event, resolver, &Default::default())
+                .await
+                .unwrap();
+        }
+
+        let state = db.get_full_state(room_id, None).await.unwrap();
+        let topics = state
+            .iter()
+            .filter(|e| e.event_content.get_type() == "m.room.topic")
+            .collect::<Vec<_>>();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].event_content.content_as_json()["topic"], "third");
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_private_receipts() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            private_receipts(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_private_receipts() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let path = "sled-test-private-receipts";
+        let _ = std::fs::remove_dir_all(path);
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            private_receipts(&*db).await;
+        });
+        drop(db_pool);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    async fn private_receipts(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let room_id = "!receipts:example.org";
+        create_room(db, room_id, &alice).await;
+
+        db.set_receipt(room_id, &alice, "m.read.private", "$secret")
+            .await
+            .unwrap();
+        db.set_receipt(room_id, &bob, "m.read", "$public")
+            .await
+            .unwrap();
+
+        let alice_view = db
+            .get_all_ephemeral_for_user(room_id, &alice)
+            .await
+            .unwrap();
+        let receipts = &alice_view["m.receipt"];
+        assert!(receipts["$secret"]["m.read.private"]["@alice:example.org"].is_object());
+        assert!(receipts["$public"]["m.read"]["@bob:example.org"].is_object());
+
+        let bob_view = db.get_all_ephemeral_for_user(room_id, &bob).await.unwrap();
+        let receipts = &bob_view["m.receipt"];
+        assert!(receipts.get("$secret").is_none());
+        assert!(receipts["$public"]["m.read"]["@bob:example.org"].is_object());
+        assert!(bob_view
+            .keys()
+            .all(|ty| ty == "m.receipt" || ty == "m.typing"));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_redaction() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            redaction(&*db, &resolver).await;
+        });
+    }
+
+    async fn redaction(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!redaction:example.org";
+        create_room(db, room_id, &alice).await;
+        let new_event = |event_content, state_key: Option<&MatrixId>, redacts| NewEvent {
+            event_content,
+            sender: alice.clone(),
+            state_key: state_key.map(MatrixId::clone_inner),
+            redacts,
+            unsigned: None,
+        };
+        let join = EventContent::Member(Member {
+            avatar_url: None,
+            displayname: Some(String::from("Alice")),
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+        });
+        db.add_event(
+            room_id,
+            new_event(join, Some(&alice), None),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let message =
+            EventContent::new("m.room.message", serde_json::json!({ "body": "oops" })).unwrap();
+        let message_id = db
+            .add_event(
+                room_id,
+                new_event(message, None, None),
+                resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+
+        let redaction = EventContent::Redaction(Redaction { reason: None });
+        for _ in 0..2 {
+            db.add_event(
+                room_id,
+                new_event(redaction.clone(), None, Some(message_id.clone())),
+                resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            let redacted = db.get_pdu(room_id, &message_id).await.unwrap().unwrap();
+            assert_eq!(redacted.event_id(), message_id);
+            match redacted.event_content() {
+                EventContent::Message(_) => {}
+                _ => panic!("message turned into something else"),
+            }
+            assert_eq!(
+                redacted.event_content().content_as_json(),
+                serde_json::json!({})
+            );
+            // redacting it again mustn't lose the original
+            let original = db
+                .get_unredacted_pdu(room_id, &message_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(original.event_content().content_as_json()["body"], "oops");
+        }
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_handle_limit() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new().with_handle_limit(1);
+        rt.block_on(handle_limit(&db_pool));
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_handle_limit() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let path = "sled-test-handle-limit";
+        let _ = std::fs::remove_dir_all(path);
+        let db_pool = super::sled::SledStorage::new(path)
+            .unwrap()
+            .with_handle_limit(1);
+        rt.block_on(handle_limit(&db_pool));
+        drop(db_pool);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    async fn handle_limit(db_pool: &dyn StorageManager) {
+        use actix_web::{http::StatusCode, ResponseError};
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!handle_limit:example.org";
+        let resolver = StateResolver::new(db_pool.get_unlimited_handle().await.unwrap());
+        // a request holding the only handle there is
+        let db = db_pool.get_handle().await.unwrap();
+        create_room(&*db, room_id, &alice).await;
+        let err = db_pool
+            .get_handle()
+            .await
+            .err()
+            .expect("limit wasn't enforced");
+        assert_eq!(err.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        // the resolver works on that request's behalf, so it doesn't need a place of its own
+        resolver
+            .current_state_events(room_id)
+            .await
+            .expect("the resolver needed a second handle");
+
+        drop(db);
+        db_pool
+            .get_handle()
+            .await
+            .expect("dropping a handle didn't free up space");
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_client_event_id() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            client_event_id(&*db).await;
+        });
+    }
+
+    async fn client_event_id(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!event_id:example.org";
+        create_room(db, room_id, &alice).await;
+        let create_id = db.get_prev_events(room_id).await.unwrap().0.remove(0);
+
+        let event = db
+            .get_pdu(room_id, &create_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .to_client_format();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event_id"], serde_json::json!(create_id));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_room_v6() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            room_v6(&*db, &resolver).await;
+        });
+    }
+
+    async fn room_v6(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!v6:example.org";
+        let new_event = |event_content, state_key: Option<&str>| NewEvent {
+            event_content,
+            sender: alice.clone(),
+            state_key: state_key.map(String::from),
+            redacts: None,
+            unsigned: None,
+        };
+        let create = EventContent::Create(Create {
+            creator: alice.clone(),
+            room_version: Some(String::from("6")),
+            predecessor: None,
+            extra: HashMap::new(),
+        });
+        let join = EventContent::Member(Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+        });
+        let mut power_levels = PowerLevels::no_event_default_levels(&alice);
+        power_levels.users_default = Some(0);
+        let power_levels = EventContent::PowerLevels(power_levels);
+        // one more than canonical json allows
+        let big_number = serde_json::json!({ "body": "big", "number": 9007199254740992i64 });
+        let big_message = EventContent::new("m.room.message", big_number).unwrap();
+
+        let mut event_ids = Vec::new();
+        for (content, state_key) in [
+            (create, Some("")),
+            (join, Some(alice.as_str())),
+            (power_levels, Some("")),
+            (big_message, None),
+        ]
+        .iter()
+        .cloned()
+        {
+            let event_id = db
+                .add_event(
+                    room_id,
+                    new_event(content, state_key),
+                    resolver,
+                    &Default::default(),
+                )
+                .await
+                .unwrap();
+            event_ids.push(event_id);
+        }
+
+        let mut pdus = Vec::new();
+        for event_id in event_ids.iter() {
+            pdus.push(db.get_pdu(room_id, event_id).await.unwrap().unwrap());
+        }
+        assert!(pdus.iter().all(|pdu| pdu.inner().room_version() == "6"));
+        let auth = pdus
+            .iter()
+            .map(StoredPdu::did_pass_auth)
+            .collect::<Vec<_>>();
+        assert_eq!(auth, vec![true, true, true, false]);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_state_out_of_range() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            state_out_of_range(&*db, &resolver).await;
+        });
+    }
+
+    async fn state_out_of_range(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!state:example.org";
+        create_room(db, room_id, &alice).await;
+        db.add_event(
+            room_id,
+            NewEvent {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership: Membership::Join,
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                }),
+                sender: alice.clone(),
+                state_key: Some(alice.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            },
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let query = |at| EventQuery {
+            query_type: QueryType::State {
+                at,
+                state_keys: &[],
+                not_state_keys: &[],
+            },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+            include_soft_failed: false,
+        };
+        let event_ids =
+            |pdus: Vec<StoredPdu>| pdus.iter().map(StoredPdu::event_id).collect::<Vec<_>>();
+        let (current, _) = db.query_pdus(query(None), false).await.unwrap();
+        let (huge, _) = db.query_pdus(query(Some(usize::MAX)), false).await.unwrap();
+        assert_eq!(current.len(), 2);
+        assert_eq!(event_ids(huge), event_ids(current));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_account_data() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            account_data(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_account_data() {
+        let path = "sled-test-account-data";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            account_data(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn account_data(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        db.set_user_account_data(
+            "alice",
+            "m.direct",
+            serde_json::json!({ "@bob:example.org": ["!a:b"] }),
+        )
+        .await
+        .unwrap();
+        db.set_room_account_data("alice", "!a:b", "m.tag", serde_json::json!({ "tags": {} }))
+            .await
+            .unwrap();
+        db.set_user_account_data("alice", "m.direct", serde_json::json!({}))
+            .await
+            .unwrap();
+
+        let global = db.get_user_account_data("alice").await.unwrap();
+        assert_eq!(global.len(), 1);
+        assert_eq!(global["m.direct"], serde_json::json!({}));
+        let room = db.get_room_account_data("alice", "!a:b").await.unwrap();
+        assert_eq!(room.len(), 1);
+        assert_eq!(room["m.tag"], serde_json::json!({ "tags": {} }));
+
+        assert!(db.get_user_account_data("bob").await.unwrap().is_empty());
+        assert!(db
+            .get_room_account_data("bob", "!a:b")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .get_room_account_data("alice", "!c:d")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_account_data_changes() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            account_data_changes(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_account_data_changes() {
+        let path = "sled-test-account-data-changes";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            account_data_changes(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn account_data_changes(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        let changes = db.get_account_data("alice", 0).await.unwrap();
+        assert_eq!(changes.position, 0);
+        assert!(changes.global.is_empty() && changes.rooms.is_empty());
+
+        db.set_user_account_data("alice", "m.direct", serde_json::json!({}))
+            .await
+            .unwrap();
+        db.set_room_account_data("alice", "!a:b", "m.tag", serde_json::json!({ "tags": {} }))
+            .await
+            .unwrap();
+        let changes = db.get_account_data("alice", 0).await.unwrap();
+        assert_eq!(changes.position, 2);
+        assert_eq!(changes.global["m.direct"], serde_json::json!({}));
+        assert_eq!(
+            changes.rooms["!a:b"]["m.tag"],
+            serde_json::json!({ "tags": {} })
+        );
+
+        db.set_room_account_data("alice", "!c:d", "m.tag", serde_json::json!({ "tags": {} }))
+            .await
+            .unwrap();
+        let changes = db.get_account_data("alice", 2).await.unwrap();
+        assert_eq!(changes.position, 3);
+        assert!(changes.global.is_empty());
+        assert_eq!(changes.rooms.len(), 1);
+        assert!(changes.rooms.contains_key("!c:d"));
+
+        let changes = db.get_account_data("alice", 3).await.unwrap();
+        assert_eq!(changes.position, 3);
+        assert!(changes.global.is_empty() && changes.rooms.is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_missing_pdu() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            missing_pdu(&*db).await;
+        });
+    }
+
+    async fn missing_pdu(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!exists:example.org";
+        create_room(db, room_id, &alice).await;
+
+        let missing_event = db.get_pdu(room_id, "$nonexistent").await.unwrap();
+        assert!(missing_event.is_none());
+        let missing_room = db
+            .get_pdu("!nonexistent:example.org", "$nonexistent")
+            .await
+            .unwrap_err();
+        assert!(matches!(missing_room.kind(), ErrorKind::RoomNotFound));
+    }
+
+    /// Adds a join from bob that passed auth against the state before it, but was soft failed.
+    async fn add_soft_failed_join(db: &dyn Storage, room_id: &str) -> String {
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let (prev_events, depth) = db.get_prev_events(room_id).await.unwrap();
+        let join = UnhashedPdu {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+            }),
+            room_id: String::from(room_id),
+            sender: bob.clone(),
+            state_key: Some(bob.clone_inner()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events,
+            depth: depth + 1,
+            auth_events: Vec::new(),
+        }
+        .finalize();
+        let pdu = StoredPdu {
+            inner: VersionedPdu::V4(join),
+            auth_status: AuthStatus::Pass,
+            soft_failed: true,
+        };
+        let event_id = pdu.event_id();
+        db.add_pdus(&[pdu]).await.unwrap();
+        event_id
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_soft_failed_extremities() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            soft_failed_extremities(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_soft_failed_extremities() {
+        let path = "sled-test-soft-failed-extremities";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            soft_failed_extremities(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn soft_failed_extremities(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!soft:example.org";
+        create_room(db, room_id, &alice).await;
+        let before = db.get_prev_events(room_id).await.unwrap();
+
+        // the event is kept, but new events don't build on it
+        let event_id = add_soft_failed_join(db, room_id).await;
+        assert!(db.get_pdu(room_id, &event_id).await.unwrap().is_some());
+        assert_eq!(db.get_prev_events(room_id).await.unwrap(), before);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_soft_failed_membership() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            soft_failed_membership(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_soft_failed_membership() {
+        let path = "sled-test-soft-failed-membership";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            soft_failed_membership(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn soft_failed_membership(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let room_id = "!soft:example.org";
+        create_room(db, room_id, &alice).await;
+        add_soft_failed_join(db, room_id).await;
+        assert_eq!(db.count_joined_rooms(&bob).await.unwrap(), 0);
+        assert!(db.get_joined_rooms_for_user(&bob).await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_filters() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            filters(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_filters() {
+        let path = "sled-test-filters";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            filters(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn filters(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        let filter = serde_json::json!({ "room": { "timeline": { "limit": 10 } } });
+        let filter_id = db.create_filter("alice", filter.clone()).await.unwrap();
+
+        assert_eq!(
+            db.get_filter("alice", &filter_id).await.unwrap(),
+            Some(filter)
+        );
+        // filter ids are per user
+        assert_eq!(db.get_filter("bob", &filter_id).await.unwrap(), None);
+        assert_eq!(db.get_filter("alice", "nonexistent").await.unwrap(), None);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_token_last_seen() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            token_last_seen(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_token_last_seen() {
+        let path = "sled-test-token-last-seen";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            token_last_seen(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_tokens_per_device() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            tokens_per_device(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_tokens_per_device() {
+        let path = "sled-test-tokens-per-device";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            tokens_per_device(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn tokens_per_device(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        let phone = db.create_access_token("alice", "phone").await.unwrap();
+        let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+        let user_and_device = |username: &str, device_id: &str| {
+            Some((String::from(username), String::from(device_id)))
+        };
+        assert_eq!(
+            db.try_auth_full(phone).await.unwrap(),
+            user_and_device("alice", "phone")
+        );
+        assert_eq!(
+            db.try_auth_full(laptop).await.unwrap(),
+            user_and_device("alice", "laptop")
+        );
+
+        db.delete_access_token(phone).await.unwrap();
+        assert_eq!(db.try_auth_full(phone).await.unwrap(), None);
+        assert_eq!(
+            db.try_auth_full(laptop).await.unwrap(),
+            user_and_device("alice", "laptop")
+        );
+
+        db.delete_access_token(laptop).await.unwrap();
+        assert_eq!(db.try_auth_full(laptop).await.unwrap(), None);
+        assert!(db.get_devices("alice").await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_device_management() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            device_management(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_device_management() {
+        let path = "sled-test-device-management";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            device_management(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn device_management(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        let phone_1 = db.create_access_token("alice", "phone").await.unwrap();
+        let phone_2 = db.create_access_token("alice", "phone").await.unwrap();
+        let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+        let bobs_phone = db.create_access_token("bob", "phone").await.unwrap();
+
+        db.set_device_display_name("alice", "phone", "Alice's phone")
+            .await
+            .unwrap();
+        let phone = db.get_device("alice", "phone").await.unwrap().unwrap();
+        assert_eq!(phone.display_name.as_deref(), Some("Alice's phone"));
+        let laptop_device = db.get_device("alice", "laptop").await.unwrap().unwrap();
+        assert_eq!(laptop_device.display_name, None);
+        assert!(db.get_device("alice", "toaster").await.unwrap().is_none());
+
+        db.delete_device("alice", "phone").await.unwrap();
+        assert!(db.try_auth(phone_1).await.unwrap().is_none());
+        assert!(db.try_auth(phone_2).await.unwrap().is_none());
+        assert_eq!(db.try_auth(laptop).await.unwrap().as_deref(), Some("alice"));
+        assert_eq!(
+            db.try_auth(bobs_phone).await.unwrap().as_deref(),
+            Some("bob")
+        );
+        assert!(db.get_device("alice", "phone").await.unwrap().is_none());
+
+        // logging in again on the same device doesn't bring its old name back
+        db.create_access_token("alice", "phone").await.unwrap();
+        let phone = db.get_device("alice", "phone").await.unwrap().unwrap();
+        assert_eq!(phone.display_name, None);
+    }
+
+    #[test]
+    fn contains_json_matches_nested_objects() {
+        let content = json!({
+            "body": "* hello",
+            "m.new_content": { "body": "hello", "msgtype": "m.text" },
+            "m.relates_to": { "event_id": "$abc:example.org", "rel_type": "m.replace" },
+        });
+        assert!(json_contains(&content, &json!({})));
+        assert!(json_contains(
+            &content,
+            &json!({ "m.relates_to": { "rel_type": "m.replace" } })
+        ));
+        assert!(json_contains(
+            &content,
+            &json!({ "body": "* hello", "m.new_content": { "msgtype": "m.text" } })
+        ));
+        assert!(!json_contains(
+            &content,
+            &json!({ "m.relates_to": { "rel_type": "m.annotation" } })
+        ));
+        // every key in the pattern has to be there, however deep it is
+        assert!(!json_contains(
+            &content,
+            &json!({ "m.relates_to": { "key": "👍" } })
+        ));
+        assert!(!json_contains(&content, &json!({ "msgtype": "m.text" })));
+        // non-objects are compared exactly
+        assert!(!json_contains(
+            &json!({ "a": [1, 2] }),
+            &json!({ "a": [1] })
+        ));
+        assert!(!json_contains(
+            &json!({ "a": 1 }),
+            &json!({ "a": { "b": 1 } })
+        ));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_admin_flag() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            admin_flag(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_admin_flag() {
+        let path = "sled-test-admin-flag";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            admin_flag(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn admin_flag(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        assert!(!db.is_admin("alice").await.unwrap());
+        assert!(!db.is_admin("bob").await.unwrap());
+        db.set_admin("alice", true).await.unwrap();
+        assert!(db.is_admin("alice").await.unwrap());
+        assert!(db.verify_password("alice", "password").await.unwrap());
+        db.set_admin("alice", false).await.unwrap();
+        assert!(!db.is_admin("alice").await.unwrap());
+        assert!(db.set_admin("bob", true).await.is_err());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_profile_changes() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            profile_changes(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_profile_changes() {
+        let path = "sled-test-profile-changes";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            profile_changes(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn profile_changes(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        assert!(db.set_display_name("alice", "Alice").await.unwrap());
+        assert!(!db.set_display_name("alice", "Alice").await.unwrap());
+        assert!(db.set_display_name("alice", "Alice!").await.unwrap());
+        assert!(db.set_avatar_url("alice", "mxc://a/b").await.unwrap());
+        assert!(!db.set_avatar_url("alice", "mxc://a/b").await.unwrap());
+        let profile = db.get_profile("alice").await.unwrap().unwrap();
+        assert_eq!(profile.displayname.as_deref(), Some("Alice!"));
+        assert_eq!(profile.avatar_url.as_deref(), Some("mxc://a/b"));
+        assert!(db.set_display_name("bob", "Bob").await.is_err());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_presence() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            presence(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_presence() {
+        let path = "sled-test-presence";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            presence(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn presence(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        assert!(db.get_presence("alice").await.unwrap().is_none());
+        assert!(db
+            .set_presence("carol", PresenceState::Online, None, 1)
+            .await
+            .is_err());
+        db.set_presence("alice", PresenceState::Online, None, 1)
+            .await
+            .unwrap();
+        let lunch = Some(String::from("lunch"));
+        db.set_presence("bob", PresenceState::Unavailable, lunch, 2)
+            .await
+            .unwrap();
+        let bob = db.get_presence("bob").await.unwrap().unwrap();
+        assert_eq!(bob.state, PresenceState::Unavailable);
+        assert_eq!(bob.status_msg.as_deref(), Some("lunch"));
+        assert_eq!(bob.last_active_ts, 2);
+
+        let usernames = |changes: Vec<(String, Presence)>| {
+            changes.into_iter().map(|(u, _)| u).collect::<Vec<_>>()
+        };
+        let (changes, position) = db.get_presence_changes(0).await.unwrap();
+        assert_eq!(usernames(changes), vec!["alice", "bob"]);
+        assert_eq!(position, bob.position);
+
+        // setting it again moves alice to the end of the stream, rather than adding her twice
+        db.set_presence("alice", PresenceState::Offline, None, 3)
+            .await
+            .unwrap();
+        let (changes, new_position) = db.get_presence_changes(position).await.unwrap();
+        assert_eq!(changes[0].1.state, PresenceState::Offline);
+        assert_eq!(usernames(changes), vec!["alice"]);
+        assert!(new_position > position);
+        let (changes, _) = db.get_presence_changes(0).await.unwrap();
+        assert_eq!(usernames(changes), vec!["bob", "alice"]);
+        let (changes, unchanged) = db.get_presence_changes(new_position).await.unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(unchanged, new_position);
+
+        // waiting only returns straight away if something has changed since
+        assert!(db.wait_for_presence(position).now_or_never().is_some());
+        assert!(db.wait_for_presence(new_position).now_or_never().is_none());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_deactivation() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            deactivation(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_deactivation() {
+        let path = "sled-test-deactivation";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            deactivation(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn deactivation(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        let phone = db.create_access_token("alice", "phone").await.unwrap();
+        let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+        let bob = db.create_access_token("bob", "phone").await.unwrap();
+
+        db.deactivate_user("alice").await.unwrap();
+        assert!(db.try_auth(phone).await.unwrap().is_none());
+        assert!(db.try_auth(laptop).await.unwrap().is_none());
+        assert!(db.get_devices("alice").await.unwrap().is_empty());
+        assert!(!db.verify_password("alice", "password").await.unwrap());
+        assert!(db.create_access_token("alice", "phone").await.is_err());
+        assert!(db.create_user("alice", "password").await.is_err());
+        // nobody else is affected
+        assert_eq!(db.try_auth(bob).await.unwrap().as_deref(), Some("bob"));
+        assert!(db.verify_password("bob", "password").await.unwrap());
+        assert!(db.deactivate_user("carol").await.is_err());
+    }
+
+    async fn token_last_seen(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        let old = db.create_access_token("alice", "phone").await.unwrap();
+        let new = db.create_access_token("alice", "phone").await.unwrap();
+        let devices = db.get_devices("alice").await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].last_seen_ts, None);
+
+        db.update_token_last_seen(new, Some("192.0.2.1"), 2000)
+            .await
+            .unwrap();
+        db.update_token_last_seen(old, Some("192.0.2.2"), 1000)
+            .await
+            .unwrap();
+        let devices = db.get_devices("alice").await.unwrap();
+        assert_eq!(devices[0].device_id, "phone");
+        assert_eq!(devices[0].last_seen_ip.as_deref(), Some("192.0.2.1"));
+        assert_eq!(devices[0].last_seen_ts, Some(2000));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_room_aliases() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            room_aliases(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_room_aliases() {
+        let path = "sled-test-room-aliases";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            room_aliases(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn room_aliases(db: &dyn Storage) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        create_room(db, "!aliased:example.org", &alice).await;
+        let alias = "#hangout:example.org";
+
+        assert!(matches!(
+            db.set_room_alias(alias, "!nonexistent:example.org", &alice)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::RoomNotFound
+        ));
+        db.set_room_alias(alias, "!aliased:example.org", &alice)
+            .await
+            .unwrap();
+        db.set_room_alias("#other:example.org", "!aliased:example.org", &bob)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.set_room_alias(alias, "!aliased:example.org", &bob)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::RoomInUse
+        ));
+        assert_eq!(
+            db.get_room_alias(alias).await.unwrap().as_deref(),
+            Some("!aliased:example.org")
+        );
+        // bob's failed attempt doesn't make him the creator
+        assert_eq!(db.get_room_alias_creator(alias).await.unwrap(), Some(alice));
+        assert_eq!(
+            db.get_room_alias_creator("#other:example.org")
+                .await
+                .unwrap(),
+            Some(bob)
+        );
+        let mut aliases = db.get_local_aliases("!aliased:example.org").await.unwrap();
+        aliases.sort();
+        assert_eq!(aliases, vec!["#hangout:example.org", "#other:example.org"]);
+
+        assert!(db.delete_room_alias(alias).await.unwrap());
+        assert!(!db.delete_room_alias(alias).await.unwrap());
+        assert_eq!(db.get_room_alias(alias).await.unwrap(), None);
+        assert_eq!(db.get_room_alias_creator(alias).await.unwrap(), None);
+        assert_eq!(
+            db.get_local_aliases("!aliased:example.org").await.unwrap(),
+            vec!["#other:example.org"]
+        );
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_batch_eviction() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            batch_eviction(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_batch_eviction() {
+        let path = "sled-test-batch-eviction";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            batch_eviction(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn batch_eviction(db: &dyn Storage) {
+        let devices = [("alice", "phone"), ("alice", "laptop"), ("bob", "phone")];
+        let mut ids = Vec::new();
+        // every device syncs many times, each time from the batch it got last
+        for i in 0..50 {
+            for (username, device_id) in devices.iter() {
+                let id = format!("{}-{}-{}", username, device_id, i);
+                let since = match i {
+                    0 => None,
+                    _ => Some(format!("{}-{}-{}", username, device_id, i - 1)),
+                };
+                let mut batch = Batch::default();
+                batch.rooms.insert(String::from("!room:example.org"), i);
+                db.set_batch(username, device_id, since.as_deref(), &id, batch)
+                    .await
+                    .unwrap();
+                ids.push(id);
+            }
+        }
+
+        let mut kept = Vec::new();
+        for id in ids.iter() {
+            if db.get_batch(id).await.unwrap().is_some() {
+                kept.push(id.as_str());
+            }
+        }
+        assert_eq!(
+            kept,
+            vec![
+                "alice-phone-48",
+                "alice-laptop-48",
+                "bob-phone-48",
+                "alice-phone-49",
+                "alice-laptop-49",
+                "bob-phone-49",
+            ]
+        );
+        let latest = db.get_batch("bob-phone-49").await.unwrap().unwrap();
+        assert_eq!(latest.rooms["!room:example.org"], 49);
+
+        // a client that never gets its responses keeps retrying from the same batch, which has to
+        // stay around however many times that happens
+        let mut retries = Vec::new();
+        for i in 0..BATCHES_PER_DEVICE * 2 {
+            let id = format!("retry-{}", i);
+            db.set_batch("bob", "phone", Some("bob-phone-49"), &id, Batch::default())
+                .await
+                .unwrap();
+            retries.push(id);
+        }
+        assert!(db.get_batch("bob-phone-49").await.unwrap().is_some());
+        assert!(db.get_batch(&retries[0]).await.unwrap().is_none());
+        let last_retry = retries.last().unwrap();
+        assert!(db.get_batch(last_retry).await.unwrap().is_some());
+        // until it finally gets one and moves on
+        db.set_batch(
+            "bob",
+            "phone",
+            Some(last_retry),
+            "moved-on",
+            Batch::default(),
+        )
+        .await
+        .unwrap();
+        assert!(db.get_batch("bob-phone-49").await.unwrap().is_none());
+        assert!(db.get_batch(last_retry).await.unwrap().is_some());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_cached_state() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            cached_state(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_cached_state() {
+        let path = "sled-test-cached-state";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            cached_state(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn cached_state(db: &dyn Storage) {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let mut state = HashMap::new();
+        state.insert(
+            (String::from("m.room.create"), String::new()),
+            String::from("$create"),
+        );
+        state.insert(
+            (String::from("m.room.name"), String::new()),
+            String::from("$name"),
+        );
+        db.set_cached_state(&ids(&["$b", "$a"]), state.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db.get_cached_state(&ids(&["$a", "$b"])).await.unwrap(),
+            Some(state)
+        );
+        assert_eq!(db.get_cached_state(&ids(&["$a"])).await.unwrap(), None);
+        assert_eq!(
+            db.get_cached_state(&ids(&["$a", "$b", "$c"]))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_membership_backfill() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            membership_backfill(&*db, &resolver).await;
+        });
+    }
+
+    async fn membership_backfill(db: &dyn Storage, resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        let carol = MatrixId::new("carol", "example.org").unwrap();
+        let member = |sender: &MatrixId, target: &MatrixId, membership| {
+            NewEvent::builder(
+                sender.clone(),
+                EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership,
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                }),
+            )
+            .state_key(target.clone_inner())
+            .build()
+        };
+
+        let rooms = ["!first:example.org", "!second:example.org"];
+        for room_id in rooms.iter() {
+            create_room(db, room_id, &alice).await;
+            db.add_event(
+                room_id,
+                member(&alice, &alice, Membership::Join),
+                resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+        for (sender, target, membership) in [
+            (&alice, &bob, Membership::Invite),
+            (&bob, &bob, Membership::Join),
+            (&bob, &bob, Membership::Leave),
+            (&alice, &carol, Membership::Invite),
+        ] {
+            db.add_event(
+                rooms[0],
+                member(sender, target, membership),
+                resolver,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        }
+        db.add_event(
+            rooms[1],
+            member(&alice, &carol, Membership::Invite),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        // fails auth, so it mustn't count
+        db.add_event(
+            rooms[1],
+            member(&bob, &bob, Membership::Join),
+            resolver,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let events = db
+            .stream_room_events(rooms[0])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let (timeline, _) = db
+            .query_pdus(
+                EventQuery {
+                    query_type: QueryType::Timeline { from: 0, to: None },
+                    room_id: rooms[0],
+                    senders: &[],
+                    not_senders: &[],
+                    types: &[],
+                    not_types: &[],
+                    contains_json: None,
+                    include_soft_failed: false,
+                },
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            events.iter().map(StoredPdu::event_id).collect::<Vec<_>>(),
+            timeline.iter().map(StoredPdu::event_id).collect::<Vec<_>>()
+        );
+
+        let index = build_membership_index(db).await.unwrap();
+        for user in [&alice, &bob, &carol].iter() {
+            for room_id in rooms.iter() {
+                let live = db
+                    .get_membership(user, room_id, Some(resolver))
+                    .await
+                    .unwrap();
+                let backfilled = index.get(user.as_str()).and_then(|r| r.get(*room_id));
+                assert_eq!(
+                    backfilled,
+                    live.as_ref(),
+                    "{} in {}",
+                    user.as_str(),
+                    room_id
+                );
+            }
+            let mut invites = index
+                .get(user.as_str())
+                .into_iter()
+                .flatten()
+                .filter(|(_, m)| **m == Membership::Invite)
+                .map(|(room_id, _)| room_id.clone())
+                .collect::<Vec<_>>();
+            invites.sort();
+            let mut live_invites = db.get_invited_rooms_for_user(user).await.unwrap();
+            live_invites.sort();
+            assert_eq!(invites, live_invites);
+        }
+        assert_eq!(index[carol.as_str()].len(), 2);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn added_events_are_signed() {
+        use crate::server_api::keys::verify_signature;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!signed:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            create_room(&*db, room_id, &alice).await;
+
+            let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+            let verify_keys = vec![(
+                String::from("ed25519:test"),
+                base64::encode_config(key.public_key().as_ref(), base64::STANDARD_NO_PAD),
+            )]
+            .into_iter()
+            .collect();
+            let keys = vec![(String::from("ed25519:test"), key)]
+                .into_iter()
+                .collect();
+            let join = NewEvent::builder(
+                alice.clone(),
+                EventContent::new("m.room.member", json!({ "membership": "join" })).unwrap(),
+            )
+            .state_key(alice.as_str())
+            .build();
+            let event_id = db.add_event(room_id, join, &resolver, &keys).await.unwrap();
+
+            let pdu = db.get_pdu(room_id, &event_id).await.unwrap().unwrap();
+            let redacted = match pdu.inner.redact() {
+                VersionedPdu::V4(pdu) | VersionedPdu::V5(pdu) | VersionedPdu::V6(pdu) => {
+                    serde_json::to_value(pdu).unwrap()
+                }
+            };
+            assert!(verify_signature(&redacted, "example.org", &verify_keys).is_ok());
+        });
+    }
 }
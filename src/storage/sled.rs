@@ -1,30 +1,41 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     convert::TryInto,
+    io::Cursor,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
 use bincode::{DefaultOptions, Options};
+use futures::stream::{self, BoxStream, StreamExt};
 use itertools::Itertools;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use sled::{
-    transaction::{ConflictableTransactionError, TransactionalTree},
+    transaction::{
+        ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+    },
     Db, IVec, Tree,
 };
-use tokio::sync::Mutex;
+use tokio::sync::{
+    broadcast::{channel, Sender},
+    Mutex,
+};
 use uuid::Uuid;
 
 use crate::{
     error::{Error, ErrorKind},
-    events::{ephemeral::Typing, pdu::StoredPdu},
-    storage::{Storage, StorageManager},
+    events::{ephemeral::Typing, pdu::StoredPdu, room::Membership, EventContent},
+    storage::{HandleLimit, HandlePermit, Storage, StorageManager, ToDeviceMessage, TokenInfo},
     util::MatrixId,
 };
 
-use super::{Batch, EventQuery, QueryType, UserProfile};
+use super::{
+    build_membership_index, push_batch, retain_latest_state, set_receipt_in, state_cache_key,
+    user_matches_search, AccountDataChanges, Batch, EventQuery, PasswordParams, Presence,
+    PresenceState, QueryType, StateMap, UserProfile,
+};
 
 trait TreeExt {
     type Error;
@@ -150,23 +161,111 @@ impl TreeExt for TransactionalTree {
     }
 }
 
+/// The layout version of the databases that this version of kerux writes. Databases from before
+/// the version was recorded count as version 0.
+const SCHEMA_VERSION: u32 = 8;
+
+/// The key in the default tree that the database's layout version is kept under.
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// The error for an event that an index says is in a room's timeline, but that isn't stored.
+fn missing_timeline_event(room_id: &str, event_id: &str) -> Error {
+    ErrorKind::Unknown(format!(
+        "Event in the timeline of {} doesn't exist: {}",
+        room_id, event_id
+    ))
+    .into()
+}
+
+/// Gets an event from the events or unredacted tree. Events are stored as json, because bincode
+/// can't serialize the flattened event content of a PDU.
+fn get_stored_pdu<K: AsRef<[u8]>>(tree: &Tree, key: K) -> Result<Option<StoredPdu>, Error> {
+    let pdu = tree
+        .get(key)?
+        .map(|bytes| serde_json::from_slice(&bytes))
+        .transpose()?;
+    Ok(pdu)
+}
+
+/// Reads the next field of a record written by an older version, or returns None if the record
+/// ends first because that version didn't have the field. Fields are only ever added to the end
+/// of a record, so every older layout is a prefix of the current one.
+fn read_added_field<T: DeserializeOwned>(reader: &mut Cursor<&[u8]>) -> Result<Option<T>, Error> {
+    if reader.position() == reader.get_ref().len() as u64 {
+        return Ok(None);
+    }
+    let field = DefaultOptions::new()
+        .allow_trailing_bytes()
+        .deserialize_from(&mut *reader)?;
+    Ok(Some(field))
+}
+
 #[derive(Default, Deserialize, Serialize)]
 struct User {
     password_hash: String,
     profile: UserProfile,
-    account_data: HashMap<String, JsonValue>,
+    is_admin: bool,
+    deactivated: bool,
+}
+
+impl User {
+    /// Decodes a user in any layout that it has been stored in.
+    fn decode_any_version(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Cursor::new(bytes);
+        let (password_hash, profile) = DefaultOptions::new()
+            .allow_trailing_bytes()
+            .deserialize_from(&mut reader)?;
+        // before there were admins, this was where an account data map went. It was always
+        // empty, and its zero length reads as false.
+        Ok(User {
+            password_hash,
+            profile,
+            is_admin: read_added_field(&mut reader)?.unwrap_or(false),
+            deactivated: read_added_field(&mut reader)?.unwrap_or(false),
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 struct AccessTokenData {
     username: String,
     device_id: String,
+    last_seen_ip: Option<String>,
+    last_seen_ts: Option<i64>,
+}
+
+impl AccessTokenData {
+    /// Decodes token data in any layout that it has been stored in.
+    fn decode_any_version(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Cursor::new(bytes);
+        let (username, device_id) = DefaultOptions::new()
+            .allow_trailing_bytes()
+            .deserialize_from(&mut reader)?;
+        Ok(AccessTokenData {
+            username,
+            device_id,
+            last_seen_ip: read_added_field(&mut reader)?.flatten(),
+            last_seen_ts: read_added_field(&mut reader)?.flatten(),
+        })
+    }
 }
 
-#[derive(Default)]
 struct Ephemeral {
     ephemeral: HashMap<String, JsonValue>,
     typing: HashMap<MatrixId, Instant>,
+    /// Ephemeral data doesn't live in sled, so `watch_prefix` can't see it change. This wakes
+    /// anyone waiting on the room instead.
+    notify_send: Sender<()>,
+}
+
+impl Default for Ephemeral {
+    fn default() -> Self {
+        Ephemeral {
+            ephemeral: HashMap::new(),
+            typing: HashMap::new(),
+            notify_send: channel(1).0,
+        }
+    }
 }
 
 impl Ephemeral {
@@ -180,30 +279,310 @@ impl Ephemeral {
     }
 }
 
-pub struct SledStorage(SledStorageHandle);
+pub struct SledStorage {
+    handle: SledStorageHandle,
+    handle_limit: HandleLimit,
+}
 
 impl SledStorage {
     pub fn new(path: &str) -> Result<Self, Error> {
         let db = sled::open(path)?;
-        Ok(Self(SledStorageHandle {
+        let handle = SledStorageHandle {
             all: db.clone(),
             events: db.open_tree("events")?,
+            unredacted: db.open_tree("unredacted")?,
             rooms: db.open_tree("rooms")?,
             users: db.open_tree("users")?,
             access_tokens: db.open_tree("access_tokens")?,
+            user_tokens: db.open_tree("user_tokens")?,
             txn_ids: db.open_tree("txn_ids")?,
             batches: db.open_tree("batches")?,
+            device_batches: db.open_tree("device_batches")?,
+            device_names: db.open_tree("device_names")?,
+            device_keys: db.open_tree("device_keys")?,
+            one_time_keys: db.open_tree("one_time_keys")?,
+            to_device: db.open_tree("to_device")?,
+            presence: db.open_tree("presence")?,
+            presence_stream: db.open_tree("presence_stream")?,
             room_orderings: Arc::new(Mutex::new(HashMap::new())),
+            stream_orderings: db.open_tree("stream_orderings")?,
             headless_events: db.open_tree("headless_events")?,
+            outliers: db.open_tree("outliers")?,
+            outlier_children: db.open_tree("outlier_children")?,
+            state_cache: db.open_tree("state_cache")?,
+            memberships: db.open_tree("memberships")?,
+            account_data: db.open_tree("account_data")?,
+            account_data_streams: db.open_tree("account_data_streams")?,
+            filters: db.open_tree("filters")?,
+            public_rooms: db.open_tree("public_rooms")?,
+            room_aliases: db.open_tree("room_aliases")?,
+            room_alias_creators: db.open_tree("room_alias_creators")?,
             ephemeral: Arc::new(Mutex::new(HashMap::new())),
-        }))
+            password_params: PasswordParams::default(),
+            default_displayname_to_localpart: false,
+            _permit: HandlePermit::default(),
+        };
+        Ok(Self {
+            handle,
+            handle_limit: HandleLimit::default(),
+        })
+    }
+
+    pub fn with_password_params(mut self, params: PasswordParams) -> Self {
+        self.handle.password_params = params;
+        self
+    }
+
+    /// Makes new users start out with their localpart as their display name.
+    pub fn with_default_displayname_to_localpart(mut self, enabled: bool) -> Self {
+        self.handle.default_displayname_to_localpart = enabled;
+        self
+    }
+
+    /// Brings a database created by an older version up to date, one schema version at a time.
+    /// Each version is recorded once it's reached, so an interrupted migration carries on from
+    /// the last step that finished.
+    pub async fn migrate(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        let mut version = match handle.all.get(SCHEMA_VERSION_KEY)? {
+            Some(bytes) => u32::from_be_bytes(bytes.as_ref().try_into().unwrap()),
+            // there's nothing to migrate in a new database
+            None if handle.users.is_empty() && handle.rooms.is_empty() => {
+                handle
+                    .all
+                    .insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_be_bytes())?;
+                SCHEMA_VERSION
+            }
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(ErrorKind::Unknown(format!(
+                "The database has schema version {}, which is newer than this version of kerux",
+                version
+            ))
+            .into());
+        }
+        while version < SCHEMA_VERSION {
+            tracing::info!("Migrating the database from schema version {}", version);
+            match version {
+                0 => self.backfill_indexes().await?,
+                1 => self.rewrite_access_tokens()?,
+                2 => self.rewrite_users()?,
+                3 => self.add_account_data_positions()?,
+                4 => self.rewrite_batches()?,
+                5 => self.add_presence_positions()?,
+                6 => self.index_outlier_children()?,
+                7 => self.store_max_depths()?,
+                _ => unreachable!(),
+            }
+            version += 1;
+            handle
+                .all
+                .insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the membership, user token and stream ordering indexes from the data they index.
+    /// Entries that are already there are overwritten with the same values, so this also
+    /// completes an index that was only partly built.
+    async fn backfill_indexes(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        tracing::info!("Backfilling the user token index");
+        for res in handle.access_tokens.iter() {
+            let (token, data) = res?;
+            // this runs before the token data is rewritten in the current layout
+            let data = AccessTokenData::decode_any_version(&data)?;
+            let token = Uuid::from_slice(&token).unwrap();
+            handle.user_tokens.insert(
+                format!("{}~{}", data.username, token),
+                &token.as_bytes()[..],
+            )?;
+        }
+        tracing::info!("Backfilling the membership index");
+        for (user_id, rooms) in build_membership_index(handle).await? {
+            for (room_id, membership) in rooms {
+                handle
+                    .memberships
+                    .overwrite_value(format!("{}~{}", user_id, room_id), &membership)?;
+            }
+        }
+        tracing::info!("Backfilling the stream ordering index");
+        for res in handle.rooms.iter() {
+            let (room_id, _) = res?;
+            let room_id = String::from_utf8(room_id.to_vec()).unwrap();
+            let ordering_tree = handle.get_room_ordering_tree(&room_id).await?;
+            for res in ordering_tree.iter() {
+                let (ordering, event_id) = res?;
+                let event_id = String::from_utf8(event_id.to_vec()).unwrap();
+                handle
+                    .stream_orderings
+                    .insert(format!("{}_{}", room_id, event_id), ordering)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the data of every access token in the current layout, which added when and from
+    /// where the token was last used.
+    fn rewrite_access_tokens(&self) -> Result<(), Error> {
+        let access_tokens = &self.handle.access_tokens;
+        for res in access_tokens.iter() {
+            let (token, data) = res?;
+            let data = AccessTokenData::decode_any_version(&data)?;
+            access_tokens.overwrite_value(token, data)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites every user in the current layout, which added the admin and deactivated flags.
+    fn rewrite_users(&self) -> Result<(), Error> {
+        let users = &self.handle.users;
+        for res in users.iter() {
+            let (username, user) = res?;
+            let user = User::decode_any_version(&user)?;
+            users.overwrite_value(username, user)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a stream position to each piece of account data that was stored without one.
+    fn add_account_data_positions(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        for res in handle.account_data.iter() {
+            let (key, value) = res?;
+            let value: JsonValue = serde_json::from_slice(&value)?;
+            // the content is usually an object, so a position followed by anything else almost
+            // certainly means that this was already written with a position
+            let has_position = match value.as_array().map(Vec::as_slice) {
+                Some([position, _]) => position.is_u64(),
+                _ => false,
+            };
+            if has_position {
+                continue;
+            }
+            let username = key.split(|&b| b == b'~').next().unwrap();
+            let position = handle
+                .account_data_streams
+                .update_and_fetch(username, |old| {
+                    let old = old.map_or(0, |b| u64::from_be_bytes(b.try_into().unwrap()));
+                    Some(u64::to_be_bytes(old + 1).to_vec())
+                })?
+                .unwrap();
+            let position = u64::from_be_bytes(position.as_ref().try_into().unwrap());
+            handle
+                .account_data
+                .insert(key, serde_json::to_vec(&(position, value))?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites every sync batch as json, which unlike bincode lets fields be added to batches
+    /// without breaking the since tokens that clients already have.
+    fn rewrite_batches(&self) -> Result<(), Error> {
+        let batches = &self.handle.batches;
+        for res in batches.iter() {
+            let (id, bytes) = res?;
+            if serde_json::from_slice::<Batch>(&bytes).is_ok() {
+                continue;
+            }
+            let mut reader = Cursor::new(bytes.as_ref());
+            let (rooms, invites) = DefaultOptions::new()
+                .allow_trailing_bytes()
+                .deserialize_from(&mut reader)?;
+            let batch = Batch {
+                rooms,
+                invites,
+                account_data: read_added_field(&mut reader)?.unwrap_or(0),
+                to_device: read_added_field(&mut reader)?.unwrap_or(0),
+                // old batches kept a timestamp rather than a stream position, which just means
+                // that everyone's presence gets sent again
+                presence_position: 0,
+            };
+            batches.insert(id, serde_json::to_vec(&batch)?)?;
+        }
+        Ok(())
+    }
+
+    /// Puts everyone whose presence was stored without a position into the presence stream.
+    fn add_presence_positions(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        for res in handle.presence.iter() {
+            let (username, bytes) = res?;
+            let mut reader = Cursor::new(bytes.as_ref());
+            let (state, status_msg, last_active_ts) = DefaultOptions::new()
+                .allow_trailing_bytes()
+                .deserialize_from(&mut reader)?;
+            if read_added_field::<u64>(&mut reader)?.is_some() {
+                continue;
+            }
+            let position = handle.all.generate_id()? + 1;
+            let presence = Presence {
+                state,
+                status_msg,
+                last_active_ts,
+                position,
+            };
+            handle.presence.overwrite_value(&username, presence)?;
+            handle
+                .presence_stream
+                .insert(position.to_be_bytes(), username)?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the outliers by the prev events that they're waiting for, so that they can be
+    /// found when those arrive.
+    fn index_outlier_children(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        for res in handle.outliers.iter() {
+            let (key, event_id) = res?;
+            let event_id = String::from_utf8(event_id.to_vec()).unwrap();
+            let split = key.len() - 8;
+            let room_id = String::from_utf8(key[..split - 1].to_vec()).unwrap();
+            let pdu = get_stored_pdu(&handle.events, format!("{}_{}", room_id, event_id))?.unwrap();
+            handle.index_outlier(&pdu, &key[split..])?;
+        }
+        Ok(())
+    }
+
+    /// Stores the depth of each room's deepest forward extremity, which used to be read but never
+    /// written.
+    fn store_max_depths(&self) -> Result<(), Error> {
+        let handle = &self.handle;
+        for res in handle.rooms.iter() {
+            let (room_id, _) = res?;
+            let room_id = String::from_utf8(room_id.to_vec()).unwrap();
+            let mut prefix = room_id.clone().into_bytes();
+            prefix.push(b'~');
+            for res in handle.headless_events.scan_prefix(&prefix).keys() {
+                let key = res?;
+                let event_id = String::from_utf8(key[prefix.len()..].to_vec()).unwrap();
+                let pdu = get_stored_pdu(&handle.events, format!("{}_{}", room_id, event_id))?
+                    .ok_or_else(|| missing_timeline_event(&room_id, &event_id))?;
+                handle.note_depth(&pdu)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits the number of storage handles that can be in use at once. See `HandleLimit`.
+    pub fn with_handle_limit(mut self, limit: usize) -> Self {
+        self.handle_limit = HandleLimit::new(limit);
+        self
     }
 }
 
 #[async_trait]
 impl StorageManager for SledStorage {
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error> {
-        Ok(Box::new(self.0.clone()))
+        let mut handle = self.handle.clone();
+        handle._permit = self.handle_limit.acquire()?;
+        Ok(Box::new(handle))
+    }
+
+    async fn get_unlimited_handle(&self) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(self.handle.clone()))
     }
 }
 
@@ -211,14 +590,71 @@ impl StorageManager for SledStorage {
 pub struct SledStorageHandle {
     all: Db,
     events: Tree,
+    /// "{room_id}_{event_id}" -> the event as it was before it was redacted
+    unredacted: Tree,
     rooms: Tree,
     users: Tree,
     access_tokens: Tree,
+    /// "{username}~{token}" -> token, so that a user's tokens can be found without going through
+    /// everyone's
+    user_tokens: Tree,
     txn_ids: Tree,
+    /// batch id -> json sync batch
     batches: Tree,
+    /// (username, device_id) -> ids of the batches kept for that device, oldest first
+    device_batches: Tree,
+    /// (username, device_id) -> display name
+    device_names: Tree,
+    /// (username, device_id) -> json identity keys. Bincode length-prefixes strings, so the
+    /// encoded username on its own is a prefix of all of the user's keys.
+    device_keys: Tree,
+    /// (username, device_id) followed by the raw key_id -> json one-time key
+    one_time_keys: Tree,
+    /// (username, device_id) followed by the big-endian stream position -> json to-device
+    /// message that the device hasn't acknowledged yet
+    to_device: Tree,
+    /// username -> the presence that the user last set
+    presence: Tree,
+    /// big-endian presence stream position -> username, for the latest presence that each user
+    /// has set
+    presence_stream: Tree,
+    /// room_id -> the room's ordering tree, which maps each stream ordering, as a big-endian u32,
+    /// to the event id at that point in the timeline
     room_orderings: Arc<Mutex<HashMap<String, Tree>>>,
+    /// "{room_id}_{event_id}" -> the event's stream ordering, as a big-endian u32
+    stream_orderings: Tree,
+    /// "{room_id}~{event_id}" -> (), for the events in the timeline that nothing points back to,
+    /// and room_id -> the greatest depth of those, as a big-endian i64
     headless_events: Tree,
+    /// "{room_id}~{id}" -> event id, for events that arrived before the room's create event. The
+    /// ids are big-endian and increasing, so a room's outliers are kept oldest first.
+    outliers: Tree,
+    /// "{room_id}_{prev_event_id}~{id}" -> (), for each prev event that the outlier with that id
+    /// is waiting for
+    outlier_children: Tree,
+    /// state_cache_key -> the resolved state after those events
+    state_cache: Tree,
+    /// "{user_id}~{room_id}" -> current membership
+    memberships: Tree,
+    /// "{username}~{room_id}~{event_type}" -> json (stream position, content), where room_id is
+    /// empty for global account data
+    account_data: Tree,
+    /// username -> stream position of the user's latest account data write, as a big-endian u64
+    account_data_streams: Tree,
+    /// "{username}~{filter_id}" -> json filter
+    filters: Tree,
+    /// room_id -> (), for rooms listed in the public room directory
+    public_rooms: Tree,
+    /// alias -> room id
+    room_aliases: Tree,
+    /// alias -> bincode user id of whoever created it, missing for aliases from before creators
+    /// were recorded
+    room_alias_creators: Tree,
     ephemeral: Arc<Mutex<HashMap<String, Ephemeral>>>,
+    password_params: PasswordParams,
+    default_displayname_to_localpart: bool,
+    /// Held for as long as the handle is alive.
+    _permit: HandlePermit,
 }
 
 impl SledStorageHandle {
@@ -233,54 +669,300 @@ impl SledStorageHandle {
         }
     }
 
+    /// Returns all of the user's access tokens.
+    fn tokens_of(&self, username: &str) -> Result<Vec<Uuid>, Error> {
+        let mut ret = Vec::new();
+        for res in self.user_tokens.scan_prefix(format!("{}~", username)) {
+            let (_key, token) = res?;
+            ret.push(Uuid::from_slice(&token).unwrap());
+        }
+        Ok(ret)
+    }
+
+    /// Deletes an access token that belongs to the given user.
+    fn remove_token(&self, username: &str, token: Uuid) -> Result<(), Error> {
+        self.access_tokens.remove(token.as_bytes())?;
+        self.user_tokens.remove(format!("{}~{}", username, token))?;
+        Ok(())
+    }
+
+    /// Returns the room's outliers along with their keys in the outliers tree, oldest first.
+    fn get_room_outliers(&self, room_id: &str) -> Result<Vec<(IVec, StoredPdu)>, Error> {
+        let mut ret = Vec::new();
+        for res in self.outliers.scan_prefix(format!("{}~", room_id)) {
+            let (key, event_id) = res?;
+            let event_id = String::from_utf8(event_id.to_vec()).unwrap();
+            let name = format!("{}_{}", room_id, event_id);
+            // outliers are stored like any other event
+            let pdu = get_stored_pdu(&self.events, name)?.unwrap();
+            ret.push((key, pdu));
+        }
+        Ok(ret)
+    }
+
+    /// Whether the prev event is in the room's timeline.
+    fn is_linked(&self, room_id: &str, event_id: &str) -> Result<bool, Error> {
+        let linked = self
+            .stream_orderings
+            .contains_key(format!("{}_{}", room_id, event_id))?;
+        Ok(linked)
+    }
+
+    /// Whether the event can go in its room's timeline, because everything before it is there.
+    fn can_link(&self, pdu: &StoredPdu) -> Result<bool, Error> {
+        for prev in pdu.prev_events() {
+            if !self.is_linked(pdu.room_id(), prev)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Keeps an already stored event out of the timeline until everything before it is there.
+    fn add_outlier(&self, pdu: &StoredPdu) -> Result<(), Error> {
+        let id = self.all.generate_id()?.to_be_bytes();
+        let mut key = format!("{}~", pdu.room_id()).into_bytes();
+        key.extend_from_slice(&id);
+        self.outliers.insert(key, &*pdu.event_id())?;
+        self.index_outlier(pdu, &id)
+    }
+
+    /// Records which of the outlier's prev events it is waiting for.
+    fn index_outlier(&self, pdu: &StoredPdu, id: &[u8]) -> Result<(), Error> {
+        for prev in pdu.prev_events() {
+            if !self.is_linked(pdu.room_id(), prev)? {
+                let mut key = format!("{}_{}~", pdu.room_id(), prev).into_bytes();
+                key.extend_from_slice(id);
+                self.outlier_children.insert(key, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves outliers into the timeline for as long as there are some that passed auth and whose
+    /// prev events are all there. Only the outliers that were waiting for an event which just
+    /// joined the timeline are looked at, starting with those waiting for `linked`.
+    fn promote_outliers(
+        &self,
+        ordering_tree: &Tree,
+        room_id: &str,
+        linked: String,
+    ) -> Result<(), Error> {
+        let mut linked = VecDeque::from(vec![linked]);
+        while let Some(event_id) = linked.pop_front() {
+            let prefix = format!("{}_{}~", room_id, event_id);
+            for res in self.outlier_children.scan_prefix(&prefix) {
+                let (child_key, _) = res?;
+                self.outlier_children.remove(&child_key)?;
+                let mut key = format!("{}~", room_id).into_bytes();
+                key.extend_from_slice(&child_key[prefix.len()..]);
+                let child_id = match self.outliers.get(&key)? {
+                    Some(child_id) => String::from_utf8(child_id.to_vec()).unwrap(),
+                    // it was promoted after an earlier prev event arrived
+                    None => continue,
+                };
+                let pdu =
+                    get_stored_pdu(&self.events, format!("{}_{}", room_id, child_id))?.unwrap();
+                if pdu.did_pass_auth() && self.can_link(&pdu)? {
+                    self.outliers.remove(key)?;
+                    self.link_pdu(ordering_tree, &pdu)?;
+                    linked.push_back(child_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Raises the greatest depth of the room's forward extremities to that of `pdu`, if it's deeper.
+    /// Every other event in the timeline has a deeper child, so this only needs to see new ones.
+    fn note_depth(&self, pdu: &StoredPdu) -> Result<(), Error> {
+        let depth = pdu.depth();
+        self.headless_events
+            .fetch_and_update(pdu.room_id(), |max_depth| {
+                let max_depth = max_depth.map_or(depth, |bytes| {
+                    i64::from_be_bytes(bytes[0..8].try_into().unwrap()).max(depth)
+                });
+                Some(max_depth.to_be_bytes().to_vec())
+            })?;
+        Ok(())
+    }
+
+    /// Appends an already stored event to its room's timeline, giving it the next stream
+    /// ordering.
+    fn link_pdu(&self, ordering_tree: &Tree, pdu: &StoredPdu) -> Result<(), Error> {
+        let ordering = loop {
+            // an empty timeline is about to get its create event, which starts the stream at 0
+            let idx = match ordering_tree.last()? {
+                Some((key, _value)) => u32::from_be_bytes(key[0..4].try_into().unwrap()) + 1,
+                None => 0,
+            };
+            let res = ordering_tree.compare_and_swap(
+                &u32::to_be_bytes(idx),
+                Option::<&[u8]>::None,
+                Some(&*pdu.event_id()),
+            )?;
+            if res.is_ok() {
+                break idx;
+            }
+        };
+        self.stream_orderings.insert(
+            format!("{}_{}", pdu.room_id(), pdu.event_id()),
+            &u32::to_be_bytes(ordering),
+        )?;
+        // soft failed events aren't built on, so they leave the forward extremities alone
+        if !pdu.soft_failed {
+            for prev_event in pdu.prev_events() {
+                self.headless_events
+                    .remove(&format!("{}~{}", pdu.room_id(), prev_event))?;
+            }
+            self.headless_events
+                .insert(&format!("{}~{}", pdu.room_id(), pdu.event_id()), &[])?;
+            self.note_depth(pdu)?;
+        }
+        // rooms only count as existing if their create event passed auth
+        if let EventContent::Create(_) = pdu.event_content() {
+            if pdu.did_pass_auth() {
+                self.rooms.insert(pdu.room_id(), &[])?;
+            }
+        }
+        if let EventContent::Member(content) = pdu.event_content() {
+            if pdu.did_pass_auth() && !pdu.soft_failed && self.rooms.contains_key(pdu.room_id())? {
+                self.memberships.overwrite_value(
+                    format!("{}~{}", pdu.state_key().unwrap(), pdu.room_id()),
+                    &content.membership,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     async fn get_events(
         &self,
         ordering_tree: &Tree,
         query: &EventQuery<'_>,
         from: usize,
         to: Option<usize>,
-    ) -> Result<(Vec<StoredPdu>, usize), Error> {
+    ) -> Result<(Vec<(usize, StoredPdu)>, usize), Error> {
         let mut ret = Vec::new();
 
-        let from_bytes = from.to_be_bytes();
-        let to_bytes = to.clone().map(usize::to_be_bytes);
-        let pdu_iter = match to_bytes {
-            Some(to_bytes) => ordering_tree.range(from_bytes..=to_bytes),
-            None => ordering_tree.range(from_bytes..),
+        // clamp to the end of the timeline, so that out of range queries just return nothing
+        let last = match ordering_tree.last()? {
+            Some((key, _)) => u32::from_be_bytes(key[0..4].try_into().unwrap()) as usize,
+            None => 0,
+        };
+        let to = to.map_or(last, |to| to.min(last));
+        if from > to {
+            return Ok((ret, to));
         }
-        .map_ok(|(_key, event_id)| {
-            self.events.get(&format!(
+        // the ordering tree is keyed by u32s; see link_pdu
+        let from_bytes = (from as u32).to_be_bytes();
+        let to_bytes = (to as u32).to_be_bytes();
+        for res in ordering_tree.range(from_bytes..=to_bytes) {
+            let (key, event_id) = res?;
+            let ordering = u32::from_be_bytes(key[0..4].try_into().unwrap()) as usize;
+            let pdu = self.events.get(&format!(
                 "{}_{}",
                 query.room_id,
                 String::from_utf8(Vec::from(event_id.as_ref())).unwrap()
-            ))
-        })
-        // flatten
-        .map(|res| match res {
-            Ok(Ok(v)) => Ok(v),
-            Ok(Err(e)) | Err(e) => Err(e),
-        });
-        for pdu in pdu_iter {
-            // is Ok(None) if the event is not present, but it must be present if it's in the
+            ))?;
+            // is None if the event is not present, but it must be present if it's in the
             // ordering tree
-            let pdu: StoredPdu = DefaultOptions::new().deserialize(pdu?.unwrap().as_ref())?;
-            if query.matches(&pdu.inner()) {
-                ret.push(pdu);
+            let pdu: StoredPdu = serde_json::from_slice(pdu.unwrap().as_ref())?;
+            if query.matches(&pdu) {
+                ret.push((ordering, pdu));
             }
         }
-        Ok((ret, to.unwrap()))
+        if query.query_type.is_state() {
+            retain_latest_state(&mut ret);
+        }
+        Ok((ret, to))
+    }
+
+    /// Gets the account data for a room, or global account data if `room_id` is empty.
+    fn get_room_or_global_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let prefix = format!("{}~{}~", username, room_id);
+        let mut ret = HashMap::new();
+        for res in self.account_data.scan_prefix(&prefix) {
+            let (key, value) = res?;
+            let event_type = String::from_utf8(key[prefix.len()..].to_vec()).unwrap();
+            let (_, content): (u64, JsonValue) = serde_json::from_slice(&value)?;
+            ret.insert(event_type, content);
+        }
+        Ok(ret)
+    }
+
+    /// Sets account data for a room, or global account data if `room_id` is empty.
+    fn set_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let key = format!("{}~{}~{}", username, room_id, event_type);
+        // the position and the data are written together, so that a sync which has seen a
+        // position has also seen everything that was written up to it
+        (&self.account_data_streams, &self.account_data)
+            .transaction(|(streams, account_data)| {
+                let position = streams
+                    .get(username)?
+                    .map_or(0, |b| u64::from_be_bytes(b.as_ref().try_into().unwrap()))
+                    + 1;
+                streams.insert(username, &u64::to_be_bytes(position)[..])?;
+                // stored as json because bincode can't deserialize arbitrary json values
+                let value = serde_json::to_vec(&(position, &content))
+                    .map_err(ConflictableTransactionError::Abort)?;
+                account_data.insert(key.as_bytes(), value)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => Error::from(e),
+                TransactionError::Storage(e) => Error::from(e),
+            })
+    }
+
+    /// Waits until either a new event is added to the room or its ephemeral data changes.
+    async fn wait_for_room_change(&self, room_id: &str) {
+        let mut ephemeral_recv = self
+            .ephemeral
+            .lock()
+            .await
+            .entry(String::from(room_id))
+            .or_default()
+            .notify_send
+            .subscribe();
+        // The receive can only fail if events were missed, which means something happened anyway
+        tokio::select! {
+            _ = self.events.watch_prefix(room_id) => {},
+            _ = ephemeral_recv.recv() => {},
+        }
     }
 }
 
 #[async_trait]
 impl Storage for SledStorageHandle {
     async fn create_user(&self, username: &str, password: &str) -> Result<(), Error> {
-        let salt: [u8; 16] = rand::random();
-        let password_hash = argon2::hash_encoded(password.as_bytes(), &salt, &Default::default())?;
+        let password_hash = self.password_params.hash(password)?;
+        let displayname = if self.default_displayname_to_localpart {
+            Some(username.to_string())
+        } else {
+            None
+        };
         let did_insert = self.users.try_insert_value(
             username,
             &User {
-                password_hash: password_hash.to_string(),
+                password_hash,
+                profile: UserProfile {
+                    avatar_url: None,
+                    displayname,
+                },
                 ..Default::default()
             },
         )?;
@@ -290,156 +972,527 @@ impl Storage for SledStorageHandle {
         }
     }
 
+    async fn user_exists(&self, username: &str) -> Result<bool, Error> {
+        Ok(self.users.contains_key(username)?)
+    }
+
     async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error> {
         let user: Option<User> = self.users.get_value(username)?;
-        if let Some(user) = user {
+        let outdated = if let Some(user) = user.filter(|u| !u.deactivated) {
             match argon2::verify_encoded(&user.password_hash, password.as_bytes()) {
-                Ok(true) => Ok(true),
-                Ok(false) => Ok(false),
-                Err(_) => Ok(false),
+                Ok(true) => self.password_params.is_outdated(&user.password_hash),
+                Ok(false) => return Ok(false),
+                Err(_) => return Ok(false),
             }
         } else {
-            Ok(false)
+            return Ok(false);
+        };
+        if outdated {
+            self.set_password(username, password).await?;
         }
+        Ok(true)
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> Result<(), Error> {
+        let password_hash = self.password_params.hash(password)?;
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.password_hash = password_hash;
+        self.users.overwrite_value(username, user)?;
+        Ok(())
     }
 
     async fn create_access_token(&self, username: &str, device_id: &str) -> Result<Uuid, Error> {
         let token = Uuid::new_v4();
-        if !self.users.contains_key(username)? {
-            return Err(ErrorKind::UserNotFound.into());
+        match self.users.get_value(username)? {
+            Some(User {
+                deactivated: true, ..
+            }) => return Err(ErrorKind::Forbidden.into()),
+            Some(_) => {}
+            None => return Err(ErrorKind::UserNotFound.into()),
         }
         self.access_tokens.try_insert_value(
             token.as_bytes(),
             &AccessTokenData {
                 username: username.to_string(),
                 device_id: device_id.to_string(),
+                last_seen_ip: None,
+                last_seen_ts: None,
             },
         )?;
+        self.user_tokens
+            .insert(format!("{}~{}", username, token), &token.as_bytes()[..])?;
         Ok(token)
     }
 
     async fn delete_access_token(&self, token: Uuid) -> Result<(), Error> {
-        self.access_tokens.remove(token.as_bytes())?;
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        if let Some(data) = data {
+            self.remove_token(&data.username, token)?;
+        }
         Ok(())
     }
 
     async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error> {
         let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
         if let Some(data) = data {
-            let username = data.username;
-            let iter = (&self.access_tokens).into_iter();
-            let mut to_delete = Vec::new();
-            for res in iter {
-                let (key, val) = res?;
-                let data = DefaultOptions::new()
-                    .deserialize::<AccessTokenData>(&val)
-                    .unwrap();
-                if data.username == username {
-                    to_delete.push(key);
-                }
-            }
-            for key in to_delete.into_iter() {
-                self.access_tokens.remove(key)?;
+            for token in self.tokens_of(&data.username)? {
+                self.remove_token(&data.username, token)?;
             }
         }
         Ok(())
     }
 
-    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
-        let maybe_username = self
+    async fn try_auth_full(&self, token: Uuid) -> Result<Option<(String, String)>, Error> {
+        let maybe_data = self
             .access_tokens
             .get_value(token.as_bytes())?
-            .map(|data: AccessTokenData| data.username);
-        Ok(maybe_username)
-    }
-
-    async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error> {
-        let name = format!("{}_{}", token, txn_id);
-        let is_new = self.txn_ids.insert(&name, &[])?.is_none();
-        Ok(is_new)
+            .map(|data: AccessTokenData| (data.username, data.device_id));
+        Ok(maybe_data)
     }
 
-    async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error> {
-        let profile = self.users.get_value(username)?.map(|u: User| u.profile);
-        Ok(profile)
+    async fn update_token_last_seen(
+        &self,
+        token: Uuid,
+        ip: Option<&str>,
+        ts: i64,
+    ) -> Result<(), Error> {
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        if let Some(mut data) = data {
+            data.last_seen_ip = ip.map(String::from);
+            data.last_seen_ts = Some(ts);
+            self.access_tokens.overwrite_value(token.as_bytes(), data)?;
+        }
+        Ok(())
     }
 
-    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error> {
-        let mut user: User = self
-            .users
-            .get_value(username)?
-            .ok_or(ErrorKind::UserNotFound)?;
-        user.profile.avatar_url = Some(avatar_url.to_string());
-        self.users.overwrite_value(username, user)?;
-        Ok(())
+    async fn get_tokens_for_user(&self, username: &str) -> Result<Vec<TokenInfo>, Error> {
+        let mut ret = Vec::new();
+        for token in self.tokens_of(username)? {
+            let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+            if let Some(data) = data {
+                ret.push(TokenInfo::new(
+                    token,
+                    data.device_id,
+                    data.last_seen_ip,
+                    data.last_seen_ts,
+                ));
+            }
+        }
+        Ok(ret)
     }
 
-    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<(), Error> {
-        let mut user: User = self
-            .users
-            .get_value(username)?
-            .ok_or(ErrorKind::UserNotFound)?;
-        user.profile.displayname = Some(display_name.to_string());
-        self.users.overwrite_value(username, user)?;
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        for token in self.tokens_of(username)? {
+            let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+            if matches!(data, Some(data) if data.device_id == device_id) {
+                self.remove_token(username, token)?;
+            }
+        }
+        // same reasoning as in record_txn for the key
+        let key = DefaultOptions::new().serialize(&(username, device_id))?;
+        self.device_names.remove(&key)?;
+        self.device_keys.remove(&key)?;
+        for res in self.one_time_keys.scan_prefix(&key) {
+            let (key_id, _) = res?;
+            self.one_time_keys.remove(key_id)?;
+        }
+        for res in self.to_device.scan_prefix(&key) {
+            let (position, _) = res?;
+            self.to_device.remove(position)?;
+        }
         Ok(())
     }
 
-    async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error> {
-        for pdu in pdus {
-            let name = format!("{}_{}", pdu.room_id(), pdu.event_id());
-            self.events.try_insert_value(name, pdu)?;
-            let ordering_tree = self.get_room_ordering_tree(&pdu.room_id()).await?;
-            'cas: loop {
-                if let Some((key, _value)) = ordering_tree.last()? {
-                    let idx = u32::from_be_bytes(key[0..4].try_into().unwrap()) + 1;
-                    let res = ordering_tree.compare_and_swap(
-                        &u32::to_be_bytes(idx),
-                        Option::<&[u8]>::None,
-                        Some(&*pdu.event_id()),
-                    )?;
-                    if res.is_ok() {
-                        break 'cas;
-                    }
-                }
-            }
-            for prev_event in pdu.prev_events() {
-                self.headless_events
-                    .remove(&format!("{}~{}", pdu.room_id(), prev_event))?;
-            }
-            self.headless_events
-                .insert(&format!("{}~{}", pdu.room_id(), pdu.event_id()), &[])?;
-            self.rooms.insert(pdu.room_id(), &[])?;
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: &str,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
         }
+        let key = DefaultOptions::new().serialize(&(username, device_id))?;
+        self.device_names.overwrite_value(&key, display_name)?;
         Ok(())
     }
 
-    async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error> {
-        let max_depth: i64 = self.headless_events.get_value(room_id)?.unwrap_or(-1);
-        let mut prefix = String::from(room_id).into_bytes();
-        prefix.push(b'~');
-        self.headless_events
-            .scan_prefix(&prefix)
-            .keys()
-            .map_ok(|k| k.split(|&b| b == b'~').nth(1).unwrap().to_owned())
-            .map_ok(String::from_utf8)
-            .map_ok(Result::unwrap)
-            .collect::<Result<Vec<String>, sled::Error>>()
-            .map_err(ErrorKind::SledError)
-            .map_err(ErrorKind::into)
-            .map(|prev_events| (prev_events, max_depth))
+    async fn get_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<String>, Error> {
+        let key = DefaultOptions::new().serialize(&(username, device_id))?;
+        self.device_names.get_value(&key)
     }
 
-    async fn query_pdus<'a>(
+    async fn set_device_keys(
         &self,
-        query: EventQuery<'a>,
-        wait: bool,
-    ) -> Result<(Vec<StoredPdu>, usize), Error> {
-        let ordering_tree = self.get_room_ordering_tree(&query.room_id).await?;
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let key = DefaultOptions::new().serialize(&(username, device_id))?;
+        // stored as json because bincode can't deserialize arbitrary json values
+        self.device_keys.insert(key, serde_json::to_vec(&keys)?)?;
+        Ok(())
+    }
+
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error> {
+        let prefix = DefaultOptions::new().serialize(username)?;
+        let mut ret = HashMap::new();
+        for res in self.device_keys.scan_prefix(&prefix) {
+            let (key, keys) = res?;
+            let device_id: String = DefaultOptions::new().deserialize(&key[prefix.len()..])?;
+            ret.insert(device_id, serde_json::from_slice(&keys)?);
+        }
+        Ok(ret)
+    }
+
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let prefix = DefaultOptions::new().serialize(&(username, device_id))?;
+        for (key_id, otk) in keys {
+            let mut key = prefix.clone();
+            key.extend_from_slice(key_id.as_bytes());
+            self.one_time_keys.insert(key, serde_json::to_vec(&otk)?)?;
+        }
+        Ok(())
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error> {
+        let prefix = DefaultOptions::new().serialize(&(username, device_id))?;
+        let mut counts = HashMap::new();
+        for res in self.one_time_keys.scan_prefix(&prefix) {
+            let (key, _) = res?;
+            let key_id = String::from_utf8(key[prefix.len()..].to_vec()).unwrap();
+            let algorithm = key_id.split(':').next().unwrap();
+            *counts.entry(algorithm.to_string()).or_default() += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error> {
+        let device_prefix = DefaultOptions::new().serialize(&(username, device_id))?;
+        let mut prefix = device_prefix.clone();
+        prefix.extend_from_slice(format!("{}:", algorithm).as_bytes());
+        for res in self.one_time_keys.scan_prefix(&prefix) {
+            let (key, _) = res?;
+            // whoever removes the key is the one who gets it, so nobody else can claim it too
+            if let Some(otk) = self.one_time_keys.remove(&key)? {
+                let key_id = String::from_utf8(key[device_prefix.len()..].to_vec()).unwrap();
+                return Ok(Some((key_id, serde_json::from_slice(&otk)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn send_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        message: ToDeviceMessage,
+    ) -> Result<(), Error> {
+        let mut key = DefaultOptions::new().serialize(&(username, device_id))?;
+        // ids start at 0, which is what a device that hasn't been sent anything has seen up to
+        let position = self.all.generate_id()? + 1;
+        key.extend_from_slice(&position.to_be_bytes());
+        self.to_device.insert(key, serde_json::to_vec(&message)?)?;
+        Ok(())
+    }
+
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(Vec<ToDeviceMessage>, u64), Error> {
+        let prefix = DefaultOptions::new().serialize(&(username, device_id))?;
+        let mut messages = Vec::new();
+        let mut position = since;
+        for res in self.to_device.scan_prefix(&prefix) {
+            let (key, message) = res?;
+            let key_position = u64::from_be_bytes(key[prefix.len()..].try_into().unwrap());
+            if key_position <= since {
+                self.to_device.remove(key)?;
+            } else {
+                messages.push(serde_json::from_slice(&message)?);
+                position = key_position;
+            }
+        }
+        Ok((messages, position))
+    }
+
+    async fn wait_for_to_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: u64,
+    ) -> Result<(), Error> {
+        let prefix = DefaultOptions::new().serialize(&(username, device_id))?;
+        // subscribe before looking, so that a message sent in between isn't missed
+        let mut subscriber = self.to_device.watch_prefix(&prefix);
+        if let Some(res) = self.to_device.scan_prefix(&prefix).next_back() {
+            let (key, _) = res?;
+            if u64::from_be_bytes(key[prefix.len()..].try_into().unwrap()) > since {
+                return Ok(());
+            }
+        }
+        // messages being acknowledged also shows up here, but only new ones should wake anyone
+        while let Some(event) = (&mut subscriber).await {
+            if let sled::Event::Insert { .. } = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn record_txn(
+        &self,
+        username: &str,
+        device_id: &str,
+        scope: &str,
+        txn_id: String,
+    ) -> Result<bool, Error> {
+        // usernames and device ids can both contain any separator we might pick, so let bincode
+        // length-prefix them instead
+        let name = DefaultOptions::new().serialize(&(username, device_id, scope, txn_id))?;
+        let is_new = self.txn_ids.insert(name, &[])?.is_none();
+        Ok(is_new)
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error> {
+        let profile = self.users.get_value(username)?.map(|u: User| u.profile);
+        Ok(profile)
+    }
+
+    async fn search_users(
+        &self,
+        term: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, UserProfile)>, Error> {
+        let term = term.to_lowercase();
+        let mut ret = Vec::new();
+        // the tree is sorted by username already
+        for res in self.users.iter() {
+            if ret.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (username, user) = res?;
+            let username = String::from_utf8(username.to_vec()).unwrap();
+            let user: User = DefaultOptions::new().deserialize(&user)?;
+            if !user.deactivated && user_matches_search(&term, &username, &user.profile) {
+                ret.push((username, user.profile));
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<bool, Error> {
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        if user.profile.avatar_url.as_deref() == Some(avatar_url) {
+            return Ok(false);
+        }
+        user.profile.avatar_url = Some(avatar_url.to_string());
+        self.users.overwrite_value(username, user)?;
+        Ok(true)
+    }
+
+    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<bool, Error> {
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        if user.profile.displayname.as_deref() == Some(display_name) {
+            return Ok(false);
+        }
+        user.profile.displayname = Some(display_name.to_string());
+        self.users.overwrite_value(username, user)?;
+        Ok(true)
+    }
+
+    async fn set_presence(
+        &self,
+        username: &str,
+        state: PresenceState,
+        status_msg: Option<String>,
+        ts: i64,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        // ids start at 0, which is what a client that hasn't seen any presence has seen up to
+        let position = self.all.generate_id()? + 1;
+        let presence = Presence {
+            state,
+            status_msg,
+            last_active_ts: ts,
+            position,
+        };
+        // each user only has their latest presence in the stream
+        (&self.presence, &self.presence_stream)
+            .transaction(|(presence_tree, stream)| {
+                let old: Option<Presence> =
+                    presence_tree.replace_value(username, presence.clone())?;
+                if let Some(old) = old {
+                    stream.remove(&old.position.to_be_bytes()[..])?;
+                }
+                stream.insert(&position.to_be_bytes()[..], username)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => Error::from(e),
+                TransactionError::Storage(e) => Error::from(e),
+            })
+    }
+
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error> {
+        self.presence.get_value(username)
+    }
+
+    async fn get_presence_changes(
+        &self,
+        since: u64,
+    ) -> Result<(Vec<(String, Presence)>, u64), Error> {
+        let mut changes = Vec::new();
+        let mut position = since;
+        for res in self.presence_stream.range((since + 1).to_be_bytes()..) {
+            let (key, username) = res?;
+            let username = String::from_utf8(username.to_vec()).unwrap();
+            if let Some(presence) = self.presence.get_value(&username)? {
+                changes.push((username, presence));
+            }
+            position = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+        }
+        Ok((changes, position))
+    }
+
+    async fn wait_for_presence(&self, since: u64) -> Result<(), Error> {
+        // subscribe before looking, so that a change in between isn't missed
+        let mut subscriber = self.presence_stream.watch_prefix(vec![]);
+        if let Some(res) = self.presence_stream.iter().next_back() {
+            let (key, _) = res?;
+            if u64::from_be_bytes(key.as_ref().try_into().unwrap()) > since {
+                return Ok(());
+            }
+        }
+        // a user's old position being removed also shows up here
+        while let Some(event) = (&mut subscriber).await {
+            if let sled::Event::Insert { .. } = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn is_admin(&self, username: &str) -> Result<bool, Error> {
+        let user: Option<User> = self.users.get_value(username)?;
+        Ok(matches!(user, Some(u) if u.is_admin))
+    }
+
+    async fn set_admin(&self, username: &str, is_admin: bool) -> Result<(), Error> {
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.is_admin = is_admin;
+        self.users.overwrite_value(username, user)?;
+        Ok(())
+    }
+
+    async fn deactivate_user(&self, username: &str) -> Result<(), Error> {
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.deactivated = true;
+        self.users.overwrite_value(username, user)?;
+        for device in self.get_devices(username).await? {
+            self.delete_device(username, &device.device_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error> {
+        for pdu in pdus {
+            let name = format!("{}_{}", pdu.room_id(), pdu.event_id());
+            if !self.events.contains_key(&name)? {
+                self.events.insert(&name, serde_json::to_vec(pdu)?)?;
+            }
+            let ordering_tree = self.get_room_ordering_tree(&pdu.room_id()).await?;
+            match pdu.event_content() {
+                EventContent::Create(_) => {}
+                _ if ordering_tree.is_empty() || !self.can_link(pdu)? => {
+                    self.add_outlier(pdu)?;
+                    continue;
+                }
+                _ => {}
+            }
+            self.link_pdu(&ordering_tree, pdu)?;
+            self.promote_outliers(&ordering_tree, pdu.room_id(), pdu.event_id())?;
+        }
+        Ok(())
+    }
+
+    async fn get_outliers(&self, room_id: &str) -> Result<Vec<StoredPdu>, Error> {
+        let outliers = self.get_room_outliers(room_id)?;
+        Ok(outliers.into_iter().map(|(_key, pdu)| pdu).collect())
+    }
+
+    async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error> {
+        let mut prefix = String::from(room_id).into_bytes();
+        prefix.push(b'~');
+        let prev_events = self
+            .headless_events
+            .scan_prefix(&prefix)
+            .keys()
+            .map_ok(|k| k.split(|&b| b == b'~').nth(1).unwrap().to_owned())
+            .map_ok(String::from_utf8)
+            .map_ok(Result::unwrap)
+            .collect::<Result<Vec<String>, sled::Error>>()?;
+        let max_depth = match self.headless_events.get(room_id)? {
+            Some(bytes) => i64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            None => -1, // no events in room
+        };
+        Ok((prev_events, max_depth))
+    }
+
+    async fn query_ordered_pdus<'a>(
+        &self,
+        query: EventQuery<'a>,
+        wait: bool,
+    ) -> Result<(Vec<(usize, StoredPdu)>, usize), Error> {
+        let ordering_tree = self.get_room_ordering_tree(&query.room_id).await?;
         if ordering_tree.is_empty() {
             return Err(ErrorKind::RoomNotFound.into());
         }
 
-        let (mut from, mut to) = match &query.query_type {
+        let (from, to) = match &query.query_type {
             &QueryType::Timeline { from, to } => (from, to),
             &QueryType::State { at, .. } => (0, at),
         };
@@ -451,12 +1504,48 @@ impl Storage for SledStorageHandle {
             return Ok(res);
         }
 
-        self.events.watch_prefix(&query.room_id).await;
-        from = to.unwrap();
-        to = None;
+        self.wait_for_room_change(query.room_id).await;
+
+        // this time we roll with it, from wherever the first attempt got to
+        self.get_events(&ordering_tree, &query, res.1, None).await
+    }
+
+    async fn get_stream_ordering(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<usize>, Error> {
+        let ordering_tree = self.get_room_ordering_tree(room_id).await?;
+        if ordering_tree.is_empty() {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let ordering = self
+            .stream_orderings
+            .get(format!("{}_{}", room_id, event_id))?;
+        Ok(ordering.map(|bytes| u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize))
+    }
 
-        // this time we roll with it
-        self.get_events(&ordering_tree, &query, from, to).await
+    async fn stream_room_events(
+        &self,
+        room_id: &str,
+    ) -> Result<BoxStream<'static, Result<StoredPdu, Error>>, Error> {
+        if !self.rooms.contains_key(room_id)? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let ordering_tree = self.get_room_ordering_tree(room_id).await?;
+        let events = self.events.clone();
+        let room_id = room_id.to_string();
+        let stream = stream::iter(ordering_tree.iter().values().map(move |event_id| {
+            let event_id = String::from_utf8(event_id?.to_vec()).map_err(|e| {
+                ErrorKind::Unknown(format!(
+                    "Bad event id in the timeline of {}: {}",
+                    room_id, e
+                ))
+            })?;
+            get_stored_pdu(&events, format!("{}_{}", room_id, event_id))?
+                .ok_or_else(|| missing_timeline_event(&room_id, &event_id))
+        }));
+        Ok(stream.boxed())
     }
 
     async fn get_rooms(&self) -> Result<Vec<String>, Error> {
@@ -467,12 +1556,192 @@ impl Storage for SledStorageHandle {
             .map_err(Into::into)
     }
 
-    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
-        self.events
-            .get_value(&format!("{}_{}", room_id, event_id))
+    async fn set_room_public(&self, room_id: &str, public: bool) -> Result<(), Error> {
+        if !self.rooms.contains_key(room_id)? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        if public {
+            self.public_rooms.insert(room_id, &[])?;
+        } else {
+            self.public_rooms.remove(room_id)?;
+        }
+        Ok(())
+    }
+
+    async fn get_public_rooms(&self) -> Result<Vec<String>, Error> {
+        self.public_rooms
+            .iter()
+            .map_ok(|(key, _value)| String::from_utf8(Vec::from(key.as_ref())).unwrap())
+            .collect::<Result<Vec<_>, _>>()
             .map_err(Into::into)
     }
 
+    async fn set_room_alias(
+        &self,
+        alias: &str,
+        room_id: &str,
+        creator: &MatrixId,
+    ) -> Result<(), Error> {
+        if !self.rooms.contains_key(room_id)? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        self.room_aliases
+            .compare_and_swap(alias, None as Option<&[u8]>, Some(room_id.as_bytes()))?
+            .map_err(|_| ErrorKind::RoomInUse)?;
+        self.room_alias_creators
+            .insert(alias, DefaultOptions::new().serialize(creator)?)?;
+        Ok(())
+    }
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error> {
+        let room_id = self.room_aliases.get(alias)?;
+        Ok(room_id.map(|id| String::from_utf8(id.to_vec()).unwrap()))
+    }
+
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<MatrixId>, Error> {
+        match self.room_alias_creators.get(alias)? {
+            Some(creator) => Ok(Some(DefaultOptions::new().deserialize(&creator)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error> {
+        self.room_alias_creators.remove(alias)?;
+        Ok(self.room_aliases.remove(alias)?.is_some())
+    }
+
+    async fn get_local_aliases(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let mut ret = Vec::new();
+        for res in self.room_aliases.iter() {
+            let (key, value) = res?;
+            if value == room_id.as_bytes() {
+                ret.push(String::from_utf8(key.to_vec()).unwrap());
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn get_invited_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error> {
+        let prefix = format!("{}~", user_id.as_str());
+        let mut ret = Vec::new();
+        for res in self.memberships.scan_prefix(&prefix) {
+            let (key, value) = res?;
+            let membership: Membership = DefaultOptions::new().deserialize(&value)?;
+            if membership == Membership::Invite {
+                let room_id = String::from_utf8(key[prefix.len()..].to_vec()).unwrap();
+                ret.push(room_id);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn get_joined_rooms_for_user(&self, user_id: &MatrixId) -> Result<Vec<String>, Error> {
+        let prefix = format!("{}~", user_id.as_str());
+        let mut ret = Vec::new();
+        for res in self.memberships.scan_prefix(&prefix) {
+            let (key, value) = res?;
+            let membership: Membership = DefaultOptions::new().deserialize(&value)?;
+            if membership == Membership::Join {
+                let room_id = String::from_utf8(key[prefix.len()..].to_vec()).unwrap();
+                ret.push(room_id);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn count_joined_rooms(&self, user_id: &MatrixId) -> Result<usize, Error> {
+        let mut count = 0;
+        for res in self
+            .memberships
+            .scan_prefix(format!("{}~", user_id.as_str()))
+        {
+            let (_, value) = res?;
+            let membership: Membership = DefaultOptions::new().deserialize(&value)?;
+            if membership == Membership::Join {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
+        let has_outliers = self
+            .outliers
+            .scan_prefix(format!("{}~", room_id))
+            .next()
+            .is_some();
+        if !self.rooms.contains_key(room_id)? && !has_outliers {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        get_stored_pdu(&self.events, &format!("{}_{}", room_id, event_id))
+    }
+
+    async fn get_pdus(
+        &self,
+        room_id: &str,
+        event_ids: &[String],
+    ) -> Result<Vec<Option<StoredPdu>>, Error> {
+        let has_outliers = self
+            .outliers
+            .scan_prefix(format!("{}~", room_id))
+            .next()
+            .is_some();
+        if !self.rooms.contains_key(room_id)? && !has_outliers {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let keys = event_ids
+            .iter()
+            .map(|event_id| format!("{}_{}", room_id, event_id))
+            .collect::<Vec<_>>();
+        // one transaction reads all of them, rather than going to the tree once per event
+        let found = self
+            .events
+            .transaction(|events| {
+                let found = keys
+                    .iter()
+                    .map(|key| events.get(key))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok::<_, ConflictableTransactionError<()>>(found)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(()) => unreachable!(),
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        found
+            .into_iter()
+            .map(|bytes| -> Result<Option<StoredPdu>, Error> {
+                let pdu = bytes
+                    .map(|bytes| serde_json::from_slice(&bytes))
+                    .transpose()?;
+                Ok(pdu)
+            })
+            .collect()
+    }
+
+    async fn redact_pdu(&self, room_id: &str, event_id: &str) -> Result<(), Error> {
+        let key = format!("{}_{}", room_id, event_id);
+        if let Some(pdu) = get_stored_pdu(&self.events, &key)? {
+            if !self.unredacted.contains_key(&key)? {
+                self.unredacted.insert(&key, serde_json::to_vec(&pdu)?)?;
+            }
+            self.events
+                .insert(&key, serde_json::to_vec(&pdu.redact())?)?;
+        }
+        Ok(())
+    }
+
+    async fn get_unredacted_pdu(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<StoredPdu>, Error> {
+        let key = format!("{}_{}", room_id, event_id);
+        match get_stored_pdu(&self.unredacted, &key)? {
+            Some(pdu) => Ok(Some(pdu)),
+            None => self.get_pdu(room_id, event_id).await,
+        }
+    }
+
     async fn get_all_ephemeral(&self, room_id: &str) -> Result<HashMap<String, JsonValue>, Error> {
         //TODO: this inserts an ephemeral entry even if the room doesn't actually exist - figure
         // out what to do about it
@@ -520,6 +1789,21 @@ impl Storage for SledStorageHandle {
             Some(c) => ephemeral.ephemeral.insert(String::from(event_type), c),
             None => ephemeral.ephemeral.remove(event_type),
         };
+        let _ = ephemeral.notify_send.send(());
+        Ok(())
+    }
+
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: &str,
+        event_id: &str,
+    ) -> Result<(), Error> {
+        let mut ephemerals = self.ephemeral.lock().await;
+        let ephemeral = ephemerals.entry(String::from(room_id)).or_default();
+        set_receipt_in(&mut ephemeral.ephemeral, user_id, receipt_type, event_id)?;
+        let _ = ephemeral.notify_send.send(());
         Ok(())
     }
 
@@ -540,6 +1824,7 @@ impl Storage for SledStorageHandle {
         } else {
             ephemeral.typing.remove(user_id);
         }
+        let _ = ephemeral.notify_send.send(());
 
         Ok(())
     }
@@ -548,18 +1833,401 @@ impl Storage for SledStorageHandle {
         &self,
         username: &str,
     ) -> Result<HashMap<String, JsonValue>, Error> {
-        let user: User = self
-            .users
-            .get_value(username)?
-            .ok_or(ErrorKind::UserNotFound)?;
-        Ok(user.account_data.clone())
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        self.get_room_or_global_account_data(username, "")
+    }
+
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        self.set_account_data(username, "", event_type, content)
+    }
+
+    async fn get_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        self.get_room_or_global_account_data(username, room_id)
+    }
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        event_type: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        self.set_account_data(username, room_id, event_type, content)
+    }
+
+    async fn get_account_data(
+        &self,
+        username: &str,
+        since: u64,
+    ) -> Result<AccountDataChanges, Error> {
+        let mut changes = AccountDataChanges::default();
+        if let Some(position) = self.account_data_streams.get(username)? {
+            changes.position = u64::from_be_bytes(position.as_ref().try_into().unwrap());
+        }
+        let prefix = format!("{}~", username);
+        for res in self.account_data.scan_prefix(&prefix) {
+            let (key, value) = res?;
+            let (position, content): (u64, JsonValue) = serde_json::from_slice(&value)?;
+            if position <= since {
+                continue;
+            }
+            // room ids and usernames can't contain '~', but event types can
+            let key = std::str::from_utf8(&key[prefix.len()..]).unwrap();
+            let (room_id, event_type) = key.split_once('~').unwrap();
+            let map = match room_id {
+                "" => &mut changes.global,
+                room_id => changes.rooms.entry(room_id.to_string()).or_default(),
+            };
+            map.insert(event_type.to_string(), content);
+        }
+        Ok(changes)
+    }
+
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let filter_id = format!("{:x}", rand::random::<u64>());
+        self.filters.insert(
+            format!("{}~{}", username, filter_id),
+            serde_json::to_vec(&filter)?,
+        )?;
+        Ok(filter_id)
+    }
+
+    async fn get_filter(
+        &self,
+        username: &str,
+        filter_id: &str,
+    ) -> Result<Option<JsonValue>, Error> {
+        match self.filters.get(format!("{}~{}", username, filter_id))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
     }
 
     async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error> {
-        self.batches.get_value(id)
+        let batch = self
+            .batches
+            .get(id)?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()?;
+        Ok(batch)
     }
 
-    async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error> {
-        self.batches.overwrite_value(id, batch).map(drop)
+    async fn set_batch(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Option<&str>,
+        id: &str,
+        batch: Batch,
+    ) -> Result<(), Error> {
+        // stored as json so that new fields can be given defaults for batches from before them
+        let batch = serde_json::to_vec(&batch)?;
+        // same reasoning as in record_txn for the key
+        let key = DefaultOptions::new().serialize(&(username, device_id))?;
+        // the new batch, the evictions and the device's list of batches are written together, so
+        // that concurrent syncs from the same device can't leave batches behind that the list has
+        // forgotten about
+        (&self.batches, &self.device_batches)
+            .transaction(|(batches, device_batches)| {
+                batches.insert(id, &*batch)?;
+                let mut ids: VecDeque<String> = device_batches.get_value(&key)?.unwrap_or_default();
+                for evicted in push_batch(&mut ids, since, id) {
+                    batches.remove(evicted.as_bytes())?;
+                }
+                device_batches.overwrite_value(&key, ids)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => Error::from(e),
+                TransactionError::Storage(e) => Error::from(e),
+            })
+    }
+
+    async fn get_cached_state(&self, event_ids: &[String]) -> Result<Option<StateMap>, Error> {
+        self.state_cache.get_value(state_cache_key(event_ids))
+    }
+
+    async fn set_cached_state(&self, event_ids: &[String], state: StateMap) -> Result<(), Error> {
+        self.state_cache
+            .overwrite_value(state_cache_key(event_ids), state)?;
+        Ok(())
+    }
+
+    async fn set_cached_states(&self, states: Vec<(Vec<String>, StateMap)>) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        for (event_ids, state) in states {
+            let bytes = DefaultOptions::new().serialize(&state)?;
+            batch.insert(state_cache_key(&event_ids).as_bytes(), bytes);
+        }
+        self.state_cache.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use bincode::{DefaultOptions, Options};
+    use serde_json::{json, Value as JsonValue};
+    use uuid::Uuid;
+
+    use super::{SledStorage, TreeExt, User, SCHEMA_VERSION, SCHEMA_VERSION_KEY};
+    use crate::{
+        events::EventContent,
+        state::StateResolver,
+        storage::{
+            tests::create_room, EventQuery, PasswordParams, PresenceState, QueryType, Storage,
+            StorageManager,
+        },
+        util::{storage::NewEvent, MatrixId, StorageExt},
+    };
+
+    #[test]
+    fn typing_wakes_waiting_sync() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let _ = std::fs::remove_dir_all("sled-test-typing-wakeup");
+        let storage = SledStorage::new("sled-test-typing-wakeup").unwrap();
+        rt.block_on(async {
+            let db = &storage.handle;
+            let room_id = "!typing:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+
+            // join! polls the waiter first, so it's parked by the time the typing update happens
+            let waiter = db.wait_for_room_change(room_id);
+            let typing = db.set_typing(room_id, &alice, true, 30000);
+            let (woken, typing_res) =
+                futures::join!(tokio::time::timeout(Duration::from_secs(5), waiter), typing);
+            typing_res.unwrap();
+            assert!(woken.is_ok(), "typing didn't wake the waiting sync");
+        });
+        drop(storage);
+        std::fs::remove_dir_all("sled-test-typing-wakeup").unwrap();
+    }
+
+    #[test]
+    fn login_rehashes_outdated_password() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let _ = std::fs::remove_dir_all("sled-test-rehash");
+        let weak = PasswordParams {
+            memory_cost: 1024,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        let storage = SledStorage::new("sled-test-rehash")
+            .unwrap()
+            .with_password_params(weak);
+        rt.block_on(storage.handle.create_user("alice", "password"))
+            .unwrap();
+        drop(storage);
+
+        let params = PasswordParams::default();
+        let storage = SledStorage::new("sled-test-rehash")
+            .unwrap()
+            .with_password_params(params);
+        let stored_hash = || {
+            let user: User = storage.handle.users.get_value("alice").unwrap().unwrap();
+            user.password_hash
+        };
+        assert!(params.is_outdated(&stored_hash()));
+        rt.block_on(async {
+            let db = &storage.handle;
+            assert!(!db.verify_password("alice", "wrong").await.unwrap());
+            assert!(params.is_outdated(&stored_hash()));
+            assert!(db.verify_password("alice", "password").await.unwrap());
+            assert!(!params.is_outdated(&stored_hash()));
+            assert!(db.verify_password("alice", "password").await.unwrap());
+        });
+        drop(storage);
+        std::fs::remove_dir_all("sled-test-rehash").unwrap();
+    }
+
+    #[test]
+    fn migrate_completes_partial_indexes() {
+        let path = "sled-test-migrate-indexes";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let rooms = ["!first:example.org", "!second:example.org"];
+        let storage = SledStorage::new(path).unwrap();
+        let handle = &storage.handle;
+        let db: &dyn Storage = handle;
+        let token = rt.block_on(async {
+            let resolver = StateResolver::new(storage.get_handle().await.unwrap());
+            db.create_user("alice", "password").await.unwrap();
+            for room_id in rooms.iter() {
+                create_room(db, room_id, &alice).await;
+                let join = NewEvent::builder(
+                    alice.clone(),
+                    EventContent::new("m.room.member", json!({ "membership": "join" })).unwrap(),
+                )
+                .state_key(alice.as_str())
+                .build();
+                db.add_event(room_id, join, &resolver, &Default::default())
+                    .await
+                    .unwrap();
+            }
+            db.create_access_token("alice", "phone").await.unwrap()
+        });
+        let (_, max_depth) = rt.block_on(db.get_prev_events(rooms[1])).unwrap();
+
+        // make it look like a database from before the indexes, where an earlier migration
+        // stopped after indexing one of the rooms
+        handle.all.remove(SCHEMA_VERSION_KEY).unwrap();
+        handle
+            .memberships
+            .remove(format!("{}~{}", alice.as_str(), rooms[1]))
+            .unwrap();
+        handle.stream_orderings.clear().unwrap();
+        handle.user_tokens.clear().unwrap();
+        for room_id in rooms.iter() {
+            handle.headless_events.remove(room_id).unwrap();
+        }
+
+        rt.block_on(async {
+            storage.migrate().await.unwrap();
+            assert_eq!(db.count_joined_rooms(&alice).await.unwrap(), 2);
+            assert_eq!(handle.tokens_of("alice").unwrap(), vec![token]);
+            let query = EventQuery {
+                query_type: QueryType::Timeline { from: 0, to: None },
+                room_id: rooms[1],
+                senders: &[],
+                not_senders: &[],
+                types: &[],
+                not_types: &[],
+                contains_json: None,
+                include_soft_failed: false,
+            };
+            let (events, _) = db.query_pdus(query, false).await.unwrap();
+            assert_eq!(events.len(), 2);
+            for pdu in events {
+                let ordering = db.get_stream_ordering(rooms[1], &pdu.event_id()).await;
+                assert!(ordering.unwrap().is_some());
+            }
+            let (_, migrated_depth) = db.get_prev_events(rooms[1]).await.unwrap();
+            assert_eq!(migrated_depth, max_depth);
+        });
+        let version = handle.all.get(SCHEMA_VERSION_KEY).unwrap().unwrap();
+        assert_eq!(version.as_ref(), &SCHEMA_VERSION.to_be_bytes());
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn migrate_reads_old_records() {
+        let path = "sled-test-migrate-records";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let storage = SledStorage::new(path).unwrap();
+        let handle = &storage.handle;
+        rt.block_on(handle.create_user("alice", "password"))
+            .unwrap();
+        let user: User = handle.users.get_value("alice").unwrap().unwrap();
+
+        // users used to have an account data map where the admin flag is now, and nothing after
+        let empty_account_data: HashMap<String, JsonValue> = HashMap::new();
+        let old_user = DefaultOptions::new()
+            .serialize(&(&user.password_hash, &user.profile, empty_account_data))
+            .unwrap();
+        handle.users.insert("alice", old_user).unwrap();
+        // account data used to be stored without its position in the user's account data stream
+        handle
+            .account_data
+            .insert("alice~~m.direct", serde_json::to_vec(&json!({})).unwrap())
+            .unwrap();
+        // sync batches used to be stored with bincode, and had fewer fields
+        let old_batch = DefaultOptions::new()
+            .serialize(&(
+                vec![("!room:example.org", 3usize)]
+                    .into_iter()
+                    .collect::<HashMap<_, _>>(),
+                HashSet::<String>::new(),
+            ))
+            .unwrap();
+        handle.batches.insert("old", old_batch).unwrap();
+        // presence used to be stored without a position in the presence stream
+        let old_presence = DefaultOptions::new()
+            .serialize(&(PresenceState::Unavailable, Some("lunch"), 5i64))
+            .unwrap();
+        handle.presence.insert("alice", old_presence).unwrap();
+        // and access tokens used to only know who they belonged to
+        let token = Uuid::new_v4();
+        let old_token = DefaultOptions::new()
+            .serialize(&("alice", "phone"))
+            .unwrap();
+        handle
+            .access_tokens
+            .insert(token.as_bytes(), old_token)
+            .unwrap();
+
+        rt.block_on(async {
+            storage.migrate().await.unwrap();
+            assert_eq!(
+                handle.try_auth_full(token).await.unwrap(),
+                Some((String::from("alice"), String::from("phone")))
+            );
+            assert!(handle.verify_password("alice", "password").await.unwrap());
+            assert!(!handle.is_admin("alice").await.unwrap());
+            handle.set_admin("alice", true).await.unwrap();
+            assert!(handle.is_admin("alice").await.unwrap());
+            let tokens = handle.get_tokens_for_user("alice").await.unwrap();
+            assert_eq!(tokens.len(), 1);
+            let changes = handle.get_account_data("alice", 0).await.unwrap();
+            assert_eq!(changes.position, 1);
+            assert_eq!(changes.global.get("m.direct"), Some(&json!({})));
+            handle
+                .set_user_account_data("alice", "m.direct", json!({ "@bob:example.org": [] }))
+                .await
+                .unwrap();
+            let changes = handle.get_account_data("alice", 1).await.unwrap();
+            assert_eq!(changes.position, 2);
+            let batch = handle.get_batch("old").await.unwrap().unwrap();
+            assert_eq!(batch.rooms.get("!room:example.org"), Some(&3));
+            assert_eq!(batch.to_device, 0);
+            let presence = handle.get_presence("alice").await.unwrap().unwrap();
+            assert_eq!(presence.state, PresenceState::Unavailable);
+            assert_eq!(presence.last_active_ts, 5);
+            let (changes, position) = handle.get_presence_changes(0).await.unwrap();
+            assert_eq!(changes.len(), 1);
+            assert_eq!(position, presence.position);
+            handle
+                .update_token_last_seen(token, Some("127.0.0.1"), 1)
+                .await
+                .unwrap();
+        });
+        let version = handle.all.get(SCHEMA_VERSION_KEY).unwrap().unwrap();
+        assert_eq!(version.as_ref(), &SCHEMA_VERSION.to_be_bytes());
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
     }
 }
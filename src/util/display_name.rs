@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use crate::events::{room::Membership, Event, EventContent};
+
+/// Works out what to call each member of a room, given its m.room.member events. A display name
+/// that several joined or invited members share is followed by the member's user ID, as in
+/// `name (@user:domain)`, and members without a display name are called by their user ID.
+///
+/// Returns user ID -> name, for joined and invited members only.
+pub fn disambiguated_names(member_events: &[Event]) -> HashMap<String, String> {
+    let members = member_events
+        .iter()
+        .filter_map(|event| match (&event.event_content, &event.state_key) {
+            (EventContent::Member(member), Some(user_id))
+                if member.membership == Membership::Join
+                    || member.membership == Membership::Invite =>
+            {
+                Some((user_id.as_str(), member.displayname.as_deref()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut uses = HashMap::new();
+    for (_, name) in members.iter() {
+        if let Some(name) = name {
+            *uses.entry(*name).or_insert(0) += 1;
+        }
+    }
+
+    members
+        .iter()
+        .map(|(user_id, name)| {
+            let name = match name {
+                Some(name) if uses[name] > 1 => format!("{} ({})", name, user_id),
+                Some(name) => name.to_string(),
+                None => user_id.to_string(),
+            };
+            (user_id.to_string(), name)
+        })
+        .collect()
+}
@@ -3,10 +3,12 @@ use std::sync::Arc;
 
 use crate::ServerState;
 
+pub mod display_name;
 pub mod mxid;
+pub mod push_rules;
 pub mod storage;
 
-pub use mxid::{MatrixId, MxidError};
+pub use mxid::{EventId, IdError, MatrixId, MxidError, RoomAlias, RoomId};
 pub use storage::StorageExt;
 
 #[post("/_debug/print_the_world")]
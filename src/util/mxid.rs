@@ -1,12 +1,59 @@
 use displaydoc::Display;
-use lazy_static::lazy_static;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    net::{Ipv4Addr, Ipv6Addr},
+};
 
-lazy_static! {
-    static ref SERVER_NAME_REGEX: Regex =
-        Regex::new(include_str!("./mxid_server_name.regex")).unwrap();
+/// Checks that a server name is a DNS name, an IPv4 address or a bracketed IPv6 address, followed
+/// by an optional port.
+fn is_valid_server_name(server_name: &str) -> bool {
+    let (host, port) = if let Some(rest) = server_name.strip_prefix('[') {
+        let (address, rest) = match rest.split_once(']') {
+            Some(split) => split,
+            None => return false,
+        };
+        if address.parse::<Ipv6Addr>().is_err() {
+            return false;
+        }
+        let port = match rest {
+            "" => None,
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None => return false,
+            },
+        };
+        (None, port)
+    } else {
+        match server_name.split_once(':') {
+            Some((host, port)) => (Some(host), Some(port)),
+            None => (Some(server_name), None),
+        }
+    };
+
+    if let Some(port) = port {
+        if port.is_empty()
+            || port.len() > 5
+            || !port.chars().all(|c| c.is_ascii_digit())
+            || port.parse::<u16>().is_err()
+        {
+            return false;
+        }
+    }
+    match host {
+        // something that looks like an IPv4 address has to be one
+        Some(host) if host.chars().all(|c| c.is_ascii_digit() || c == '.') => {
+            host.parse::<Ipv4Addr>().is_ok()
+        }
+        Some(host) => {
+            !host.is_empty()
+                && host.len() <= 255
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        }
+        None => true,
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
@@ -21,7 +68,7 @@ pub enum MxidError {
     InvalidChar,
     /// A Matrix ID must begin with an '@'.
     NoLeadingAt,
-    /// A Matrix ID must contain exactly one colon.
+    /// A Matrix ID must contain a colon between the localpart and the domain.
     WrongNumberOfColons,
     /// A Matrix ID must contain a valid domain name.
     InvalidDomain,
@@ -50,7 +97,7 @@ impl MatrixId {
     }
 
     pub fn domain(&self) -> &str {
-        self.0.split(':').nth(1).unwrap()
+        self.0.split_once(':').unwrap().1
     }
 
     /// Verifies that a localpart and domain could together form a valid Matrix ID.
@@ -67,7 +114,7 @@ impl MatrixId {
             return Err(MxidError::InvalidChar);
         }
 
-        if !SERVER_NAME_REGEX.is_match(domain) {
+        if !is_valid_server_name(domain) {
             return Err(MxidError::InvalidDomain);
         }
 
@@ -84,15 +131,10 @@ impl MatrixId {
             return Err(MxidError::NoLeadingAt);
         }
         let remaining: &str = &mxid[1..];
-        let (localpart, domain) = {
-            let mut iter = remaining.split(':');
-            let localpart = iter.next().unwrap();
-            let domain = iter.next().ok_or(MxidError::WrongNumberOfColons)?;
-            if iter.next() != None {
-                return Err(MxidError::WrongNumberOfColons);
-            }
-            (localpart, domain)
-        };
+        // localparts can't contain colons, but the domain can have a port or be an IPv6 address
+        let (localpart, domain) = remaining
+            .split_once(':')
+            .ok_or(MxidError::WrongNumberOfColons)?;
         Self::validate_parts(localpart, domain)?;
 
         Ok(())
@@ -114,3 +156,241 @@ impl TryFrom<&str> for MatrixId {
         Ok(MatrixId(value.to_string()))
     }
 }
+
+#[derive(Debug, Display)]
+pub enum IdError {
+    /// An identifier can only be 255 characters long.
+    TooLong,
+    /// This kind of identifier must begin with '{0}'.
+    WrongSigil(char),
+    /// A room alias must contain a colon between the localpart and the domain.
+    WrongNumberOfColons,
+    /// A room alias must have a localpart.
+    EmptyLocalpart,
+    /// An identifier must contain a valid domain name.
+    InvalidDomain,
+}
+
+/// Checks the parts of identifiers that all kinds have in common, and returns what follows the
+/// sigil.
+fn validate_sigil(id: &str, sigil: char) -> Result<&str, IdError> {
+    if id.len() > 255 {
+        return Err(IdError::TooLong);
+    }
+    id.strip_prefix(sigil).ok_or(IdError::WrongSigil(sigil))
+}
+
+/// A room alias, such as `#room:example.org`.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RoomAlias(String);
+
+impl RoomAlias {
+    pub fn new(localpart: &str, domain: &str) -> Result<Self, IdError> {
+        RoomAlias::try_from(format!("#{}:{}", localpart, domain))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn localpart(&self) -> &str {
+        self.0.trim_start_matches('#').split(':').next().unwrap()
+    }
+
+    pub fn domain(&self) -> &str {
+        self.0.split_once(':').unwrap().1
+    }
+
+    /// Verifies that a `&str` forms a valid room alias.
+    pub fn validate_all(alias: &str) -> Result<(), IdError> {
+        let remaining = validate_sigil(alias, '#')?;
+        let (localpart, domain) = remaining
+            .split_once(':')
+            .ok_or(IdError::WrongNumberOfColons)?;
+        if localpart.is_empty() {
+            return Err(IdError::EmptyLocalpart);
+        }
+        if !is_valid_server_name(domain) {
+            return Err(IdError::InvalidDomain);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for RoomAlias {
+    type Error = IdError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RoomAlias::validate_all(&value)?;
+        Ok(RoomAlias(value))
+    }
+}
+
+impl TryFrom<&str> for RoomAlias {
+    type Error = IdError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        RoomAlias::validate_all(value)?;
+        Ok(RoomAlias(value.to_string()))
+    }
+}
+
+/// A room ID, such as `!opaque:example.org`. Everything between the sigil and the first colon is
+/// opaque.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RoomId(String);
+
+impl RoomId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Verifies that a `&str` forms a valid room ID.
+    pub fn validate_all(room_id: &str) -> Result<(), IdError> {
+        let remaining = validate_sigil(room_id, '!')?;
+        // the domain may have a port, so only the first colon is a separator
+        let (_, domain) = remaining.split_once(':').ok_or(IdError::InvalidDomain)?;
+        if !is_valid_server_name(domain) {
+            return Err(IdError::InvalidDomain);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for RoomId {
+    type Error = IdError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RoomId::validate_all(&value)?;
+        Ok(RoomId(value))
+    }
+}
+
+impl TryFrom<&str> for RoomId {
+    type Error = IdError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        RoomId::validate_all(value)?;
+        Ok(RoomId(value.to_string()))
+    }
+}
+
+/// An event ID, such as `$opaque`. Since room version 4 these are hashes of the event, so unlike
+/// the other identifiers they don't have a domain.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct EventId(String);
+
+impl EventId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Verifies that a `&str` forms a valid event ID.
+    pub fn validate_all(event_id: &str) -> Result<(), IdError> {
+        validate_sigil(event_id, '$').map(drop)
+    }
+}
+
+impl TryFrom<String> for EventId {
+    type Error = IdError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        EventId::validate_all(&value)?;
+        Ok(EventId(value))
+    }
+}
+
+impl TryFrom<&str> for EventId {
+    type Error = IdError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        EventId::validate_all(value)?;
+        Ok(EventId(value.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::{EventId, IdError, MatrixId, MxidError, RoomAlias, RoomId};
+
+    #[test]
+    fn room_alias() {
+        let alias = RoomAlias::try_from("#hangout:example.org").unwrap();
+        assert_eq!(alias.localpart(), "hangout");
+        assert_eq!(alias.domain(), "example.org");
+        assert_eq!(RoomAlias::new("hangout", "example.org").unwrap(), alias);
+        assert!(matches!(
+            RoomAlias::try_from("hangout:example.org"),
+            Err(IdError::WrongSigil('#'))
+        ));
+        assert!(matches!(
+            RoomAlias::try_from("#hangout"),
+            Err(IdError::WrongNumberOfColons)
+        ));
+        assert!(matches!(
+            RoomAlias::try_from("#:example.org"),
+            Err(IdError::EmptyLocalpart)
+        ));
+        assert!(matches!(
+            RoomAlias::try_from("#hangout:not a domain"),
+            Err(IdError::InvalidDomain)
+        ));
+    }
+
+    #[test]
+    fn room_and_event_ids() {
+        // the domain of a room id may have a port
+        assert!(RoomId::try_from("!abc:example.org:8448").is_ok());
+        assert!(matches!(
+            RoomId::try_from("$abc:example.org"),
+            Err(IdError::WrongSigil('!'))
+        ));
+        assert!(matches!(
+            RoomId::try_from("!abc"),
+            Err(IdError::InvalidDomain)
+        ));
+
+        assert!(EventId::try_from("$YWJj").is_ok());
+        assert!(matches!(
+            EventId::try_from("!YWJj"),
+            Err(IdError::WrongSigil('$'))
+        ));
+        // ids are deserialized through the same validation, which is what actix uses for paths
+        assert!(serde_json::from_str::<RoomId>("\"!abc:example.org\"").is_ok());
+        assert!(serde_json::from_str::<RoomId>("\"abc:example.org\"").is_err());
+    }
+
+    #[test]
+    fn server_names() {
+        let mxid = MatrixId::try_from("@a:[::1]:8448").unwrap();
+        assert_eq!(mxid.localpart(), "a");
+        assert_eq!(mxid.domain(), "[::1]:8448");
+        assert!(MatrixId::try_from("@a:[::1]").is_ok());
+        assert!(MatrixId::try_from("@a:1.2.3.4").is_ok());
+        assert!(MatrixId::try_from("@a:example.org:8448").is_ok());
+        for bad in &[
+            "@a:exa mple",
+            "@a:",
+            "@a:1.2.3.256",
+            "@a:[::1",
+            "@a:[nonsense]",
+            "@a:[::1]8448",
+            "@a:example.org:",
+            "@a:example.org:99999",
+            "@a:example.org:80:80",
+        ] {
+            assert!(
+                matches!(MatrixId::try_from(*bad), Err(MxidError::InvalidDomain)),
+                "{} was accepted",
+                bad
+            );
+        }
+
+        let alias = RoomAlias::try_from("#room:[::1]:8448").unwrap();
+        assert_eq!(alias.domain(), "[::1]:8448");
+        assert!(RoomId::try_from("!abc:1.2.3.4:8448").is_ok());
+        assert!(matches!(
+            RoomId::try_from("!abc:exa mple"),
+            Err(IdError::InvalidDomain)
+        ));
+    }
+}
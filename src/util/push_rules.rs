@@ -0,0 +1,287 @@
+use serde_json::{json, Value as JsonValue};
+use std::convert::TryFrom;
+
+use crate::{events::room::PowerLevels, util::MatrixId};
+
+/// The account data type that a user's push rules are stored under.
+pub const PUSH_RULES: &str = "m.push_rules";
+
+/// The server-default push rules from the spec, which apply until the user changes them.
+pub fn default_push_rules(user_id: &MatrixId) -> JsonValue {
+    let notify = json!(["notify", { "set_tweak": "highlight", "value": false }]);
+    let notify_sound = json!([
+        "notify",
+        { "set_tweak": "sound", "value": "default" },
+        { "set_tweak": "highlight", "value": false }
+    ]);
+    let highlight_sound = json!([
+        "notify",
+        { "set_tweak": "sound", "value": "default" },
+        { "set_tweak": "highlight" }
+    ]);
+    let event_match =
+        |key: &str, pattern: &str| json!({ "kind": "event_match", "key": key, "pattern": pattern });
+    let rule = |rule_id: &str, conditions: Vec<JsonValue>, actions: &JsonValue| {
+        json!({
+            "rule_id": rule_id,
+            "default": true,
+            "enabled": true,
+            "conditions": conditions,
+            "actions": actions,
+        })
+    };
+    let one_to_one = json!({ "kind": "room_member_count", "is": "2" });
+
+    json!({
+        "global": {
+            "override": [
+                {
+                    "rule_id": ".m.rule.master",
+                    "default": true,
+                    "enabled": false,
+                    "conditions": [],
+                    "actions": ["dont_notify"],
+                },
+                rule(
+                    ".m.rule.suppress_notices",
+                    vec![event_match("content.msgtype", "m.notice")],
+                    &json!(["dont_notify"]),
+                ),
+                rule(
+                    ".m.rule.invite_for_me",
+                    vec![
+                        event_match("type", "m.room.member"),
+                        event_match("content.membership", "invite"),
+                        event_match("state_key", user_id.as_str()),
+                    ],
+                    &notify_sound,
+                ),
+                rule(
+                    ".m.rule.member_event",
+                    vec![event_match("type", "m.room.member")],
+                    &json!(["dont_notify"]),
+                ),
+                rule(
+                    ".m.rule.contains_display_name",
+                    vec![json!({ "kind": "contains_display_name" })],
+                    &highlight_sound,
+                ),
+                rule(
+                    ".m.rule.tombstone",
+                    vec![
+                        event_match("type", "m.room.tombstone"),
+                        event_match("state_key", ""),
+                    ],
+                    &json!(["notify", { "set_tweak": "highlight" }]),
+                ),
+                rule(
+                    ".m.rule.roomnotif",
+                    vec![
+                        event_match("content.body", "@room"),
+                        json!({ "kind": "sender_notification_permission", "key": "room" }),
+                    ],
+                    &json!(["notify", { "set_tweak": "highlight" }]),
+                ),
+            ],
+            "content": [
+                {
+                    "rule_id": ".m.rule.contains_user_name",
+                    "default": true,
+                    "enabled": true,
+                    "pattern": user_id.localpart(),
+                    "actions": highlight_sound,
+                },
+            ],
+            "room": [],
+            "sender": [],
+            "underride": [
+                rule(
+                    ".m.rule.call",
+                    vec![event_match("type", "m.call.invite")],
+                    &json!([
+                        "notify",
+                        { "set_tweak": "sound", "value": "ring" },
+                        { "set_tweak": "highlight", "value": false }
+                    ]),
+                ),
+                rule(
+                    ".m.rule.encrypted_room_one_to_one",
+                    vec![one_to_one.clone(), event_match("type", "m.room.encrypted")],
+                    &notify_sound,
+                ),
+                rule(
+                    ".m.rule.room_one_to_one",
+                    vec![one_to_one, event_match("type", "m.room.message")],
+                    &notify_sound,
+                ),
+                rule(
+                    ".m.rule.message",
+                    vec![event_match("type", "m.room.message")],
+                    &notify,
+                ),
+                rule(
+                    ".m.rule.encrypted",
+                    vec![event_match("type", "m.room.encrypted")],
+                    &notify,
+                ),
+            ],
+        }
+    })
+}
+
+/// The parts of a room that push rule conditions can depend on, from the point of view of the
+/// user whose rules they are.
+pub struct PushContext<'a> {
+    /// The user's display name in the room.
+    pub display_name: Option<&'a str>,
+    pub joined_member_count: usize,
+    pub power_levels: &'a PowerLevels,
+}
+
+/// What a user's push rules say should happen for an event.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Actions {
+    pub notify: bool,
+    pub highlight: bool,
+}
+
+/// Runs an event, in client format, through a user's push rules. The first enabled rule that
+/// matches decides what happens; if none do, the user isn't notified.
+pub fn evaluate(rules: &JsonValue, event: &JsonValue, ctx: &PushContext<'_>) -> Actions {
+    for kind in &["override", "content", "room", "sender", "underride"] {
+        let rules = match rules["global"][kind].as_array() {
+            Some(rules) => rules,
+            None => continue,
+        };
+        for rule in rules {
+            if rule["enabled"] == false {
+                continue;
+            }
+            let rule_id = rule["rule_id"].as_str().unwrap_or_default();
+            let matches = match *kind {
+                "content" => match (rule["pattern"].as_str(), event["content"]["body"].as_str()) {
+                    (Some(pattern), Some(body)) => matches_words(pattern, body),
+                    _ => false,
+                },
+                "room" => event["room_id"] == rule_id,
+                "sender" => event["sender"] == rule_id,
+                _ => rule["conditions"]
+                    .as_array()
+                    .map(|conditions| conditions.iter().all(|c| condition_holds(c, event, ctx)))
+                    .unwrap_or(true),
+            };
+            if matches {
+                return actions(&rule["actions"]);
+            }
+        }
+    }
+    Actions::default()
+}
+
+fn actions(actions: &JsonValue) -> Actions {
+    let mut ret = Actions::default();
+    for action in actions.as_array().into_iter().flatten() {
+        if action == "notify" {
+            ret.notify = true;
+        } else if action["set_tweak"] == "highlight" {
+            ret.highlight = action.get("value").map(|v| v == true).unwrap_or(true);
+        }
+    }
+    // highlighting without notifying means nothing
+    ret.highlight &= ret.notify;
+    ret
+}
+
+fn condition_holds(condition: &JsonValue, event: &JsonValue, ctx: &PushContext<'_>) -> bool {
+    match condition["kind"].as_str() {
+        Some("event_match") => {
+            let key = condition["key"].as_str().unwrap_or_default();
+            let pattern = condition["pattern"].as_str().unwrap_or_default();
+            let value = key.split('.').fold(event, |value, field| &value[field]);
+            match value.as_str() {
+                Some(body) if key == "content.body" => matches_words(pattern, body),
+                Some(value) => glob_matches(&lowercase_chars(pattern), &lowercase_chars(value)),
+                None => false,
+            }
+        }
+        Some("contains_display_name") => {
+            match (ctx.display_name, event["content"]["body"].as_str()) {
+                (Some(name), Some(body)) if !name.is_empty() => {
+                    // the display name is matched literally, not as a glob
+                    let name = lowercase_chars(name);
+                    any_words(body, |words| words == &*name)
+                }
+                _ => false,
+            }
+        }
+        Some("room_member_count") => {
+            let is = condition["is"].as_str().unwrap_or_default();
+            let split = is.find(|c: char| c.is_ascii_digit()).unwrap_or(is.len());
+            let (op, count) = is.split_at(split);
+            let count = match count.parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => return false,
+            };
+            let members = ctx.joined_member_count;
+            match op {
+                "" | "==" => members == count,
+                "<" => members < count,
+                ">" => members > count,
+                "<=" => members <= count,
+                ">=" => members >= count,
+                _ => false,
+            }
+        }
+        Some("sender_notification_permission") => {
+            let sender = match event["sender"].as_str().map(MatrixId::try_from) {
+                Some(Ok(sender)) => sender,
+                _ => return false,
+            };
+            condition["key"] == "room"
+                && ctx.power_levels.get_user_level(&sender) >= ctx.power_levels.notifications().room
+        }
+        _ => false,
+    }
+}
+
+fn lowercase_chars(s: &str) -> Vec<char> {
+    s.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// Whether the glob matches some run of whole words in the text, ignoring case.
+fn matches_words(pattern: &str, text: &str) -> bool {
+    let pattern = lowercase_chars(pattern);
+    any_words(text, |words| glob_matches(&pattern, words))
+}
+
+/// Whether the predicate holds for some run of whole words in the lowercased text.
+fn any_words(text: &str, mut predicate: impl FnMut(&[char]) -> bool) -> bool {
+    let text = lowercase_chars(text);
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+    for start in 0..text.len() {
+        if start > 0 && is_word(&text[start - 1]) {
+            continue;
+        }
+        for end in start + 1..=text.len() {
+            if end < text.len() && is_word(&text[end]) {
+                continue;
+            }
+            if predicate(&text[start..end]) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Matches a glob, where `*` matches any run of characters and `?` matches any one character.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        Some((c, rest)) => match text.split_first() {
+            Some((t, text)) if *c == '?' || c == t => glob_matches(rest, text),
+            _ => false,
+        },
+    }
+}
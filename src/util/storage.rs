@@ -1,9 +1,11 @@
 use async_trait::async_trait;
 use displaydoc::Display;
+use ring::signature::Ed25519KeyPair;
 use serde_json::Value as JsonValue;
+use std::{collections::HashMap, time::Duration};
 
 use crate::{
-    error::Error,
+    error::{Error, ErrorKind},
     events::{
         pdu::StoredPdu,
         room::Membership,
@@ -15,7 +17,6 @@ use crate::{
     util::MatrixId,
 };
 
-// TODO: builder pattern
 #[derive(Debug)]
 pub struct NewEvent {
     pub event_content: EventContent,
@@ -25,6 +26,55 @@ pub struct NewEvent {
     pub unsigned: Option<JsonValue>,
 }
 
+impl NewEvent {
+    /// Starts building an event, which every event needs a sender and content for.
+    pub fn builder(sender: MatrixId, event_content: EventContent) -> NewEventBuilder {
+        NewEventBuilder {
+            event_content,
+            sender,
+            state_key: None,
+            redacts: None,
+            unsigned: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NewEventBuilder {
+    event_content: EventContent,
+    sender: MatrixId,
+    state_key: Option<String>,
+    redacts: Option<String>,
+    unsigned: Option<JsonValue>,
+}
+
+impl NewEventBuilder {
+    pub fn state_key(mut self, state_key: impl Into<String>) -> Self {
+        self.state_key = Some(state_key.into());
+        self
+    }
+
+    pub fn redacts(mut self, redacts: impl Into<String>) -> Self {
+        self.redacts = Some(redacts.into());
+        self
+    }
+
+    pub fn unsigned(mut self, unsigned: JsonValue) -> Self {
+        self.unsigned = Some(unsigned);
+        self
+    }
+
+    pub fn build(self) -> NewEvent {
+        NewEvent {
+            event_content: self.event_content,
+            sender: self.sender,
+            state_key: self.state_key,
+            redacts: self.redacts,
+            unsigned: self.unsigned,
+        }
+    }
+}
+
 #[derive(Debug, Display)]
 pub enum AddEventError {
     /// A user tried to send an event to a room which they are not in.
@@ -39,6 +89,46 @@ pub enum AddEventError {
     InsufficientPowerLevel,
     /// The event to be added was invalid.
     InvalidEvent(String),
+    /// An event that the event refers to hasn't been received: {0}
+    MissingEvent(String),
+    /// The event's content hash doesn't match its content.
+    BadContentHash,
+    /// The event isn't signed by the server of its sender.
+    BadSignature,
+}
+
+/// Auth checks a PDU against the state before it and stores it, applying it if it is a
+/// redaction that isn't soft failed.
+async fn store_checked(
+    db: &dyn Storage,
+    pdu: VersionedPdu,
+    state: &State,
+    soft_failed: bool,
+    state_resolver: &StateResolver,
+) -> Result<String, Error> {
+    let auth_status =
+        crate::validate::auth::auth_check_v1(db, &pdu, state, state_resolver.create_events())
+            .await?;
+    let stored_pdu = StoredPdu {
+        inner: pdu,
+        auth_status,
+        soft_failed,
+    };
+    let room_id = stored_pdu.room_id().to_owned();
+    let event_id = stored_pdu.event_id().to_owned();
+    let redacts = match stored_pdu.event_content() {
+        EventContent::Redaction(_) if stored_pdu.did_pass_auth() && !soft_failed => {
+            stored_pdu.redacts().map(String::from)
+        }
+        _ => None,
+    };
+    db.add_pdus(std::slice::from_ref(&stored_pdu)).await?;
+    state_resolver.note_new_event(&stored_pdu, state).await?;
+    if let Some(redacts) = redacts {
+        db.redact_pdu(&room_id, &redacts).await?;
+    }
+
+    Ok(event_id)
 }
 
 pub fn calc_auth_events(event: &NewEvent, state: &State) -> Vec<String> {
@@ -61,18 +151,38 @@ pub fn calc_auth_events(event: &NewEvent, state: &State) -> Vec<String> {
                 auth_events.push(join_rules_event.to_string());
             }
         }
-        // TODO: third party invites
+        if let Some(invite) = &content.third_party_invite {
+            if let Some(third_party_invite_event) =
+                state.get(("m.room.third_party_invite", &invite.signed.token))
+            {
+                auth_events.push(third_party_invite_event.to_string());
+            }
+        }
     }
     auth_events
 }
 
 #[async_trait]
 pub trait StorageExt {
+    /// Creates an event on top of the room's current extremities, signs it with `keys` and
+    /// stores it.
     async fn add_event(
         &self,
         room_id: &str,
         event: NewEvent,
         state_resolver: &StateResolver,
+        keys: &HashMap<String, Ed25519KeyPair>,
+    ) -> Result<String, Error>;
+
+    /// Adds an event that another server sent us. Events timestamped further in the future than
+    /// `clock_skew_tolerance` are rejected, so that they can't skew the ordering of the room.
+    /// Events that pass auth against the state before them but not the room's current state are
+    /// stored soft failed.
+    async fn receive_pdu(
+        &self,
+        pdu: VersionedPdu,
+        state_resolver: &StateResolver,
+        clock_skew_tolerance: Duration,
     ) -> Result<String, Error>;
 
     async fn get_sender_power_level(&self, room_id: &str, event_id: &str) -> Result<u32, Error>;
@@ -87,14 +197,30 @@ impl<'a> StorageExt for dyn Storage + 'a {
         room_id: &str,
         event: NewEvent,
         state_resolver: &StateResolver,
+        keys: &HashMap<String, Ed25519KeyPair>,
     ) -> Result<String, Error> {
-        if let EventContent::Create(_) = event.event_content {
-            panic!("wrong function");
-        }
-        let (prev_events, max_depth) = self.get_prev_events(room_id).await?;
-        let state = state_resolver.resolve(room_id, &prev_events).await?;
-
-        let auth_events = calc_auth_events(&event, &state);
+        let (prev_events, max_depth, state, auth_events, room_version) =
+            if let EventContent::Create(create) = &event.event_content {
+                // a missing room version means version 1, which we don't support
+                let room_version = create.room_version.clone().unwrap_or_else(|| "1".into());
+                let state = state_resolver.resolve(room_id, &[]).await?;
+                (Vec::new(), -1, state, Vec::new(), room_version)
+            } else {
+                let (prev_events, max_depth) = self.get_prev_events(room_id).await?;
+                let state = state_resolver.resolve(room_id, &prev_events).await?;
+                let auth_events = calc_auth_events(&event, &state);
+                let create_id = state
+                    .get(("m.room.create", ""))
+                    .ok_or(ErrorKind::RoomNotFound)?;
+                let room_version = state_resolver
+                    .create_events()
+                    .get(self, room_id, create_id)
+                    .await?
+                    .ok_or(ErrorKind::RoomNotFound)?
+                    .room_version
+                    .unwrap_or_else(|| "1".into());
+                (prev_events, max_depth, state, auth_events, room_version)
+            };
 
         let origin = event.sender.domain().to_owned();
         let unhashed = UnhashedPdu {
@@ -104,23 +230,51 @@ impl<'a> StorageExt for dyn Storage + 'a {
             state_key: event.state_key,
             unsigned: event.unsigned,
             redacts: event.redacts,
-            origin,
+            origin: origin.clone(),
             origin_server_ts: chrono::Utc::now().timestamp_millis(),
             prev_events,
             depth: max_depth.saturating_add(1),
             auth_events,
         };
-        let pdu = VersionedPdu::V4(unhashed.finalize());
+        let pdu = VersionedPdu::new(&room_version, unhashed.finalize())
+            .ok_or(ErrorKind::UnsupportedRoomVersion)?
+            .sign(&origin, keys);
 
-        let auth_status = crate::validate::auth::auth_check_v1(self, &pdu, &state).await?;
-        let stored_pdu = StoredPdu {
-            inner: pdu,
-            auth_status,
-        };
-        let event_id = stored_pdu.event_id().to_owned();
-        self.add_pdus(&[stored_pdu]).await?;
+        store_checked(self, pdu, &state, false, state_resolver).await
+    }
 
-        Ok(event_id)
+    async fn receive_pdu(
+        &self,
+        pdu: VersionedPdu,
+        state_resolver: &StateResolver,
+        clock_skew_tolerance: Duration,
+    ) -> Result<String, Error> {
+        let latest_ts =
+            chrono::Utc::now().timestamp_millis() + clock_skew_tolerance.as_millis() as i64;
+        if pdu.origin_server_ts() > latest_ts {
+            return Err(ErrorKind::from(AddEventError::InvalidEvent(format!(
+                "origin_server_ts {} is too far in the future",
+                pdu.origin_server_ts()
+            )))
+            .into());
+        }
+
+        //TODO: fetch missing prev events instead of failing to resolve the state before them
+        let state = state_resolver
+            .resolve(pdu.room_id(), pdu.prev_events())
+            .await?;
+        // an event can be allowed by the state before it and still break the rules as they stand
+        // now, e.g. if it comes from someone who was banned in the meantime
+        let current_state = state_resolver.resolve_current(pdu.room_id()).await?;
+        let soft_failed = !crate::validate::auth::auth_check_v1(
+            self,
+            &pdu,
+            &current_state,
+            state_resolver.create_events(),
+        )
+        .await?
+        .is_pass();
+        store_checked(self, pdu, &state, soft_failed, state_resolver).await
     }
 
     //TODO: check return type
@@ -1,13 +1,20 @@
-use std::{collections::HashMap, convert::TryFrom};
+use std::{collections::HashMap, convert::TryFrom, future::Future, sync::Mutex};
 
+use ring::signature::{UnparsedPublicKey, ED25519};
 use serde::{Deserialize, Serialize};
+use serde_canonical::ser::to_string as to_canonical_json;
+use serde_json::Value as JsonValue;
 
 use crate::{
     error::Error,
     events::{
-        room::{Create, JoinRule, JoinRules, Member, Membership, PowerLevels},
+        pdu::StoredPdu,
+        room::{
+            Create, JoinRule, JoinRules, Member, MemberThirdPartyInvite, Membership, PowerLevels,
+            ThirdPartyInvite,
+        },
         room_version::VersionedPdu,
-        EventContent,
+        EventContent, EventType,
     },
     state::State,
     storage::Storage,
@@ -26,60 +33,185 @@ impl AuthStatus {
     }
 }
 
+/// Remembers the create event of each room, since every auth check needs it. A room's create event
+/// can't change, so nothing is ever evicted.
+#[derive(Default)]
+pub struct CreateEvents {
+    /// room_id -> (event_id, content)
+    cache: Mutex<HashMap<String, (String, Create)>>,
+}
+
+impl CreateEvents {
+    /// Gets the content of the event if it is the room's create event, and None otherwise. The
+    /// database is only asked once per room.
+    pub async fn get(
+        &self,
+        db: &dyn Storage,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<Create>, Error> {
+        self.get_or_fetch(room_id, event_id, || db.get_pdu(room_id, event_id))
+            .await
+    }
+
+    async fn get_or_fetch<F, Fut>(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        fetch: F,
+    ) -> Result<Option<Create>, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<StoredPdu>, Error>>,
+    {
+        if let Some((create_id, create)) = self.cache.lock().unwrap().get(room_id) {
+            if create_id == event_id {
+                return Ok(Some(create.clone()));
+            }
+        }
+        let pdu = match fetch().await? {
+            Some(pdu) => pdu,
+            None => return Ok(None),
+        };
+        let create = match pdu.event_content() {
+            EventContent::Create(create) if pdu.state_key() == Some("") => create.clone(),
+            _ => return Ok(None),
+        };
+        // a room only has one create event, so don't let a bogus one replace it
+        self.cache
+            .lock()
+            .unwrap()
+            .entry(room_id.to_string())
+            .or_insert_with(|| (event_id.to_string(), create.clone()));
+        Ok(Some(create))
+    }
+}
+
+/// Why an event couldn't be checked at all.
+enum Unauthable {
+    /// Something went wrong on our end.
+    Error(Error),
+    /// The event, or one of the events that authorise it, is malformed or missing. These come
+    /// from whoever sent the event, so this just means the event fails auth.
+    Malformed,
+}
+
+impl From<Error> for Unauthable {
+    fn from(e: Error) -> Self {
+        Unauthable::Error(e)
+    }
+}
+
+impl From<serde_json::Error> for Unauthable {
+    fn from(e: serde_json::Error) -> Self {
+        Unauthable::Error(e.into())
+    }
+}
+
 pub async fn auth_check_v1(
     db: &dyn Storage,
     pdu: &VersionedPdu,
     state: &State,
+    create_events: &CreateEvents,
 ) -> Result<AuthStatus, Error> {
+    match check_v1(db, pdu, state, create_events).await {
+        Ok(status) => Ok(status),
+        Err(Unauthable::Malformed) => Ok(AuthStatus::Fail),
+        Err(Unauthable::Error(e)) => Err(e),
+    }
+}
+
+/// Gets the content of the event in `state` with the given state key. Unlike
+/// `State::get_content`, an event that's missing, or isn't the state event it's meant to be, is
+/// `Malformed` rather than a panic.
+async fn auth_content<T: EventType>(
+    db: &dyn Storage,
+    state: &State,
+    state_key: &str,
+) -> Result<Option<T>, Unauthable> {
+    let event_id = match state.get((T::EVENT_TYPE, state_key)) {
+        Some(event_id) => event_id,
+        None => return Ok(None),
+    };
+    let pdu = db
+        .get_pdu(state.room_id(), event_id)
+        .await?
+        .ok_or(Unauthable::Malformed)?;
+    if pdu.state_key() != Some(state_key) {
+        return Err(Unauthable::Malformed);
+    }
+    let content = T::try_from(pdu.event_content().clone()).map_err(|_| Unauthable::Malformed)?;
+    Ok(Some(content))
+}
+
+async fn check_v1(
+    db: &dyn Storage,
+    pdu: &VersionedPdu,
+    state: &State,
+    create_events: &CreateEvents,
+) -> Result<AuthStatus, Unauthable> {
     use AuthStatus::{Fail, Pass};
 
-    // This function panics a lot eg when auth_events or prev_events don't exist. This is
-    // intentional at the moment because if we're crafting a new event and we get that stuff
-    // wrong it's a program error, and I think if we're receiving an event via federation then
-    // we should have already attempted to receive any missing {auth,prev}_events
+    if let VersionedPdu::V6(_) = pdu {
+        let content = serde_json::to_value(pdu.event_content())?;
+        if !has_canonical_numbers(&content) {
+            return Ok(Fail);
+        }
+    }
+
     if let EventContent::Create(_) = pdu.event_content() {
         if !pdu.prev_events().is_empty() {
             return Ok(Fail);
         }
-        let room_id_domain = pdu.room_id().split_once(':').expect("invalid room id").1;
+        let room_id_domain = match pdu.room_id().split_once(':') {
+            Some((_, domain)) => domain,
+            None => return Ok(Fail),
+        };
         if pdu.sender().domain() != room_id_domain {
             return Ok(Fail);
         }
-        // cant check room version if v4 is embedded in the type system lmao
+        // the room version was already checked when the pdu was constructed
         return Ok(Pass);
     }
 
-    let mut auth_events = HashMap::new();
+    // the create event is usually the first auth event, in which case this doesn't touch the
+    // database
+    let mut has_create_event = false;
     for event_id in pdu.auth_events().iter() {
-        let pdu = db
-            .get_pdu(&pdu.room_id(), event_id)
+        if create_events
+            .get(db, pdu.room_id(), event_id)
             .await?
-            .expect("auth event doesn't exist");
-        auth_events.insert(
-            (
-                pdu.event_content().get_type().to_string(),
-                pdu.state_key().expect("auth event isn't state").to_string(),
-            ),
-            pdu,
-        );
+            .is_some()
+        {
+            has_create_event = true;
+            break;
+        }
     }
-
-    if !auth_events.contains_key(&("m.room.create".to_string(), "".to_string())) {
+    if !has_create_event {
         return Ok(Fail);
     }
 
-    if pdu.event_content().get_type() == "m.room.aliases" {
-        if pdu.state_key() == None {
-            return Ok(Fail);
+    // room version 6 got rid of this special case, so there the event is authorised like any
+    // other state event
+    if let EventContent::Aliases(_) = pdu.event_content() {
+        if !matches!(pdu, VersionedPdu::V6(_)) {
+            // only a server can set its own aliases, and it doesn't need any power to do so
+            if pdu.state_key() != Some(pdu.sender().domain()) {
+                return Ok(Fail);
+            }
+            return Ok(Pass);
         }
-        // whee im ignoring step 4-2 because i cant find proper docs for it and it's probably
-        // obsolete by now anywayyyyyyyyy
-        // also 4-3 is misleading because it looks like a short circuit but isnt wheeeeeee
     }
 
-    let creator = state.get_content::<Create>(db, "").await?.unwrap().creator;
-    let power_levels = state
-        .get_content::<PowerLevels>(db, "")
+    let create_id = state
+        .get(("m.room.create", ""))
+        .ok_or(Unauthable::Malformed)?;
+    let creator = create_events
+        .get(db, pdu.room_id(), create_id)
+        .await?
+        .ok_or(Unauthable::Malformed)?
+        .creator;
+    let power_levels = auth_content::<PowerLevels>(db, state, "")
         .await?
         .unwrap_or_else(|| PowerLevels::no_event_default_levels(&creator));
 
@@ -94,25 +226,21 @@ pub async fn auth_check_v1(
 
                 // if the room has just been created by this user, allow them to join
                 if pdu.prev_events().len() == 1 {
-                    let prev_event = db
-                        .get_pdu(&pdu.room_id(), &pdu.prev_events()[0])
-                        .await?
-                        .expect("prev_event doesn't exist");
-                    if let EventContent::Create(create_content) = prev_event.event_content() {
+                    // a prev event that hasn't arrived yet can't be the create event
+                    let prev_event = db.get_pdu(pdu.room_id(), &pdu.prev_events()[0]).await?;
+                    if let Some(EventContent::Create(create_content)) =
+                        prev_event.as_ref().map(StoredPdu::event_content)
+                    {
                         if *pdu.sender() == create_content.creator {
                             return Ok(Pass);
                         }
-                    } else {
-                        // oh no
-                        panic!("oh no");
+                        // not so sure about this bit
+                        return Ok(Fail);
                     }
-                    // not so sure about this bit
-                    return Ok(Fail);
                 }
 
                 // get the user's membership in this room if they have one
-                let membership = state
-                    .get_content::<Member>(db, pdu.sender().as_str())
+                let membership = auth_content::<Member>(db, state, pdu.sender().as_str())
                     .await?
                     .map(|c| c.membership);
 
@@ -122,8 +250,7 @@ pub async fn auth_check_v1(
                 }
 
                 // get the room's join rules
-                let join_rule = state
-                    .get_content::<JoinRules>(db, "")
+                let join_rule = auth_content::<JoinRules>(db, state, "")
                     .await?
                     .map(|c| c.join_rule);
 
@@ -136,14 +263,44 @@ pub async fn auth_check_v1(
                     return Ok(Pass);
                 }
 
+                // a signed third party invite stands in for an m.room.member invite
+                if join_rule == Some(JoinRule::Invite) {
+                    if let Some(invite) = &content.third_party_invite {
+                        let room_id = pdu.room_id();
+                        let user_id = pdu.sender().as_str();
+                        if check_third_party_invite(db, state, room_id, user_id, invite)
+                            .await?
+                            .is_some()
+                        {
+                            return Ok(Pass);
+                        }
+                    }
+                }
+
                 return Ok(Fail);
             }
             Membership::Invite => {
-                //TODO: third party invites
+                if let Some(invite) = &content.third_party_invite {
+                    let target_user_id = pdu.state_key().ok_or(Unauthable::Malformed)?;
+                    let target_user_membership = auth_content::<Member>(db, state, target_user_id)
+                        .await?
+                        .map(|c| c.membership);
+                    if target_user_membership == Some(Membership::Ban) {
+                        return Ok(Fail);
+                    }
+
+                    let third_party_invite =
+                        check_third_party_invite(db, state, pdu.room_id(), target_user_id, invite)
+                            .await?;
+                    // only whoever sent the third party invite can exchange it for a real one
+                    return match third_party_invite {
+                        Some(event) if event.sender() == pdu.sender() => Ok(Pass),
+                        _ => Ok(Fail),
+                    };
+                }
 
                 // get the sender's membership in this room if they have one
-                let sender_membership = state
-                    .get_content::<Member>(db, pdu.sender().as_str())
+                let sender_membership = auth_content::<Member>(db, state, pdu.sender().as_str())
                     .await?
                     .map(|c| c.membership);
 
@@ -153,9 +310,8 @@ pub async fn auth_check_v1(
                 }
 
                 // can't invite people if they're banned or already in
-                let target_user_id = pdu.state_key().clone().expect("invitation has no target");
-                let target_user_membership = state
-                    .get_content::<Member>(db, &target_user_id)
+                let target_user_id = pdu.state_key().ok_or(Unauthable::Malformed)?;
+                let target_user_membership = auth_content::<Member>(db, state, target_user_id)
                     .await?
                     .map(|c| c.membership);
                 match target_user_membership {
@@ -171,8 +327,7 @@ pub async fn auth_check_v1(
                 }
             }
             Membership::Leave => {
-                let sender_membership = state
-                    .get_content::<Member>(db, pdu.sender().as_str())
+                let sender_membership = auth_content::<Member>(db, state, pdu.sender().as_str())
                     .await?
                     .map(|c| c.membership);
 
@@ -190,9 +345,8 @@ pub async fn auth_check_v1(
                     return Ok(Fail);
                 }
 
-                let target_user_id = pdu.state_key().clone().expect("kick has no target");
-                let target_user_membership = state
-                    .get_content::<Member>(db, &target_user_id)
+                let target_user_id = pdu.state_key().ok_or(Unauthable::Malformed)?;
+                let target_user_membership = auth_content::<Member>(db, state, target_user_id)
                     .await?
                     .map(|c| c.membership);
 
@@ -207,7 +361,7 @@ pub async fn auth_check_v1(
                 // you in power level
                 let sender_level = power_levels.get_user_level(&pdu.sender());
                 let target_level = power_levels.get_user_level(
-                    &MatrixId::try_from(target_user_id).expect("target not valid matrix id"),
+                    &MatrixId::try_from(target_user_id).map_err(|_| Unauthable::Malformed)?,
                 );
                 if sender_level >= power_levels.kick() && sender_level > target_level {
                     return Ok(Pass);
@@ -216,8 +370,7 @@ pub async fn auth_check_v1(
                 return Ok(Fail);
             }
             Membership::Ban => {
-                let sender_membership = state
-                    .get_content::<Member>(db, pdu.sender().as_str())
+                let sender_membership = auth_content::<Member>(db, state, pdu.sender().as_str())
                     .await?
                     .map(|c| c.membership);
 
@@ -227,9 +380,9 @@ pub async fn auth_check_v1(
                 }
 
                 let sender_level = power_levels.get_user_level(&pdu.sender());
-                let target_user_id = pdu.state_key().clone().expect("ban has no target");
+                let target_user_id = pdu.state_key().ok_or(Unauthable::Malformed)?;
                 let target_level = power_levels.get_user_level(
-                    &MatrixId::try_from(target_user_id).expect("target not valid matrix id"),
+                    &MatrixId::try_from(target_user_id).map_err(|_| Unauthable::Malformed)?,
                 );
 
                 if sender_level >= power_levels.ban() && sender_level > target_level {
@@ -242,8 +395,7 @@ pub async fn auth_check_v1(
         }
     }
 
-    let sender_membership = state
-        .get_content::<Member>(db, pdu.sender().as_str())
+    let sender_membership = auth_content::<Member>(db, state, pdu.sender().as_str())
         .await?
         .map(|c| c.membership);
 
@@ -323,6 +475,14 @@ pub async fn auth_check_v1(
         {
             return Ok(Fail);
         }
+        // earlier room versions don't protect the notification levels
+        if matches!(pdu, VersionedPdu::V6(_))
+            && old_power_levels.notifications().room != new_power_levels.notifications().room
+            && (old_power_levels.notifications().room > sender_level
+                || new_power_levels.notifications().room > sender_level)
+        {
+            return Ok(Fail);
+        }
 
         for (key, new_value) in new_power_levels.events.iter() {
             let old_value = old_power_levels.events.get(key);
@@ -387,3 +547,163 @@ pub async fn auth_check_v1(
 
     Ok(Pass)
 }
+
+/// Checks that `invite` claims an m.room.third_party_invite in the room for `user_id`, and that
+/// it was signed with that invite's public key. Returns the claimed event if so.
+async fn check_third_party_invite(
+    db: &dyn Storage,
+    state: &State,
+    room_id: &str,
+    user_id: &str,
+    invite: &MemberThirdPartyInvite,
+) -> Result<Option<StoredPdu>, Error> {
+    if invite.signed.mxid != user_id {
+        return Ok(None);
+    }
+    let event_id = match state.get(("m.room.third_party_invite", &invite.signed.token)) {
+        Some(event_id) => event_id,
+        None => return Ok(None),
+    };
+    let event = match db.get_pdu(room_id, event_id).await? {
+        Some(event) => event,
+        None => return Ok(None),
+    };
+    let public_key = match event.event_content() {
+        EventContent::ThirdPartyInvite(ThirdPartyInvite {
+            public_key: Some(public_key),
+            ..
+        }) => public_key,
+        _ => return Ok(None),
+    };
+    let public_key = match decode_base64(public_key) {
+        Some(public_key) => UnparsedPublicKey::new(&ED25519, public_key),
+        None => return Ok(None),
+    };
+
+    let mut signed = serde_json::to_value(&invite.signed)?;
+    signed.as_object_mut().unwrap().remove("signatures");
+    let message = match to_canonical_json(&signed) {
+        Ok(message) => message,
+        Err(_) => return Ok(None),
+    };
+    for signature in invite
+        .signed
+        .signatures
+        .values()
+        .flat_map(|keys| keys.values())
+    {
+        if let Some(signature) = decode_base64(signature) {
+            if public_key.verify(message.as_bytes(), &signature).is_ok() {
+                return Ok(Some(event));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Decodes unpadded base64, which identity servers may send in either the standard or the URL
+/// safe alphabet.
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches('=');
+    base64::decode_config(input, base64::STANDARD_NO_PAD)
+        .or_else(|_| base64::decode_config(input, base64::URL_SAFE_NO_PAD))
+        .ok()
+}
+
+/// Checks that all numbers in a JSON value are integers in the range that canonical JSON allows,
+/// as required from room version 6 onwards.
+fn has_canonical_numbers(value: &JsonValue) -> bool {
+    const MAX: i64 = (1 << 53) - 1;
+    match value {
+        JsonValue::Number(n) => matches!(n.as_i64(), Some(n) if (-MAX..=MAX).contains(&n)),
+        JsonValue::Array(values) => values.iter().all(has_canonical_numbers),
+        JsonValue::Object(map) => map.values().all(has_canonical_numbers),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{AuthStatus, CreateEvents};
+    use crate::{
+        error::Error,
+        events::{
+            pdu::StoredPdu,
+            room::{Create, Name},
+            room_version::{v4::UnhashedPdu, VersionedPdu},
+            EventContent,
+        },
+        util::MatrixId,
+    };
+
+    fn pdu(event_content: EventContent) -> StoredPdu {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        StoredPdu {
+            inner: VersionedPdu::V4(
+                UnhashedPdu {
+                    event_content,
+                    room_id: String::from("!room:example.org"),
+                    sender: alice,
+                    state_key: Some(String::new()),
+                    unsigned: None,
+                    redacts: None,
+                    origin: String::from("example.org"),
+                    origin_server_ts: 0,
+                    prev_events: Vec::new(),
+                    depth: 0,
+                    auth_events: Vec::new(),
+                }
+                .finalize(),
+            ),
+            auth_status: AuthStatus::Pass,
+            soft_failed: false,
+        }
+    }
+
+    #[test]
+    fn create_event_is_fetched_once() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let create = pdu(EventContent::Create(Create {
+                creator: MatrixId::new("alice", "example.org").unwrap(),
+                room_version: Some(String::from("4")),
+                predecessor: None,
+                extra: HashMap::new(),
+            }));
+            let name = pdu(EventContent::Name(Name {
+                name: Some(String::from("room")),
+            }));
+            let (create_id, name_id) = (create.event_id().to_owned(), name.event_id().to_owned());
+
+            let cache = CreateEvents::default();
+            let fetches = AtomicUsize::new(0);
+            let fetch = |pdu: &StoredPdu| {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                let pdu = pdu.clone();
+                async move { Ok::<_, Error>(Some(pdu)) }
+            };
+            for _ in 0..10 {
+                let content = cache
+                    .get_or_fetch("!room:example.org", &create_id, || fetch(&create))
+                    .await
+                    .unwrap();
+                assert_eq!(content.unwrap().creator.localpart(), "alice");
+                let content = cache
+                    .get_or_fetch("!room:example.org", &name_id, || fetch(&name))
+                    .await
+                    .unwrap();
+                assert!(content.is_none());
+            }
+            // other events don't get cached, but the create event is only fetched the first time
+            assert_eq!(fetches.load(Ordering::SeqCst), 11);
+        });
+    }
+}